@@ -0,0 +1,256 @@
+//! The bootloader configuration file.
+//!
+//! The file consists of `key = value` lines, blank lines and lines starting
+//! with `#` are ignored. The recognized keys are:
+//!
+//! - `kernel`: Path of the kernel image on the boot volume.
+//! - `cmdline`: The command line passed to the kernel, overridden by the load
+//!   options the bootloader is started with.
+//! - `video`: The graphics mode, one of `current`, `highest`, a resolution like
+//!   `1024x768`, or a mode number.
+//! - `timeout`: Seconds the boot menu waits before booting, 0 skips the menu.
+//! - `module`: Path of a file to load alongside the kernel, may be repeated.
+//! - `loglevel`: One of `error`, `warn`, `info`, or `debug`.
+
+use crate::graphics::ModePolicy;
+use crate::log::LogLevel;
+use alloc::borrow::ToOwned;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+use rk_uefi::cstr16;
+use rk_uefi::data_types::{CStr16, CString16, EfiStatus, EncodeError};
+use rk_uefi::protocol::{
+    EfiFileProtocol, EFI_FILE_HIDDEN, EFI_FILE_MODE_READ, EFI_FILE_READ_ONLY, EFI_FILE_SYSTEM,
+};
+
+/// The keys recognized in the configuration file.
+const KEYS: [&str; 6] = [
+    "kernel", "cmdline", "video", "timeout", "module", "loglevel",
+];
+
+/// Returns the path of the configuration file on the boot volume.
+pub fn path() -> &'static CStr16 {
+    cstr16!("\\EFI\\ROCKHOPPER\\BOOT.CFG")
+}
+
+/// The bootloader configuration.
+pub struct Config {
+    /// Path of the kernel image.
+    pub kernel: CString16,
+    /// The command line passed to the kernel.
+    pub cmdline: String,
+    /// How to choose the graphics mode.
+    pub video: ModePolicy,
+    /// Seconds the boot menu waits before booting automatically.
+    pub timeout: usize,
+    /// Paths of additional files to load.
+    pub modules: Vec<CString16>,
+    /// How much output the bootloader produces.
+    pub log_level: LogLevel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernel: cstr16!("RK_KERNEL.ELF").to_owned(),
+            cmdline: String::new(),
+            video: ModePolicy::Current,
+            timeout: 3,
+            modules: Vec::new(),
+            log_level: LogLevel::Info,
+        }
+    }
+}
+
+/// An error in the configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Read(EfiStatus),
+    /// The file is not valid UTF-8.
+    InvalidUtf8 { line: usize },
+    /// A line is not on the form `key = value`.
+    MissingEquals { line: usize },
+    /// A line has a key which is not recognized.
+    UnknownKey { line: usize, key: String },
+    /// A value could not be parsed.
+    InvalidValue {
+        line: usize,
+        key: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    /// A path cannot be represented in UCS-2.
+    InvalidPath { line: usize, error: EncodeError },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read(status) => write!(f, "{}: could not read file: {:?}", path(), status),
+            Self::InvalidUtf8 { line } => write!(f, "{}:{}: invalid UTF-8", path(), line),
+            Self::MissingEquals { line } => {
+                write!(
+                    f,
+                    "{}:{}: expected a line on the form key = value",
+                    path(),
+                    line
+                )
+            }
+            Self::UnknownKey { line, key } => {
+                write!(
+                    f,
+                    "{}:{}: unknown key `{}`, expected one of ",
+                    path(),
+                    line,
+                    key
+                )?;
+                for (i, known) in KEYS.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", known)?;
+                }
+                Ok(())
+            }
+            Self::InvalidValue {
+                line,
+                key,
+                value,
+                expected,
+            } => write!(
+                f,
+                "{}:{}: invalid value `{}` for `{}`, expected {}",
+                path(),
+                line,
+                value,
+                key,
+                expected
+            ),
+            Self::InvalidPath { line, error } => {
+                write!(f, "{}:{}: invalid path: {}", path(), line, error)
+            }
+        }
+    }
+}
+
+/// Reads and parses the configuration file from the root of the boot volume.
+///
+/// Returns the default configuration if the file does not exist.
+pub fn load(root: &EfiFileProtocol) -> Result<Config, ConfigError> {
+    let mut ptr = core::ptr::null_mut();
+    let status = root.open(
+        &mut ptr,
+        path(),
+        EFI_FILE_MODE_READ,
+        EFI_FILE_READ_ONLY | EFI_FILE_HIDDEN | EFI_FILE_SYSTEM,
+    );
+    if status == EfiStatus::EFI_NOT_FOUND {
+        return Ok(Config::default());
+    } else if status.is_error() {
+        return Err(ConfigError::Read(status));
+    }
+    let file = unsafe { &*(ptr as *const EfiFileProtocol) };
+
+    let file_size = file.file_size().map_err(ConfigError::Read)?;
+    let mut size: usize = file_size.try_into().unwrap();
+    let mut buffer = vec![0u8; size];
+    let status = file.read(&mut size, unsafe {
+        &mut *(buffer.as_mut_ptr() as *mut core::ffi::c_void)
+    });
+    file.close();
+    if status.is_error() {
+        return Err(ConfigError::Read(status));
+    }
+    buffer.truncate(size);
+
+    let text = core::str::from_utf8(&buffer).map_err(|e| ConfigError::InvalidUtf8 {
+        line: buffer[..e.valid_up_to()]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1,
+    })?;
+    parse(text)
+}
+
+/// Parses the contents of a configuration file.
+pub fn parse(text: &str) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => return Err(ConfigError::MissingEquals { line: line_number }),
+        };
+
+        let invalid = |key: &'static str, expected: &'static str| ConfigError::InvalidValue {
+            line: line_number,
+            key,
+            value: value.to_string(),
+            expected,
+        };
+
+        match key {
+            "kernel" => config.kernel = parse_path(value, line_number)?,
+            "cmdline" => config.cmdline = value.to_string(),
+            "video" => {
+                config.video = parse_video(value).ok_or_else(|| {
+                    invalid("video", "current, highest, WIDTHxHEIGHT, or a mode number")
+                })?
+            }
+            "timeout" => {
+                config.timeout = value
+                    .parse()
+                    .map_err(|_| invalid("timeout", "a number of seconds"))?
+            }
+            "module" => config.modules.push(parse_path(value, line_number)?),
+            "loglevel" => {
+                config.log_level = LogLevel::from_name(value)
+                    .ok_or_else(|| invalid("loglevel", "error, warn, info, or debug"))?
+            }
+            _ => {
+                return Err(ConfigError::UnknownKey {
+                    line: line_number,
+                    key: key.to_string(),
+                })
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// Parses a path, accepting forward slashes as separators.
+fn parse_path(value: &str, line: usize) -> Result<CString16, ConfigError> {
+    value
+        .replace('/', "\\")
+        .parse()
+        .map_err(|error| ConfigError::InvalidPath { line, error })
+}
+
+/// Parses a graphics mode policy.
+fn parse_video(value: &str) -> Option<ModePolicy> {
+    match value {
+        "current" => Some(ModePolicy::Current),
+        "highest" => Some(ModePolicy::Highest),
+        _ => {
+            let mut parts = value.splitn(2, 'x');
+            let first = parts.next()?.parse().ok()?;
+            match parts.next() {
+                Some(height) => Some(ModePolicy::Resolution(first, height.parse().ok()?)),
+                None => Some(ModePolicy::Mode(first)),
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Graphics mode selection and the boot splash.
+
+use core::fmt;
+use rk_bootinfo::PixelFormat;
+use rk_uefi::data_types::EfiStatus;
+use rk_uefi::protocol::{
+    EfiGraphicsOutputBltPixel, EfiGraphicsOutputModeInformation, EfiGraphicsOutputProtocol,
+    EfiGraphicsPixelFormat,
+};
+
+/// How to choose the graphics mode to boot the kernel in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ModePolicy {
+    /// Keep the mode set up by the firmware.
+    Current,
+    /// The supported mode with the most pixels.
+    Highest,
+    /// A supported mode with the given width and height.
+    Resolution(u32, u32),
+    /// A specific mode number.
+    Mode(u32),
+}
+
+impl fmt::Display for ModePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Current => write!(f, "current"),
+            Self::Highest => write!(f, "highest"),
+            Self::Resolution(width, height) => write!(f, "{}x{}", width, height),
+            Self::Mode(mode) => write!(f, "mode {}", mode),
+        }
+    }
+}
+
+/// An error which can occur when selecting a graphics mode.
+#[derive(Debug)]
+pub enum ModeError {
+    /// No supported mode matches the policy.
+    NotFound(ModePolicy),
+    /// The mode exists, but the kernel cannot draw to its frame buffer.
+    UnsupportedPixelFormat(u32, EfiGraphicsPixelFormat),
+    /// The firmware could not query or set the mode.
+    Firmware(u32, EfiStatus),
+}
+
+impl fmt::Display for ModeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound(policy) => write!(f, "no supported graphics mode matches {}", policy),
+            Self::UnsupportedPixelFormat(mode, format) => write!(
+                f,
+                "graphics mode {} has unsupported pixel format {:?}",
+                mode, format
+            ),
+            Self::Firmware(mode, status) => {
+                write!(f, "could not use graphics mode {}: {:?}", mode, status)
+            }
+        }
+    }
+}
+
+/// Returns the pixel format of a mode, or `None` if the kernel cannot draw to
+/// it.
+///
+/// Bit mask formats are not supported, and neither are modes without a linear
+/// frame buffer.
+pub fn pixel_format(info: &EfiGraphicsOutputModeInformation) -> Option<PixelFormat> {
+    match info.pixel_format {
+        EfiGraphicsPixelFormat::PixelRedGreenBlueReserved8BitPerColor => Some(PixelFormat::Rgb),
+        EfiGraphicsPixelFormat::PixelBlueGreenRedReserved8BitPerColor => Some(PixelFormat::Bgr),
+        _ => None,
+    }
+}
+
+/// Returns the number of the mode to use according to the policy.
+pub fn select_mode(gop: &EfiGraphicsOutputProtocol, policy: ModePolicy) -> Result<u32, ModeError> {
+    let current = gop.mode().mode;
+
+    match policy {
+        ModePolicy::Current => validate_mode(gop, current).map(|_| current),
+        ModePolicy::Mode(mode) => validate_mode(gop, mode).map(|_| mode),
+        ModePolicy::Highest => supported_modes(gop)
+            .max_by_key(|(mode, info)| {
+                // Prefer the current mode among modes with the same pixel count
+                let pixels = info.horizontal_resolution as u64 * info.vertical_resolution as u64;
+                (pixels, *mode == current)
+            })
+            .map(|(mode, _)| mode)
+            .ok_or(ModeError::NotFound(policy)),
+        ModePolicy::Resolution(width, height) => supported_modes(gop)
+            .find(|(_, info)| {
+                info.horizontal_resolution == width && info.vertical_resolution == height
+            })
+            .map(|(mode, _)| mode)
+            .ok_or(ModeError::NotFound(policy)),
+    }
+}
+
+/// Selects a mode according to the policy and switches to it, returning the
+/// mode number.
+///
+/// If no mode matches the policy, the current mode is used if it's supported,
+/// and otherwise the highest supported mode.
+pub fn set_mode(gop: &EfiGraphicsOutputProtocol, policy: ModePolicy) -> Result<u32, ModeError> {
+    let mode = match select_mode(gop, policy) {
+        Ok(mode) => mode,
+        Err(error) => {
+            warn!("{}, falling back", error);
+            select_mode(gop, ModePolicy::Current)
+                .or_else(|_| select_mode(gop, ModePolicy::Highest))?
+        }
+    };
+
+    // Setting a mode clears the screen, so avoid it if the mode is already active
+    if mode != gop.mode().mode {
+        let status = gop.set_mode(mode);
+        if status.is_error() {
+            return Err(ModeError::Firmware(mode, status));
+        }
+    }
+
+    Ok(mode)
+}
+
+/// Checks that a mode exists and has a supported pixel format.
+fn validate_mode(gop: &EfiGraphicsOutputProtocol, mode: u32) -> Result<(), ModeError> {
+    let info = gop
+        .query_mode(mode)
+        .map_err(|status| ModeError::Firmware(mode, status))?;
+    match pixel_format(info) {
+        Some(_) => Ok(()),
+        None => Err(ModeError::UnsupportedPixelFormat(mode, info.pixel_format)),
+    }
+}
+
+/// Returns an iterator over all modes with a supported pixel format.
+fn supported_modes(
+    gop: &EfiGraphicsOutputProtocol,
+) -> impl Iterator<Item = (u32, &EfiGraphicsOutputModeInformation)> {
+    (0..gop.mode().max_mode)
+        .filter_map(move |mode| gop.query_mode(mode).ok().map(|info| (mode, info)))
+        .filter(|(_, info)| pixel_format(info).is_some())
+}
+
+/// Prints every available graphics mode.
+pub fn print_modes(gop: &EfiGraphicsOutputProtocol) {
+    debug!("Current mode: {}", gop.mode().mode);
+
+    // Query info about each available graphics output mode and print it
+    for i in 0..gop.mode().max_mode {
+        let info = gop.query_mode(i).expect("Cannot get info");
+        debug!(
+            "Mode {}, width {}, height {}, format {:?}",
+            i, info.horizontal_resolution, info.vertical_resolution, info.pixel_format
+        );
+    }
+}
+
+/// The background color of the splash, matching the kernel's terminal.
+const SPLASH_BACKGROUND: u32 = 0x333333;
+
+/// The rectangles making up the splash logo, a stylized rockhopper penguin, as
+/// `(x, y, width, height, color)` on a grid of 8 by 8 units.
+const SPLASH_LOGO: [(usize, usize, usize, usize, u32); 6] = [
+    // Body
+    (2, 1, 4, 7, 0x101010),
+    // Belly
+    (3, 3, 2, 4, 0xffffff),
+    // Crest
+    (1, 1, 2, 1, 0xf0c020),
+    (5, 1, 2, 1, 0xf0c020),
+    // Eye
+    (3, 2, 1, 1, 0xc02020),
+    // Beak
+    (4, 2, 1, 1, 0xe06020),
+];
+
+/// Draws the boot splash, centered on the screen.
+pub fn draw_splash(gop: &EfiGraphicsOutputProtocol) {
+    let info = gop.mode().info();
+    let width = info.horizontal_resolution as usize;
+    let height = info.vertical_resolution as usize;
+
+    gop.fill(
+        EfiGraphicsOutputBltPixel::from_rgb(SPLASH_BACKGROUND),
+        0,
+        0,
+        width,
+        height,
+    );
+
+    // Scale the logo to an eighth of the smallest screen dimension
+    let unit = core::cmp::max(width.min(height) / 64, 1);
+    let logo_x = (width - 8 * unit) / 2;
+    let logo_y = (height - 8 * unit) / 2;
+    for &(x, y, w, h, color) in SPLASH_LOGO.iter() {
+        gop.fill(
+            EfiGraphicsOutputBltPixel::from_rgb(color),
+            logo_x + x * unit,
+            logo_y + y * unit,
+            w * unit,
+            h * unit,
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Leveled console output, the verbosity is set by the configuration file.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// How much output the bootloader produces, each level includes the messages
+/// of the levels before it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /// Parses a level from its lowercase name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+            Self::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Sets the most verbose level which is output.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns whether messages of the given level are output.
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    match level {
+        LogLevel::Error => rk_uefi::println!("Error: {}", args),
+        LogLevel::Warn => rk_uefi::println!("Warning: {}", args),
+        LogLevel::Info | LogLevel::Debug => rk_uefi::println!("{}", args),
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Error, format_args!($($arg)*)));
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Warn, format_args!($($arg)*)));
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Info, format_args!($($arg)*)));
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Debug, format_args!($($arg)*)));
+}
@@ -1,38 +1,70 @@
 #![no_std]
 #![no_main]
+#![feature(alloc_error_handler)]
 #![feature(asm)]
 
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+mod log;
+
+mod config;
+mod graphics;
+mod memory_map;
+mod menu;
+
+use crate::memory_map::MemoryMap;
+use crate::menu::BootOptions;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use core::convert::TryInto;
 use core::panic::PanicInfo;
+use rk_bootinfo::{BuildError, Builder, Framebuffer, KernelSymbols, MemoryRegion};
+use rk_uefi::allocator::Allocator;
 use rk_uefi::data_types::{
-    Char16, EfiAllocateType, EfiHandle, EfiMemoryDescriptor, EfiMemoryType, EfiPhysicalAddress,
+    CStr16, EfiAllocateType, EfiHandle, EfiMemoryDescriptor, EfiMemoryType, EfiPhysicalAddress,
     EfiStatus,
 };
 use rk_uefi::guid::{
-    EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID, EFI_LOADED_IMAGE_PROTOCOL_GUID,
-    EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
+    EFI_ACPI_10_TABLE_GUID, EFI_ACPI_20_TABLE_GUID, EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
+    EFI_LOADED_IMAGE_PROTOCOL_GUID, EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
 };
 use rk_uefi::protocol::{
     EfiFileProtocol, EfiFileSystemInfo, EfiGraphicsOutputProtocol, EfiLoadedImageProtocol,
     EfiSimpleFileSystemProtocol, EFI_FILE_HIDDEN, EFI_FILE_MODE_READ, EFI_FILE_READ_ONLY,
     EFI_FILE_SYSTEM, EFI_FILE_SYSTEM_INFO_ID,
 };
+use rk_uefi::system_table;
 use rk_uefi::table::EfiSystemTable;
-use rk_uefi::{print, println, system_table};
-
-/// The data structure passed to the kernel on entry.
-#[repr(C)]
-struct EntryData {
-    greeting: u32,
-    fb_base: u64,
-    fb_horizontal_resolution: u32,
-    fb_vertical_resolution: u32,
-    fb_pixels_per_scan_line: u32,
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator;
+
+/// A file loaded alongside the kernel.
+struct Module {
+    /// The path of the file on the boot volume.
+    name: String,
+    /// The physical address the file is loaded at.
+    addr: u64,
+    /// The size of the file in bytes.
+    size: u64,
 }
 
 /// The amount of pages we will reserve for our own paging tables.
 const PAGING_PAGES_COUNT: usize = 10;
 
+/// The number of pages reserved for the kernel stack, making up the 2 MiB at
+/// the very top of virtual memory.
+const STACK_PAGES_COUNT: usize = 512;
+
+/// The virtual address the boot information is mapped to, the 2 MiB below the
+/// stack.
+const BOOT_INFO_VIRT_ADDR: u64 = 0xffff_ffff_ffc0_0000;
+
+/// The maximum number of pages of boot information, as mapped below the stack.
+const BOOT_INFO_MAX_PAGES: usize = 512;
+
 /// The main entry point for the UEFI application.
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, system_table: &'static mut EfiSystemTable) -> EfiStatus {
@@ -40,78 +72,96 @@ fn efi_main(image_handle: EfiHandle, system_table: &'static mut EfiSystemTable)
 
     rk_uefi::system_table().con_out().reset(false);
 
-    let mut entry_data = EntryData {
-        greeting: 0x1f427,
-        fb_base: 0,
-        fb_horizontal_resolution: 0,
-        fb_vertical_resolution: 0,
-        fb_pixels_per_scan_line: 0,
+    // Read the configuration before anything else, as it decides how much we
+    // print
+    let root = unsafe { get_volume_root(image_handle).as_ref().unwrap() };
+    let config = match config::load(root) {
+        Ok(config) => config,
+        Err(error) => panic!("Invalid configuration: {}", error),
     };
+    log::set_level(config.log_level);
 
-    println!("Hello World!");
+    info!("Hello World!");
 
     // Print the firmware vendor and revision
-    print!("Firmware: ");
-    rk_uefi::system_table()
-        .con_out()
-        .output_string(rk_uefi::system_table().firmware_vendor());
-    println!(
-        ", rev. {:#010x}",
+    info!(
+        "Firmware: {}, rev. {:#010x}",
+        rk_uefi::system_table().firmware_vendor(),
         rk_uefi::system_table().firmware_revision()
     );
 
     // Print the UEFI revision
     let revision = rk_uefi::system_table().revision();
-    println!("UEFI v{}.{}\n", (revision >> 16) as u16, revision as u16);
+    info!("UEFI v{}.{}\n", (revision >> 16) as u16, revision as u16);
 
     // Print volume label
-    let root = unsafe { get_volume_root(image_handle).as_ref().unwrap() };
-    let mut buffer_size: usize = 1024; // This should be more than enough, and wasteful
-    let buffer = rk_uefi::system_table()
-        .boot_services()
-        .allocate_pool(EfiMemoryType::EfiLoaderData, buffer_size)
-        .expect("Could not allocate buffer");
+    // Use a buffer of u64s to get the alignment required by the info structure
+    let mut buffer = vec![0u64; 128];
+    let mut buffer_size: usize = buffer.len() * 8; // This should be more than enough
     let status = root.get_info(&EFI_FILE_SYSTEM_INFO_ID, &mut buffer_size, unsafe {
-        &mut *buffer
+        &mut *(buffer.as_mut_ptr() as *mut core::ffi::c_void)
     });
     if status.is_error() {
         panic!("ERROR! {:?}", status);
     }
-    let file_info = unsafe { &*(buffer as *const EfiFileSystemInfo) };
-    print!("Volume Label: ");
-    rk_uefi::system_table()
-        .con_out()
-        .output_string(&file_info.volume_label[0]);
-    println!("");
+    let file_info = unsafe { &*(buffer.as_ptr() as *const EfiFileSystemInfo) };
+    info!("Volume Label: {}", file_info.volume_label());
+    drop(buffer);
+
+    let gop = locate_gop();
+
+    // Let the user adjust the boot options
+    // The load options take precedence over the command line in the configuration
+    let defaults = BootOptions {
+        kernel_path: config.kernel,
+        graphics_mode: config.video,
+        command_line: load_options_command_line(image_handle).unwrap_or(config.cmdline),
+    };
+    let options = menu::run(defaults, config.timeout, root, gop);
+    info!("Kernel: {}", options.kernel_path);
+    info!("Command line: {}", options.command_line);
+
+    // Switch to the graphics mode selected by the policy
+    graphics::print_modes(gop);
+    if let Err(error) = graphics::set_mode(gop, options.graphics_mode) {
+        panic!("Could not select a graphics mode: {}", error);
+    }
 
     // Get info about the current graphics mode
-    let mut ptr = core::ptr::null_mut();
-    let status = rk_uefi::system_table().boot_services().locate_protocol(
-        &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
-        core::ptr::null_mut(),
-        &mut ptr,
-    );
-    if status.is_error() {
-        panic!("Unable to locate GOP");
-    }
-    let gop = unsafe { &*(ptr as *mut EfiGraphicsOutputProtocol) };
     let gop_mode = gop.mode();
-    let gop_mode_info = unsafe { *gop.mode().info };
-    println!(
-        "Mode {}, width {}, height {}, fb base {:#x}",
+    let gop_mode_info = gop_mode.info();
+    // The pixel format was validated when selecting the mode
+    let pixel_format = graphics::pixel_format(gop_mode_info).unwrap();
+    info!(
+        "Mode {}, width {}, height {}, format {:?}, fb base {:#x}",
         gop_mode.mode,
         gop_mode_info.horizontal_resolution,
         gop_mode_info.vertical_resolution,
+        pixel_format,
         gop_mode.frame_buffer_base.0
     );
-    // Pass this info to the kernel
-    entry_data.fb_base = gop_mode.frame_buffer_base.0;
-    entry_data.fb_horizontal_resolution = gop_mode_info.horizontal_resolution;
-    entry_data.fb_vertical_resolution = gop_mode_info.vertical_resolution;
-    entry_data.fb_pixels_per_scan_line = gop_mode_info.pixels_per_scan_line;
+    let framebuffer = Framebuffer::new(
+        gop_mode.frame_buffer_base.0,
+        gop_mode_info.horizontal_resolution,
+        gop_mode_info.vertical_resolution,
+        gop_mode_info.pixels_per_scan_line,
+        pixel_format,
+    );
 
     // Load the kernel ELF
-    let kernel_elf_addr = load_kernel_elf(image_handle);
+    let kernel_elf_addr = load_kernel_elf(image_handle, &options.kernel_path);
+
+    // Load the modules
+    let mut modules = Vec::new();
+    for path in config.modules.iter() {
+        let (addr, size) = load_file(root, path);
+        debug!("Module {}: {} bytes at {:#x} (phys)", path, size, addr.0);
+        modules.push(Module {
+            name: path.to_string(),
+            addr: addr.0,
+            size,
+        });
+    }
 
     let kernel_elf_file_header = unsafe { *(kernel_elf_addr.0 as *const rk_elf64::FileHeader) };
     // TODO: Make sure we actually have a correct ELF file header (check magic
@@ -168,24 +218,52 @@ fn efi_main(image_handle: EfiHandle, system_table: &'static mut EfiSystemTable)
     if kernel_size > 2 * 1024 * 1024 {
         panic!("Kernel cannot be larger than 2 MiB");
     }
-    println!("kernel_size = {} bytes", kernel_size);
-    println!("kernel_phys_addr = {:#x} (phys)", kernel_phys_addr.0);
-    println!("kernel_virt_addr = {:#x} (virt)", kernel_virt_addr);
-    println!("kernel_entry = {:#x} (virt)", kernel_entry);
+    debug!("kernel_size = {} bytes", kernel_size);
+    debug!("kernel_phys_addr = {:#x} (phys)", kernel_phys_addr.0);
+    debug!("kernel_virt_addr = {:#x} (virt)", kernel_virt_addr);
+    debug!("kernel_entry = {:#x} (virt)", kernel_entry);
+
+    // The kernel ELF stays in memory, so the kernel can use its symbols
+    let kernel_symbols = find_kernel_symbols(kernel_elf_addr.0);
+    if kernel_symbols.is_none() {
+        warn!("Kernel symbols not found");
+    }
+
+    let rsdp = find_rsdp();
+    match rsdp {
+        Some(rsdp) => debug!("rsdp = {:#x} (phys)", rsdp),
+        None => warn!("ACPI RSDP not found"),
+    }
+
+    // Allocate pages for our own paging tables, these must be below 4 GiB as the
+    // kernel reads them through the identity mapping of the first 4 GiB
+    let paging_addr = rk_uefi::system_table()
+        .boot_services()
+        .allocate_pages_with_address(
+            EfiAllocateType::AllocateMaxAddress,
+            EfiMemoryType::EfiLoaderData,
+            PAGING_PAGES_COUNT,
+            EfiPhysicalAddress(0xffff_ffff),
+        )
+        .expect("Could not allocate pages for the paging tables");
+    debug!("paging_addr = {:#x} (phys)", paging_addr.0);
 
-    // Allocate a page for the entry data
-    let entry_data_page_addr = rk_uefi::system_table()
+    // Allocate pages for the kernel stack
+    let stack_addr = rk_uefi::system_table()
         .boot_services()
         .allocate_pages(
             EfiAllocateType::AllocateAnyPages,
             EfiMemoryType::EfiLoaderData,
-            1,
+            STACK_PAGES_COUNT,
         )
-        .expect("Could not allocate memory page for entry data");
+        .expect("Could not allocate pages for the kernel stack");
+    debug!("stack_addr = {:#x} (phys)", stack_addr.0);
+
+    // Show the splash while the kernel takes over, nothing may be printed after
+    // this point
+    graphics::draw_splash(gop);
 
     // Get the memory map
-    // We don't actually pass or use it yet, but we need the map key to exit boot
-    // services.
     let mut memory_map_size: usize = 0;
     let mut memory_map = core::ptr::null_mut();
     let mut map_key: usize = 0;
@@ -202,11 +280,52 @@ fn efi_main(image_handle: EfiHandle, system_table: &'static mut EfiSystemTable)
         panic!("Unexpected status while getting memory map size, got {:?}, expected EFI_BUFFER_TOO_SMALL", status);
     }
     // Calculate how many pages are needed to fit the memory map. Adding space for
-    // two extra descriptors since the memory map may need to be expanded while
-    // allocating space for itself. Also adding 4095 bytes (1 byte less than the
-    // page size) to make sure any rounding is upwards.
-    memory_map_size += 2 * descriptor_size;
+    // four extra descriptors since the memory map may need to be expanded while
+    // allocating space for itself and the boot information. Also adding 4095
+    // bytes (1 byte less than the page size) to make sure any rounding is
+    // upwards.
+    memory_map_size += 4 * descriptor_size;
     let pages = (memory_map_size + 4095) / 4096;
+
+    // Allocate pages for the boot information, with room for every descriptor
+    // of the memory map and a page for the other tags
+    let boot_info_size = memory_map_size / descriptor_size * core::mem::size_of::<MemoryRegion>()
+        + options.command_line.len()
+        + modules
+            .iter()
+            .map(|module| 32 + module.name.len())
+            .sum::<usize>()
+        + 4096;
+    let boot_info_pages = (boot_info_size + 4095) / 4096;
+    if boot_info_pages > BOOT_INFO_MAX_PAGES {
+        panic!("Boot information cannot be larger than 2 MiB");
+    }
+    let boot_info_addr = rk_uefi::system_table()
+        .boot_services()
+        .allocate_pages(
+            EfiAllocateType::AllocateAnyPages,
+            EfiMemoryType::EfiLoaderData,
+            boot_info_pages,
+        )
+        .expect("Could not allocate pages for the boot information");
+    let boot_info_buffer = unsafe {
+        core::slice::from_raw_parts_mut(boot_info_addr.0 as *mut u8, boot_info_pages * 4096)
+    };
+
+    // Write everything but the memory map to the boot information
+    let mut boot_info = Builder::new(boot_info_buffer).unwrap();
+    let result = add_boot_info_tags(
+        &mut boot_info,
+        &framebuffer,
+        &options.command_line,
+        rsdp,
+        kernel_symbols.as_ref(),
+        &modules,
+    );
+    if let Err(error) = result {
+        panic!("Could not write the boot information: {}", error);
+    }
+
     // Allocate pages for the memory map
     let memory_map_addr = rk_uefi::system_table()
         .boot_services()
@@ -234,63 +353,65 @@ fn efi_main(image_handle: EfiHandle, system_table: &'static mut EfiSystemTable)
         .boot_services()
         .exit_boot_services(image_handle, map_key);
 
-    // Write the entry data to memory
-    unsafe {
-        core::ptr::write(entry_data_page_addr.0 as *mut EntryData, entry_data);
-    }
+    // Finish the boot information with the memory map
+    let mut memory_map =
+        unsafe { MemoryMap::new(memory_map_addr.0, memory_map_size, descriptor_size) };
+    memory_map.sort();
+    boot_info
+        .add_memory_map(memory_map.regions())
+        .and_then(|_| boot_info.finish())
+        .expect("Could not write the memory map to the boot information");
 
     // Create new page tables for our higher half kernel
+    let pml4_addr: u64 = paging_addr.0;
     unsafe {
-        // TODO: It's not safe to assume the space at 0x70000- to be free for us to use
-        // for page tables
-
         // Zeroes out the pages we'll use for paging tables
-        core::ptr::write_bytes(0x70000 as *mut u8, 0, PAGING_PAGES_COUNT * 4096);
+        core::ptr::write_bytes(pml4_addr as *mut u8, 0, PAGING_PAGES_COUNT * 4096);
 
         // Keeps track of the next free memory page we can use for our page tables
         let mut next_paging_page: usize = 2; // We'll use the first two now
 
         // PML4
-        core::ptr::write(0x70000 as *mut u64, 0x71000 | 0b11);
+        let pdp_addr = pml4_addr + 0x1000;
+        core::ptr::write(pml4_addr as *mut u64, pdp_addr | 0b11);
 
         // Identity map the first 4 GiB using four PDP huge pages
-        core::ptr::write(0x71000 as *mut u64, 0b1000_0011);
-        core::ptr::write((0x71000 + 1 * 8) as *mut u64, 0x4000_0000 | 0b1000_0011);
-        core::ptr::write((0x71000 + 2 * 8) as *mut u64, 0x8000_0000 | 0b1000_0011);
-        core::ptr::write((0x71000 + 3 * 8) as *mut u64, 0xc000_0000 | 0b1000_0011);
+        core::ptr::write(pdp_addr as *mut u64, 0b1000_0011);
+        core::ptr::write((pdp_addr + 1 * 8) as *mut u64, 0x4000_0000 | 0b1000_0011);
+        core::ptr::write((pdp_addr + 2 * 8) as *mut u64, 0x8000_0000 | 0b1000_0011);
+        core::ptr::write((pdp_addr + 3 * 8) as *mut u64, 0xc000_0000 | 0b1000_0011);
 
         // Map the kernel code
         for i in 0..(kernel_size + 4095) / 4096 {
             next_paging_page = map_page(
+                pml4_addr,
                 kernel_virt_addr + i * 4096,
                 kernel_phys_addr.0 + i * 4096,
                 next_paging_page,
             );
         }
 
-        // Map the 2 MiB at the very top of virtual memory to 16-18 MiB, this will be
-        // used for the stack
-        // TODO: It's not safe to assume that the memory at 16-18 MiB is free for us to
-        // use for the stack
-        for i in 0..512 {
+        // Map the 2 MiB at the very top of virtual memory to the pages allocated for
+        // the stack
+        for i in 0..STACK_PAGES_COUNT as u64 {
             next_paging_page = map_page(
+                pml4_addr,
                 0xffff_ffff_ffe0_0000 + 0x1000 * i,
-                0x1000000 + 0x1000 * i,
+                stack_addr.0 + 0x1000 * i,
                 next_paging_page,
             );
         }
 
-        // Map the entry data page to where the kernel expects it
-        let entry_data_page_virt_addr = rk_elf64::find_symbol(kernel_elf_addr.0, "entry_data")
-            .expect("Could not find entry_data symbol in kernel elf")
-            .st_value;
-        next_paging_page = map_page(
-            entry_data_page_virt_addr,
-            entry_data_page_addr.0,
-            next_paging_page,
-        );
+        // Map the boot information below the stack
+        for i in 0..boot_info_pages as u64 {
+            next_paging_page = map_page(
+                pml4_addr,
+                BOOT_INFO_VIRT_ADDR + 0x1000 * i,
+                boot_info_addr.0 + 0x1000 * i,
+                next_paging_page,
+            );
+        }
     }
-    let pml4_addr: u64 = 0x70000;
 
     // Make sure PSE and PAE is enabled (PSE is always enabled when PAE is enabled
     // regardless of the PSE bit, but we set it anyways, just in case)
@@ -304,26 +425,29 @@ fn efi_main(image_handle: EfiHandle, system_table: &'static mut EfiSystemTable)
         rk_x86_64::register::cr3::write(pml4_addr);
     }
 
-    // Move the stack to the very top of virtual memory and jump into the kernel
+    // Move the stack to the very top of virtual memory and jump into the kernel,
+    // passing the address of the boot information as the first argument
     unsafe {
         asm!(
             "mov rsp, 0",
             "jmp {}",
             in(reg) kernel_entry,
+            in("rdi") BOOT_INFO_VIRT_ADDR,
             options(noreturn)
         );
     }
 }
 
-/// Maps a single 4096 KiB page.
+/// Maps a single 4096 KiB page in the page tables at `pml4_addr`.
 ///
-/// Helps keep track of how many paging pages we have left.
+/// Helps keep track of how many paging pages we have left, these follow the
+/// PML4 in memory.
 ///
 /// Addresses must be properly aligned.
 ///
 /// It does not overwrite an existing mapping, and panics if such a collision
 /// occurs.
-unsafe fn map_page(virt: u64, phys: u64, mut next_paging_page: usize) -> usize {
+unsafe fn map_page(pml4_addr: u64, virt: u64, phys: u64, mut next_paging_page: usize) -> usize {
     // TODO: This function could really do with some cleaning up...
 
     if next_paging_page >= PAGING_PAGES_COUNT {
@@ -339,13 +463,13 @@ unsafe fn map_page(virt: u64, phys: u64, mut next_paging_page: usize) -> usize {
     let pd_index = (virt >> (12 + 9)) % 512;
     let pt_index = (virt >> 12) % 512;
 
-    let pml4_entry_addr: u64 = 0x70000 + pml4_index * 8;
+    let pml4_entry_addr: u64 = pml4_addr + pml4_index * 8;
     let pml4_entry = rk_x86_64::paging::PageTableEntry::read(pml4_entry_addr as *const u64);
     let pdp_addr: u64;
     if pml4_entry.is_present() {
         pdp_addr = pml4_entry.addr();
     } else {
-        pdp_addr = (0x70000 + next_paging_page * 4096) as u64;
+        pdp_addr = pml4_addr + (next_paging_page * 4096) as u64;
         next_paging_page += 1;
         if next_paging_page >= PAGING_PAGES_COUNT {
             panic!("Ran out of page tables");
@@ -361,7 +485,7 @@ unsafe fn map_page(virt: u64, phys: u64, mut next_paging_page: usize) -> usize {
     if pdp_entry.is_present() {
         pd_addr = pdp_entry.addr();
     } else {
-        pd_addr = (0x70000 + next_paging_page * 4096) as u64;
+        pd_addr = pml4_addr + (next_paging_page * 4096) as u64;
         next_paging_page += 1;
         if next_paging_page >= PAGING_PAGES_COUNT {
             panic!("Ran out of page tables");
@@ -375,7 +499,7 @@ unsafe fn map_page(virt: u64, phys: u64, mut next_paging_page: usize) -> usize {
     if pd_entry.is_present() {
         pt_addr = pd_entry.addr();
     } else {
-        pt_addr = (0x70000 + next_paging_page * 4096) as u64;
+        pt_addr = pml4_addr + (next_paging_page * 4096) as u64;
         next_paging_page += 1;
         if next_paging_page >= PAGING_PAGES_COUNT {
             panic!("Ran out of page tables");
@@ -395,188 +519,181 @@ unsafe fn map_page(virt: u64, phys: u64, mut next_paging_page: usize) -> usize {
     next_paging_page
 }
 
-/// Loads the kernel ELF and returns the physical address.
-///
-/// The kernel is always loaded in a 2 MiB contiguous page block.
-fn load_kernel_elf(image: EfiHandle) -> EfiPhysicalAddress {
+/// Adds every tag but the memory map to the boot information.
+fn add_boot_info_tags(
+    boot_info: &mut Builder,
+    framebuffer: &Framebuffer,
+    command_line: &str,
+    rsdp: Option<u64>,
+    kernel_symbols: Option<&KernelSymbols>,
+    modules: &[Module],
+) -> Result<(), BuildError> {
+    boot_info.add_framebuffer(framebuffer)?;
+    boot_info.add_command_line(command_line)?;
+    if let Some(rsdp) = rsdp {
+        boot_info.add_rsdp(rsdp)?;
+    }
+    if let Some(symbols) = kernel_symbols {
+        boot_info.add_kernel_symbols(symbols)?;
+    }
+    for module in modules {
+        boot_info.add_module(module.addr, module.size, &module.name)?;
+    }
+    Ok(())
+}
+
+/// Returns the location of the symbol and string tables in the kernel ELF.
+fn find_kernel_symbols(kernel_elf_addr: u64) -> Option<KernelSymbols> {
+    let symtab = rk_elf64::find_section_header(kernel_elf_addr, ".symtab").ok()?;
+    let strtab = rk_elf64::find_section_header(kernel_elf_addr, ".strtab").ok()?;
+    Some(KernelSymbols {
+        symtab_addr: kernel_elf_addr + symtab.sh_offset,
+        symtab_size: symtab.sh_size,
+        strtab_addr: kernel_elf_addr + strtab.sh_offset,
+        strtab_size: strtab.sh_size,
+    })
+}
+
+/// Returns the physical address of the ACPI RSDP, preferring the one for ACPI
+/// 2.0 and later.
+fn find_rsdp() -> Option<u64> {
+    let tables = system_table().configuration_table();
+    let find = |guid| {
+        tables
+            .iter()
+            .find(|table| table.vendor_guid() == guid)
+            .map(|table| table.vendor_table() as u64)
+    };
+    find(EFI_ACPI_20_TABLE_GUID).or_else(|| find(EFI_ACPI_10_TABLE_GUID))
+}
+
+/// Loads the kernel ELF at the given path and returns the physical address.
+fn load_kernel_elf(image: EfiHandle, path: &CStr16) -> EfiPhysicalAddress {
     let root = unsafe { get_volume_root(image).as_ref().unwrap() };
+    let (kernel_addr, file_size) = load_file(root, path);
+
+    debug!("Kernel ELF Size = {} bytes", file_size);
 
-    // RK_KERNEL.ELF
-    const FILE_NAME: [Char16; 14] = [
-        Char16(0x0052),
-        Char16(0x004b),
-        Char16(0x005f),
-        Char16(0x004b),
-        Char16(0x0045),
-        Char16(0x0052),
-        Char16(0x004e),
-        Char16(0x0045),
-        Char16(0x004c),
-        Char16(0x002e),
-        Char16(0x0045),
-        Char16(0x004c),
-        Char16(0x0046),
-        Char16(0),
-    ];
-
-    // Get a file handle for the kernel
+    kernel_addr
+}
+
+/// Loads the file at the given path into page-aligned memory, returning the
+/// physical address and the size of the file.
+fn load_file(root: &EfiFileProtocol, path: &CStr16) -> (EfiPhysicalAddress, u64) {
     let mut ptr = core::ptr::null_mut();
     let status = root.open(
         &mut ptr,
-        &FILE_NAME[0],
+        path,
         EFI_FILE_MODE_READ,
         EFI_FILE_READ_ONLY | EFI_FILE_HIDDEN | EFI_FILE_SYSTEM,
     );
     if status.is_error() {
-        panic!("Could not open RK_KERNEL.ELF: {:?}", status);
+        panic!("Could not open {}: {:?}", path, status);
     }
     let file_handle = unsafe { &*(ptr as *const EfiFileProtocol) };
 
-    let file_size = file_handle.file_size().expect("Could not get kernel size");
-
-    println!("Kernel ELF Size = {} bytes", file_size);
+    let file_size = file_handle
+        .file_size()
+        .unwrap_or_else(|status| panic!("Could not get the size of {}: {:?}", path, status));
 
-    // Panic if the kernel ELF is larger than 4 MiB
-    if file_size > 4 * 1024 * 1024 {
-        panic!("Kernel ELF too big, max 4 MiB");
-    }
-
-    // Allocate pages for a 4 MiB contiguous block. Recall that each UEFI page is
-    // always 4096 KiB, so we need 1024 pages.
-    let kernel_addr = system_table()
+    // Allocate at least one page, so even empty files get an address
+    let mut size: usize = file_size.try_into().unwrap();
+    let pages = core::cmp::max((size + 4095) / 4096, 1);
+    let addr = system_table()
         .boot_services()
         .allocate_pages(
             EfiAllocateType::AllocateAnyPages,
             EfiMemoryType::EfiLoaderData,
-            1024,
+            pages,
         )
-        .expect("Could not allocate page memory for the kernel ELF");
+        .unwrap_or_else(|status| panic!("Could not allocate memory for {}: {:?}", path, status));
 
-    let buffer = unsafe { &mut *(kernel_addr.0 as *mut core::ffi::c_void) };
-    let mut size: usize = 4 * 1024 * 1024;
+    let buffer = unsafe { &mut *(addr.0 as *mut core::ffi::c_void) };
     let status = file_handle.read(&mut size, buffer);
-    if status.is_error() {
-        panic!("Could not read kernel ELF");
+    file_handle.close();
+    if status.is_error() || size as u64 != file_size {
+        panic!("Could not read {}: {:?}", path, status);
     }
 
-    kernel_addr
+    (addr, file_size)
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    print!("{}", info);
+    error!("{}", info);
 
     rk_x86_64::hang()
 }
 
-fn get_volume_root(image: EfiHandle) -> *mut EfiFileProtocol {
-    let mut ptr1 = core::ptr::null_mut();
-    system_table().boot_services().handle_protocol(
-        image,
-        &EFI_LOADED_IMAGE_PROTOCOL_GUID,
-        &mut ptr1,
-    );
-    let loaded_image = unsafe { &*(ptr1 as *mut EfiLoadedImageProtocol) };
-
-    let mut ptr2 = core::ptr::null_mut();
-    system_table().boot_services().handle_protocol(
-        loaded_image.device_handle(),
-        &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
-        &mut ptr2,
-    );
-    let volume = unsafe { &*(ptr2 as *mut EfiSimpleFileSystemProtocol) };
-
-    let mut ptr3 = core::ptr::null_mut();
-    volume.open_volume(&mut ptr3);
-    ptr3
+#[alloc_error_handler]
+fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    panic!("ALLOCATION ERROR: {:?}", layout);
 }
 
-#[allow(dead_code)]
-fn read_test_file(image: EfiHandle) {
-    let root = unsafe { get_volume_root(image).as_ref().unwrap() };
-
-    const TEST_FILE_NAME: [Char16; 9] = [
-        Char16(0x0054),
-        Char16(0x0045),
-        Char16(0x0053),
-        Char16(0x0054),
-        Char16(0x002e),
-        Char16(0x0054),
-        Char16(0x0058),
-        Char16(0x0054),
-        Char16(0x0),
-    ];
-
-    // Print the name of the file
-    print!("\nLoading File ");
-    system_table().con_out().output_string(&TEST_FILE_NAME[0]);
-    print!("\n");
-
+/// Locates the graphics output protocol.
+fn locate_gop() -> &'static EfiGraphicsOutputProtocol {
     let mut ptr = core::ptr::null_mut();
-    let s1 = root.open(
+    let status = system_table().boot_services().locate_protocol(
+        &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
+        core::ptr::null_mut(),
         &mut ptr,
-        &TEST_FILE_NAME[0],
-        EFI_FILE_MODE_READ,
-        EFI_FILE_READ_ONLY | EFI_FILE_HIDDEN | EFI_FILE_SYSTEM,
     );
-    if s1.is_error() {
-        panic!("Could not open file: {:?}", s1);
+    if status.is_error() {
+        panic!("Unable to locate GOP");
     }
-    let file_handle = unsafe { &*(ptr as *const EfiFileProtocol) };
-
-    let file_size = file_handle.file_size().expect("Could not get file size");
-    println!("File Size = {} bytes", file_size);
-
-    // Read
-    let mut size: usize = file_size.try_into().unwrap();
-    let buffer = system_table()
-        .boot_services()
-        .allocate_pool(EfiMemoryType::EfiLoaderData, size)
-        .expect("Could not allocate read buffer");
-    let rs = file_handle.read(&mut size, unsafe { &mut *buffer });
-    println!("Read status = {:?}", rs);
-    println!("Read size = {}", size);
-
-    // Close
-    let cs = file_handle.close();
-    println!("Close status = {:?}", cs);
-
-    // Print content
-    print!("PRINTING FILE CONTENTS ==");
-    system_table()
-        .con_out()
-        .output_string(unsafe { &*(buffer as *const Char16) });
-    println!("==");
-
-    let fs = system_table().boot_services().free_pool(buffer);
-    println!("Free buffer status = {:?}", fs);
+    // This should be safe as we got a success status code
+    unsafe { &*(ptr as *mut EfiGraphicsOutputProtocol) }
 }
 
-#[allow(dead_code)]
-fn print_available_graphics_modes() {
+/// Returns the loaded image protocol of the given image.
+fn get_loaded_image(image: EfiHandle) -> &'static EfiLoadedImageProtocol {
     let mut ptr = core::ptr::null_mut();
-    let r1 = system_table().boot_services().locate_protocol(
-        &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
-        core::ptr::null_mut(),
+    let status = system_table().boot_services().handle_protocol(
+        image,
+        &EFI_LOADED_IMAGE_PROTOCOL_GUID,
         &mut ptr,
     );
-    if r1.is_error() {
-        panic!("Unable to locate GOP");
+    if status.is_error() {
+        panic!("Could not get the loaded image protocol: {:?}", status);
+    }
+    unsafe { &*(ptr as *mut EfiLoadedImageProtocol) }
+}
+
+/// Returns the kernel command line passed in the load options of the image, or
+/// `None` if there are no options.
+///
+/// The UEFI shell passes the whole shell command line, so a leading path to an
+/// `.EFI` file is skipped.
+fn load_options_command_line(image: EfiHandle) -> Option<String> {
+    let options: String = get_loaded_image(image)
+        .load_options_str()?
+        .chars()
+        .collect();
+    let mut options = options.trim();
+    let first_word = options.split_whitespace().next().unwrap_or("");
+    if first_word.to_ascii_uppercase().ends_with(".EFI") {
+        options = options[first_word.len()..].trim_start();
+    }
+
+    if options.is_empty() {
+        None
     } else {
-        println!("Located GOP");
+        Some(options.to_string())
     }
+}
 
-    // This should be safe as we got a success status code
-    let gop = unsafe { &*(ptr as *mut EfiGraphicsOutputProtocol) };
+fn get_volume_root(image: EfiHandle) -> *mut EfiFileProtocol {
+    let loaded_image = get_loaded_image(image);
 
-    println!("Current mode: {}", gop.mode().mode);
+    let mut ptr2 = core::ptr::null_mut();
+    system_table().boot_services().handle_protocol(
+        loaded_image.device_handle(),
+        &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
+        &mut ptr2,
+    );
+    let volume = unsafe { &*(ptr2 as *mut EfiSimpleFileSystemProtocol) };
 
-    // Query info about each available graphics output mode and print it
-    for i in 0..gop.mode().max_mode {
-        let info = gop.query_mode(i).expect("Cannot get info");
-        println!(
-            "Mode {}, width {}, height {}",
-            i, info.horizontal_resolution, info.vertical_resolution
-        );
-    }
+    let mut ptr3 = core::ptr::null_mut();
+    volume.open_volume(&mut ptr3);
+    ptr3
 }
@@ -0,0 +1,107 @@
+//! Conversion of the UEFI memory map to the memory map passed to the kernel.
+//!
+//! Nothing in here allocates, as the memory map is converted after the boot
+//! services have been exited.
+
+use rk_bootinfo::{MemoryRegion, MemoryRegionKind};
+use rk_uefi::data_types::{EfiMemoryDescriptor, EfiMemoryType};
+
+/// A memory map returned by the firmware.
+///
+/// The descriptors are `descriptor_size` bytes apart, which may be larger
+/// than the size of [EfiMemoryDescriptor].
+pub struct MemoryMap {
+    addr: u64,
+    size: usize,
+    descriptor_size: usize,
+}
+
+impl MemoryMap {
+    /// Wraps a memory map written by the firmware.
+    ///
+    /// # Safety
+    /// `addr` must point to `size` bytes of memory descriptors, written by the
+    /// firmware with the given descriptor size.
+    pub unsafe fn new(addr: u64, size: usize, descriptor_size: usize) -> Self {
+        Self {
+            addr,
+            size,
+            descriptor_size,
+        }
+    }
+
+    /// Returns the number of descriptors.
+    fn len(&self) -> usize {
+        self.size / self.descriptor_size
+    }
+
+    /// Returns a pointer to the descriptor at the given index.
+    fn ptr(&self, index: usize) -> *mut EfiMemoryDescriptor {
+        (self.addr as usize + index * self.descriptor_size) as *mut EfiMemoryDescriptor
+    }
+
+    /// Returns the descriptor at the given index.
+    fn get(&self, index: usize) -> &EfiMemoryDescriptor {
+        unsafe { &*self.ptr(index) }
+    }
+
+    /// Sorts the descriptors by address.
+    ///
+    /// The firmware is not required to return them in order. Uses an insertion
+    /// sort, as the map is usually sorted already.
+    pub fn sort(&mut self) {
+        for i in 1..self.len() {
+            let mut j = i;
+            while j > 0 && self.get(j - 1).physical_start() > self.get(j).physical_start() {
+                unsafe {
+                    core::ptr::swap_nonoverlapping(
+                        self.ptr(j - 1) as *mut u8,
+                        self.ptr(j) as *mut u8,
+                        self.descriptor_size,
+                    );
+                }
+                j -= 1;
+            }
+        }
+    }
+
+    /// Returns an iterator over the regions of the map, as passed to the
+    /// kernel.
+    pub fn regions(&self) -> impl Iterator<Item = MemoryRegion> + '_ {
+        (0..self.len()).map(move |i| {
+            let descriptor = self.get(i);
+            MemoryRegion::new(
+                descriptor.physical_start(),
+                descriptor.number_of_pages() * 4096,
+                region_kind(descriptor.memory_type()),
+            )
+        })
+    }
+}
+
+/// Returns the kind of region passed to the kernel for a UEFI memory type.
+///
+/// Memory used by the boot services is free once they have been exited, as is
+/// the bootloader's code. The bootloader's data is kept, as it holds everything
+/// passed to the kernel.
+fn region_kind(memory_type: u32) -> MemoryRegionKind {
+    const LOADER_CODE: u32 = EfiMemoryType::EfiLoaderCode as u32;
+    const LOADER_DATA: u32 = EfiMemoryType::EfiLoaderData as u32;
+    const BOOT_SERVICES_CODE: u32 = EfiMemoryType::EfiBootServicesCode as u32;
+    const BOOT_SERVICES_DATA: u32 = EfiMemoryType::EfiBootServicesData as u32;
+    const CONVENTIONAL: u32 = EfiMemoryType::EfiConventionalMemory as u32;
+    const UNUSABLE: u32 = EfiMemoryType::EfiUnusableMemory as u32;
+    const ACPI_RECLAIM: u32 = EfiMemoryType::EfiACPIReclaimMemory as u32;
+    const ACPI_NVS: u32 = EfiMemoryType::EfiACPIMemoryNVS as u32;
+
+    match memory_type {
+        LOADER_CODE | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA | CONVENTIONAL => {
+            MemoryRegionKind::Usable
+        }
+        LOADER_DATA => MemoryRegionKind::Bootloader,
+        UNUSABLE => MemoryRegionKind::Unusable,
+        ACPI_RECLAIM => MemoryRegionKind::AcpiReclaimable,
+        ACPI_NVS => MemoryRegionKind::AcpiNvs,
+        _ => MemoryRegionKind::Reserved,
+    }
+}
@@ -0,0 +1,294 @@
+//! An interactive boot menu, letting the user pick a kernel image, graphics
+//! mode, and kernel command line before booting.
+
+use crate::graphics::{pixel_format, ModePolicy};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use rk_uefi::data_types::{CString16, EfiStatus, EfiTpl};
+use rk_uefi::protocol::{
+    EfiFileInfo, EfiFileProtocol, EfiGraphicsOutputProtocol, EFI_BACKGROUND_BLACK,
+    EFI_BACKGROUND_LIGHTGRAY, EFI_BLACK, EFI_FILE_DIRECTORY, EFI_LIGHTGRAY, EFI_WHITE, SCAN_DOWN,
+    SCAN_LEFT, SCAN_RIGHT, SCAN_UP,
+};
+use rk_uefi::table::{EfiTimerDelay, EVT_TIMER};
+use rk_uefi::{print, system_table};
+
+/// The options the kernel will be booted with.
+pub struct BootOptions {
+    /// Path of the kernel image on the boot volume.
+    pub kernel_path: CString16,
+    /// How to choose the graphics mode.
+    pub graphics_mode: ModePolicy,
+    /// The command line passed to the kernel.
+    pub command_line: String,
+}
+
+/// The menu items, in the order they're shown.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Item {
+    Kernel,
+    GraphicsMode,
+    CommandLine,
+}
+
+const ITEMS: [Item; 3] = [Item::Kernel, Item::GraphicsMode, Item::CommandLine];
+
+/// The number of 100 ns units in a second, the unit used by UEFI timers.
+const TIMER_SECOND: u64 = 10_000_000;
+
+/// State of the boot menu.
+struct Menu<'a> {
+    options: BootOptions,
+    /// Kernel images available on the boot volume.
+    kernels: Vec<CString16>,
+    gop: &'a EfiGraphicsOutputProtocol,
+    /// The graphics mode policy the menu started with, offered in addition to
+    /// every specific mode.
+    default_graphics_mode: ModePolicy,
+    /// Index into `ITEMS` of the selected item.
+    selected: usize,
+    /// Seconds left until booting automatically, or `None` if the countdown
+    /// has been interrupted.
+    remaining: Option<usize>,
+}
+
+/// Shows the boot menu, returning the options once the user chooses to boot or
+/// the timeout expires.
+///
+/// The menu starts with the given default options, and is skipped entirely if
+/// the timeout is zero.
+pub fn run(
+    defaults: BootOptions,
+    timeout: usize,
+    root: &EfiFileProtocol,
+    gop: &EfiGraphicsOutputProtocol,
+) -> BootOptions {
+    if timeout == 0 {
+        return defaults;
+    }
+
+    let boot_services = system_table().boot_services();
+    let con_in = system_table().con_in();
+
+    // The firmware watchdog would reset the machine if we wait for too long
+    boot_services.set_watchdog_timer(0, 0);
+
+    // Discard any keystrokes made before the menu was shown
+    con_in.reset(false);
+
+    let timer = boot_services
+        .create_event(EVT_TIMER, EfiTpl::TPL_CALLBACK)
+        .expect("Could not create timer event");
+    boot_services.set_timer(timer, EfiTimerDelay::TimerPeriodic, TIMER_SECOND);
+
+    let mut menu = Menu {
+        kernels: find_kernel_images(root),
+        default_graphics_mode: defaults.graphics_mode,
+        options: defaults,
+        gop,
+        selected: 0,
+        remaining: Some(timeout),
+    };
+
+    // Make sure the default kernel is among the choices
+    if !menu.kernels.contains(&menu.options.kernel_path) {
+        menu.kernels.insert(0, menu.options.kernel_path.clone());
+    }
+
+    system_table().con_out().enable_cursor(false);
+
+    loop {
+        menu.draw();
+
+        let index = boot_services
+            .wait_for_event(&[con_in.wait_for_key(), timer])
+            .expect("Could not wait for input");
+
+        if index == 1 {
+            // The timer fired, count down if we haven't been interrupted
+            match menu.remaining {
+                Some(0) | Some(1) => break,
+                Some(remaining) => menu.remaining = Some(remaining - 1),
+                None => {}
+            }
+            continue;
+        }
+
+        let key = match con_in.read_key_stroke() {
+            Ok(key) => key,
+            Err(EfiStatus::EFI_NOT_READY) => continue,
+            Err(status) => panic!("Could not read keystroke: {:?}", status),
+        };
+
+        // Any keystroke stops the countdown
+        menu.remaining = None;
+
+        match (key.scan_code, key.unicode_char.to_char()) {
+            (SCAN_UP, _) => menu.selected = (menu.selected + ITEMS.len() - 1) % ITEMS.len(),
+            (SCAN_DOWN, _) => menu.selected = (menu.selected + 1) % ITEMS.len(),
+            (SCAN_LEFT, _) => menu.change(false),
+            (SCAN_RIGHT, _) => menu.change(true),
+            (_, Some('\r')) => break,
+            (_, Some('\u{8}')) => {
+                if ITEMS[menu.selected] == Item::CommandLine {
+                    menu.options.command_line.pop();
+                }
+            }
+            (_, Some(c)) if !c.is_control() => {
+                if ITEMS[menu.selected] == Item::CommandLine {
+                    menu.options.command_line.push(c);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    boot_services.close_event(timer);
+
+    let con_out = system_table().con_out();
+    con_out.set_attribute(EFI_LIGHTGRAY | EFI_BACKGROUND_BLACK);
+    con_out.clear_screen();
+    con_out.enable_cursor(true);
+
+    menu.options
+}
+
+impl Menu<'_> {
+    /// Cycles the value of the selected item forwards or backwards.
+    fn change(&mut self, forwards: bool) {
+        match ITEMS[self.selected] {
+            Item::Kernel => {
+                let current = self
+                    .kernels
+                    .iter()
+                    .position(|k| *k == self.options.kernel_path)
+                    .unwrap_or(0);
+                let next = cycle(current, self.kernels.len(), forwards);
+                self.options.kernel_path = self.kernels[next].clone();
+            }
+            Item::GraphicsMode => {
+                // Index zero represents the default policy, the specific modes follow
+                let count = self.gop.mode().max_mode as usize + 1;
+                let current = match self.options.graphics_mode {
+                    ModePolicy::Mode(mode)
+                        if self.default_graphics_mode != ModePolicy::Mode(mode) =>
+                    {
+                        mode as usize + 1
+                    }
+                    _ => 0,
+                };
+                let next = cycle(current, count, forwards);
+                self.options.graphics_mode = if next == 0 {
+                    self.default_graphics_mode
+                } else {
+                    ModePolicy::Mode(next as u32 - 1)
+                };
+            }
+            Item::CommandLine => {}
+        }
+    }
+
+    /// Draws the whole menu.
+    fn draw(&self) {
+        let con_out = system_table().con_out();
+        con_out.set_attribute(EFI_LIGHTGRAY | EFI_BACKGROUND_BLACK);
+        con_out.clear_screen();
+
+        con_out.set_attribute(EFI_WHITE | EFI_BACKGROUND_BLACK);
+        print!("Rockhopper Boot Menu\n\n");
+
+        for (i, item) in ITEMS.iter().enumerate() {
+            let mut value = String::new();
+            match item {
+                Item::Kernel => write!(value, "< {} >", self.options.kernel_path),
+                Item::GraphicsMode => match self.options.graphics_mode {
+                    ModePolicy::Mode(mode) => match self.gop.query_mode(mode) {
+                        Ok(info) => write!(
+                            value,
+                            "< {}: {}x{}{} >",
+                            mode,
+                            info.horizontal_resolution,
+                            info.vertical_resolution,
+                            if pixel_format(info).is_some() {
+                                ""
+                            } else {
+                                " (unsupported)"
+                            }
+                        ),
+                        Err(_) => write!(value, "< {}: unavailable >", mode),
+                    },
+                    policy => write!(value, "< {} >", policy),
+                },
+                Item::CommandLine => write!(value, "{}_", self.options.command_line),
+            }
+            .unwrap();
+
+            let label = match item {
+                Item::Kernel => "Kernel:",
+                Item::GraphicsMode => "Graphics mode:",
+                Item::CommandLine => "Command line:",
+            };
+
+            con_out.set_attribute(EFI_LIGHTGRAY | EFI_BACKGROUND_BLACK);
+            print!("  {:<16}", label);
+            if i == self.selected {
+                con_out.set_attribute(EFI_BLACK | EFI_BACKGROUND_LIGHTGRAY);
+            }
+            print!("{}", value);
+            con_out.set_attribute(EFI_LIGHTGRAY | EFI_BACKGROUND_BLACK);
+            print!("\n");
+        }
+
+        print!("\n");
+        if let Some(remaining) = self.remaining {
+            print!(
+                "Booting in {} seconds, press any key to interrupt\n",
+                remaining
+            );
+        }
+        print!("Up/Down: Select  Left/Right: Change  Enter: Boot\n");
+    }
+}
+
+/// Returns the index following (or preceding) `current` among `count` indices,
+/// wrapping around at the ends.
+fn cycle(current: usize, count: usize, forwards: bool) -> usize {
+    if forwards {
+        (current + 1) % count
+    } else {
+        (current + count - 1) % count
+    }
+}
+
+/// Returns the names of all files in the given directory with an `.ELF`
+/// extension.
+fn find_kernel_images(directory: &EfiFileProtocol) -> Vec<CString16> {
+    let mut kernels = Vec::new();
+
+    // Each read of a directory returns the info of one entry, use u64s to get the
+    // alignment required by the info structure
+    let mut buffer = vec![0u64; 128];
+    loop {
+        let mut size = buffer.len() * 8;
+        let status = directory.read(&mut size, unsafe {
+            &mut *(buffer.as_mut_ptr() as *mut core::ffi::c_void)
+        });
+        // A size of zero indicates the end of the directory
+        if status.is_error() || size == 0 {
+            break;
+        }
+
+        let info = unsafe { &*(buffer.as_ptr() as *const EfiFileInfo) };
+        if info.attribute() & EFI_FILE_DIRECTORY != 0 {
+            continue;
+        }
+        let name: String = info.file_name().chars().collect();
+        if name.to_ascii_uppercase().ends_with(".ELF") {
+            kernels.push(info.file_name().to_owned());
+        }
+    }
+
+    kernels
+}
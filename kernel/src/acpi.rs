@@ -0,0 +1,87 @@
+//! Lookup of ACPI tables through the RSDP passed by the bootloader.
+//!
+//! Tables are read through the direct map of physical memory, and are assumed
+//! not to change after boot.
+
+use crate::boot_info;
+use crate::memory::PHYS_MEM_OFFSET;
+use core::convert::TryInto;
+
+/// The size of the header shared by all system description tables.
+pub const HEADER_SIZE: usize = 36;
+
+/// Returns the bytes at the given physical address.
+///
+/// # Safety
+/// The memory must be mapped and stay unchanged for the lifetime of the
+/// kernel.
+unsafe fn physical_slice(addr: u64, length: usize) -> &'static [u8] {
+    core::slice::from_raw_parts((PHYS_MEM_OFFSET | addr) as *const u8, length)
+}
+
+/// Returns whether the bytes sum to zero, as the checksums of ACPI structures
+/// require.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Returns the table at the given physical address if its header and checksum
+/// are valid.
+fn table_at(addr: u64) -> Option<&'static [u8]> {
+    let header = unsafe { physical_slice(addr, HEADER_SIZE) };
+    let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = unsafe { physical_slice(addr, length) };
+    if checksum_valid(table) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// Returns the RSDT or XSDT, along with the size of its entries, preferring
+/// the XSDT when the RSDP is of revision 2 or later.
+fn root_table() -> Option<(&'static [u8], usize)> {
+    let rsdp_addr = boot_info().rsdp()?;
+    let rsdp = unsafe { physical_slice(rsdp_addr, 20) };
+    if &rsdp[0..8] != b"RSD PTR " || !checksum_valid(rsdp) {
+        return None;
+    }
+
+    if rsdp[15] >= 2 {
+        let rsdp = unsafe { physical_slice(rsdp_addr, 36) };
+        let xsdt_addr = u64::from_le_bytes(rsdp[24..32].try_into().unwrap());
+        if checksum_valid(rsdp) && xsdt_addr != 0 {
+            if let Some(xsdt) = table_at(xsdt_addr) {
+                if &xsdt[0..4] == b"XSDT" {
+                    return Some((xsdt, 8));
+                }
+            }
+        }
+    }
+
+    let rsdt_addr = u32::from_le_bytes(rsdp[16..20].try_into().unwrap()) as u64;
+    match table_at(rsdt_addr) {
+        Some(rsdt) if &rsdt[0..4] == b"RSDT" => Some((rsdt, 4)),
+        _ => None,
+    }
+}
+
+/// Returns the first table with the given signature, like `MCFG`, including
+/// its header.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (root, entry_size) = root_table()?;
+    root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let addr = if entry_size == 8 {
+                u64::from_le_bytes(entry.try_into().unwrap())
+            } else {
+                u32::from_le_bytes(entry.try_into().unwrap()) as u64
+            };
+            table_at(addr)
+        })
+        .find(|table| &table[0..4] == signature)
+}
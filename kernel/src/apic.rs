@@ -0,0 +1,126 @@
+//! The local APIC of the CPU, which delivers interrupts from devices.
+//!
+//! The legacy 8259 PICs are remapped past the CPU exceptions and masked, so
+//! every interrupt is delivered through the local APIC, like the message
+//! signaled interrupts of PCI devices.
+
+use crate::memory::PHYS_MEM_OFFSET;
+use rk_x86_64::port;
+use rk_x86_64::register::msr;
+use spin::Once;
+
+/// The MSR holding the physical address of the local APIC registers.
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// The global enable bit of [IA32_APIC_BASE].
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const REGISTER_ID: u64 = 0x20;
+const REGISTER_EOI: u64 = 0xb0;
+const REGISTER_SPURIOUS: u64 = 0xf0;
+const REGISTER_LVT_TIMER: u64 = 0x320;
+const REGISTER_TIMER_INITIAL_COUNT: u64 = 0x380;
+const REGISTER_TIMER_CURRENT_COUNT: u64 = 0x390;
+const REGISTER_TIMER_DIVIDE: u64 = 0x3e0;
+
+/// The software enable bit of the spurious interrupt vector register.
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+
+/// The mask bit of local vector table entries.
+const LVT_MASKED: u32 = 1 << 16;
+
+/// The periodic mode of the timer local vector table entry.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// The value of the divide configuration register dividing the bus clock by
+/// 16 for the timer.
+const TIMER_DIVIDE_16: u32 = 0x3;
+
+/// The vector of spurious interrupts, which must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The first of the 16 vectors the legacy PICs are remapped to.
+pub const PIC_VECTOR_BASE: u8 = 0x20;
+
+/// The virtual address of the local APIC registers.
+static BASE: Once<u64> = Once::new();
+
+fn read(register: u64) -> u32 {
+    let base = BASE.get().expect("Local APIC is not initialized");
+    unsafe { core::ptr::read_volatile((base + register) as *const u32) }
+}
+
+fn write(register: u64, value: u32) {
+    let base = BASE.get().expect("Local APIC is not initialized");
+    unsafe { core::ptr::write_volatile((base + register) as *mut u32, value) }
+}
+
+/// Remaps the legacy PICs to [PIC_VECTOR_BASE] and masks all their
+/// interrupts. Without remapping, a spurious interrupt from a PIC would be
+/// mistaken for a CPU exception.
+fn disable_pics() {
+    unsafe {
+        // Start initialization, in cascade mode with a fourth command word
+        port::write_u8(0x20, 0x11);
+        port::write_u8(0xa0, 0x11);
+        // The vector offsets
+        port::write_u8(0x21, PIC_VECTOR_BASE);
+        port::write_u8(0xa1, PIC_VECTOR_BASE + 8);
+        // The secondary PIC is on line 2 of the primary
+        port::write_u8(0x21, 1 << 2);
+        port::write_u8(0xa1, 2);
+        // 8086 mode
+        port::write_u8(0x21, 0x01);
+        port::write_u8(0xa1, 0x01);
+        // Mask every line
+        port::write_u8(0x21, 0xff);
+        port::write_u8(0xa1, 0xff);
+    }
+}
+
+/// Disables the legacy PICs and enables the local APIC.
+pub fn init() {
+    disable_pics();
+
+    let apic_base = unsafe { msr::read(IA32_APIC_BASE) };
+    unsafe {
+        msr::write(IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+    }
+    BASE.call_once(|| PHYS_MEM_OFFSET | (apic_base & 0x000f_ffff_ffff_f000));
+
+    write(REGISTER_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// Returns the identifier of the local APIC, which message signaled
+/// interrupts are addressed to.
+pub fn id() -> u8 {
+    (read(REGISTER_ID) >> 24) as u8
+}
+
+/// Signals the end of the interrupt being handled, allowing interrupts of the
+/// same or lower priority to be delivered.
+pub fn end_of_interrupt() {
+    write(REGISTER_EOI, 0);
+}
+
+/// Starts the timer counting down from the given count at a sixteenth of the
+/// bus clock, masked, such that [timer_count] tells the time passed. Used to
+/// measure the frequency of the timer.
+pub fn start_timer_masked(count: u32) {
+    write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REGISTER_LVT_TIMER, LVT_MASKED);
+    write(REGISTER_TIMER_INITIAL_COUNT, count);
+}
+
+/// Starts the timer signaling the vector every time it has counted down the
+/// given count at a sixteenth of the bus clock.
+pub fn start_timer_periodic(vector: u8, count: u32) {
+    write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REGISTER_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    write(REGISTER_TIMER_INITIAL_COUNT, count);
+}
+
+/// Returns the current count of the timer.
+pub fn timer_count() -> u32 {
+    read(REGISTER_TIMER_CURRENT_COUNT)
+}
@@ -0,0 +1,343 @@
+//! A write-back cache of the contents of block devices.
+//!
+//! Devices are cached in pages of 4 KiB, each covering an aligned range of
+//! sectors, and stored in a frame of physical memory. Writes only go to the
+//! cache, and reach the device when the page is evicted or the device is
+//! flushed. When the cache is full, the least recently used page is evicted.
+//!
+//! The cache is shared by all devices. Its size is given by the
+//! `block_cache_size` command line option, and defaults to a sixteenth of the
+//! usable memory.
+
+use super::{BlockDevice, Error};
+use crate::cmdline::CMDLINE;
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use crate::println;
+use crate::sync::mutex::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The size of the pages devices are cached in.
+const PAGE_SIZE: usize = 4096;
+
+/// The identifier of the next cached device.
+static NEXT_DEVICE_ID: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref CACHE: Mutex<Cache> = {
+        let size = CMDLINE
+            .get_size("block_cache_size")
+            .unwrap_or_else(|| memory::usable_memory() / 16);
+        Mutex::new(Cache::new(size as usize / PAGE_SIZE))
+    };
+}
+
+/// Returns the contents of the page at the given physical address.
+///
+/// # Safety
+/// The page must be owned by the cache, which must be locked while the slice
+/// exists.
+unsafe fn page_mut<'a>(addr: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET | addr) as *mut u8, PAGE_SIZE)
+}
+
+/// A cached page of a device.
+struct Page {
+    device: Arc<dyn BlockDevice>,
+    /// The physical address of the frame holding the contents.
+    addr: u64,
+    /// Whether the page has been written since it was read from the device.
+    dirty: bool,
+    /// The value of the clock when the page was last used.
+    last_used: u64,
+}
+
+impl Page {
+    /// Returns the first sector of the page with the given index, and the
+    /// number of sectors in it, which is less than a full page at the end of
+    /// the device.
+    fn sectors(device: &dyn BlockDevice, index: u64) -> (u64, usize) {
+        let sectors_per_page = (PAGE_SIZE / device.sector_size()) as u64;
+        let first_sector = index * sectors_per_page;
+        let count = core::cmp::min(sectors_per_page, device.sector_count() - first_sector);
+        (first_sector, count as usize)
+    }
+
+    /// Writes the page to the device if it's dirty.
+    fn write_back(&mut self, index: u64) -> Result<(), Error> {
+        if self.dirty {
+            let (first_sector, count) = Self::sectors(self.device.as_ref(), index);
+            let length = count * self.device.sector_size();
+            let contents = unsafe { page_mut(self.addr) };
+            self.device
+                .write_sectors(first_sector, &contents[..length])?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// The pages of all cached devices.
+struct Cache {
+    /// The cached pages, by the identifier of the device and the index of the
+    /// page in the device.
+    pages: BTreeMap<(usize, u64), Page>,
+    /// The keys of the cached pages by when they were last used, least
+    /// recently used first.
+    lru: BTreeMap<u64, (usize, u64)>,
+    /// Counts every use of a page.
+    clock: u64,
+    max_pages: usize,
+}
+
+impl Cache {
+    fn new(max_pages: usize) -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            max_pages,
+        }
+    }
+
+    /// Returns the physical address of a cached page, reading it from the
+    /// device unless `load` is false, in which case the contents are
+    /// undefined. Returns `None` if the page cannot be cached, because the
+    /// cache is disabled or out of memory.
+    fn get(
+        &mut self,
+        key: (usize, u64),
+        device: &Arc<dyn BlockDevice>,
+        load: bool,
+    ) -> Result<Option<u64>, Error> {
+        self.clock += 1;
+        if let Some(page) = self.pages.get_mut(&key) {
+            self.lru.remove(&page.last_used);
+            page.last_used = self.clock;
+            self.lru.insert(self.clock, key);
+            return Ok(Some(page.addr));
+        }
+
+        if self.max_pages == 0 {
+            return Ok(None);
+        }
+        if self.pages.len() >= self.max_pages {
+            self.evict()?;
+        }
+        let addr = match memory::allocate_frame() {
+            Ok(addr) => addr,
+            Err(()) => return Ok(None),
+        };
+        if load {
+            let (first_sector, count) = Page::sectors(device.as_ref(), key.1);
+            let length = count * device.sector_size();
+            let contents = unsafe { page_mut(addr) };
+            if let Err(error) = device.read_sectors(first_sector, &mut contents[..length]) {
+                unsafe {
+                    memory::free_frame(addr);
+                }
+                return Err(error);
+            }
+        }
+
+        self.pages.insert(
+            key,
+            Page {
+                device: device.clone(),
+                addr,
+                dirty: false,
+                last_used: self.clock,
+            },
+        );
+        self.lru.insert(self.clock, key);
+        Ok(Some(addr))
+    }
+
+    /// Evicts the least recently used page, writing it back if it's dirty.
+    fn evict(&mut self) -> Result<(), Error> {
+        let (last_used, key) = match self.lru.iter().next() {
+            Some((&last_used, &key)) => (last_used, key),
+            None => return Ok(()),
+        };
+        self.pages.get_mut(&key).unwrap().write_back(key.1)?;
+        self.lru.remove(&last_used);
+        let page = self.pages.remove(&key).unwrap();
+        unsafe {
+            memory::free_frame(page.addr);
+        }
+        Ok(())
+    }
+
+    /// Writes back the dirty pages of a device.
+    fn write_back(&mut self, id: usize) -> Result<(), Error> {
+        for (&(_, index), page) in self.pages.range_mut((id, 0)..=(id, u64::MAX)) {
+            page.write_back(index)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back and removes the pages of a device.
+    fn remove(&mut self, id: usize) -> Result<(), Error> {
+        let result = self.write_back(id);
+        let keys: Vec<(usize, u64)> = self
+            .pages
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            let page = self.pages.remove(&key).unwrap();
+            self.lru.remove(&page.last_used);
+            unsafe {
+                memory::free_frame(page.addr);
+            }
+        }
+        result
+    }
+}
+
+/// A block device accessed through the cache.
+///
+/// The device itself must not be cached, and should not be accessed in other
+/// ways while cached.
+pub struct CachedDevice {
+    /// Identifies the pages of the device in the cache.
+    id: usize,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            id: NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed),
+            device,
+        }
+    }
+
+    /// Returns whether the sectors of the device fit in pages, which is the
+    /// case for any sector size up to the size of a page.
+    fn is_cacheable(&self) -> bool {
+        self.device.sector_size() <= PAGE_SIZE
+    }
+
+    /// Calls `access` for each part of an access to sectors starting at
+    /// `sector` with a buffer of the given length, which is split at page
+    /// boundaries. It's given the key of the page, the sector and offset in
+    /// the page the part starts at, and the length of the part.
+    fn for_each_page(
+        &self,
+        sector: u64,
+        length: usize,
+        mut access: impl FnMut((usize, u64), u64, usize, usize) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let sector_size = self.device.sector_size();
+        let sectors_per_page = (PAGE_SIZE / sector_size) as u64;
+        let mut done = 0;
+        while done < length {
+            let current = sector + (done / sector_size) as u64;
+            let index = current / sectors_per_page;
+            let offset = (current - index * sectors_per_page) as usize * sector_size;
+            let part = core::cmp::min(length - done, PAGE_SIZE - offset);
+            access((self.id, index), current, offset, part)?;
+            done += part;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        super::check_access(self, sector, buffer.len())?;
+        if !self.is_cacheable() {
+            return self.device.read_sectors(sector, buffer);
+        }
+
+        let mut cache = CACHE.lock();
+        let mut done = 0;
+        self.for_each_page(sector, buffer.len(), |key, current, offset, length| {
+            let part = &mut buffer[done..done + length];
+            match cache.get(key, &self.device, true)? {
+                Some(addr) => {
+                    let contents = unsafe { page_mut(addr) };
+                    part.copy_from_slice(&contents[offset..offset + length]);
+                }
+                None => self.device.read_sectors(current, part)?,
+            }
+            done += length;
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        super::check_access(self, sector, buffer.len())?;
+        if !self.is_cacheable() {
+            return self.device.write_sectors(sector, buffer);
+        }
+
+        let mut cache = CACHE.lock();
+        let mut done = 0;
+        self.for_each_page(sector, buffer.len(), |key, current, offset, length| {
+            let part = &buffer[done..done + length];
+            // A page which is overwritten completely is not read first
+            let (_, count) = Page::sectors(self.device.as_ref(), key.1);
+            let whole_page = offset == 0 && length == count * self.device.sector_size();
+            match cache.get(key, &self.device, !whole_page)? {
+                Some(addr) => {
+                    let contents = unsafe { page_mut(addr) };
+                    contents[offset..offset + length].copy_from_slice(part);
+                    cache.pages.get_mut(&key).unwrap().dirty = true;
+                }
+                None => self.device.write_sectors(current, part)?,
+            }
+            done += length;
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        CACHE.lock().write_back(self.id)?;
+        self.device.flush()
+    }
+}
+
+impl Drop for CachedDevice {
+    fn drop(&mut self) {
+        let result = CACHE
+            .lock()
+            .remove(self.id)
+            .and_then(|_| self.device.flush());
+        if let Err(error) = result {
+            println!("Could not write back cached sectors: {}", error);
+        }
+    }
+}
+
+/// Writes back the dirty pages of all devices, and flushes them.
+pub fn sync() -> Result<(), Error> {
+    let mut cache = CACHE.lock();
+    let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+    for (&(_, index), page) in cache.pages.iter_mut() {
+        if page.dirty {
+            page.write_back(index)?;
+            if !devices
+                .iter()
+                .any(|device| Arc::ptr_eq(device, &page.device))
+            {
+                devices.push(page.device.clone());
+            }
+        }
+    }
+    for device in devices {
+        device.flush()?;
+    }
+    Ok(())
+}
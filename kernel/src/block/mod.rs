@@ -0,0 +1,142 @@
+//! Block devices, like disks and their partitions.
+//!
+//! Drivers register the disks they find with [register_disk], which puts the
+//! disk behind the [buffer cache](cache) and scans it for a
+//! [partition table](partition). The disk and each of its partitions are then
+//! available by name, like `vda` and `vda1`, to be mounted by file systems.
+
+pub mod cache;
+pub mod partition;
+
+use crate::println;
+use crate::sync::spinlock::SpinLock;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// An error returned by block device operations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The sectors are past the end of the device.
+    OutOfRange,
+    /// The length of the buffer is not a multiple of the sector size.
+    InvalidBuffer,
+    /// The device does not support the operation, like writing to read-only
+    /// media.
+    NotSupported,
+    /// The device reported an error, or did not respond.
+    Io,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OutOfRange => write!(f, "sector out of range"),
+            Self::InvalidBuffer => write!(f, "buffer is not a whole number of sectors"),
+            Self::NotSupported => write!(f, "operation not supported by device"),
+            Self::Io => write!(f, "input/output error"),
+        }
+    }
+}
+
+/// A device storing data in fixed size sectors.
+///
+/// Buffers passed to the device always hold a whole number of sectors, which
+/// [check_access] verifies along with the range of sectors.
+pub trait BlockDevice: Send + Sync {
+    /// Returns the size of a sector in bytes, which is a power of two.
+    fn sector_size(&self) -> usize;
+
+    /// Returns the number of sectors.
+    fn sector_count(&self) -> u64;
+
+    /// Reads consecutive sectors, starting at the given sector, into the
+    /// buffer.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes the buffer to consecutive sectors, starting at the given sector.
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Makes sure all written sectors are stored on the device.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Checks that a buffer of the given length holds a whole number of sectors,
+/// and that those sectors starting at `sector` are on the device. Returns the
+/// number of sectors.
+pub fn check_access(device: &dyn BlockDevice, sector: u64, length: usize) -> Result<u64, Error> {
+    let sector_size = device.sector_size();
+    if length & (sector_size - 1) != 0 {
+        return Err(Error::InvalidBuffer);
+    }
+    let count = (length / sector_size) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(Error::OutOfRange),
+    }
+}
+
+/// The registered disks and partitions, by name.
+static DEVICES: SpinLock<Vec<(String, Arc<dyn BlockDevice>)>> = SpinLock::new(Vec::new());
+
+/// Registers a disk found by a driver, along with the partitions on it.
+///
+/// Partitions are named after the disk followed by their number, with a `p`
+/// in between if the name of the disk ends with a digit, like `vda1` and
+/// `nvme0n1p1`.
+pub fn register_disk(name: &str, disk: Arc<dyn BlockDevice>) {
+    let disk: Arc<dyn BlockDevice> = Arc::new(cache::CachedDevice::new(disk));
+    println!(
+        "Disk {}: {} sectors of {} bytes",
+        name,
+        disk.sector_count(),
+        disk.sector_size()
+    );
+    let partitions = match partition::scan(disk.as_ref()) {
+        Ok(partitions) => partitions,
+        Err(error) => {
+            println!("Could not read the partition table of {}: {}", name, error);
+            Vec::new()
+        }
+    };
+
+    let mut devices = DEVICES.lock();
+    devices.push((name.into(), disk.clone()));
+    for entry in partitions {
+        let separator = if name.ends_with(|c: char| c.is_ascii_digit()) {
+            "p"
+        } else {
+            ""
+        };
+        let partition_name = format!("{}{}{}", name, separator, entry.number);
+        println!(
+            "Partition {}: {} sectors from {}, {}",
+            partition_name, entry.sector_count, entry.first_sector, entry.partition_type
+        );
+        devices.push((
+            partition_name,
+            Arc::new(partition::Partition::new(disk.clone(), entry)),
+        ));
+    }
+}
+
+/// Returns the disk or partition with the given name.
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(device_name, _)| device_name == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Returns the names of all disks and partitions.
+pub fn names() -> Vec<String> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect()
+}
@@ -0,0 +1,412 @@
+//! Partition tables, both MBR and GPT.
+//!
+//! A disk with a protective MBR is read as GPT, using the backup header at the
+//! end of the disk if the primary header or its entries are damaged. Extended
+//! MBR partitions are followed, with the logical partitions inside numbered
+//! from 5.
+
+use super::{BlockDevice, Error};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+/// The MBR partition type of a protective MBR, covering a GPT disk.
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// The MBR partition types of extended partitions.
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// The maximum number of logical partitions followed in an extended
+/// partition, which protects against loops.
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+/// The signature at the start of a GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The maximum number of GPT entries read, which is enough for the 128
+/// entries disks are normally formatted with.
+const MAX_GPT_ENTRIES: u32 = 1024;
+
+/// A GUID, as used by GPT.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The type GUID of unused GPT entries.
+    pub const UNUSED: Self = Self([0; 16]);
+
+    /// The type GUID of EFI system partitions.
+    pub const EFI_SYSTEM: Self = Self([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The first three groups are stored little endian
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The type of a partition, as given by its partition table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Mbr(partition_type) => write!(f, "MBR type {:#04x}", partition_type),
+            Self::Gpt(guid) => write!(f, "GPT type {}", guid),
+        }
+    }
+}
+
+/// A partition found in a partition table.
+#[derive(Clone, Debug)]
+pub struct PartitionEntry {
+    /// The number of the partition, starting at 1.
+    pub number: u32,
+    pub first_sector: u64,
+    pub sector_count: u64,
+    pub partition_type: PartitionType,
+    /// The name of a GPT partition.
+    pub name: Option<String>,
+}
+
+/// A partition of a disk, accessed as a block device of its own.
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    entry: PartitionEntry,
+}
+
+impl Partition {
+    /// Creates a block device for a partition of the disk. The partition must
+    /// be within the disk, which [scan] makes sure of.
+    pub fn new(disk: Arc<dyn BlockDevice>, entry: PartitionEntry) -> Self {
+        Self { disk, entry }
+    }
+
+    /// Returns the entry describing the partition.
+    pub fn entry(&self) -> &PartitionEntry {
+        &self.entry
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.entry.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        super::check_access(self, sector, buffer.len())?;
+        self.disk
+            .read_sectors(self.entry.first_sector + sector, buffer)
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        super::check_access(self, sector, buffer.len())?;
+        self.disk
+            .write_sectors(self.entry.first_sector + sector, buffer)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.disk.flush()
+    }
+}
+
+/// Returns the partitions of a disk, which is empty if the disk has no
+/// partition table.
+pub fn scan(disk: &dyn BlockDevice) -> Result<Vec<PartitionEntry>, Error> {
+    let sector_size = disk.sector_size();
+    if sector_size < 512 || disk.sector_count() < 2 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = vec![0; sector_size];
+    disk.read_sectors(0, &mut mbr)?;
+    let mbr_entries = match mbr_entries(&mbr, disk.sector_count()) {
+        Some(entries) => entries,
+        None => return Ok(Vec::new()),
+    };
+
+    if mbr_entries
+        .iter()
+        .any(|&(partition_type, _, _)| partition_type == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return scan_gpt(disk);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, &(partition_type, first_sector, sector_count)) in mbr_entries.iter().enumerate() {
+        if partition_type == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&partition_type) {
+            scan_extended(disk, first_sector, sector_count, &mut partitions)?;
+        } else {
+            partitions.push(PartitionEntry {
+                number: index as u32 + 1,
+                first_sector,
+                sector_count,
+                partition_type: PartitionType::Mbr(partition_type),
+                name: None,
+            });
+        }
+    }
+    Ok(partitions)
+}
+
+/// Returns the type, first sector, and number of sectors of each of the four
+/// entries in an MBR or extended boot record, or `None` if the sector isn't
+/// one.
+///
+/// Boot sectors of file systems also end with the MBR signature, so the entries
+/// are checked to be valid and within the disk.
+fn mbr_entries(sector: &[u8], sector_count: u64) -> Option<[(u8, u64, u64); 4]> {
+    if sector[510] != 0x55 || sector[511] != 0xaa {
+        return None;
+    }
+    let mut entries = [(0, 0, 0); 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let raw = &sector[446 + i * 16..446 + (i + 1) * 16];
+        let status = raw[0];
+        let partition_type = raw[4];
+        let first_sector = u32::from_le_bytes(raw[8..12].try_into().unwrap()) as u64;
+        let count = u32::from_le_bytes(raw[12..16].try_into().unwrap()) as u64;
+        if status != 0x00 && status != 0x80 {
+            return None;
+        }
+        if partition_type == 0 {
+            continue;
+        }
+        // A protective MBR may claim more sectors than the disk has
+        if partition_type != MBR_TYPE_GPT_PROTECTIVE
+            && (first_sector == 0 || count == 0 || first_sector + count > sector_count)
+        {
+            return None;
+        }
+        *entry = (partition_type, first_sector, count);
+    }
+    Some(entries)
+}
+
+/// Adds the logical partitions of an extended partition.
+///
+/// Each logical partition has an extended boot record in front of it, with an
+/// entry for the partition relative to the record, and an entry for the next
+/// record relative to the extended partition.
+fn scan_extended(
+    disk: &dyn BlockDevice,
+    first_sector: u64,
+    sector_count: u64,
+    partitions: &mut Vec<PartitionEntry>,
+) -> Result<(), Error> {
+    let mut sector = vec![0; disk.sector_size()];
+    let mut record = first_sector;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        disk.read_sectors(record, &mut sector)?;
+        let entries = match mbr_entries(&sector, disk.sector_count()) {
+            Some(entries) => entries,
+            None => break,
+        };
+
+        // The entries are relative, so only the end of the logical partition
+        // needs to be checked against the extended partition
+        let (partition_type, start, count) = entries[0];
+        if partition_type != 0 && record + start + count <= first_sector + sector_count {
+            partitions.push(PartitionEntry {
+                number,
+                first_sector: record + start,
+                sector_count: count,
+                partition_type: PartitionType::Mbr(partition_type),
+                name: None,
+            });
+        }
+
+        let (next_type, next_start, _) = entries[1];
+        if !MBR_TYPES_EXTENDED.contains(&next_type) || next_start >= sector_count {
+            break;
+        }
+        record = first_sector + next_start;
+    }
+    Ok(())
+}
+
+/// Returns the partitions of a GPT disk, which is empty if neither the primary
+/// nor the backup partition table is valid.
+fn scan_gpt(disk: &dyn BlockDevice) -> Result<Vec<PartitionEntry>, Error> {
+    let last_sector = disk.sector_count() - 1;
+    let mut header = match read_gpt_header(disk, 1)? {
+        Some(header) => Some(header),
+        None => read_gpt_header(disk, last_sector)?,
+    };
+    let mut entries = match &header {
+        Some(header) => read_gpt_entries(disk, header)?,
+        None => None,
+    };
+    // The entries of the primary header may be damaged on their own
+    if entries.is_none() {
+        header = read_gpt_header(disk, last_sector)?;
+        if let Some(header) = &header {
+            entries = read_gpt_entries(disk, header)?;
+        }
+    }
+    let (header, entries) = match (header, entries) {
+        (Some(header), Some(entries)) => (header, entries),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(header.entry_size).enumerate() {
+        let type_guid = Guid(entry[..16].try_into().unwrap());
+        if type_guid == Guid::UNUSED {
+            continue;
+        }
+        let first_sector = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let end_sector = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if first_sector < header.first_usable
+            || end_sector > header.last_usable
+            || end_sector < first_sector
+        {
+            continue;
+        }
+
+        // The name is UTF-16, padded with zeroes
+        let name = core::char::decode_utf16(
+            entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|&unit| unit != 0),
+        )
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect();
+
+        partitions.push(PartitionEntry {
+            number: index as u32 + 1,
+            first_sector,
+            sector_count: end_sector - first_sector + 1,
+            partition_type: PartitionType::Gpt(type_guid),
+            name: Some(name),
+        });
+    }
+    Ok(partitions)
+}
+
+/// The fields of a GPT header needed to read the partition entries.
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_sector: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Reads the GPT header at the given sector, returning `None` if it's invalid.
+fn read_gpt_header(disk: &dyn BlockDevice, sector: u64) -> Result<Option<GptHeader>, Error> {
+    let mut buffer = vec![0; disk.sector_size()];
+    disk.read_sectors(sector, &mut buffer)?;
+    let u32_at = |offset: usize| u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+
+    if &buffer[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = u32_at(12) as usize;
+    if header_size < 92 || header_size > buffer.len() {
+        return Ok(None);
+    }
+    // The checksum is calculated with the checksum field set to zero
+    let header_crc = u32_at(16);
+    let mut header = buffer[..header_size].to_vec();
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header) != header_crc || u64_at(24) != sector {
+        return Ok(None);
+    }
+
+    let header = GptHeader {
+        first_usable: u64_at(40),
+        last_usable: u64_at(48),
+        entries_sector: u64_at(72),
+        entry_count: u32_at(80),
+        entry_size: u32_at(84) as usize,
+        entries_crc: u32_at(88),
+    };
+    if header.last_usable >= disk.sector_count()
+        || header.first_usable > header.last_usable
+        || header.entry_size < 128
+        || !header.entry_size.is_power_of_two()
+        || header.entry_count > MAX_GPT_ENTRIES
+    {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// Reads the partition entries of a GPT header, returning `None` if they don't
+/// match the checksum in the header.
+fn read_gpt_entries(disk: &dyn BlockDevice, header: &GptHeader) -> Result<Option<Vec<u8>>, Error> {
+    let sector_size = disk.sector_size();
+    let length = header.entry_count as usize * header.entry_size;
+    // Sector sizes are powers of two
+    let whole_sectors = (length + sector_size - 1) & !(sector_size - 1);
+    if header.entries_sector + (whole_sectors / sector_size) as u64 > disk.sector_count() {
+        return Ok(None);
+    }
+    let mut entries = vec![0; whole_sectors];
+    disk.read_sectors(header.entries_sector, &mut entries)?;
+    entries.truncate(length);
+    if crc32(&entries) != header.entries_crc {
+        return Ok(None);
+    }
+    Ok(Some(entries))
+}
+
+/// The lookup table of [crc32], with the remainder of every byte.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut remainder = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            remainder = if remainder & 1 == 1 {
+                (remainder >> 1) ^ 0xedb8_8320
+            } else {
+                remainder >> 1
+            };
+            bit += 1;
+        }
+        table[i] = remainder;
+        i += 1;
+    }
+    table
+};
+
+/// Calculates the CRC-32 checksum used by GPT, which is the one also used by
+/// Ethernet and zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
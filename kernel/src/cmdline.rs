@@ -0,0 +1,139 @@
+//! The kernel command line passed by the bootloader.
+//!
+//! The command line consists of whitespace separated options, either on the
+//! form `key=value` or a bare `key`. Values containing whitespace can be
+//! enclosed in double quotes, like `init="/bin/sh -l"`. If an option is given
+//! more than once, the last occurrence wins.
+//!
+//! The recognized options are:
+//!
+//! - `init`: Path of a program to run as the first process, which the kernel
+//!   waits for.
+//! - `echo_port`: A TCP port to echo connections on, for testing the network
+//!   stack from the host.
+//! - `quiet`: Skips the tests printed while booting.
+
+use core::str::FromStr;
+
+lazy_static! {
+    /// The command line the kernel was booted with.
+    pub static ref CMDLINE: CommandLine = CommandLine::new(crate::boot_info().command_line());
+}
+
+/// A parsed command line, with typed lookups of its options.
+pub struct CommandLine {
+    text: &'static str,
+}
+
+impl CommandLine {
+    /// Creates a command line from its text.
+    pub fn new(text: &'static str) -> Self {
+        Self { text: text.trim() }
+    }
+
+    /// Returns the whole command line.
+    pub fn as_str(&self) -> &'static str {
+        self.text
+    }
+
+    /// Returns an iterator over all options, as keys and optional values.
+    pub fn options(&self) -> Options<'static> {
+        Options { rest: self.text }
+    }
+
+    /// Returns whether the option is present, with or without a value.
+    pub fn contains(&self, key: &str) -> bool {
+        self.options().any(|(k, _)| k == key)
+    }
+
+    /// Returns the value of an option, or an empty string if it's given
+    /// without a value.
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.options()
+            .filter(|&(k, _)| k == key)
+            .last()
+            .map(|(_, value)| value.unwrap_or(""))
+    }
+
+    /// Returns the value of an option parsed as `T`, or `None` if the option is
+    /// missing or cannot be parsed.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key)?.parse().ok()
+    }
+
+    /// Returns the value of a boolean option.
+    ///
+    /// `on`, `yes`, `true`, and `1` are true, `off`, `no`, `false`, and `0` are
+    /// false. An option without a value is true.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            "" | "on" | "yes" | "true" | "1" => Some(true),
+            "off" | "no" | "false" | "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a size option in bytes.
+    ///
+    /// The value is a number with an optional `K`, `M`, `G`, or `T` suffix
+    /// (case insensitive) multiplying it by the respective power of 1024.
+    pub fn get_size(&self, key: &str) -> Option<u64> {
+        parse_size(self.get(key)?)
+    }
+}
+
+/// Parses a size with an optional binary unit suffix.
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        b't' | b'T' => (&value[..value.len() - 1], 40),
+        _ => (value, 0),
+    };
+    let number: u64 = number.parse().ok()?;
+    number.checked_mul(1 << shift)
+}
+
+/// An iterator over the options of a command line.
+pub struct Options<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        // Find the end of the option, skipping whitespace within quotes
+        let mut quoted = false;
+        let end = self
+            .rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| self.rest.len());
+        let option = &self.rest[..end];
+        self.rest = &self.rest[end..];
+
+        let mut parts = option.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let value = parts.next().map(|value| {
+            if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            }
+        });
+        Some((key, value))
+    }
+}
@@ -0,0 +1,561 @@
+//! The driver of AHCI SATA controllers, which registers each disk found as
+//! `sda`, `sdb`, and so on.
+//!
+//! Each port uses a single command slot, so commands are issued one at a time
+//! and their completion is polled. Like the virtio driver, data is transferred
+//! through frames owned by the driver. After a failed command the port is
+//! restarted, resetting the device if needed, and the command is retried once.
+
+use super::delay;
+use crate::block::{self, BlockDevice, Error};
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use crate::pci::{self, Bar, Device, Driver, Match};
+use crate::println;
+use crate::sync::mutex::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub static DRIVER: Driver = Driver {
+    name: "ahci",
+    matches: &[Match::Class {
+        class: 0x01,
+        subclass: 0x06,
+        prog_if: Some(0x01),
+    }],
+    probe,
+};
+
+/// The index of the BAR holding the HBA registers.
+const ABAR: usize = 5;
+
+/// The offsets of the generic host control registers.
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_PI: u64 = 0x0c;
+const HBA_CAP2: u64 = 0x24;
+const HBA_BOHC: u64 = 0x28;
+
+/// The HBA supports 64-bit addresses.
+const CAP_S64A: u32 = 1 << 31;
+/// The ports support command list override.
+const CAP_SCLO: u32 = 1 << 24;
+/// The HBA supports BIOS/OS handoff.
+const CAP2_BOH: u32 = 1 << 0;
+/// Software is AHCI aware.
+const GHC_AE: u32 = 1 << 31;
+/// The BIOS owns the HBA.
+const BOHC_BOS: u32 = 1 << 0;
+/// The OS requests ownership of the HBA.
+const BOHC_OOS: u32 = 1 << 1;
+
+/// The offsets of the port registers.
+const PORT_CLB: u64 = 0x00;
+const PORT_FB: u64 = 0x08;
+const PORT_IS: u64 = 0x10;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SCTL: u64 = 0x2c;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_CLO: u32 = 1 << 3;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// A task file error occurred.
+const IS_TFES: u32 = 1 << 30;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// The signature of a SATA disk, as opposed to ATAPI or port multipliers.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const ATA_READ_DMA: u8 = 0xc8;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA: u8 = 0xca;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE: u8 = 0xe7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY_DEVICE: u8 = 0xec;
+
+/// The type of a register FIS sent from the host to the device.
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+/// The offset of the received FIS area in the frame of the command list.
+const RECEIVED_FIS_OFFSET: u64 = 1024;
+
+/// The offset of the physical region descriptor table in the command table.
+const PRDT_OFFSET: u64 = 0x80;
+
+const FRAME_SIZE: usize = 4096;
+
+/// The largest number of frames transferred by a single command.
+const MAX_SEGMENTS: usize = 32;
+
+/// The time to wait for a port to start or stop, in microseconds.
+const PORT_TIMEOUT: usize = 500_000;
+
+/// The time to wait for a command to complete, in microseconds.
+const COMMAND_TIMEOUT: usize = 5_000_000;
+
+/// The number of disks found, which gives the name of the next one.
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the contents of a frame.
+///
+/// # Safety
+/// The frame must be owned by the caller, and not be accessed by the device
+/// while the slice exists.
+unsafe fn frame_mut<'a>(addr: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET | addr) as *mut u8, FRAME_SIZE)
+}
+
+/// The registers of the HBA, or of one of its ports.
+#[derive(Copy, Clone)]
+struct Registers(u64);
+
+impl Registers {
+    fn read(self, offset: u64) -> u32 {
+        unsafe { core::ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(self, offset: u64, value: u32) {
+        unsafe { core::ptr::write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+
+    /// Waits until the bits of `mask` in a register are equal to those of
+    /// `value`, for about the given number of microseconds.
+    fn wait(self, offset: u64, mask: u32, value: u32, timeout: usize) -> Result<(), ()> {
+        for _ in 0..timeout {
+            if self.read(offset) & mask == value {
+                return Ok(());
+            }
+            delay(1);
+        }
+        Err(())
+    }
+}
+
+/// A command sent to the device.
+struct Command {
+    ata_command: u8,
+    lba: u64,
+    /// The number of sectors, written to the FIS as is.
+    count: u16,
+    /// The number of bytes transferred through the segments.
+    length: usize,
+    write: bool,
+}
+
+/// A port with a device attached, along with the memory used to issue
+/// commands.
+struct Port {
+    registers: Registers,
+    /// The frame holding the command list, followed by the received FIS area.
+    command_list: u64,
+    /// The frame holding the command table of the single slot used.
+    command_table: u64,
+    /// The frames data is transferred through.
+    segments: Vec<u64>,
+    supports_clo: bool,
+}
+
+impl Port {
+    /// Allocates the memory of the port, which must be below 4 GiB unless the
+    /// HBA supports 64-bit addresses.
+    fn new(registers: Registers, cap: u32) -> Result<Self, &'static str> {
+        let mut port = Self {
+            registers,
+            command_list: 0,
+            command_table: 0,
+            segments: Vec::with_capacity(MAX_SEGMENTS),
+            supports_clo: cap & CAP_SCLO != 0,
+        };
+        let allocate = || -> Result<u64, &'static str> {
+            let frame = memory::allocate_frame().map_err(|_| "could not allocate memory")?;
+            if frame >> 32 != 0 && cap & CAP_S64A == 0 {
+                unsafe { memory::free_frame(frame) };
+                return Err("memory is not addressable by the controller");
+            }
+            unsafe { frame_mut(frame).copy_from_slice(&[0; FRAME_SIZE]) };
+            Ok(frame)
+        };
+        // Frames allocated so far are freed when the port is dropped
+        port.command_list = allocate()?;
+        port.command_table = allocate()?;
+        for _ in 0..MAX_SEGMENTS {
+            let frame = allocate()?;
+            port.segments.push(frame);
+        }
+        Ok(port)
+    }
+
+    /// Stops processing the command list and receiving FISes.
+    fn stop(&self) -> Result<(), ()> {
+        let registers = self.registers;
+        registers.write(PORT_CMD, registers.read(PORT_CMD) & !CMD_ST);
+        registers.wait(PORT_CMD, CMD_CR, 0, PORT_TIMEOUT)?;
+        registers.write(PORT_CMD, registers.read(PORT_CMD) & !CMD_FRE);
+        registers.wait(PORT_CMD, CMD_FR, 0, PORT_TIMEOUT)
+    }
+
+    /// Points the port to its memory and starts it, once the device is idle.
+    fn start(&self) -> Result<(), ()> {
+        let registers = self.registers;
+        registers.write(PORT_CLB, self.command_list as u32);
+        registers.write(PORT_CLB + 4, (self.command_list >> 32) as u32);
+        let received_fis = self.command_list + RECEIVED_FIS_OFFSET;
+        registers.write(PORT_FB, received_fis as u32);
+        registers.write(PORT_FB + 4, (received_fis >> 32) as u32);
+
+        registers.write(PORT_SERR, u32::MAX);
+        registers.write(PORT_IS, u32::MAX);
+        registers.write(PORT_CMD, registers.read(PORT_CMD) | CMD_FRE);
+        registers.wait(PORT_TFD, TFD_BSY | TFD_DRQ, 0, PORT_TIMEOUT)?;
+        registers.write(PORT_CMD, registers.read(PORT_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    /// Resets the device with a COMRESET, and waits for it to come back.
+    fn reset_device(&self) -> Result<(), ()> {
+        let registers = self.registers;
+        let sctl = registers.read(PORT_SCTL) & !0xf;
+        registers.write(PORT_SCTL, sctl | 1);
+        delay(1000);
+        registers.write(PORT_SCTL, sctl);
+        registers.wait(PORT_SSTS, 0xf, 3, PORT_TIMEOUT)?;
+        registers.write(PORT_SERR, u32::MAX);
+        Ok(())
+    }
+
+    /// Recovers from a failed command by restarting the port, which clears
+    /// the error. If the device is still busy, the busy state is cleared with
+    /// a command list override if supported, or the device is reset.
+    fn recover(&self) -> Result<(), ()> {
+        let registers = self.registers;
+        registers.write(PORT_CMD, registers.read(PORT_CMD) & !CMD_ST);
+        registers.wait(PORT_CMD, CMD_CR, 0, PORT_TIMEOUT)?;
+        registers.write(PORT_SERR, u32::MAX);
+        registers.write(PORT_IS, u32::MAX);
+
+        if registers.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+            let cleared = self.supports_clo && {
+                registers.write(PORT_CMD, registers.read(PORT_CMD) | CMD_CLO);
+                registers.wait(PORT_CMD, CMD_CLO, 0, PORT_TIMEOUT).is_ok()
+            };
+            if !cleared {
+                self.stop()?;
+                self.reset_device()?;
+            }
+        }
+        self.start()
+    }
+
+    /// Issues a command in slot 0 and waits for it to complete.
+    fn issue(&self, command: &Command) -> Result<(), Error> {
+        let registers = self.registers;
+        registers
+            .wait(PORT_TFD, TFD_BSY | TFD_DRQ, 0, COMMAND_TIMEOUT)
+            .map_err(|_| Error::Io)?;
+
+        // The command FIS
+        let table = unsafe { frame_mut(self.command_table) };
+        table[..PRDT_OFFSET as usize].copy_from_slice(&[0; PRDT_OFFSET as usize]);
+        let lba = command.lba.to_le_bytes();
+        let count = command.count.to_le_bytes();
+        table[0] = FIS_TYPE_REG_H2D;
+        // The FIS holds a command rather than a device control update
+        table[1] = 0x80;
+        table[2] = command.ata_command;
+        table[4..7].copy_from_slice(&lba[0..3]);
+        // LBA addressing, with bits 24 to 27 of 28-bit addresses
+        let extended = matches!(
+            command.ata_command,
+            ATA_READ_DMA_EXT | ATA_WRITE_DMA_EXT | ATA_FLUSH_CACHE_EXT
+        );
+        table[7] = if extended {
+            1 << 6
+        } else {
+            1 << 6 | (lba[3] & 0xf)
+        };
+        table[8..11].copy_from_slice(&lba[3..6]);
+        table[12..14].copy_from_slice(&count);
+
+        // The physical region descriptor table
+        let mut entries = 0;
+        let mut remaining = command.length;
+        for &frame in &self.segments {
+            if remaining == 0 {
+                break;
+            }
+            let part = core::cmp::min(remaining, FRAME_SIZE);
+            let offset = PRDT_OFFSET as usize + entries * 16;
+            table[offset..offset + 8].copy_from_slice(&frame.to_le_bytes());
+            table[offset + 8..offset + 12].copy_from_slice(&[0; 4]);
+            // The byte count is stored minus one
+            table[offset + 12..offset + 16].copy_from_slice(&(part as u32 - 1).to_le_bytes());
+            entries += 1;
+            remaining -= part;
+        }
+
+        // The command header of slot 0
+        let header = unsafe { frame_mut(self.command_list) };
+        let mut flags = 5 | (entries as u32) << 16;
+        if command.write {
+            flags |= 1 << 6;
+        }
+        header[0..4].copy_from_slice(&flags.to_le_bytes());
+        header[4..8].copy_from_slice(&[0; 4]);
+        header[8..16].copy_from_slice(&self.command_table.to_le_bytes());
+
+        registers.write(PORT_IS, u32::MAX);
+        registers.write(PORT_CI, 1);
+        for _ in 0..COMMAND_TIMEOUT {
+            if registers.read(PORT_IS) & IS_TFES != 0 {
+                break;
+            }
+            if registers.read(PORT_CI) & 1 == 0 {
+                return if registers.read(PORT_TFD) & TFD_ERR != 0 {
+                    Err(Error::Io)
+                } else {
+                    Ok(())
+                };
+            }
+            delay(1);
+        }
+
+        let _ = self.recover();
+        Err(Error::Io)
+    }
+
+    /// Issues a command, retrying it once if it fails.
+    fn issue_with_retry(&self, command: &Command) -> Result<(), Error> {
+        self.issue(command).or_else(|_| self.issue(command))
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        unsafe {
+            for &frame in [self.command_list, self.command_table]
+                .iter()
+                .chain(&self.segments)
+            {
+                if frame != 0 {
+                    memory::free_frame(frame);
+                }
+            }
+        }
+    }
+}
+
+/// A SATA disk attached to an AHCI port.
+pub struct AhciDisk {
+    port: Mutex<Port>,
+    sector_size: usize,
+    sector_count: u64,
+    /// Whether the disk supports 48-bit addresses.
+    lba48: bool,
+}
+
+impl AhciDisk {
+    /// Identifies the device on a port, and returns it as a disk along with its
+    /// model.
+    fn identify(port: Port) -> Result<(Self, String), &'static str> {
+        port.issue(&Command {
+            ata_command: ATA_IDENTIFY_DEVICE,
+            lba: 0,
+            count: 0,
+            length: 512,
+            write: false,
+        })
+        .map_err(|_| "IDENTIFY DEVICE failed")?;
+
+        let data = unsafe { frame_mut(port.segments[0]) };
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sector_count = if lba48 {
+            (0..4).fold(0, |count, i| count | (word(100 + i) as u64) << (16 * i))
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+        // The logical sector size is given in words if it's valid and larger
+        // than 512 bytes
+        let sector_info = word(106);
+        let sector_size = if sector_info & 0xc000 == 0x4000 && sector_info & (1 << 12) != 0 {
+            (word(117) as usize | (word(118) as usize) << 16) * 2
+        } else {
+            512
+        };
+        if !sector_size.is_power_of_two() || sector_size > FRAME_SIZE || sector_count == 0 {
+            return Err("unsupported sector size");
+        }
+
+        // The model is stored with the bytes of each word swapped
+        let model: String = (27..47)
+            .flat_map(|index| {
+                let [low, high] = word(index).to_le_bytes();
+                [high, low].to_vec()
+            })
+            .map(|byte| byte as char)
+            .collect();
+
+        let disk = Self {
+            port: Mutex::new(port),
+            sector_size,
+            sector_count,
+            lba48,
+        };
+        Ok((disk, model.trim().into()))
+    }
+
+    /// Returns the number of sectors transferred by a single command.
+    fn sectors_per_command(&self) -> usize {
+        let sectors = MAX_SEGMENTS * FRAME_SIZE / self.sector_size;
+        // 28-bit commands transfer up to 256 sectors, and 48-bit ones up to
+        // 65536, which both write 0 as the count
+        core::cmp::min(sectors, if self.lba48 { 65536 } else { 256 })
+    }
+
+    /// Returns a read or write command for the given sectors.
+    fn transfer(&self, sector: u64, length: usize, write: bool) -> Command {
+        let ata_command = match (self.lba48, write) {
+            (true, false) => ATA_READ_DMA_EXT,
+            (true, true) => ATA_WRITE_DMA_EXT,
+            (false, false) => ATA_READ_DMA,
+            (false, true) => ATA_WRITE_DMA,
+        };
+        Command {
+            ata_command,
+            lba: sector,
+            count: (length / self.sector_size) as u16,
+            length,
+            write,
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check_access(self, sector, buffer.len())?;
+        let port = self.port.lock();
+        let chunk_sectors = self.sectors_per_command();
+        let chunk_size = chunk_sectors * self.sector_size;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let chunk_sector = sector + (i * chunk_sectors) as u64;
+            port.issue_with_retry(&self.transfer(chunk_sector, chunk.len(), false))?;
+            for (part, &frame) in chunk.chunks_mut(FRAME_SIZE).zip(&port.segments) {
+                let contents = unsafe { frame_mut(frame) };
+                part.copy_from_slice(&contents[..part.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        block::check_access(self, sector, buffer.len())?;
+        let port = self.port.lock();
+        let chunk_sectors = self.sectors_per_command();
+        let chunk_size = chunk_sectors * self.sector_size;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            for (part, &frame) in chunk.chunks(FRAME_SIZE).zip(&port.segments) {
+                let contents = unsafe { frame_mut(frame) };
+                contents[..part.len()].copy_from_slice(part);
+            }
+            let chunk_sector = sector + (i * chunk_sectors) as u64;
+            port.issue_with_retry(&self.transfer(chunk_sector, chunk.len(), true))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let ata_command = if self.lba48 {
+            ATA_FLUSH_CACHE_EXT
+        } else {
+            ATA_FLUSH_CACHE
+        };
+        self.port.lock().issue_with_retry(&Command {
+            ata_command,
+            lba: 0,
+            count: 0,
+            length: 0,
+            write: false,
+        })
+    }
+}
+
+/// Takes ownership of the HBA from the firmware if needed, and enables AHCI
+/// mode.
+fn take_ownership(hba: Registers) {
+    if hba.read(HBA_CAP2) & CAP2_BOH != 0 {
+        hba.write(HBA_BOHC, hba.read(HBA_BOHC) | BOHC_OOS);
+        if hba.wait(HBA_BOHC, BOHC_BOS, 0, 25_000).is_err() {
+            println!("AHCI: firmware did not release the controller");
+        }
+    }
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AE);
+}
+
+/// Sets up the disk attached to a port, if any, and registers it.
+fn probe_port(hba: Registers, cap: u32, index: u64) -> Result<(), &'static str> {
+    let registers = Registers(hba.0 + 0x100 + index * 0x80);
+    let ssts = registers.read(PORT_SSTS);
+    // A device must be present with communication established, and active
+    let (detection, power) = (ssts & 0xf, (ssts >> 8) & 0xf);
+    if detection != 3 || power != 1 || registers.read(PORT_SIG) != SIGNATURE_ATA {
+        return Ok(());
+    }
+
+    let port = Port::new(registers, cap)?;
+    port.stop().map_err(|_| "port did not stop")?;
+    port.start().map_err(|_| "port did not start")?;
+    let (disk, model) = AhciDisk::identify(port)?;
+
+    let disk_index = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+    let name = if disk_index < 26 {
+        format!("sd{}", (b'a' + disk_index as u8) as char)
+    } else {
+        format!("sd{}", disk_index)
+    };
+    println!("AHCI port {}: {}", index, model);
+    block::register_disk(&name, Arc::new(disk));
+    Ok(())
+}
+
+/// Sets up an AHCI controller and the disks attached to its ports.
+fn probe(device: &Arc<Device>) -> Result<(), &'static str> {
+    let hba = match device.bars[ABAR] {
+        Some(Bar::Memory { address, .. }) => Registers(PHYS_MEM_OFFSET | address),
+        _ => return Err("no register BAR"),
+    };
+    device.enable(pci::COMMAND_MEMORY | pci::COMMAND_BUS_MASTER);
+    take_ownership(hba);
+
+    let cap = hba.read(HBA_CAP);
+    let implemented = hba.read(HBA_PI);
+    for index in 0..32 {
+        if implemented & (1 << index) != 0 {
+            if let Err(error) = probe_port(hba, cap, index) {
+                println!("AHCI port {}: {}", index, error);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,23 @@
+//! Drivers of devices found on the PCI bus.
+
+pub mod ahci;
+pub mod nvme;
+pub mod virtio;
+
+use crate::pci;
+use crate::time;
+use core::time::Duration;
+
+/// Registers the drivers, which are probed for the matching devices.
+pub fn init() {
+    pci::register_driver(&ahci::DRIVER);
+    pci::register_driver(&nvme::DRIVER);
+    pci::register_driver(&virtio::blk::DRIVER);
+    pci::register_driver(&virtio::net::DRIVER);
+}
+
+/// Busy-waits for at least the given number of microseconds, see
+/// [time::delay].
+pub fn delay(microseconds: usize) {
+    time::delay(Duration::from_micros(microseconds as u64));
+}
@@ -0,0 +1,683 @@
+//! The driver of NVMe controllers, which registers each active namespace as a
+//! disk named `nvme0n1`, `nvme0n2`, and so on.
+//!
+//! Besides the admin queue, a single pair of I/O queues is used, and commands
+//! are submitted one at a time on each. Data is transferred through frames
+//! owned by the driver, described to the controller with a PRP list when it
+//! spans more than two of them. Completion is signaled with an interrupt if
+//! the controller supports MSI-X, and polled otherwise.
+
+use super::delay;
+use crate::apic;
+use crate::block::{self, BlockDevice, Error};
+use crate::interrupts;
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use crate::pci::{self, msi, Bar, Device, Driver, Match};
+use crate::println;
+use crate::sync::mutex::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub static DRIVER: Driver = Driver {
+    name: "nvme",
+    matches: &[Match::Class {
+        class: 0x01,
+        subclass: 0x08,
+        prog_if: Some(0x02),
+    }],
+    probe,
+};
+
+/// The offsets of the controller registers.
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+const REG_DOORBELLS: u64 = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+/// The size of submission queue entries, 64 bytes as a power of two.
+const CC_IOSQES: u32 = 6 << 16;
+/// The size of completion queue entries, 16 bytes as a power of two.
+const CC_IOCQES: u32 = 4 << 20;
+
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+
+const FRAME_SIZE: usize = 4096;
+
+/// The largest number of frames transferred by a single command.
+const MAX_SEGMENTS: usize = 32;
+
+/// The number of entries of each queue, for which a submission queue fills a
+/// frame.
+const QUEUE_SIZE: u16 = (FRAME_SIZE / SUBMISSION_ENTRY_SIZE) as u16;
+
+/// The number of controllers found, which gives the name of the next one.
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the contents of a frame.
+///
+/// # Safety
+/// The frame must be owned by the caller, and not be accessed by the device
+/// while the slice exists.
+unsafe fn frame_mut<'a>(addr: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET | addr) as *mut u8, FRAME_SIZE)
+}
+
+/// Allocates a zeroed frame.
+fn allocate_zeroed() -> Result<u64, ()> {
+    let frame = memory::allocate_frame()?;
+    unsafe { frame_mut(frame).copy_from_slice(&[0; FRAME_SIZE]) };
+    Ok(frame)
+}
+
+/// The registers of the controller.
+#[derive(Copy, Clone)]
+struct Registers(u64);
+
+impl Registers {
+    fn read(self, offset: u64) -> u32 {
+        unsafe { core::ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(self, offset: u64, value: u32) {
+        unsafe { core::ptr::write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+
+    /// Reads a quad word register as two double words.
+    fn read_u64(self, offset: u64) -> u64 {
+        self.read(offset) as u64 | (self.read(offset + 4) as u64) << 32
+    }
+
+    /// Writes a quad word register as two double words, the low one first.
+    fn write_u64(self, offset: u64, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// A command, as the 16 double words of a submission queue entry. The
+/// command identifier is filled in when it's submitted.
+#[derive(Copy, Clone, Default)]
+struct Command([u32; 16]);
+
+impl Command {
+    fn new(opcode: u8, namespace: u32) -> Self {
+        let mut command = Self::default();
+        command.0[0] = opcode as u32;
+        command.0[1] = namespace;
+        command
+    }
+
+    /// Sets the data pointers, PRP1 and PRP2.
+    fn data(mut self, prp1: u64, prp2: u64) -> Self {
+        self.0[6] = prp1 as u32;
+        self.0[7] = (prp1 >> 32) as u32;
+        self.0[8] = prp2 as u32;
+        self.0[9] = (prp2 >> 32) as u32;
+        self
+    }
+
+    /// Sets a command specific double word, from 10 to 15.
+    fn dword(mut self, index: usize, value: u32) -> Self {
+        self.0[index] = value;
+        self
+    }
+}
+
+/// A submission queue along with the completion queue it completes to.
+struct QueuePair {
+    /// The frames of the submission and completion queues.
+    submission: u64,
+    completion: u64,
+    /// The virtual addresses of the doorbells.
+    submission_doorbell: u64,
+    completion_doorbell: u64,
+    submission_tail: u16,
+    completion_head: u16,
+    /// The phase the controller marks new completion entries with, which
+    /// flips every time the queue wraps around.
+    phase: bool,
+    next_id: u16,
+}
+
+impl QueuePair {
+    fn new(registers: Registers, doorbell_stride: u64, id: u16) -> Result<Self, ()> {
+        let submission = allocate_zeroed()?;
+        let completion = match allocate_zeroed() {
+            Ok(completion) => completion,
+            Err(()) => {
+                unsafe { memory::free_frame(submission) };
+                return Err(());
+            }
+        };
+        let doorbells = registers.0 + REG_DOORBELLS;
+        Ok(Self {
+            submission,
+            completion,
+            submission_doorbell: doorbells + 2 * id as u64 * doorbell_stride,
+            completion_doorbell: doorbells + (2 * id as u64 + 1) * doorbell_stride,
+            submission_tail: 0,
+            completion_head: 0,
+            phase: true,
+            next_id: 0,
+        })
+    }
+
+    /// Returns the double word of the completion queue entry at the head.
+    fn completion_dword(&self, index: usize) -> u32 {
+        let entry = (PHYS_MEM_OFFSET | self.completion)
+            + (self.completion_head as usize * COMPLETION_ENTRY_SIZE + index * 4) as u64;
+        unsafe { core::ptr::read_volatile(entry as *const u32) }
+    }
+
+    /// Returns whether the controller posted a new completion entry.
+    fn has_completion(&self) -> bool {
+        (self.completion_dword(3) & 1 << 16 != 0) == self.phase
+    }
+
+    /// Submits a command and returns its identifier.
+    fn submit(&mut self, mut command: Command) -> u16 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        command.0[0] = (command.0[0] & 0xffff) | (id as u32) << 16;
+
+        let entry = (PHYS_MEM_OFFSET | self.submission)
+            + (self.submission_tail as usize * SUBMISSION_ENTRY_SIZE) as u64;
+        for (i, &dword) in command.0.iter().enumerate() {
+            unsafe { core::ptr::write_volatile((entry + i as u64 * 4) as *mut u32, dword) };
+        }
+        self.submission_tail = (self.submission_tail + 1) % QUEUE_SIZE;
+        unsafe {
+            core::ptr::write_volatile(
+                self.submission_doorbell as *mut u32,
+                self.submission_tail as u32,
+            )
+        };
+        id
+    }
+
+    /// Pops the completion entry at the head, and returns the identifier of
+    /// the command, the command specific result, and the status.
+    fn pop_completion(&mut self) -> (u16, u32, u16) {
+        let result = self.completion_dword(0);
+        let dword3 = self.completion_dword(3);
+        self.completion_head += 1;
+        if self.completion_head == QUEUE_SIZE {
+            self.completion_head = 0;
+            self.phase = !self.phase;
+        }
+        unsafe {
+            core::ptr::write_volatile(
+                self.completion_doorbell as *mut u32,
+                self.completion_head as u32,
+            )
+        };
+        (dword3 as u16, result, (dword3 >> 17) as u16)
+    }
+}
+
+impl Drop for QueuePair {
+    fn drop(&mut self) {
+        unsafe {
+            memory::free_frame(self.submission);
+            memory::free_frame(self.completion);
+        }
+    }
+}
+
+/// The I/O queues, along with the frames data is transferred through.
+struct Io {
+    queues: QueuePair,
+    segments: Vec<u64>,
+    /// The frame holding the PRP list of transfers spanning more than two
+    /// segments.
+    prp_list: u64,
+}
+
+impl Drop for Io {
+    fn drop(&mut self) {
+        unsafe {
+            memory::free_frame(self.prp_list);
+            for &frame in &self.segments {
+                memory::free_frame(frame);
+            }
+        }
+    }
+}
+
+/// An NVMe controller.
+pub struct Controller {
+    registers: Registers,
+    admin: Mutex<QueuePair>,
+    io: Mutex<Option<Io>>,
+    /// Whether completion is signaled with an interrupt.
+    interrupts: bool,
+    /// The number of segments a single command may transfer.
+    max_segments: usize,
+    /// Whether the controller has a volatile write cache, which must be
+    /// flushed.
+    write_cache: bool,
+}
+
+impl Controller {
+    /// Waits for a command to complete, and returns its result.
+    fn wait(&self, queues: &mut QueuePair, id: u16) -> Result<u32, ()> {
+        loop {
+            let fatal = || self.registers.read(REG_CSTS) & CSTS_FATAL != 0;
+            if self.interrupts {
+                interrupts::wait_until(|| queues.has_completion() || fatal());
+            } else {
+                while !queues.has_completion() && !fatal() {
+                    core::hint::spin_loop();
+                }
+            }
+            if !queues.has_completion() {
+                // The controller failed, and will not complete the command
+                return Err(());
+            }
+            let (completed_id, result, status) = queues.pop_completion();
+            // Only one command is submitted at a time, so completions of
+            // other commands are not expected, and skipped
+            if completed_id == id {
+                return match status & 0x7ff {
+                    0 => Ok(result),
+                    _ => Err(()),
+                };
+            }
+        }
+    }
+
+    /// Submits an admin command and waits for it to complete.
+    fn admin(&self, command: Command) -> Result<u32, ()> {
+        let mut admin = self.admin.lock();
+        let id = admin.submit(command);
+        self.wait(&mut admin, id)
+    }
+
+    /// Identifies the controller or a namespace, returning the data in a
+    /// frame which the caller must free.
+    fn identify(&self, cns: u32, namespace: u32) -> Result<u64, &'static str> {
+        let frame = allocate_zeroed().map_err(|_| "could not allocate memory")?;
+        let command = Command::new(ADMIN_IDENTIFY, namespace)
+            .data(frame, 0)
+            .dword(10, cns);
+        match self.admin(command) {
+            Ok(_) => Ok(frame),
+            Err(_) => {
+                unsafe { memory::free_frame(frame) };
+                Err("IDENTIFY failed")
+            }
+        }
+    }
+
+    /// Creates the I/O queues and allocates the frames for transfers.
+    fn create_io_queues(&self, doorbell_stride: u64) -> Result<(), &'static str> {
+        let queues = QueuePair::new(self.registers, doorbell_stride, 1)
+            .map_err(|_| "could not allocate memory")?;
+        let prp_list = memory::allocate_frame().map_err(|_| "could not allocate memory")?;
+        let mut io = Io {
+            queues,
+            segments: Vec::with_capacity(self.max_segments),
+            prp_list,
+        };
+        for _ in 0..self.max_segments {
+            let frame = memory::allocate_frame().map_err(|_| "could not allocate memory")?;
+            io.segments.push(frame);
+        }
+
+        // A single queue of each kind, with values counting from 0
+        self.admin(
+            Command::new(ADMIN_SET_FEATURES, 0)
+                .dword(10, FEATURE_NUMBER_OF_QUEUES)
+                .dword(11, 0),
+        )
+        .map_err(|_| "could not set the number of queues")?;
+
+        // The queue is identified by 1, is physically contiguous, and
+        // signals the first MSI-X vector if interrupts are enabled
+        let size = (QUEUE_SIZE as u32 - 1) << 16;
+        let interrupts_enabled = if self.interrupts { 1 << 1 } else { 0 };
+        self.admin(
+            Command::new(ADMIN_CREATE_IO_CQ, 0)
+                .data(io.queues.completion, 0)
+                .dword(10, size | 1)
+                .dword(11, interrupts_enabled | 1),
+        )
+        .map_err(|_| "could not create the completion queue")?;
+        self.admin(
+            Command::new(ADMIN_CREATE_IO_SQ, 0)
+                .data(io.queues.submission, 0)
+                .dword(10, size | 1)
+                .dword(11, 1 << 16 | 1),
+        )
+        .map_err(|_| "could not create the submission queue")?;
+
+        *self.io.lock() = Some(io);
+        Ok(())
+    }
+
+    /// Submits an I/O command transferring the given number of bytes through
+    /// the segments, and waits for it to complete.
+    fn io(&self, io: &mut Io, command: Command, length: usize) -> Result<(), Error> {
+        let pages = ((length + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)) / FRAME_SIZE;
+        let (prp1, prp2) = match pages {
+            0 => (0, 0),
+            1 => (io.segments[0], 0),
+            2 => (io.segments[0], io.segments[1]),
+            _ => {
+                let list = unsafe { frame_mut(io.prp_list) };
+                for (i, &frame) in io.segments[1..pages].iter().enumerate() {
+                    list[i * 8..i * 8 + 8].copy_from_slice(&frame.to_le_bytes());
+                }
+                (io.segments[0], io.prp_list)
+            }
+        };
+        let id = io.queues.submit(command.data(prp1, prp2));
+        self.wait(&mut io.queues, id)
+            .map(|_| ())
+            .map_err(|_| Error::Io)
+    }
+}
+
+/// An active namespace of a controller.
+pub struct Namespace {
+    controller: Arc<Controller>,
+    id: u32,
+    sector_size: usize,
+    sector_count: u64,
+}
+
+impl Namespace {
+    /// Returns the number of sectors transferred by a single command.
+    fn sectors_per_command(&self) -> usize {
+        // The number of blocks of a command counts from 0 in 16 bits
+        core::cmp::min(
+            self.controller.max_segments * FRAME_SIZE / self.sector_size,
+            65536,
+        )
+    }
+
+    /// Returns a read or write command for the given sectors.
+    fn transfer(&self, opcode: u8, sector: u64, length: usize) -> Command {
+        let blocks = (length / self.sector_size) as u32;
+        Command::new(opcode, self.id)
+            .dword(10, sector as u32)
+            .dword(11, (sector >> 32) as u32)
+            .dword(12, blocks - 1)
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check_access(self, sector, buffer.len())?;
+        let mut io = self.controller.io.lock();
+        let io = io.as_mut().ok_or(Error::Io)?;
+        let chunk_sectors = self.sectors_per_command();
+        let chunk_size = chunk_sectors * self.sector_size;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let chunk_sector = sector + (i * chunk_sectors) as u64;
+            let command = self.transfer(IO_READ, chunk_sector, chunk.len());
+            self.controller.io(io, command, chunk.len())?;
+            for (part, &frame) in chunk.chunks_mut(FRAME_SIZE).zip(&io.segments) {
+                let contents = unsafe { frame_mut(frame) };
+                part.copy_from_slice(&contents[..part.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        block::check_access(self, sector, buffer.len())?;
+        let mut io = self.controller.io.lock();
+        let io = io.as_mut().ok_or(Error::Io)?;
+        let chunk_sectors = self.sectors_per_command();
+        let chunk_size = chunk_sectors * self.sector_size;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            for (part, &frame) in chunk.chunks(FRAME_SIZE).zip(&io.segments) {
+                let contents = unsafe { frame_mut(frame) };
+                contents[..part.len()].copy_from_slice(part);
+            }
+            let chunk_sector = sector + (i * chunk_sectors) as u64;
+            let command = self.transfer(IO_WRITE, chunk_sector, chunk.len());
+            self.controller.io(io, command, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        if !self.controller.write_cache {
+            return Ok(());
+        }
+        let mut io = self.controller.io.lock();
+        let io = io.as_mut().ok_or(Error::Io)?;
+        self.controller.io(io, Command::new(IO_FLUSH, self.id), 0)
+    }
+}
+
+/// Disables the controller, waiting for it to stop for about the given number
+/// of microseconds.
+fn disable(registers: Registers, timeout: usize) -> Result<(), &'static str> {
+    registers.write(REG_CC, registers.read(REG_CC) & !CC_ENABLE);
+    for _ in 0..timeout {
+        if registers.read(REG_CSTS) & CSTS_READY == 0 {
+            return Ok(());
+        }
+        delay(1);
+    }
+    Err("controller did not stop")
+}
+
+/// Enables the controller, waiting for it to become ready for about the given
+/// number of microseconds.
+fn enable(registers: Registers, timeout: usize) -> Result<(), &'static str> {
+    registers.write(REG_CC, CC_IOSQES | CC_IOCQES | CC_ENABLE);
+    for _ in 0..timeout {
+        let status = registers.read(REG_CSTS);
+        if status & CSTS_FATAL != 0 {
+            return Err("controller failed");
+        }
+        if status & CSTS_READY != 0 {
+            return Ok(());
+        }
+        delay(1);
+    }
+    Err("controller did not start")
+}
+
+/// Sets up MSI-X with the first vector, used by every queue. Returns the
+/// vector if interrupts are enabled, since controllers without MSI-X must be
+/// polled.
+fn enable_interrupts(device: &Device) -> Option<u8> {
+    let msi_x = device.msi_x?;
+    // Waiting commands are woken by the interrupt itself, and check the queue
+    let vector = interrupts::allocate_vector(|| {})?;
+    let (address, data) = msi::message(apic::id(), vector);
+    if msi_x.enable().is_err() || msi_x.set_vector(0, address, data).is_err() {
+        msi_x.disable();
+        interrupts::free_vector(vector);
+        return None;
+    }
+    Some(vector)
+}
+
+/// Stops a device from signaling interrupts with MSI-X, and frees the vector
+/// set up by [enable_interrupts].
+fn disable_interrupts(device: &Device, vector: u8) {
+    if let Some(msi_x) = device.msi_x {
+        msi_x.disable();
+    }
+    interrupts::free_vector(vector);
+}
+
+/// Returns a string of the identify data, which is padded with spaces.
+fn identify_string(bytes: &[u8]) -> String {
+    let string: String = bytes.iter().map(|&byte| byte as char).collect();
+    string.trim().into()
+}
+
+/// Identifies an active namespace and registers it as a disk.
+fn register_namespace(
+    controller: &Arc<Controller>,
+    controller_index: usize,
+    id: u32,
+) -> Result<(), &'static str> {
+    let frame = controller.identify(IDENTIFY_NAMESPACE, id)?;
+    let data = unsafe { frame_mut(frame) };
+    let sector_count = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    let format = (data[26] & 0xf) as usize;
+    let lba_format =
+        u32::from_le_bytes(data[128 + format * 4..132 + format * 4].try_into().unwrap());
+    unsafe { memory::free_frame(frame) };
+
+    let sector_size = 1usize << ((lba_format >> 16) & 0xff);
+    if sector_count == 0 {
+        return Ok(());
+    }
+    if !(512..=FRAME_SIZE).contains(&sector_size) || lba_format & 0xffff != 0 {
+        return Err("unsupported block format");
+    }
+    let namespace = Namespace {
+        controller: controller.clone(),
+        id,
+        sector_size,
+        sector_count,
+    };
+    let name = format!("nvme{}n{}", controller_index, id);
+    block::register_disk(&name, Arc::new(namespace));
+    Ok(())
+}
+
+/// Returns the identifiers of the active namespaces, from the list of active
+/// namespaces if the controller supports it, or by trying every namespace
+/// otherwise.
+fn active_namespaces(controller: &Controller, count: u32) -> Vec<u32> {
+    let version = controller.registers.read(REG_VS);
+    if version >= 0x0001_0100 {
+        if let Ok(frame) = controller.identify(IDENTIFY_ACTIVE_NAMESPACES, 0) {
+            let data = unsafe { frame_mut(frame) };
+            let ids = data
+                .chunks_exact(4)
+                .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+                .take_while(|&id| id != 0)
+                .collect();
+            unsafe { memory::free_frame(frame) };
+            return ids;
+        }
+    }
+    (1..=count).collect()
+}
+
+/// Identifies an enabled controller and creates the I/O queues. Returns the
+/// model and the number of namespaces.
+fn setup(controller: &mut Controller, doorbell_stride: u64) -> Result<(String, u32), &'static str> {
+    let frame = controller.identify(IDENTIFY_CONTROLLER, 0)?;
+    let data = unsafe { frame_mut(frame) };
+    let model = identify_string(&data[24..64]);
+    // The maximum transfer size is a power of two in pages, or 0 if unlimited
+    let mdts = data[77];
+    let namespace_count = u32::from_le_bytes(data[516..520].try_into().unwrap());
+    controller.write_cache = data[525] & 1 != 0;
+    unsafe { memory::free_frame(frame) };
+    if mdts != 0 && mdts < 6 {
+        controller.max_segments = 1 << mdts;
+    }
+
+    controller.create_io_queues(doorbell_stride)?;
+    Ok((model, namespace_count))
+}
+
+/// Sets up an NVMe controller and registers its namespaces.
+fn probe(device: &Arc<Device>) -> Result<(), &'static str> {
+    let registers = match device.bars[0] {
+        Some(Bar::Memory { address, .. }) => Registers(PHYS_MEM_OFFSET | address),
+        _ => return Err("no register BAR"),
+    };
+    device.enable(pci::COMMAND_MEMORY | pci::COMMAND_BUS_MASTER);
+
+    let cap = registers.read_u64(REG_CAP);
+    // The timeout for enabling and disabling is given in units of 500 ms
+    let timeout = ((cap >> 24) & 0xff) as usize * 500_000;
+    let doorbell_stride = 4 << ((cap >> 32) & 0xf);
+    let max_entries = (cap & 0xffff) as u32 + 1;
+    if (cap >> 48) & 0xf != 0 {
+        return Err("pages of 4 KiB are not supported");
+    }
+    if max_entries < QUEUE_SIZE as u32 {
+        return Err("queues are too small");
+    }
+
+    disable(registers, timeout)?;
+    let admin =
+        QueuePair::new(registers, doorbell_stride, 0).map_err(|_| "could not allocate memory")?;
+    let size = QUEUE_SIZE as u32 - 1;
+    registers.write(REG_AQA, size << 16 | size);
+    registers.write_u64(REG_ASQ, admin.submission);
+    registers.write_u64(REG_ACQ, admin.completion);
+    enable(registers, timeout)?;
+
+    let vector = enable_interrupts(device);
+    let mut controller = Controller {
+        registers,
+        admin: Mutex::new(admin),
+        io: Mutex::new(None),
+        interrupts: vector.is_some(),
+        max_segments: MAX_SEGMENTS,
+        write_cache: false,
+    };
+
+    let (model, namespace_count) = match setup(&mut controller, doorbell_stride) {
+        Ok(identity) => identity,
+        Err(error) => {
+            // Stop the controller from using the admin queue before it's freed
+            let _ = disable(registers, timeout);
+            if let Some(vector) = vector {
+                disable_interrupts(device, vector);
+            }
+            return Err(error);
+        }
+    };
+
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+    println!("NVMe controller nvme{}: {}", index, model);
+    let controller = Arc::new(controller);
+    for id in active_namespaces(&controller, namespace_count) {
+        if let Err(error) = register_namespace(&controller, index, id) {
+            println!("NVMe namespace nvme{}n{}: {}", index, id, error);
+        }
+    }
+    Ok(())
+}
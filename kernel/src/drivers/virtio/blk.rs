@@ -0,0 +1,323 @@
+//! The driver of virtio block devices, which registers each as a disk named
+//! `vda`, `vdb`, and so on.
+//!
+//! Requests are handled one at a time. Data is transferred through frames
+//! owned by the driver, so the buffers of callers need not be physically
+//! contiguous, and larger accesses are split into several requests. Completion
+//! is signaled with an interrupt if the device supports MSI-X, and polled
+//! otherwise.
+
+use super::queue::{Buffer, Virtqueue, MAX_SIZE};
+use super::{Transport, VENDOR_ID};
+use crate::block::{self, BlockDevice, Error};
+use crate::interrupts;
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use crate::pci::{Device, Driver, Match};
+use crate::sync::mutex::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The device tells the maximum number of segments in a request.
+const F_SEG_MAX: u64 = 1 << 2;
+/// The device is read-only.
+const F_RO: u64 = 1 << 5;
+/// The device supports flush requests.
+const F_FLUSH: u64 = 1 << 9;
+
+/// The offsets of fields in the device configuration.
+const CONFIG_CAPACITY: u64 = 0;
+const CONFIG_SEG_MAX: u64 = 12;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Requests always address sectors of 512 bytes.
+const SECTOR_SIZE: usize = 512;
+
+const FRAME_SIZE: usize = 4096;
+
+/// The largest number of frames transferred by a single request.
+const MAX_SEGMENTS: usize = 32;
+
+/// The offset of the status byte in the frame of the request header.
+const STATUS_OFFSET: u64 = 16;
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    matches: &[
+        // The transitional and the modern device
+        Match::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1001,
+        },
+        Match::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1042,
+        },
+    ],
+    probe,
+};
+
+/// The number of disks found, which gives the name of the next one.
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the contents of a frame.
+///
+/// # Safety
+/// The frame must be owned by the caller, and not be accessed by the device
+/// while the slice exists.
+unsafe fn frame_mut<'a>(addr: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET | addr) as *mut u8, FRAME_SIZE)
+}
+
+/// The queue and the frames used by requests.
+struct Requests {
+    queue: Virtqueue,
+    /// The frame holding the request header, followed by the status byte.
+    header: u64,
+    /// The frames data is transferred through.
+    segments: Vec<u64>,
+}
+
+impl Requests {
+    /// Allocates the frames for requests with up to the given number of
+    /// segments. The queue is handed back if they cannot be allocated, as the
+    /// device must be stopped before it's freed.
+    fn new(queue: Virtqueue, max_segments: usize) -> Result<Self, Virtqueue> {
+        let mut frames = Vec::with_capacity(max_segments + 1);
+        for _ in 0..=max_segments {
+            match memory::allocate_frame() {
+                Ok(frame) => frames.push(frame),
+                Err(()) => {
+                    for &frame in &frames {
+                        unsafe { memory::free_frame(frame) };
+                    }
+                    return Err(queue);
+                }
+            }
+        }
+        let header = frames.pop().unwrap();
+        Ok(Self {
+            queue,
+            header,
+            segments: frames,
+        })
+    }
+}
+
+impl Drop for Requests {
+    fn drop(&mut self) {
+        unsafe {
+            memory::free_frame(self.header);
+            for &frame in &self.segments {
+                memory::free_frame(frame);
+            }
+        }
+    }
+}
+
+/// A virtio block device.
+pub struct VirtioBlk {
+    transport: Transport,
+    requests: Mutex<Requests>,
+    sector_count: u64,
+    read_only: bool,
+    supports_flush: bool,
+    /// Whether completion is signaled with an interrupt.
+    interrupts: bool,
+    /// The vector of the interrupts of the device, if they're enabled.
+    vector: Option<u8>,
+}
+
+impl VirtioBlk {
+    /// Submits a request transferring the given number of bytes through the
+    /// segments, and waits for it to complete.
+    fn submit(
+        &self,
+        requests: &mut Requests,
+        kind: u32,
+        sector: u64,
+        length: usize,
+    ) -> Result<(), Error> {
+        let header = PHYS_MEM_OFFSET | requests.header;
+        unsafe {
+            core::ptr::write_volatile(header as *mut u32, kind);
+            core::ptr::write_volatile((header + 4) as *mut u32, 0);
+            core::ptr::write_volatile((header + 8) as *mut u64, sector);
+            core::ptr::write_volatile((header + STATUS_OFFSET) as *mut u8, u8::MAX);
+        }
+
+        let mut buffers = Vec::with_capacity(requests.segments.len() + 2);
+        buffers.push(Buffer {
+            addr: requests.header,
+            length: 16,
+            device_writable: false,
+        });
+        let mut remaining = length;
+        for &frame in &requests.segments {
+            if remaining == 0 {
+                break;
+            }
+            let part = core::cmp::min(remaining, FRAME_SIZE);
+            buffers.push(Buffer {
+                addr: frame,
+                length: part as u32,
+                device_writable: kind == REQUEST_IN,
+            });
+            remaining -= part;
+        }
+        buffers.push(Buffer {
+            addr: requests.header + STATUS_OFFSET,
+            length: 1,
+            device_writable: true,
+        });
+
+        requests.queue.push(&buffers).map_err(|_| Error::Io)?;
+        requests.queue.notify();
+        if self.interrupts {
+            let queue = &requests.queue;
+            interrupts::wait_until(|| queue.has_used());
+        } else {
+            while !requests.queue.has_used() {
+                core::hint::spin_loop();
+            }
+        }
+        // Only one request is submitted at a time, so this is the one
+        requests.queue.pop_used();
+
+        match unsafe { core::ptr::read_volatile((header + STATUS_OFFSET) as *const u8) } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(Error::NotSupported),
+            _ => Err(Error::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check_access(self, sector, buffer.len())?;
+        let mut requests = self.requests.lock();
+        let chunk_size = requests.segments.len() * FRAME_SIZE;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let chunk_sector = sector + (i * chunk_size / SECTOR_SIZE) as u64;
+            self.submit(&mut requests, REQUEST_IN, chunk_sector, chunk.len())?;
+            for (part, &frame) in chunk.chunks_mut(FRAME_SIZE).zip(&requests.segments) {
+                let contents = unsafe { frame_mut(frame) };
+                part.copy_from_slice(&contents[..part.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        block::check_access(self, sector, buffer.len())?;
+        if self.read_only {
+            return Err(Error::NotSupported);
+        }
+        let mut requests = self.requests.lock();
+        let chunk_size = requests.segments.len() * FRAME_SIZE;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            for (part, &frame) in chunk.chunks(FRAME_SIZE).zip(&requests.segments) {
+                let contents = unsafe { frame_mut(frame) };
+                contents[..part.len()].copy_from_slice(part);
+            }
+            let chunk_sector = sector + (i * chunk_size / SECTOR_SIZE) as u64;
+            self.submit(&mut requests, REQUEST_OUT, chunk_sector, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        if !self.supports_flush {
+            return Ok(());
+        }
+        let mut requests = self.requests.lock();
+        self.submit(&mut requests, REQUEST_FLUSH, 0, 0)
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        // Stop the device from using the queue before its frames are freed
+        self.transport.reset();
+        if let Some(vector) = self.vector {
+            self.transport.disable_interrupts(vector);
+        }
+    }
+}
+
+/// Sets up a virtio block device and registers it as a disk.
+fn probe(device: &Arc<Device>) -> Result<(), &'static str> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(F_SEG_MAX | F_RO | F_FLUSH)?;
+    if !transport.has_config() {
+        transport.fail();
+        return Err("no device configuration");
+    }
+
+    // Waiting requests are woken by the interrupt itself, and check the queue
+    let vector = transport.enable_interrupts(|| {});
+    let (queue, interrupts) = match transport.setup_queue(0, MAX_SIZE, vector.is_some()) {
+        Ok(queue) => queue,
+        Err(error) => {
+            transport.abort(vector);
+            return Err(error);
+        }
+    };
+
+    // A request takes a descriptor for each segment, and one each for the
+    // header and the status
+    let mut max_segments = core::cmp::min(MAX_SEGMENTS, (queue.size() as usize).saturating_sub(2));
+    if features & F_SEG_MAX != 0 {
+        let seg_max = transport.config_u32(CONFIG_SEG_MAX) as usize;
+        if seg_max > 0 {
+            max_segments = core::cmp::min(max_segments, seg_max);
+        }
+    }
+    if max_segments == 0 {
+        transport.abort(vector);
+        return Err("queue is too small");
+    }
+    let requests = match Requests::new(queue, max_segments) {
+        Ok(requests) => requests,
+        Err(queue) => {
+            transport.abort(vector);
+            drop(queue);
+            return Err("could not allocate memory for requests");
+        }
+    };
+
+    let sector_count = transport.config_u64(CONFIG_CAPACITY);
+    transport.driver_ok();
+
+    let disk = VirtioBlk {
+        transport,
+        requests: Mutex::new(requests),
+        sector_count,
+        read_only: features & F_RO != 0,
+        supports_flush: features & F_FLUSH != 0,
+        interrupts,
+        vector,
+    };
+    let index = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+    let name = if index < 26 {
+        format!("vd{}", (b'a' + index as u8) as char)
+    } else {
+        format!("vd{}", index)
+    };
+    block::register_disk(&name, Arc::new(disk));
+    Ok(())
+}
@@ -0,0 +1,320 @@
+//! Virtio 1.0 devices over PCI.
+//!
+//! The [Transport] finds the configuration structures of a device through its
+//! vendor specific PCI capabilities, negotiates features, and sets up
+//! [Virtqueue]s to exchange buffers with the device.
+
+pub mod blk;
+pub mod net;
+pub mod queue;
+
+use crate::apic;
+use crate::interrupts;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pci::{self, msi, Bar, Device};
+use alloc::sync::Arc;
+use queue::Virtqueue;
+
+/// The vendor identifier of virtio devices.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// The feature telling the device complies with virtio 1.0, which is required.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The identifier of vendor specific PCI capabilities.
+const CAPABILITY_VENDOR: u8 = 0x09;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+/// The offsets of the registers in the common configuration structure.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// The MSI-X entry value telling no interrupt is signaled.
+const NO_VECTOR: u16 = 0xffff;
+
+/// Reads a register at a virtual address.
+///
+/// # Safety
+/// The address must be of a mapped register of the given size.
+unsafe fn read<T: Copy>(addr: u64) -> T {
+    core::ptr::read_volatile(addr as *const T)
+}
+
+/// Writes a register at a virtual address.
+///
+/// # Safety
+/// The address must be of a mapped register of the given size.
+unsafe fn write<T: Copy>(addr: u64, value: T) {
+    core::ptr::write_volatile(addr as *mut T, value)
+}
+
+/// Writes a quad word register as two double words, since the device may not
+/// support larger accesses.
+///
+/// # Safety
+/// The address must be of a mapped quad word register.
+unsafe fn write_u64(addr: u64, value: u64) {
+    write(addr, value as u32);
+    write(addr + 4, (value >> 32) as u32);
+}
+
+/// The PCI transport of a virtio device.
+pub struct Transport {
+    device: Arc<Device>,
+    /// The virtual addresses of the configuration structures.
+    common: u64,
+    notify: u64,
+    device_config: u64,
+    /// Multiplied with the notify offset of a queue to get the offset of its
+    /// notification register.
+    notify_multiplier: u32,
+}
+
+impl Transport {
+    /// Finds the configuration structures of a device, enables it on the bus,
+    /// and resets it.
+    pub fn new(device: &Arc<Device>) -> Result<Self, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut device_config = None;
+        let mut notify_multiplier = 0;
+
+        for &(id, offset) in &device.capabilities {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let address = device.address;
+            let cfg_type = pci::config::read_u8(address, offset + 3);
+            let bar = pci::config::read_u8(address, offset + 4) as usize;
+            let bar_offset = pci::config::read_u32(address, offset + 8) as u64;
+            let structure = match device.bars.get(bar) {
+                Some(Some(Bar::Memory { address, .. })) => PHYS_MEM_OFFSET | (address + bar_offset),
+                _ => continue,
+            };
+            // The first capability of each type is the preferred one
+            match cfg_type {
+                CFG_TYPE_COMMON if common.is_none() => common = Some(structure),
+                CFG_TYPE_NOTIFY if notify.is_none() => {
+                    notify = Some(structure);
+                    notify_multiplier = pci::config::read_u32(address, offset + 16);
+                }
+                CFG_TYPE_DEVICE if device_config.is_none() => device_config = Some(structure),
+                _ => {}
+            }
+        }
+
+        let transport = match (common, notify) {
+            (Some(common), Some(notify)) => Self {
+                device: device.clone(),
+                common,
+                notify,
+                device_config: device_config.unwrap_or(0),
+                notify_multiplier,
+            },
+            _ => return Err("no virtio 1.0 capabilities"),
+        };
+        device.enable(pci::COMMAND_MEMORY | pci::COMMAND_BUS_MASTER);
+        transport.reset();
+        Ok(transport)
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { read(self.common + COMMON_DEVICE_STATUS) }
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { write(self.common + COMMON_DEVICE_STATUS, status) }
+    }
+
+    /// Resets the device, waiting for the reset to complete.
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tells the device the driver failed to set it up.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Negotiates the features supported by both the device and the driver,
+    /// which must include [F_VERSION_1], and returns them.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, &'static str> {
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut offered = 0;
+        for select in 0..2u32 {
+            unsafe {
+                write(self.common + COMMON_DEVICE_FEATURE_SELECT, select);
+                offered |=
+                    (read::<u32>(self.common + COMMON_DEVICE_FEATURE) as u64) << (32 * select);
+            }
+        }
+        let features = offered & (supported | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("device does not support virtio 1.0");
+        }
+        for select in 0..2u32 {
+            unsafe {
+                write(self.common + COMMON_DRIVER_FEATURE_SELECT, select);
+                write(
+                    self.common + COMMON_DRIVER_FEATURE,
+                    (features >> (32 * select)) as u32,
+                );
+            }
+        }
+
+        self.set_status(self.status() | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err("device rejected the features");
+        }
+        Ok(features)
+    }
+
+    /// Sets up MSI-X for the device, with a single vector used by every queue
+    /// which calls the handler. Returns the vector if interrupts are enabled,
+    /// since devices without MSI-X must be polled.
+    pub fn enable_interrupts(&self, handler: impl Fn() + Send + Sync + 'static) -> Option<u8> {
+        let msi_x = self.device.msi_x?;
+        let vector = interrupts::allocate_vector(handler)?;
+        let (address, data) = msi::message(apic::id(), vector);
+        if msi_x.enable().is_err() || msi_x.set_vector(0, address, data).is_err() {
+            msi_x.disable();
+            interrupts::free_vector(vector);
+            return None;
+        }
+        // Configuration changes are not handled
+        unsafe { write(self.common + COMMON_MSIX_CONFIG, NO_VECTOR) };
+        Some(vector)
+    }
+
+    /// Stops the device from signaling interrupts with MSI-X, and frees the
+    /// vector set up by [enable_interrupts](Self::enable_interrupts).
+    pub fn disable_interrupts(&self, vector: u8) {
+        if let Some(msi_x) = self.device.msi_x {
+            msi_x.disable();
+        }
+        interrupts::free_vector(vector);
+    }
+
+    /// Gives up on setting up the device, resetting it so that it no longer
+    /// uses its queues, and disabling the interrupts set up with the vector.
+    pub fn abort(&self, vector: Option<u8>) {
+        self.fail();
+        self.reset();
+        if let Some(vector) = vector {
+            self.disable_interrupts(vector);
+        }
+    }
+
+    /// Sets up a queue with up to the given number of descriptors. If
+    /// `interrupts` is true, the queue signals the vector set up by
+    /// [enable_interrupts](Self::enable_interrupts).
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        max_size: u16,
+        interrupts: bool,
+    ) -> Result<(Virtqueue, bool), &'static str> {
+        let common = self.common;
+        unsafe {
+            write(common + COMMON_QUEUE_SELECT, index);
+            let device_size: u16 = read(common + COMMON_QUEUE_SIZE);
+            if device_size == 0 {
+                return Err("queue does not exist");
+            }
+            // Queue sizes are powers of two, so the smaller one is too
+            let size = core::cmp::min(device_size, max_size);
+            write(common + COMMON_QUEUE_SIZE, size);
+
+            let notify_offset: u16 = read(common + COMMON_QUEUE_NOTIFY_OFF);
+            let notify = self.notify + notify_offset as u64 * self.notify_multiplier as u64;
+            let queue =
+                Virtqueue::new(index, size, notify).map_err(|_| "could not allocate queue")?;
+
+            // The device may not be able to use the vector, and tells so by
+            // reading back no vector
+            let mut interrupts = interrupts;
+            if interrupts {
+                write(common + COMMON_QUEUE_MSIX_VECTOR, 0u16);
+                interrupts = read::<u16>(common + COMMON_QUEUE_MSIX_VECTOR) != NO_VECTOR;
+            }
+
+            let (descriptors, driver, device) = queue.addresses();
+            write_u64(common + COMMON_QUEUE_DESC, descriptors);
+            write_u64(common + COMMON_QUEUE_DRIVER, driver);
+            write_u64(common + COMMON_QUEUE_DEVICE, device);
+            write(common + COMMON_QUEUE_ENABLE, 1u16);
+            Ok((queue, interrupts))
+        }
+    }
+
+    /// Tells the device the driver is ready, after setting up the queues.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Reads registers of the device specific configuration. Since the
+    /// configuration may change between reads, they're read again until the
+    /// generation is the same before and after.
+    fn read_config<T>(&self, read_registers: impl Fn(u64) -> T) -> T {
+        assert!(self.device_config != 0, "device has no configuration");
+        loop {
+            let generation: u8 = unsafe { read(self.common + COMMON_CONFIG_GENERATION) };
+            let value = read_registers(self.device_config);
+            if generation == unsafe { read::<u8>(self.common + COMMON_CONFIG_GENERATION) } {
+                return value;
+            }
+        }
+    }
+
+    /// Reads a word of the device specific configuration.
+    pub fn config_u16(&self, offset: u64) -> u16 {
+        self.read_config(|config| unsafe { read(config + offset) })
+    }
+
+    /// Reads a double word of the device specific configuration.
+    pub fn config_u32(&self, offset: u64) -> u32 {
+        self.read_config(|config| unsafe { read(config + offset) })
+    }
+
+    /// Reads a quad word of the device specific configuration, as two double
+    /// words.
+    pub fn config_u64(&self, offset: u64) -> u64 {
+        self.read_config(|config| unsafe {
+            read::<u32>(config + offset) as u64 | (read::<u32>(config + offset + 4) as u64) << 32
+        })
+    }
+
+    /// Returns whether the device has device specific configuration.
+    pub fn has_config(&self) -> bool {
+        self.device_config != 0
+    }
+}
@@ -0,0 +1,300 @@
+//! The driver of virtio network devices, which registers each as an interface
+//! named `eth0`, `eth1`, and so on.
+//!
+//! Every buffer is a frame of its own, large enough for any Ethernet frame
+//! following the virtio header. Received buffers are put back in the receive
+//! queue as soon as their contents are copied out, so the device always has
+//! buffers to receive into.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{Transport, VENDOR_ID};
+use crate::interrupts;
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use crate::net::{self, Error, MacAddress, NetworkInterface, ETHERNET_HEADER_SIZE};
+use crate::pci::{Device, Driver, Match};
+use crate::sync::mutex::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The device has a MAC address.
+const F_MAC: u64 = 1 << 5;
+/// The device reports the status of the link.
+const F_STATUS: u64 = 1 << 16;
+
+/// The offsets of fields in the device configuration.
+const CONFIG_MAC: u64 = 0;
+const CONFIG_STATUS: u64 = 6;
+
+const STATUS_LINK_UP: u16 = 1 << 0;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The number of buffers in each queue.
+const QUEUE_SIZE: u16 = 64;
+
+/// The size of the header preceding every frame, which is zeroed for frames
+/// sent since no offloads are negotiated.
+const HEADER_SIZE: usize = 12;
+
+/// The largest payload of a frame.
+const MTU: usize = 1500;
+
+const FRAME_SIZE: usize = 4096;
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-net",
+    matches: &[
+        // The transitional and the modern device
+        Match::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1000,
+        },
+        Match::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1041,
+        },
+    ],
+    probe,
+};
+
+/// The number of interfaces found, which gives the name of the next one.
+static INTERFACE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the contents of a frame.
+///
+/// # Safety
+/// The frame must be owned by the caller, and not be accessed by the device
+/// while the slice exists.
+unsafe fn frame_mut<'a>(addr: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET | addr) as *mut u8, FRAME_SIZE)
+}
+
+/// A queue along with the frames of the buffers in it.
+struct BufferQueue {
+    queue: Virtqueue,
+    /// The frame of the buffer in each chain, by its first descriptor.
+    in_use: Vec<Option<u64>>,
+    /// Frames not in the queue.
+    free: Vec<u64>,
+}
+
+impl BufferQueue {
+    /// Allocates the frames of the buffers for a queue. The queue is handed
+    /// back if they cannot be allocated, as the device must be stopped before
+    /// it's freed.
+    fn new(queue: Virtqueue) -> Result<Self, Virtqueue> {
+        let mut free = Vec::with_capacity(queue.size() as usize);
+        for _ in 0..queue.size() {
+            match memory::allocate_frame() {
+                Ok(frame) => free.push(frame),
+                Err(()) => {
+                    for &frame in &free {
+                        unsafe { memory::free_frame(frame) };
+                    }
+                    return Err(queue);
+                }
+            }
+        }
+        Ok(Self {
+            in_use: vec![None; queue.size() as usize],
+            free,
+            queue,
+        })
+    }
+
+    /// Puts a buffer in the queue, without notifying the device.
+    fn push(&mut self, frame: u64, length: usize, device_writable: bool) {
+        let buffer = Buffer {
+            addr: frame,
+            length: length as u32,
+            device_writable,
+        };
+        // There is a descriptor for every frame
+        let head = self.queue.push(&[buffer]).unwrap();
+        self.in_use[head as usize] = Some(frame);
+    }
+
+    /// Takes a buffer returned by the device, and returns its frame and the
+    /// number of bytes written to it.
+    fn pop(&mut self) -> Option<(u64, usize)> {
+        let (head, length) = self.queue.pop_used()?;
+        let frame = self.in_use[head as usize].take()?;
+        Some((frame, length as usize))
+    }
+}
+
+impl Drop for BufferQueue {
+    fn drop(&mut self) {
+        for &frame in self.free.iter().chain(self.in_use.iter().flatten()) {
+            unsafe { memory::free_frame(frame) };
+        }
+    }
+}
+
+/// A virtio network device.
+pub struct VirtioNet {
+    transport: Transport,
+    mac_address: MacAddress,
+    /// Whether the device reports the status of the link.
+    has_status: bool,
+    receive: Mutex<BufferQueue>,
+    transmit: Mutex<BufferQueue>,
+    /// Whether the device signals interrupts.
+    interrupts: bool,
+    /// The vector of the interrupts of the device, if they're enabled.
+    vector: Option<u8>,
+}
+
+impl NetworkInterface for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    fn link_up(&self) -> bool {
+        !self.has_status || self.transport.config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() < ETHERNET_HEADER_SIZE || frame.len() > ETHERNET_HEADER_SIZE + MTU {
+            return Err(Error::InvalidFrame);
+        }
+        if !self.link_up() {
+            return Err(Error::LinkDown);
+        }
+
+        let mut transmit = self.transmit.lock();
+        // Reclaim the buffers of frames which have been sent, waiting for one
+        // if all are in use
+        loop {
+            while let Some((buffer, _)) = transmit.pop() {
+                transmit.free.push(buffer);
+            }
+            if !transmit.free.is_empty() {
+                break;
+            }
+            if self.interrupts {
+                let queue = &transmit.queue;
+                interrupts::wait_until(|| queue.has_used());
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+
+        let buffer = transmit.free.pop().unwrap();
+        let contents = unsafe { frame_mut(buffer) };
+        contents[..HEADER_SIZE].copy_from_slice(&[0; HEADER_SIZE]);
+        contents[HEADER_SIZE..HEADER_SIZE + frame.len()].copy_from_slice(frame);
+        transmit.push(buffer, HEADER_SIZE + frame.len(), false);
+        transmit.queue.notify();
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut receive = self.receive.lock();
+        loop {
+            let (buffer, length) = receive.pop()?;
+            let contents = unsafe { frame_mut(buffer) };
+            let frame = if length >= HEADER_SIZE + ETHERNET_HEADER_SIZE {
+                Some(contents[HEADER_SIZE..length].to_vec())
+            } else {
+                None
+            };
+            receive.push(buffer, FRAME_SIZE, true);
+            receive.queue.notify();
+            // Runt frames are dropped
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+impl Drop for VirtioNet {
+    fn drop(&mut self) {
+        // Stop the device from using the queues before their frames are freed
+        self.transport.reset();
+        if let Some(vector) = self.vector {
+            self.transport.disable_interrupts(vector);
+        }
+    }
+}
+
+/// Sets up a queue with its buffers, or gives up on the device with
+/// [Transport::abort], before any queue is freed.
+fn setup_queue(
+    transport: &Transport,
+    index: u16,
+    vector: Option<u8>,
+) -> Result<(BufferQueue, bool), &'static str> {
+    let (queue, interrupts) = match transport.setup_queue(index, QUEUE_SIZE, vector.is_some()) {
+        Ok(queue) => queue,
+        Err(error) => {
+            transport.abort(vector);
+            return Err(error);
+        }
+    };
+    match BufferQueue::new(queue) {
+        Ok(buffers) => Ok((buffers, interrupts)),
+        Err(queue) => {
+            transport.abort(vector);
+            drop(queue);
+            Err("could not allocate memory for buffers")
+        }
+    }
+}
+
+/// Sets up a virtio network device and registers it as an interface.
+fn probe(device: &Arc<Device>) -> Result<(), &'static str> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(F_MAC | F_STATUS)?;
+
+    // Received frames wake the threads waiting for the network, while waiting
+    // senders are woken by the interrupt itself and check the queue
+    let vector = transport.enable_interrupts(net::notify);
+    let (mut receive, receive_interrupts) = setup_queue(&transport, RECEIVE_QUEUE, vector)?;
+    let (transmit, transmit_interrupts) = setup_queue(&transport, TRANSMIT_QUEUE, vector)?;
+    let interrupts = receive_interrupts && transmit_interrupts;
+
+    let mac_address = if features & F_MAC != 0 {
+        let low = transport.config_u32(CONFIG_MAC).to_le_bytes();
+        let high = transport.config_u16(CONFIG_MAC + 4).to_le_bytes();
+        MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+    } else {
+        // A locally administered address, unique to the PCI function
+        let address = device.address;
+        MacAddress([
+            0x02,
+            (address.segment >> 8) as u8,
+            address.segment as u8,
+            address.bus,
+            address.device,
+            address.function,
+        ])
+    };
+
+    // Give every receive buffer to the device
+    while let Some(buffer) = receive.free.pop() {
+        receive.push(buffer, FRAME_SIZE, true);
+    }
+    transport.driver_ok();
+    receive.queue.notify();
+
+    let interface = VirtioNet {
+        transport,
+        mac_address,
+        has_status: features & F_STATUS != 0,
+        receive: Mutex::new(receive),
+        transmit: Mutex::new(transmit),
+        interrupts,
+        vector,
+    };
+    let name = format!("eth{}", INTERFACE_COUNT.fetch_add(1, Ordering::Relaxed));
+    net::register_interface(&name, Arc::new(interface));
+    Ok(())
+}
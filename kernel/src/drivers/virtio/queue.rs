@@ -0,0 +1,231 @@
+//! Split virtqueues, through which buffers are passed to a device.
+//!
+//! A queue consists of three parts, each in its own frame: the descriptor
+//! table holding the buffers, the available ring where the driver puts chains
+//! of descriptors for the device, and the used ring where the device returns
+//! them when it's done.
+
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use core::sync::atomic::{fence, Ordering};
+
+/// The largest queue size, for which the descriptor table fills a frame.
+pub const MAX_SIZE: u16 = 256;
+
+/// The descriptor continues in the one given by `next`.
+const DESCRIPTOR_NEXT: u16 = 1;
+/// The buffer is written by the device instead of read.
+const DESCRIPTOR_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Descriptor {
+    /// The physical address of the buffer.
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct UsedElement {
+    /// The first descriptor of the chain.
+    id: u32,
+    /// The number of bytes written to the buffers of the chain.
+    len: u32,
+}
+
+/// A buffer passed to the device.
+#[derive(Copy, Clone, Debug)]
+pub struct Buffer {
+    /// The physical address of the buffer.
+    pub addr: u64,
+    pub length: u32,
+    /// Whether the device writes to the buffer, rather than reads from it.
+    pub device_writable: bool,
+}
+
+/// A split virtqueue.
+///
+/// The device must be reset before the queue is dropped, as it would otherwise
+/// keep accessing its frames.
+pub struct Virtqueue {
+    /// The index of the queue in the device.
+    index: u16,
+    size: u16,
+    /// The virtual address of the notification register of the queue.
+    notify: u64,
+    /// The physical addresses of the descriptor table, the available ring,
+    /// and the used ring.
+    descriptors: u64,
+    available: u64,
+    used: u64,
+    /// The first descriptor in the list of free descriptors, linked through
+    /// their `next` field.
+    free_head: u16,
+    free_count: u16,
+    /// The index of the next entry of the available ring.
+    next_available: u16,
+    /// The index of the next entry of the used ring to be returned.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocates a queue with the given number of descriptors, which must be a
+    /// power of two no larger than [MAX_SIZE], notified through the register
+    /// at the given virtual address.
+    pub fn new(index: u16, size: u16, notify: u64) -> Result<Self, ()> {
+        debug_assert!(size.is_power_of_two() && size <= MAX_SIZE);
+        let mut frames = [0; 3];
+        let mut allocated = 0;
+        while allocated < frames.len() {
+            let frame = match memory::allocate_frame() {
+                Ok(frame) => frame,
+                Err(()) => {
+                    for &frame in &frames[..allocated] {
+                        unsafe { memory::free_frame(frame) };
+                    }
+                    return Err(());
+                }
+            };
+            unsafe { core::ptr::write_bytes((PHYS_MEM_OFFSET | frame) as *mut u8, 0, 4096) };
+            frames[allocated] = frame;
+            allocated += 1;
+        }
+
+        let queue = Self {
+            index,
+            size,
+            notify,
+            descriptors: frames[0],
+            available: frames[1],
+            used: frames[2],
+            free_head: 0,
+            free_count: size,
+            next_available: 0,
+            last_used: 0,
+        };
+        for i in 0..size {
+            unsafe { (*queue.descriptor(i)).next = i + 1 };
+        }
+        Ok(queue)
+    }
+
+    /// Returns the physical addresses of the descriptor table, the available
+    /// ring, and the used ring.
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        (self.descriptors, self.available, self.used)
+    }
+
+    /// Returns the number of descriptors.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        let table = (PHYS_MEM_OFFSET | self.descriptors) as *mut Descriptor;
+        table.wrapping_add(index as usize)
+    }
+
+    /// Returns a pointer to the index of the available ring, followed by the
+    /// ring.
+    fn available_index(&self) -> *mut u16 {
+        ((PHYS_MEM_OFFSET | self.available) + 2) as *mut u16
+    }
+
+    /// Returns a pointer to the index of the used ring.
+    fn used_index(&self) -> *const u16 {
+        ((PHYS_MEM_OFFSET | self.used) + 2) as *const u16
+    }
+
+    /// Adds a chain of buffers to the available ring, and returns the index of
+    /// its first descriptor. The device is not notified until
+    /// [notify](Self::notify) is called. Fails if there are not enough free
+    /// descriptors.
+    pub fn push(&mut self, buffers: &[Buffer]) -> Result<u16, ()> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(());
+        }
+
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = self.free_head;
+            let descriptor = unsafe { &mut *self.descriptor(index) };
+            self.free_head = descriptor.next;
+            descriptor.addr = buffer.addr;
+            descriptor.len = buffer.length;
+            descriptor.flags = if buffer.device_writable {
+                DESCRIPTOR_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESCRIPTOR_NEXT;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let ring = self.available_index().add(1);
+            *ring.add((self.next_available % self.size) as usize) = head;
+            self.next_available = self.next_available.wrapping_add(1);
+            // The descriptors must be visible before the index is updated
+            fence(Ordering::SeqCst);
+            core::ptr::write_volatile(self.available_index(), self.next_available);
+        }
+        Ok(head)
+    }
+
+    /// Notifies the device of new buffers in the available ring.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { core::ptr::write_volatile(self.notify as *mut u16, self.index) };
+    }
+
+    /// Returns whether the device has returned chains to the used ring which
+    /// have not been popped yet.
+    pub fn has_used(&self) -> bool {
+        unsafe { core::ptr::read_volatile(self.used_index()) != self.last_used }
+    }
+
+    /// Pops a chain returned by the device from the used ring, and frees its
+    /// descriptors. Returns the index of its first descriptor, and the number
+    /// of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        // The ring entry must not be read before the index
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            let ring = ((PHYS_MEM_OFFSET | self.used) + 4) as *const UsedElement;
+            core::ptr::read_volatile(ring.add((self.last_used % self.size) as usize))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Return the chain to the free list
+        let head = element.id as u16;
+        let mut index = head;
+        loop {
+            let descriptor = unsafe { &mut *self.descriptor(index) };
+            self.free_count += 1;
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                descriptor.next = self.free_head;
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = head;
+        Some((head, element.len))
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        unsafe {
+            memory::free_frame(self.descriptors);
+            memory::free_frame(self.available);
+            memory::free_frame(self.used);
+        }
+    }
+}
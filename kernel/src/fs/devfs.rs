@@ -0,0 +1,104 @@
+//! A file system of devices, mounted at `/dev`.
+//!
+//! It's a single directory holding a fixed set of character devices:
+//!
+//! - `console`: The terminal. Writes are printed, and reads read nothing, as
+//!   there's no keyboard input yet.
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::print;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::any::Any;
+
+/// The inode number of the root directory. The devices are numbered from 1,
+/// in the order of [DEVICES].
+const ROOT_INODE: u64 = 0;
+
+/// The names of the devices.
+const DEVICES: [&str; 1] = ["console"];
+
+/// The device file system.
+pub struct Devfs {
+    root: Arc<Root>,
+}
+
+impl Devfs {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(Root),
+        }
+    }
+}
+
+impl FileSystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// The directory of the devices.
+struct Root;
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: ROOT_INODE,
+            file_type: FileType::Directory,
+            permissions: 0o755,
+            size: 0,
+            links: 2,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        match name {
+            "console" => Ok(Arc::new(Console)),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        Ok(DEVICES.get(index).map(|name| DirEntry {
+            name: name.to_string(),
+            inode: index as u64 + 1,
+            file_type: FileType::CharDevice,
+        }))
+    }
+}
+
+/// The terminal.
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: 1,
+            file_type: FileType::CharDevice,
+            permissions: 0o620,
+            size: 0,
+            links: 1,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, Error> {
+        print!("{}", String::from_utf8_lossy(buffer));
+        Ok(buffer.len())
+    }
+}
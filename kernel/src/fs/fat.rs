@@ -0,0 +1,391 @@
+//! FAT12, FAT16, and FAT32 file systems, using the [rk_fat] crate.
+//!
+//! FAT has no inodes, so files are identified by the location of their entry
+//! in the parent directory, which also gives their inode number. The inodes in
+//! use are tracked, such that renaming a file moves its inode along with the
+//! entry. The clusters of a file are freed as soon as it's removed, so a file
+//! which is removed while open can no longer be read or written.
+//!
+//! There are no permissions or symbolic links. Files have mode 644 and
+//! directories 755, except for read-only entries, which have no write
+//! permissions.
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::block::BlockDevice;
+use crate::println;
+use crate::sync::mutex::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use core::any::Any;
+use rk_fat::Dir;
+
+/// The inode number of the root directory, which has no entry.
+const ROOT_INODE: u64 = 1;
+
+impl From<rk_fat::Error> for Error {
+    fn from(error: rk_fat::Error) -> Self {
+        match error {
+            rk_fat::Error::Io | rk_fat::Error::InvalidFileSystem | rk_fat::Error::Corrupted => {
+                Self::Io
+            }
+            rk_fat::Error::NotFound => Self::NotFound,
+            rk_fat::Error::AlreadyExists => Self::AlreadyExists,
+            rk_fat::Error::NotADirectory => Self::NotADirectory,
+            rk_fat::Error::IsADirectory => Self::IsADirectory,
+            rk_fat::Error::NotEmpty => Self::NotEmpty,
+            rk_fat::Error::InvalidName | rk_fat::Error::InvalidMove => Self::InvalidArgument,
+            rk_fat::Error::NoSpace => Self::NoSpace,
+            rk_fat::Error::FileTooLarge => Self::FileTooLarge,
+        }
+    }
+}
+
+/// Gives [rk_fat] access to a block device.
+struct Device(Arc<dyn BlockDevice>);
+
+impl rk_fat::BlockDevice for Device {
+    fn block_size(&self) -> usize {
+        self.0.sector_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.0.sector_count()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), rk_fat::Error> {
+        self.0
+            .read_sectors(block, buffer)
+            .map_err(|_| rk_fat::Error::Io)
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), rk_fat::Error> {
+        self.0
+            .write_sectors(block, buffer)
+            .map_err(|_| rk_fat::Error::Io)
+    }
+
+    fn flush(&self) -> Result<(), rk_fat::Error> {
+        self.0.flush().map_err(|_| rk_fat::Error::Io)
+    }
+}
+
+/// Returns the inode number of an entry, which is its index on the device.
+/// This is never [ROOT_INODE], as the boot sector comes first.
+fn inode_number(entry: &rk_fat::DirEntry) -> u64 {
+    entry.offset() / 32
+}
+
+/// State shared by all inodes of a file system.
+///
+/// The file system is always locked before the inode table, and both before
+/// the node of an inode.
+struct Shared {
+    fs: Mutex<rk_fat::FileSystem<Device>>,
+    /// The inodes in use, by the offset of their entry.
+    inodes: Mutex<BTreeMap<u64, Weak<FatInode>>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Err(error) = self.fs.get_mut().flush() {
+            println!("Could not flush FAT file system: {}", error);
+        }
+    }
+}
+
+/// A FAT file system on a block device.
+pub struct FatFs {
+    root: Arc<FatInode>,
+}
+
+impl FatFs {
+    /// Opens the file system on the device.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let fs = rk_fat::FileSystem::new(Device(device))?;
+        let root_dir = fs.root_dir();
+        let shared = Arc::new(Shared {
+            fs: Mutex::new(fs),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+        let root = Arc::new(FatInode {
+            shared,
+            node: Mutex::new(Node::Root(root_dir)),
+        });
+        Ok(Self { root })
+    }
+
+    /// Returns the variant of FAT.
+    pub fn fat_type(&self) -> rk_fat::FatType {
+        self.root.shared.fs.lock().fat_type()
+    }
+
+    /// Writes the free cluster hints and flushes the device, which otherwise
+    /// happens when the file system is dropped.
+    pub fn sync(&self) -> Result<(), Error> {
+        Ok(self.root.shared.fs.lock().flush()?)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// What an inode refers to.
+enum Node {
+    Root(Dir),
+    Entry(rk_fat::DirEntry),
+    /// An entry which has been removed, kept for its metadata.
+    Removed(rk_fat::DirEntry),
+}
+
+/// A file or directory in a [FatFs].
+struct FatInode {
+    shared: Arc<Shared>,
+    node: Mutex<Node>,
+}
+
+impl FatInode {
+    /// Returns the directory this inode refers to.
+    fn dir(&self) -> Result<Dir, Error> {
+        match &*self.node.lock() {
+            Node::Root(dir) => Ok(*dir),
+            Node::Entry(entry) => entry.as_dir().ok_or(Error::NotADirectory),
+            Node::Removed(_) => Err(Error::NotFound),
+        }
+    }
+
+    /// Returns the inode for an entry, which is created unless it's in use.
+    /// The file system must be locked, such that the entry is current.
+    fn inode_for(&self, entry: rk_fat::DirEntry) -> Arc<Self> {
+        let mut inodes = self.shared.inodes.lock();
+        if let Some(inode) = inodes.get(&entry.offset()).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let offset = entry.offset();
+        let inode = Arc::new(Self {
+            shared: self.shared.clone(),
+            node: Mutex::new(Node::Entry(entry)),
+        });
+        inodes.insert(offset, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Marks the inode of a removed entry as removed, if it's in use. The file
+    /// system must be locked.
+    fn forget(&self, offset: u64) {
+        let inode = self
+            .shared
+            .inodes
+            .lock()
+            .remove(&offset)
+            .and_then(|inode| inode.upgrade());
+        if let Some(inode) = inode {
+            let mut node = inode.node.lock();
+            if let Node::Entry(entry) = &*node {
+                *node = Node::Removed(entry.clone());
+            }
+        }
+    }
+
+    /// Removes the entry with the given name from the directory, if `check`
+    /// accepts it.
+    fn remove(
+        &self,
+        name: &str,
+        check: impl Fn(&rk_fat::DirEntry) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut fs = self.shared.fs.lock();
+        let dir = self.dir()?;
+        let entry = fs.find(dir, name)?;
+        check(&entry)?;
+        fs.remove(dir, name)?;
+        self.forget(entry.offset());
+        Ok(())
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        // Entries of inodes no longer in use are removed from the table, unless
+        // a new inode has been created for the entry already
+        if let Node::Entry(entry) = &*self.node.lock() {
+            let mut inodes = self.shared.inodes.lock();
+            let unused = inodes
+                .get(&entry.offset())
+                .map_or(false, |inode| inode.strong_count() == 0);
+            if unused {
+                inodes.remove(&entry.offset());
+            }
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let (inode, file_type, size, attributes) = match &*self.node.lock() {
+            Node::Root(_) => (ROOT_INODE, FileType::Directory, 0, 0),
+            Node::Entry(entry) | Node::Removed(entry) => {
+                let file_type = if entry.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                };
+                (
+                    inode_number(entry),
+                    file_type,
+                    entry.size as u64,
+                    entry.attributes,
+                )
+            }
+        };
+        let mut permissions = match file_type {
+            FileType::Directory => 0o755,
+            _ => 0o644,
+        };
+        if attributes & rk_fat::ATTR_READ_ONLY != 0 {
+            permissions &= !0o222;
+        }
+        Metadata {
+            inode,
+            file_type,
+            permissions,
+            size,
+            links: 1,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let fs = self.shared.fs.lock();
+        match &*self.node.lock() {
+            Node::Root(_) => Err(Error::IsADirectory),
+            Node::Entry(entry) => Ok(fs.read(entry, offset, buffer)?),
+            Node::Removed(_) => Err(Error::NotFound),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, Error> {
+        let mut fs = self.shared.fs.lock();
+        match &mut *self.node.lock() {
+            Node::Root(_) => Err(Error::IsADirectory),
+            Node::Entry(entry) => Ok(fs.write(entry, offset, buffer)?),
+            Node::Removed(_) => Err(Error::NotFound),
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Error> {
+        if size > u32::MAX as u64 {
+            return Err(Error::FileTooLarge);
+        }
+        let mut fs = self.shared.fs.lock();
+        match &mut *self.node.lock() {
+            Node::Root(_) => Err(Error::IsADirectory),
+            Node::Entry(entry) => Ok(fs.truncate(entry, size as u32)?),
+            Node::Removed(_) => Err(Error::NotFound),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        let fs = self.shared.fs.lock();
+        let entry = fs.find(self.dir()?, name)?;
+        Ok(self.inode_for(entry))
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        let fs = self.shared.fs.lock();
+        let entries = fs.read_dir(self.dir()?)?;
+        Ok(entries.into_iter().nth(index).map(|entry| DirEntry {
+            inode: inode_number(&entry),
+            file_type: if entry.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::File
+            },
+            name: entry.name,
+        }))
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        file_type: FileType,
+        _permissions: u32,
+    ) -> Result<Arc<dyn Inode>, Error> {
+        let mut fs = self.shared.fs.lock();
+        let dir = self.dir()?;
+        let entry = match file_type {
+            FileType::File => fs.create_file(dir, name)?,
+            FileType::Directory => fs.create_dir(dir, name)?,
+            _ => return Err(Error::NotSupported),
+        };
+        Ok(self.inode_for(entry))
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Error> {
+        self.remove(name, |entry| {
+            if entry.is_dir() {
+                Err(Error::IsADirectory)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), Error> {
+        self.remove(name, |entry| {
+            if entry.is_dir() {
+                Ok(())
+            } else {
+                Err(Error::NotADirectory)
+            }
+        })
+    }
+
+    fn rename(&self, name: &str, new_directory: &dyn Inode, new_name: &str) -> Result<(), Error> {
+        let new_directory = match new_directory.as_any().downcast_ref::<Self>() {
+            Some(inode) if Arc::ptr_eq(&inode.shared, &self.shared) => inode,
+            _ => return Err(Error::CrossDevice),
+        };
+
+        let mut fs = self.shared.fs.lock();
+        let dir = self.dir()?;
+        let new_dir = new_directory.dir()?;
+        let entry = fs.find(dir, name)?;
+        let replaced = match fs.find(new_dir, new_name) {
+            Ok(existing) if existing.offset() != entry.offset() => Some(existing.offset()),
+            Ok(_) | Err(rk_fat::Error::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+        let new_entry = fs.rename(dir, name, new_dir, new_name)?;
+        if let Some(offset) = replaced {
+            self.forget(offset);
+        }
+
+        // Move the inode of the entry, if it's in use, to the new location
+        let inode = {
+            let mut inodes = self.shared.inodes.lock();
+            let inode = inodes.remove(&entry.offset());
+            if let Some(inode) = &inode {
+                inodes.insert(new_entry.offset(), inode.clone());
+            }
+            inode
+        };
+        if let Some(inode) = inode.and_then(|inode| inode.upgrade()) {
+            *inode.node.lock() = Node::Entry(new_entry);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! Open files and file descriptors.
+
+use super::vfs::{self, Dentry};
+use super::{DirEntry, Error, FileType, Metadata};
+use crate::sync::mutex::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::BitOr;
+
+/// The maximum number of files open in a [FileTable].
+const MAX_OPEN_FILES: usize = 256;
+
+/// Flags controlling how a file is opened.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// Open the file for reading.
+    pub const READ: Self = Self(1 << 0);
+    /// Open the file for writing.
+    pub const WRITE: Self = Self(1 << 1);
+    /// Create the file if it doesn't exist.
+    pub const CREATE: Self = Self(1 << 2);
+    /// Together with [CREATE](Self::CREATE), fail if the file already exists.
+    pub const EXCLUSIVE: Self = Self(1 << 3);
+    /// Discard the contents of the file when opened for writing.
+    pub const TRUNCATE: Self = Self(1 << 4);
+    /// Write at the end of the file, regardless of the offset.
+    pub const APPEND: Self = Self(1 << 5);
+    /// Fail unless the file is a directory.
+    pub const DIRECTORY: Self = Self(1 << 6);
+    /// Open a symbolic link at the end of the path instead of following it.
+    pub const NO_FOLLOW: Self = Self(1 << 7);
+
+    /// Returns the flags with the given bits.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw bits of the flags.
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether all the given flags are set.
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A position to seek to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// An offset from the start of the file.
+    Start(u64),
+    /// An offset from the current position.
+    Current(i64),
+    /// An offset from the end of the file.
+    End(i64),
+}
+
+/// An open file, with its own offset.
+///
+/// Directories are opened like files, but read with
+/// [read_dir](File::read_dir), where the offset is the position of the next
+/// entry.
+pub struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl File {
+    /// Returns the dentry the file was opened through.
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    /// Returns the flags the file was opened with.
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// Reads from the current offset into the buffer, advancing the offset by
+    /// the number of bytes read.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(Error::BadFileDescriptor);
+        }
+        if self.dentry.file_type() == FileType::Directory {
+            return Err(Error::IsADirectory);
+        }
+        let mut offset = self.offset.lock();
+        let count = self.dentry.inode().read_at(*offset, buffer)?;
+        *offset += count as u64;
+        Ok(count)
+    }
+
+    /// Writes the buffer at the current offset, or at the end of the file if
+    /// opened for appending, and advances the offset past the written bytes.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(Error::BadFileDescriptor);
+        }
+        let inode = self.dentry.inode();
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = inode.metadata().size;
+        }
+        let count = inode.write_at(*offset, buffer)?;
+        *offset += count as u64;
+        Ok(count)
+    }
+
+    /// Moves the offset, returning the new offset from the start of the file.
+    ///
+    /// The offset may be past the end of the file, but not before the start.
+    pub fn seek(&self, position: SeekFrom) -> Result<u64, Error> {
+        let mut offset = self.offset.lock();
+        let (base, delta) = match position {
+            SeekFrom::Start(start) => (start, 0),
+            SeekFrom::Current(delta) => (*offset, delta),
+            SeekFrom::End(delta) => (self.dentry.inode().metadata().size, delta),
+        };
+        let new_offset = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        }
+        .ok_or(Error::InvalidArgument)?;
+        // Offsets must fit in an i64 for relative seeks back to the start
+        i64::try_from(new_offset).map_err(|_| Error::InvalidArgument)?;
+        *offset = new_offset;
+        Ok(new_offset)
+    }
+
+    /// Returns information about the file.
+    pub fn stat(&self) -> Metadata {
+        self.dentry.inode().metadata()
+    }
+
+    /// Returns the next entry of a directory, or `None` after the last entry.
+    pub fn read_dir(&self) -> Result<Option<DirEntry>, Error> {
+        if self.dentry.file_type() != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let mut offset = self.offset.lock();
+        let entry = self.dentry.inode().read_dir(*offset as usize)?;
+        if entry.is_some() {
+            *offset += 1;
+        }
+        Ok(entry)
+    }
+}
+
+/// Opens the file at the given path.
+///
+/// If the file is created, it gets the given permissions.
+pub fn open(path: &str, flags: OpenFlags, permissions: u32) -> Result<Arc<File>, Error> {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let dentry = match vfs::lookup(path, follow) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(Error::AlreadyExists);
+        }
+        Ok(dentry) => dentry,
+        Err(Error::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            vfs::create(path, FileType::File, permissions)?
+        }
+        Err(error) => return Err(error),
+    };
+
+    match dentry.file_type() {
+        FileType::Directory if flags.contains(OpenFlags::WRITE) => {
+            return Err(Error::IsADirectory);
+        }
+        FileType::Directory => {}
+        _ if flags.contains(OpenFlags::DIRECTORY) => return Err(Error::NotADirectory),
+        _ => {}
+    }
+
+    if flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE)
+        && dentry.file_type() == FileType::File
+    {
+        dentry.inode().truncate(0)?;
+    }
+
+    Ok(Arc::new(File {
+        dentry,
+        flags,
+        offset: Mutex::new(0),
+    }))
+}
+
+/// A table of open files, indexed by file descriptors.
+///
+/// The same open file can be referred to by several descriptors, sharing its
+/// offset.
+pub struct FileTable {
+    files: Vec<Option<Arc<File>>>,
+}
+
+impl FileTable {
+    /// Creates an empty table.
+    pub const fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// Adds an open file to the table, returning the lowest free descriptor.
+    pub fn insert(&mut self, file: Arc<File>) -> Result<usize, Error> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_OPEN_FILES {
+            return Err(Error::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    /// Returns the open file with the given descriptor.
+    pub fn get(&self, fd: usize) -> Result<Arc<File>, Error> {
+        self.files
+            .get(fd)
+            .cloned()
+            .flatten()
+            .ok_or(Error::BadFileDescriptor)
+    }
+
+    /// Closes a descriptor. The file is closed once no descriptors refer to
+    /// it.
+    pub fn close(&mut self, fd: usize) -> Result<(), Error> {
+        match self.files.get_mut(fd) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                Ok(())
+            }
+            _ => Err(Error::BadFileDescriptor),
+        }
+    }
+
+    /// Returns a new descriptor for the same open file as `fd`.
+    pub fn duplicate(&mut self, fd: usize) -> Result<usize, Error> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+}
@@ -0,0 +1,530 @@
+//! A read-only file system for the initial ramdisk.
+//!
+//! The ramdisk is an archive in either the "new ASCII" cpio format (as written
+//! by `cpio -H newc`) or the ustar tar format, loaded as a boot module. The
+//! archive is indexed once, and file contents are read directly from it.
+//!
+//! [InitrdFs] makes the ramdisk available to the [virtual file
+//! system](super::vfs), such that it can be mounted.
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::cmdline::CMDLINE;
+use crate::modules;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::convert::TryFrom;
+use core::fmt;
+
+/// The type of a file mode, as stored in the `S_IFMT` bits of a mode.
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// The kind of an entry in the ramdisk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link, the target is the contents of the entry.
+    Symlink,
+}
+
+impl EntryKind {
+    /// Returns the corresponding type of inode.
+    fn file_type(self) -> FileType {
+        match self {
+            Self::File => FileType::File,
+            Self::Directory => FileType::Directory,
+            Self::Symlink => FileType::Symlink,
+        }
+    }
+}
+
+/// A file or directory in the ramdisk.
+#[derive(Copy, Clone, Debug)]
+pub struct Entry<'a> {
+    /// The path relative to the root, without leading or trailing slashes.
+    pub path: &'a str,
+    pub kind: EntryKind,
+    /// The permission bits of the mode.
+    pub permissions: u32,
+    /// The contents of the entry, empty for directories.
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// Returns the last component of the path, or an empty string for the
+    /// root.
+    pub fn name(&self) -> &'a str {
+        self.path.rsplit('/').next().unwrap()
+    }
+
+    /// Returns the size of the contents in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Reads the contents starting at the given offset into the buffer,
+    /// returning the number of bytes read.
+    pub fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+        if self.kind == EntryKind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        if offset >= self.data.len() {
+            return Ok(0);
+        }
+        let count = core::cmp::min(buffer.len(), self.data.len() - offset);
+        buffer[..count].copy_from_slice(&self.data[offset..offset + count]);
+        Ok(count)
+    }
+}
+
+/// An error in the ramdisk archive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The archive is neither a newc cpio nor a ustar tar archive.
+    UnknownFormat,
+    /// The archive ends within the entry at the given offset.
+    Truncated { offset: usize },
+    /// The header at the given offset is malformed.
+    InvalidHeader { offset: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownFormat => write!(f, "unknown archive format"),
+            Self::Truncated { offset } => write!(f, "archive truncated at {:#x}", offset),
+            Self::InvalidHeader { offset } => write!(f, "invalid header at {:#x}", offset),
+        }
+    }
+}
+
+/// Indexes the ramdisk loaded as the module named by the `initrd` command line
+/// option, or `INITRD` by default.
+///
+/// Returns `Ok(None)` if there is no such module.
+pub fn load() -> Result<Option<Initrd<'static>>, ParseError> {
+    let name = CMDLINE.get("initrd").unwrap_or("INITRD");
+    match modules::find(name) {
+        Some(module) => Initrd::parse(module.data).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// An indexed ramdisk archive.
+pub struct Initrd<'a> {
+    /// Every entry, sorted by path. Includes the root, and any directory which
+    /// is only implied by the paths of other entries.
+    entries: Vec<Entry<'a>>,
+}
+
+impl<'a> Initrd<'a> {
+    /// Indexes the archive, detecting its format.
+    pub fn parse(archive: &'a [u8]) -> Result<Self, ParseError> {
+        let mut entries = if archive.starts_with(b"07070") {
+            parse_cpio(archive)?
+        } else if archive.len() >= 512 && &archive[257..262] == b"ustar" {
+            parse_tar(archive)?
+        } else {
+            return Err(ParseError::UnknownFormat);
+        };
+
+        // Add the root and any missing parent directories
+        entries.push(directory(""));
+        let mut i = 0;
+        while i < entries.len() {
+            if let Some(end) = entries[i].path.rfind('/') {
+                let parent = &entries[i].path[..end];
+                if !entries.iter().any(|entry| entry.path == parent) {
+                    entries.push(directory(parent));
+                }
+            }
+            i += 1;
+        }
+
+        // Later entries for the same path replace earlier ones
+        entries.reverse();
+        entries.sort_by(|a, b| a.path.cmp(b.path));
+        entries.dedup_by(|a, b| a.path == b.path);
+
+        Ok(Self { entries })
+    }
+
+    /// Returns the root directory.
+    pub fn root(&self) -> &Entry<'a> {
+        // The root has the empty path, which sorts first
+        &self.entries[0]
+    }
+
+    /// Returns the entry at the given path.
+    ///
+    /// Paths are relative to the root, and may start with a slash. Empty
+    /// components and `.` are ignored, `..` refers to the parent directory.
+    pub fn lookup(&self, path: &str) -> Result<&Entry<'a>, Error> {
+        let mut entry = self.root();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    // Every parent directory has an entry
+                    entry = self.get(parent_path(entry.path)).unwrap();
+                }
+                name => {
+                    if entry.kind != EntryKind::Directory {
+                        return Err(Error::NotADirectory);
+                    }
+                    entry = self.child(entry, name).ok_or(Error::NotFound)?;
+                }
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Returns an iterator over the entries in the directory at the given path.
+    pub fn read_dir<'b>(
+        &'b self,
+        path: &str,
+    ) -> Result<impl Iterator<Item = &'b Entry<'a>> + 'b, Error> {
+        let directory = self.lookup(path)?;
+        if directory.kind != EntryKind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let parent = directory.path;
+        Ok(self
+            .entries
+            .iter()
+            .filter(move |entry| !entry.path.is_empty() && parent_path(entry.path) == parent))
+    }
+
+    /// Reads the contents of the file at the given path starting at the given
+    /// offset, returning the number of bytes read.
+    pub fn read(&self, path: &str, offset: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.lookup(path)?.read_at(offset, buffer)
+    }
+
+    /// Returns the entry with exactly the given normalized path.
+    fn get(&self, path: &str) -> Option<&Entry<'a>> {
+        self.index_of(path).map(|index| &self.entries[index])
+    }
+
+    /// Returns the index of the entry with exactly the given normalized path.
+    fn index_of(&self, path: &str) -> Option<usize> {
+        self.entries
+            .binary_search_by(|entry| entry.path.cmp(path))
+            .ok()
+    }
+
+    /// Returns the entry with the given name in a directory.
+    fn child(&self, directory: &Entry<'a>, name: &str) -> Option<&Entry<'a>> {
+        self.child_index(directory, name)
+            .map(|index| &self.entries[index])
+    }
+
+    /// Returns the index of the entry with the given name in a directory.
+    fn child_index(&self, directory: &Entry<'a>, name: &str) -> Option<usize> {
+        if directory.path.is_empty() {
+            self.index_of(name)
+        } else {
+            // Avoid allocating the joined path by comparing its parts
+            self.entries
+                .iter()
+                .position(|entry| parent_path(entry.path) == directory.path && entry.name() == name)
+        }
+    }
+}
+
+/// The ramdisk as a read-only file system, which can be mounted.
+pub struct InitrdFs {
+    initrd: Arc<Initrd<'static>>,
+}
+
+impl InitrdFs {
+    pub fn new(initrd: Initrd<'static>) -> Self {
+        Self {
+            initrd: Arc::new(initrd),
+        }
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(InitrdInode {
+            initrd: self.initrd.clone(),
+            index: 0,
+        })
+    }
+}
+
+/// An entry of the ramdisk as an inode.
+struct InitrdInode {
+    initrd: Arc<Initrd<'static>>,
+    /// The index of the entry, which doubles as the inode number.
+    index: usize,
+}
+
+impl InitrdInode {
+    fn entry(&self) -> &Entry<'static> {
+        &self.initrd.entries[self.index]
+    }
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Metadata {
+        let entry = self.entry();
+        Metadata {
+            // Inode numbers start at 1, as 0 means no inode
+            inode: self.index as u64 + 1,
+            file_type: entry.kind.file_type(),
+            permissions: entry.permissions,
+            size: entry.size() as u64,
+            links: 1,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+        self.entry().read_at(offset, buffer)
+    }
+
+    fn read_link(&self) -> Result<String, Error> {
+        let entry = self.entry();
+        if entry.kind != EntryKind::Symlink {
+            return Err(Error::InvalidArgument);
+        }
+        core::str::from_utf8(entry.data)
+            .map(String::from)
+            .map_err(|_| Error::InvalidArgument)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        let index = self
+            .initrd
+            .child_index(self.entry(), name)
+            .ok_or(Error::NotFound)?;
+        Ok(Arc::new(InitrdInode {
+            initrd: self.initrd.clone(),
+            index,
+        }))
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        let parent = self.entry().path;
+        let child = self
+            .initrd
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.path.is_empty() && parent_path(entry.path) == parent)
+            .nth(index);
+        Ok(child.map(|(index, entry)| DirEntry {
+            name: String::from(entry.name()),
+            inode: index as u64 + 1,
+            file_type: entry.kind.file_type(),
+        }))
+    }
+}
+
+/// Returns the path of the parent directory.
+fn parent_path(path: &str) -> &str {
+    path.rfind('/').map_or("", |end| &path[..end])
+}
+
+/// Creates an entry for a directory which is implied by other paths.
+fn directory(path: &str) -> Entry<'_> {
+    Entry {
+        path,
+        kind: EntryKind::Directory,
+        permissions: 0o755,
+        data: &[],
+    }
+}
+
+/// Removes leading `./` and slashes, and trailing slashes.
+fn normalize_path(mut path: &str) -> &str {
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+    let path = path.trim_end_matches('/');
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+/// Rounds up to a multiple of `align`, which must be a power of two.
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Parses a newc cpio archive.
+///
+/// Each entry is a 110 byte header of ASCII hex fields, followed by the name
+/// and the contents, each padded to 4 bytes. The archive ends with an entry
+/// named `TRAILER!!!`.
+fn parse_cpio(archive: &[u8]) -> Result<Vec<Entry<'_>>, ParseError> {
+    const HEADER_SIZE: usize = 110;
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(ParseError::Truncated { offset })?;
+        // "070701" is the plain format, "070702" adds a checksum we ignore
+        if &header[..6] != b"070701" && &header[..6] != b"070702" {
+            return Err(ParseError::InvalidHeader { offset });
+        }
+        let field = |index: usize| {
+            let start = 6 + index * 8;
+            core::str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or(ParseError::InvalidHeader { offset })
+        };
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name size includes the terminating null
+        let name_start = offset + HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .ok_or(ParseError::Truncated { offset })?;
+        let name = name
+            .split_last()
+            .filter(|(last, _)| **last == 0)
+            .and_then(|(_, name)| core::str::from_utf8(name).ok())
+            .ok_or(ParseError::InvalidHeader { offset })?;
+
+        let data_start = align_up(name_start + name_size, 4);
+        let data = archive
+            .get(data_start..data_start + file_size)
+            .ok_or(ParseError::Truncated { offset })?;
+
+        if name == "TRAILER!!!" {
+            break;
+        }
+
+        let path = normalize_path(name);
+        let kind = match mode & S_IFMT {
+            S_IFDIR => Some(EntryKind::Directory),
+            S_IFREG => Some(EntryKind::File),
+            S_IFLNK => Some(EntryKind::Symlink),
+            // Device nodes and the like have no meaning in the ramdisk
+            _ => None,
+        };
+        if let (Some(kind), false) = (kind, path.is_empty()) {
+            entries.push(Entry {
+                path,
+                kind,
+                permissions: mode & 0o7777,
+                data: if kind == EntryKind::Directory {
+                    &[]
+                } else {
+                    data
+                },
+            });
+        }
+
+        offset = align_up(data_start + file_size, 4);
+    }
+
+    Ok(entries)
+}
+
+/// Parses a ustar tar archive.
+///
+/// Each entry is a 512 byte header followed by the contents, padded to 512
+/// bytes. The archive ends with a header of zeroes.
+fn parse_tar(archive: &[u8]) -> Result<Vec<Entry<'_>>, ParseError> {
+    const BLOCK_SIZE: usize = 512;
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = match archive.get(offset..offset + BLOCK_SIZE) {
+            Some(header) => header,
+            // Some archivers omit the end of archive blocks
+            None if offset == archive.len() => break,
+            None => return Err(ParseError::Truncated { offset }),
+        };
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if &header[257..262] != b"ustar" {
+            return Err(ParseError::InvalidHeader { offset });
+        }
+
+        let string = |start: usize, len: usize| {
+            let field = &header[start..start + len];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+            core::str::from_utf8(&field[..end]).map_err(|_| ParseError::InvalidHeader { offset })
+        };
+        let octal = |start: usize, len: usize| {
+            string(start, len).and_then(|field| {
+                usize::from_str_radix(field.trim_matches(|c| c == ' ' || c == '\0'), 8)
+                    .map_err(|_| ParseError::InvalidHeader { offset })
+            })
+        };
+
+        let name = string(0, 100)?;
+        let prefix = string(345, 155)?;
+        let permissions = octal(100, 8)? as u32 & 0o7777;
+        let size = octal(124, 12)?;
+        let type_flag = header[156];
+
+        let data_start = offset + BLOCK_SIZE;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(ParseError::Truncated { offset })?;
+
+        // A path split between the prefix and the name is stored contiguously in
+        // the header, but with padding between them, so it cannot be borrowed.
+        // Such entries are skipped, as they only occur with paths longer than 100
+        // bytes.
+        let kind = match type_flag {
+            b'0' | 0 => Some(EntryKind::File),
+            b'5' => Some(EntryKind::Directory),
+            b'2' => Some(EntryKind::Symlink),
+            _ => None,
+        };
+        let path = normalize_path(name);
+        if let (Some(kind), true, false) = (kind, prefix.is_empty(), path.is_empty()) {
+            let data = match kind {
+                EntryKind::File => data,
+                EntryKind::Directory => &[],
+                // The target of a symbolic link is stored in the header
+                EntryKind::Symlink => string(157, 100)?.as_bytes(),
+            };
+            entries.push(Entry {
+                path,
+                kind,
+                permissions,
+                data,
+            });
+        }
+
+        offset = data_start + align_up(size, BLOCK_SIZE);
+    }
+
+    Ok(entries)
+}
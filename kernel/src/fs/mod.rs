@@ -0,0 +1,240 @@
+//! File systems.
+//!
+//! File systems implement the [FileSystem] and [Inode] traits, and are mounted
+//! into a single tree by the [virtual file system](vfs). Files are then
+//! opened by path, and accessed through [File](file::File) handles.
+
+pub mod devfs;
+pub mod fat;
+pub mod file;
+pub mod initrd;
+pub mod tmpfs;
+pub mod vfs;
+
+use crate::cmdline::CMDLINE;
+use crate::memory;
+use crate::println;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::fmt;
+
+/// An error returned by file system operations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No file or directory exists at the path.
+    NotFound,
+    /// A component of the path used as a directory is not a directory.
+    NotADirectory,
+    /// The operation requires a file, but the path refers to a directory.
+    IsADirectory,
+    /// A file or directory already exists at the path.
+    AlreadyExists,
+    /// The directory to remove is not empty.
+    NotEmpty,
+    /// The file system cannot be modified.
+    ReadOnly,
+    /// The operation is not supported by the file system.
+    NotSupported,
+    /// An argument, like a file name or seek offset, is invalid.
+    InvalidArgument,
+    /// The file descriptor is not open, or not open for the operation.
+    BadFileDescriptor,
+    /// The file descriptor table is full.
+    TooManyOpenFiles,
+    /// Too many symbolic links were followed while resolving a path.
+    SymlinkLoop,
+    /// The operation would move a file between file systems.
+    CrossDevice,
+    /// The file system or directory is in use.
+    Busy,
+    /// There is no space left for the data.
+    NoSpace,
+    /// The file would grow beyond the largest size supported by the file
+    /// system.
+    FileTooLarge,
+    /// Reading from or writing to a device failed.
+    Io,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no such file or directory"),
+            Self::NotADirectory => write!(f, "not a directory"),
+            Self::IsADirectory => write!(f, "is a directory"),
+            Self::AlreadyExists => write!(f, "file exists"),
+            Self::NotEmpty => write!(f, "directory not empty"),
+            Self::ReadOnly => write!(f, "read-only file system"),
+            Self::NotSupported => write!(f, "operation not supported"),
+            Self::InvalidArgument => write!(f, "invalid argument"),
+            Self::BadFileDescriptor => write!(f, "bad file descriptor"),
+            Self::TooManyOpenFiles => write!(f, "too many open files"),
+            Self::SymlinkLoop => write!(f, "too many levels of symbolic links"),
+            Self::CrossDevice => write!(f, "cross-device link"),
+            Self::Busy => write!(f, "device or resource busy"),
+            Self::NoSpace => write!(f, "no space left on device"),
+            Self::FileTooLarge => write!(f, "file too large"),
+            Self::Io => write!(f, "input/output error"),
+        }
+    }
+}
+
+/// The type of an inode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    /// A device accessed a character at a time, like a terminal.
+    CharDevice,
+    /// A device accessed a block at a time, like a disk.
+    BlockDevice,
+}
+
+/// Information about an inode, as returned by `stat`.
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    /// The number of the inode, unique within its file system.
+    pub inode: u64,
+    pub file_type: FileType,
+    /// The permission bits of the mode.
+    pub permissions: u32,
+    /// The size in bytes, or the length of the target for symbolic links.
+    pub size: u64,
+    /// The number of directory entries referring to the inode.
+    pub links: u32,
+}
+
+/// An entry in a directory.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// A file system which can be mounted.
+pub trait FileSystem: Send + Sync {
+    /// Returns the name of the file system type, like `tmpfs`.
+    fn name(&self) -> &'static str;
+
+    /// Returns the root directory.
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// A file, directory, or other object in a file system.
+///
+/// Operations which aren't meaningful for the type of the inode are never
+/// called, so a file is never asked to look up a name, and a directory is
+/// never read from. Paths are resolved by the [virtual file system](vfs), so
+/// names are never empty and never `.` or `..`.
+///
+/// The default implementations of operations which modify the file system
+/// fail with [Error::ReadOnly], and the others with [Error::NotSupported].
+pub trait Inode: Send + Sync {
+    /// Returns information about the inode.
+    fn metadata(&self) -> Metadata;
+
+    /// Returns the inode as [Any], such that a file system can recover its own
+    /// inode type from inodes passed to it.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Reads the contents starting at the given offset into the buffer,
+    /// returning the number of bytes read. Reading at or past the end reads
+    /// nothing.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Writes the buffer starting at the given offset, extending the file if
+    /// necessary, and returns the number of bytes written.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Changes the size of a file, discarding the contents past the new size
+    /// or filling the new space with zeroes.
+    fn truncate(&self, _size: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Returns the target of a symbolic link.
+    fn read_link(&self) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Returns the inode with the given name in a directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Returns the entry at the given position in a directory, or `None` past
+    /// the last entry. The entries for `.` and `..` are not included.
+    fn read_dir(&self, _index: usize) -> Result<Option<DirEntry>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Creates an empty file or directory with the given name in a directory.
+    fn create(
+        &self,
+        _name: &str,
+        _file_type: FileType,
+        _permissions: u32,
+    ) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Creates a symbolic link with the given name and target in a directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Removes the entry for a file or symbolic link from a directory.
+    fn unlink(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Removes an empty directory from a directory.
+    fn rmdir(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Moves the entry with the given name to another name in `new_directory`,
+    /// which is in the same file system. Replaces any existing file at the
+    /// destination, or directory if both are empty directories.
+    fn rename(
+        &self,
+        _name: &str,
+        _new_directory: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+}
+
+/// Mounts a [tmpfs](tmpfs) as the root file system, the [devices](devfs) at
+/// `/dev`, and the initial ramdisk at `/initrd` if there is one.
+///
+/// The size of the tmpfs is given by the `tmpfs_size` command line option, and
+/// defaults to half the usable memory.
+pub fn init() {
+    let size = CMDLINE
+        .get_size("tmpfs_size")
+        .unwrap_or_else(|| memory::usable_memory() / 2);
+    vfs::mount_root(Arc::new(tmpfs::Tmpfs::new(size)))
+        .expect("Could not mount the root file system");
+
+    vfs::mkdir("/dev", 0o755).unwrap();
+    vfs::mount("/dev", Arc::new(devfs::Devfs::new())).expect("Could not mount /dev");
+
+    match initrd::load() {
+        Ok(Some(initrd)) => {
+            vfs::mkdir("/initrd", 0o755).unwrap();
+            vfs::mount("/initrd", Arc::new(initrd::InitrdFs::new(initrd)))
+                .expect("Could not mount the initrd");
+        }
+        Ok(None) => println!("No initrd"),
+        Err(error) => println!("Invalid initrd: {}", error),
+    }
+}
@@ -0,0 +1,464 @@
+//! A writable file system kept entirely in memory.
+//!
+//! File contents are stored in frames of physical memory, one page at a time,
+//! and pages are only allocated once written to. Reading a page which hasn't
+//! been written reads zeroes, so files can have holes. The number of pages
+//! used by a file system is limited, such that it cannot exhaust physical
+//! memory.
+//!
+//! The tree itself is kept on the kernel heap. Inodes are freed, along with
+//! their pages, once they have been removed and are no longer open.
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::memory::{self, PHYS_MEM_OFFSET};
+use crate::sync::spinlock::SpinLock;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The size of the pages file contents are stored in.
+const PAGE_SIZE: u64 = 4096;
+
+/// State shared by all inodes of a file system.
+struct Shared {
+    /// The number of the next inode created.
+    next_inode: AtomicU64,
+    /// The number of pages in use.
+    pages: AtomicUsize,
+    /// The maximum number of pages in use.
+    max_pages: usize,
+    /// Held while renaming, such that two directories are never locked in
+    /// opposite orders.
+    rename_lock: SpinLock<()>,
+}
+
+impl Shared {
+    /// Allocates a zeroed page, returning its physical address.
+    fn allocate_page(&self) -> Result<u64, Error> {
+        // Reserve the page before allocating it, so the limit holds even when
+        // allocating concurrently
+        if self.pages.fetch_add(1, Ordering::Relaxed) >= self.max_pages {
+            self.pages.fetch_sub(1, Ordering::Relaxed);
+            return Err(Error::NoSpace);
+        }
+        match memory::allocate_frame() {
+            Ok(addr) => {
+                unsafe {
+                    core::ptr::write_bytes(
+                        (PHYS_MEM_OFFSET | addr) as *mut u8,
+                        0,
+                        PAGE_SIZE as usize,
+                    );
+                }
+                Ok(addr)
+            }
+            Err(()) => {
+                self.pages.fetch_sub(1, Ordering::Relaxed);
+                Err(Error::NoSpace)
+            }
+        }
+    }
+
+    /// Frees a page allocated with [allocate_page](Self::allocate_page).
+    fn free_page(&self, addr: u64) {
+        unsafe {
+            memory::free_frame(addr);
+        }
+        self.pages.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the contents of the page at the given physical address.
+///
+/// # Safety
+/// The page must be owned by the caller, and not be accessed in other ways
+/// while the slice exists.
+unsafe fn page_mut<'a>(addr: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET | addr) as *mut u8, PAGE_SIZE as usize)
+}
+
+/// An in-memory file system.
+pub struct Tmpfs {
+    shared: Arc<Shared>,
+    root: Arc<TmpfsInode>,
+}
+
+impl Tmpfs {
+    /// Creates an empty file system, storing at most `max_size` bytes of file
+    /// contents.
+    pub fn new(max_size: u64) -> Self {
+        let shared = Arc::new(Shared {
+            next_inode: AtomicU64::new(1),
+            pages: AtomicUsize::new(0),
+            max_pages: (max_size / PAGE_SIZE) as usize,
+            rename_lock: SpinLock::new(()),
+        });
+        let root = TmpfsInode::new(&shared, 0o755, Data::directory());
+        Self { shared, root }
+    }
+
+    /// Returns the number of bytes used and the maximum number of bytes, as
+    /// whole pages.
+    pub fn usage(&self) -> (u64, u64) {
+        (
+            self.shared.pages.load(Ordering::Relaxed) as u64 * PAGE_SIZE,
+            self.shared.max_pages as u64 * PAGE_SIZE,
+        )
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// The contents of an inode.
+enum Data {
+    File {
+        size: u64,
+        /// The physical addresses of the pages which have been written, by
+        /// their index in the file.
+        pages: BTreeMap<u64, u64>,
+    },
+    Directory {
+        entries: BTreeMap<String, Arc<TmpfsInode>>,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+impl Data {
+    fn directory() -> Self {
+        Self::Directory {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            Self::File { .. } => FileType::File,
+            Self::Directory { .. } => FileType::Directory,
+            Self::Symlink { .. } => FileType::Symlink,
+        }
+    }
+}
+
+/// A file, directory, or symbolic link in a [Tmpfs].
+struct TmpfsInode {
+    shared: Arc<Shared>,
+    inode: u64,
+    permissions: u32,
+    data: SpinLock<Data>,
+}
+
+impl TmpfsInode {
+    fn new(shared: &Arc<Shared>, permissions: u32, data: Data) -> Arc<Self> {
+        Arc::new(Self {
+            shared: shared.clone(),
+            inode: shared.next_inode.fetch_add(1, Ordering::Relaxed),
+            permissions,
+            data: SpinLock::new(data),
+        })
+    }
+
+    /// Adds a new inode to a directory.
+    fn insert(&self, name: &str, permissions: u32, data: Data) -> Result<Arc<dyn Inode>, Error> {
+        let mut guard = self.data.lock();
+        let entries = match &mut *guard {
+            Data::Directory { entries } => entries,
+            _ => return Err(Error::NotADirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        let inode = TmpfsInode::new(&self.shared, permissions, data);
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    /// Removes an entry from a directory, if `check` accepts the inode.
+    fn remove(&self, name: &str, check: impl Fn(&Data) -> Result<(), Error>) -> Result<(), Error> {
+        let mut guard = self.data.lock();
+        let entries = match &mut *guard {
+            Data::Directory { entries } => entries,
+            _ => return Err(Error::NotADirectory),
+        };
+        let inode = entries.get(name).ok_or(Error::NotFound)?;
+        check(&inode.data.lock())?;
+        entries.remove(name);
+        Ok(())
+    }
+}
+
+impl Drop for TmpfsInode {
+    fn drop(&mut self) {
+        if let Data::File { pages, .. } = self.data.get_mut() {
+            for &addr in pages.values() {
+                self.shared.free_page(addr);
+            }
+        }
+    }
+}
+
+impl Inode for TmpfsInode {
+    fn metadata(&self) -> Metadata {
+        let data = self.data.lock();
+        let (size, links) = match &*data {
+            Data::File { size, .. } => (*size, 1),
+            Data::Directory { entries } => {
+                // Every subdirectory refers to its parent with `..`
+                let subdirectories = entries
+                    .values()
+                    .filter(|inode| inode.data.lock().file_type() == FileType::Directory)
+                    .count();
+                (entries.len() as u64, 2 + subdirectories as u32)
+            }
+            Data::Symlink { target } => (target.len() as u64, 1),
+        };
+        Metadata {
+            inode: self.inode,
+            file_type: data.file_type(),
+            permissions: self.permissions,
+            size,
+            links,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let data = self.data.lock();
+        let (size, pages) = match &*data {
+            Data::File { size, pages } => (*size, pages),
+            _ => return Err(Error::IsADirectory),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let page_offset = (position % PAGE_SIZE) as usize;
+            let chunk = core::cmp::min(count - done, PAGE_SIZE as usize - page_offset);
+            let target = &mut buffer[done..done + chunk];
+            match pages.get(&(position / PAGE_SIZE)) {
+                Some(&addr) => {
+                    let page = unsafe { page_mut(addr) };
+                    target.copy_from_slice(&page[page_offset..page_offset + chunk]);
+                }
+                // Holes read as zeroes
+                None => target.iter_mut().for_each(|byte| *byte = 0),
+            }
+            done += chunk;
+        }
+
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, Error> {
+        let mut data = self.data.lock();
+        let (size, pages) = match &mut *data {
+            Data::File { size, pages } => (size, pages),
+            _ => return Err(Error::IsADirectory),
+        };
+        offset
+            .checked_add(buffer.len() as u64)
+            .ok_or(Error::InvalidArgument)?;
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let page_offset = (position % PAGE_SIZE) as usize;
+            let chunk = core::cmp::min(buffer.len() - done, PAGE_SIZE as usize - page_offset);
+            let index = position / PAGE_SIZE;
+            let addr = match pages.get(&index) {
+                Some(&addr) => addr,
+                None => match self.shared.allocate_page() {
+                    Ok(addr) => {
+                        pages.insert(index, addr);
+                        addr
+                    }
+                    // Report a short write if anything was written
+                    Err(error) if done == 0 => return Err(error),
+                    Err(_) => break,
+                },
+            };
+            let page = unsafe { page_mut(addr) };
+            page[page_offset..page_offset + chunk].copy_from_slice(&buffer[done..done + chunk]);
+            done += chunk;
+        }
+
+        *size = core::cmp::max(*size, offset + done as u64);
+        Ok(done)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), Error> {
+        let mut data = self.data.lock();
+        let (size, pages) = match &mut *data {
+            Data::File { size, pages } => (size, pages),
+            _ => return Err(Error::IsADirectory),
+        };
+
+        if new_size < *size {
+            // Clear the rest of the last page, such that growing the file again
+            // reads zeroes
+            let last_page = new_size / PAGE_SIZE;
+            let page_offset = (new_size % PAGE_SIZE) as usize;
+            if page_offset != 0 {
+                if let Some(&addr) = pages.get(&last_page) {
+                    let page = unsafe { page_mut(addr) };
+                    page[page_offset..].iter_mut().for_each(|byte| *byte = 0);
+                }
+            }
+
+            // Free the pages past the new end
+            let first_unused = if page_offset == 0 {
+                last_page
+            } else {
+                last_page + 1
+            };
+            let unused = pages.split_off(&first_unused);
+            for &addr in unused.values() {
+                self.shared.free_page(addr);
+            }
+        }
+
+        *size = new_size;
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, Error> {
+        match &*self.data.lock() {
+            Data::Symlink { target } => Ok(target.clone()),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        match &*self.data.lock() {
+            Data::Directory { entries } => match entries.get(name) {
+                Some(inode) => Ok(inode.clone()),
+                None => Err(Error::NotFound),
+            },
+            _ => Err(Error::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        match &*self.data.lock() {
+            Data::Directory { entries } => {
+                Ok(entries.iter().nth(index).map(|(name, inode)| DirEntry {
+                    name: name.clone(),
+                    inode: inode.inode,
+                    file_type: inode.data.lock().file_type(),
+                }))
+            }
+            _ => Err(Error::NotADirectory),
+        }
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        file_type: FileType,
+        permissions: u32,
+    ) -> Result<Arc<dyn Inode>, Error> {
+        let data = match file_type {
+            FileType::File => Data::File {
+                size: 0,
+                pages: BTreeMap::new(),
+            },
+            FileType::Directory => Data::directory(),
+            _ => return Err(Error::NotSupported),
+        };
+        self.insert(name, permissions, data)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Error> {
+        let data = Data::Symlink {
+            target: target.to_string(),
+        };
+        self.insert(name, 0o777, data)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Error> {
+        self.remove(name, |data| match data {
+            Data::Directory { .. } => Err(Error::IsADirectory),
+            _ => Ok(()),
+        })
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), Error> {
+        self.remove(name, |data| match data {
+            Data::Directory { entries } if entries.is_empty() => Ok(()),
+            Data::Directory { .. } => Err(Error::NotEmpty),
+            _ => Err(Error::NotADirectory),
+        })
+    }
+
+    fn rename(&self, name: &str, new_directory: &dyn Inode, new_name: &str) -> Result<(), Error> {
+        let new_directory = match new_directory.as_any().downcast_ref::<TmpfsInode>() {
+            Some(inode) if Arc::ptr_eq(&inode.shared, &self.shared) => inode,
+            _ => return Err(Error::CrossDevice),
+        };
+
+        let _rename_guard = self.shared.rename_lock.lock();
+        let same_directory = core::ptr::eq(self, new_directory);
+        let mut old_data = self.data.lock();
+        let mut new_data = if same_directory {
+            None
+        } else {
+            Some(new_directory.data.lock())
+        };
+
+        let old_entries = match &mut *old_data {
+            Data::Directory { entries } => entries,
+            _ => return Err(Error::NotADirectory),
+        };
+        let inode = old_entries.get(name).ok_or(Error::NotFound)?.clone();
+        let new_entries = match &mut new_data {
+            Some(new_data) => match &mut **new_data {
+                Data::Directory { entries } => entries,
+                _ => return Err(Error::NotADirectory),
+            },
+            None => &mut *old_entries,
+        };
+
+        // Check whether the destination can be replaced
+        if let Some(existing) = new_entries.get(new_name) {
+            if Arc::ptr_eq(existing, &inode) {
+                return Ok(());
+            }
+            let is_directory = inode.data.lock().file_type() == FileType::Directory;
+            match &*existing.data.lock() {
+                Data::Directory { entries } if is_directory && !entries.is_empty() => {
+                    return Err(Error::NotEmpty);
+                }
+                Data::Directory { .. } if !is_directory => return Err(Error::IsADirectory),
+                Data::Directory { .. } => {}
+                _ if is_directory => return Err(Error::NotADirectory),
+                _ => {}
+            }
+        }
+
+        new_entries.insert(new_name.to_string(), inode);
+        if same_directory {
+            new_entries.remove(name);
+        } else {
+            old_entries.remove(name);
+        }
+        Ok(())
+    }
+}
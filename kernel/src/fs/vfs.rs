@@ -0,0 +1,412 @@
+//! The virtual file system, joining mounted file systems into a single tree.
+//!
+//! Paths are resolved through a tree of [Dentry]s, each naming an inode within
+//! its parent directory. Dentries are cached for as long as they're in use, and
+//! remember the parent they were found through, which is how `..` is resolved.
+//!
+//! Mounting a file system on a directory attaches the root of the file system
+//! to the dentry of that directory, hiding its original contents. The root
+//! dentry of a mount takes the name and parent of the directory it's mounted
+//! on, so `..` leaves the mount as expected.
+//!
+//! Relative paths are resolved from the root, except by [lookup_at].
+
+use super::{Error, FileSystem, FileType, Inode, Metadata};
+use crate::sync::mutex::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+/// The number of symbolic links followed while resolving a single path before
+/// giving up.
+const MAX_SYMLINKS: usize = 8;
+
+/// The root of the tree, the root dentry of the root file system.
+static ROOT: Once<Arc<Dentry>> = Once::new();
+
+/// The mounted file systems, with the root file system first.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// The identifier of the next mount.
+static NEXT_MOUNT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A mounted file system.
+struct Mount {
+    id: usize,
+    file_system: Arc<dyn FileSystem>,
+    /// The root dentry of the file system.
+    root: Arc<Dentry>,
+    /// The dentry of the directory the file system is mounted on, or `None` for
+    /// the root file system.
+    mountpoint: Option<Arc<Dentry>>,
+}
+
+/// A named reference to an inode, as found by resolving a path.
+pub struct Dentry {
+    /// The name in the parent directory, empty for the root.
+    name: String,
+    inode: Arc<dyn Inode>,
+    /// The type of the inode, which never changes.
+    file_type: FileType,
+    /// The parent directory, `None` for the root.
+    parent: Option<Arc<Dentry>>,
+    /// The identifier of the mount the inode belongs to.
+    mount: usize,
+    /// The children which have been looked up and are still in use.
+    children: Mutex<BTreeMap<String, Weak<Dentry>>>,
+    /// The root dentry of the file system mounted on this directory.
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(name: &str, inode: Arc<dyn Inode>, parent: Option<Arc<Dentry>>, mount: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            file_type: inode.metadata().file_type,
+            inode,
+            parent,
+            mount,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        }
+    }
+
+    /// Returns the name in the parent directory, or an empty string for the
+    /// root.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the inode.
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// Returns the type of the inode.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Returns the parent directory, or `None` for the root.
+    pub fn parent(&self) -> Option<&Arc<Dentry>> {
+        self.parent.as_ref()
+    }
+
+    /// Returns the absolute path.
+    pub fn path(&self) -> String {
+        let mut names = Vec::new();
+        let mut dentry = self;
+        while let Some(parent) = &dentry.parent {
+            names.push(dentry.name.as_str());
+            dentry = parent;
+        }
+        if names.is_empty() {
+            return "/".to_string();
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        path
+    }
+
+    /// Returns the child with the given name, looking it up in the file system
+    /// unless it's cached. If a file system is mounted on the child, its root
+    /// is returned instead.
+    fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, Error> {
+        if self.file_type != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut children = self.children.lock();
+        let mut dentry = match children.get(name).and_then(Weak::upgrade) {
+            Some(dentry) => dentry,
+            None => {
+                let inode = self.inode.lookup(name)?;
+                let dentry = Arc::new(Dentry::new(name, inode, Some(self.clone()), self.mount));
+                children.insert(name.to_string(), Arc::downgrade(&dentry));
+                dentry
+            }
+        };
+        drop(children);
+
+        // File systems may be mounted on top of each other
+        loop {
+            let root = dentry.mounted.lock().clone();
+            match root {
+                Some(root) => dentry = root,
+                None => return Ok(dentry),
+            }
+        }
+    }
+
+    /// Removes a child from the cache, after it has been removed or renamed.
+    fn forget(&self, name: &str) {
+        self.children.lock().remove(name);
+    }
+}
+
+/// Returns the root of the tree.
+pub fn root() -> Result<Arc<Dentry>, Error> {
+    ROOT.get().cloned().ok_or(Error::NotFound)
+}
+
+/// Mounts a file system as the root of the tree.
+///
+/// The root file system can only be mounted once.
+pub fn mount_root(file_system: Arc<dyn FileSystem>) -> Result<(), Error> {
+    let mut mounts = MOUNTS.lock();
+    if ROOT.get().is_some() {
+        return Err(Error::Busy);
+    }
+    let id = NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed);
+    let root = Arc::new(Dentry::new("", file_system.root(), None, id));
+    ROOT.call_once(|| root.clone());
+    mounts.push(Mount {
+        id,
+        file_system,
+        root,
+        mountpoint: None,
+    });
+    Ok(())
+}
+
+/// Mounts a file system on the directory at the given path.
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<(), Error> {
+    // Looking up a directory with a file system mounted on it returns the root
+    // of that file system, so the new file system is mounted on top of it
+    let mountpoint = lookup(path, true)?;
+    if mountpoint.file_type != FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+    if mountpoint.parent.is_none() {
+        // The root is never looked up by name, so anything mounted on it would
+        // be unreachable
+        return Err(Error::Busy);
+    }
+
+    let mut mounts = MOUNTS.lock();
+    let mut mounted = mountpoint.mounted.lock();
+    if mounted.is_some() {
+        return Err(Error::Busy);
+    }
+
+    let id = NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed);
+    let root = Arc::new(Dentry::new(
+        &mountpoint.name,
+        file_system.root(),
+        mountpoint.parent.clone(),
+        id,
+    ));
+    *mounted = Some(root.clone());
+    drop(mounted);
+    mounts.push(Mount {
+        id,
+        file_system,
+        root,
+        mountpoint: Some(mountpoint),
+    });
+    Ok(())
+}
+
+/// Unmounts the file system mounted at the given path.
+///
+/// Fails with [Error::Busy] if other file systems are mounted within it. Files
+/// which are open remain usable.
+pub fn unmount(path: &str) -> Result<(), Error> {
+    let root = lookup(path, true)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| Arc::ptr_eq(&mount.root, &root))
+        .ok_or(Error::InvalidArgument)?;
+    let mountpoint = match &mounts[index].mountpoint {
+        Some(mountpoint) => mountpoint.clone(),
+        None => return Err(Error::Busy),
+    };
+    let id = mounts[index].id;
+    if mounts.iter().any(|mount| match &mount.mountpoint {
+        Some(mountpoint) => mountpoint.mount == id,
+        None => false,
+    }) {
+        return Err(Error::Busy);
+    }
+
+    *mountpoint.mounted.lock() = None;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Returns the paths and file system names of all mounts.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.root.path(), mount.file_system.name()))
+        .collect()
+}
+
+/// Resolves a path from the root.
+///
+/// If `follow` is set, a symbolic link at the end of the path is followed,
+/// otherwise the link itself is returned. Links within the path are always
+/// followed.
+pub fn lookup(path: &str, follow: bool) -> Result<Arc<Dentry>, Error> {
+    lookup_at(&root()?, path, follow)
+}
+
+/// Resolves a path, starting from the given directory if it's relative.
+pub fn lookup_at(base: &Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>, Error> {
+    if path.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let mut links = 0;
+    walk(base, path, follow, &mut links)
+}
+
+/// Resolves a path, counting the symbolic links followed.
+fn walk(
+    base: &Arc<Dentry>,
+    path: &str,
+    follow: bool,
+    links: &mut usize,
+) -> Result<Arc<Dentry>, Error> {
+    let mut dentry = if path.starts_with('/') {
+        root()?
+    } else {
+        base.clone()
+    };
+
+    let mut components = path
+        .split('/')
+        .filter(|&component| !component.is_empty() && component != ".")
+        .peekable();
+    while let Some(name) = components.next() {
+        if dentry.file_type != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        dentry = if name == ".." {
+            // The parent of the root is the root itself
+            dentry.parent.clone().unwrap_or(dentry)
+        } else {
+            dentry.child(name)?
+        };
+
+        let last = components.peek().is_none();
+        if dentry.file_type == FileType::Symlink && (follow || !last) {
+            if *links == MAX_SYMLINKS {
+                return Err(Error::SymlinkLoop);
+            }
+            *links += 1;
+            let target = dentry.inode.read_link()?;
+            // Every symbolic link has a parent, as the root is a directory
+            let parent = dentry.parent.clone().unwrap();
+            dentry = walk(&parent, &target, true, links)?;
+        }
+    }
+
+    Ok(dentry)
+}
+
+/// Resolves the parent directory of a path, returning it with the last
+/// component of the path.
+fn lookup_parent(path: &str) -> Result<(Arc<Dentry>, &str), Error> {
+    let path = path.trim_end_matches('/');
+    let (directory, name) = match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(end) => (&path[..end], &path[end + 1..]),
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::InvalidArgument);
+    }
+    let directory = lookup(directory, true)?;
+    if directory.file_type != FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+    Ok((directory, name))
+}
+
+/// Returns information about the inode at the given path, following a
+/// symbolic link at the end of the path.
+pub fn stat(path: &str) -> Result<Metadata, Error> {
+    Ok(lookup(path, true)?.inode.metadata())
+}
+
+/// Creates a file or directory at the given path, returning its dentry.
+pub fn create(path: &str, file_type: FileType, permissions: u32) -> Result<Arc<Dentry>, Error> {
+    let (directory, name) = lookup_parent(path)?;
+    directory.inode.create(name, file_type, permissions)?;
+    directory.child(name)
+}
+
+/// Creates a directory at the given path.
+pub fn mkdir(path: &str, permissions: u32) -> Result<(), Error> {
+    create(path, FileType::Directory, permissions).map(|_| ())
+}
+
+/// Creates a symbolic link to `target` at the given path.
+pub fn symlink(target: &str, path: &str) -> Result<(), Error> {
+    let (directory, name) = lookup_parent(path)?;
+    directory.inode.symlink(name, target).map(|_| ())
+}
+
+/// Returns the target of the symbolic link at the given path.
+pub fn read_link(path: &str) -> Result<String, Error> {
+    lookup(path, false)?.inode.read_link()
+}
+
+/// Removes the file or symbolic link at the given path.
+pub fn unlink(path: &str) -> Result<(), Error> {
+    let (directory, name) = lookup_parent(path)?;
+    directory.inode.unlink(name)?;
+    directory.forget(name);
+    Ok(())
+}
+
+/// Removes the empty directory at the given path.
+pub fn rmdir(path: &str) -> Result<(), Error> {
+    let (directory, name) = lookup_parent(path)?;
+    if directory.child(name)?.mount != directory.mount {
+        return Err(Error::Busy);
+    }
+    directory.inode.rmdir(name)?;
+    directory.forget(name);
+    Ok(())
+}
+
+/// Moves the file or directory at `old_path` to `new_path`, within the same
+/// file system.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), Error> {
+    let (old_directory, old_name) = lookup_parent(old_path)?;
+    let (new_directory, new_name) = lookup_parent(new_path)?;
+    if old_directory.mount != new_directory.mount {
+        return Err(Error::CrossDevice);
+    }
+
+    let dentry = old_directory.child(old_name)?;
+    if dentry.mount != old_directory.mount {
+        return Err(Error::Busy);
+    }
+    // A directory cannot be moved into itself
+    if dentry.file_type == FileType::Directory {
+        let mut ancestor = Some(&new_directory);
+        while let Some(directory) = ancestor {
+            if Arc::ptr_eq(directory, &dentry) {
+                return Err(Error::InvalidArgument);
+            }
+            ancestor = directory.parent.as_ref();
+        }
+    }
+
+    old_directory
+        .inode
+        .rename(old_name, new_directory.inode.as_ref(), new_name)?;
+    old_directory.forget(old_name);
+    new_directory.forget(new_name);
+    Ok(())
+}
@@ -1,16 +1,59 @@
-use rk_x86_64::gdt;
+use crate::memory::KernelStack;
+use core::cell::UnsafeCell;
+use rk_x86_64::gdt::{self, TaskStateSegment};
+use spin::Once;
+
+/// The selectors of the segments, which are their offsets in the GDT. The
+/// user data segment precedes the user code segment, as `sysret` expects.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x8;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/// The user selectors request a privilege level of 3.
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+const TSS_SELECTOR: u16 = 0x28;
+
+/// The index in the interrupt stack table of the stack double faults are
+/// handled on, such that overflowing a stack into its guard page can be
+/// reported.
+pub const DOUBLE_FAULT_STACK_INDEX: u16 = 1;
 
 // Create a custom type for the GDT such that the size doesn't have to be
 // updated twice (in the declaration and in the load call) when changed later.
-type GlobalDescriptorTable = [u64; 3];
+type GlobalDescriptorTable = [u64; 7];
+
+/// The TSS, which is read by the CPU whenever an interrupt switches stacks.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// The TSS is only written with interrupts disabled, while switching threads
+unsafe impl Sync for Tss {}
 
-static GDT: GlobalDescriptorTable = [
-    gdt::null(),
-    gdt::kernel_code_segment(),
-    gdt::kernel_data_segment(),
-];
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
+/// The stack double faults are handled on.
+static DOUBLE_FAULT_STACK: Once<KernelStack> = Once::new();
+
+lazy_static! {
+    static ref GDT: GlobalDescriptorTable = {
+        let [tss_low, tss_high] = gdt::tss_segment(TSS.0.get() as u64);
+        [
+            gdt::null(),
+            gdt::kernel_code_segment(),
+            gdt::kernel_data_segment(),
+            gdt::user_data_segment(),
+            gdt::user_code_segment(),
+            tss_low,
+            tss_high,
+        ]
+    };
+}
 
 pub fn init() {
+    let stack = DOUBLE_FAULT_STACK
+        .call_once(|| KernelStack::allocate().expect("Could not allocate the double fault stack"));
+    unsafe {
+        (*TSS.0.get()).interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize - 1] = stack.top();
+    }
+
     unsafe {
         // Load the GDT, this is safe because the GDT is static and will exists for as
         // long as the kernel is running.
@@ -19,19 +62,28 @@ pub fn init() {
             GDT.as_ptr() as u64,
         );
 
-        // Load the code segment register with the kernel code segment (offset 0x8 in
-        // the GDT)
-        rk_x86_64::register::cs::write(0x8);
+        // Load the code segment register with the kernel code segment
+        rk_x86_64::register::cs::write(KERNEL_CODE_SELECTOR);
 
-        // Load the data segment registers with the kernel data segment (offset
-        // 0x10 in the GDT)
+        // Load the data segment registers with the kernel data segment
         asm!(
             "mov ds, ax",
             "mov es, ax",
             "mov fs, ax",
             "mov gs, ax",
             "mov ss, ax",
-            in("ax") 0x10
+            in("ax") KERNEL_DATA_SELECTOR
         );
+
+        gdt::load_tss(TSS_SELECTOR);
     }
 }
+
+/// Sets the stack the CPU switches to when an interrupt arrives in user mode,
+/// which is the kernel stack of the thread running.
+///
+/// # Safety
+/// The stack must stay mapped until another one is set.
+pub unsafe fn set_kernel_stack(top: u64) {
+    (*TSS.0.get()).privilege_stack_table[0] = top;
+}
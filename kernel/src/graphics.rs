@@ -1,9 +1,12 @@
+pub use rk_bootinfo::PixelFormat;
+
 #[derive(Copy, Clone)]
 pub struct Screen {
     fb_base: u64,
     horizontal_resolution: u32,
     vertical_resolution: u32,
     pixels_per_scan_line: u32,
+    pixel_format: PixelFormat,
 }
 
 impl Screen {
@@ -17,12 +20,14 @@ impl Screen {
         horizontal_resolution: u32,
         vertical_resolution: u32,
         pixels_per_scan_line: u32,
+        pixel_format: PixelFormat,
     ) -> Self {
         Screen {
             fb_base,
             horizontal_resolution,
             vertical_resolution,
             pixels_per_scan_line,
+            pixel_format,
         }
     }
 
@@ -49,7 +54,17 @@ impl Screen {
     }
 
     /// Puts a single pixel on the screen at the specified coordinates.
+    ///
+    /// The pixel is given on the form `0x00RRGGBB`, and converted to the pixel
+    /// format of the frame buffer.
     pub fn put_pixel(&self, x: u32, y: u32, pixel: u32) {
+        let pixel = match self.pixel_format {
+            PixelFormat::Bgr => pixel,
+            PixelFormat::Rgb => {
+                (pixel & 0x0000ff00) | (pixel & 0x00ff0000) >> 16 | (pixel & 0x000000ff) << 16
+            }
+        };
+
         // This should be safe as long as the screen buffer is valid
         unsafe {
             (self.fb_base as *mut u32)
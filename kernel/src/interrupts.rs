@@ -1,19 +1,128 @@
+use crate::apic;
+use crate::gdt;
 use crate::println;
-use rk_x86_64::idt::{InterruptDescriptorTable, InterruptFrame};
+use crate::process;
+use crate::sync::spinlock::SpinLock;
+use crate::thread;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use rk_x86_64::idt::{InterruptDescriptorTable, InterruptFrame, InterruptHandler};
+use rk_x86_64::interrupts as cpu;
+use rk_x86_64::register::cr2;
+
+/// The first vector handed out to drivers by [allocate_vector], following the
+/// vectors of the remapped legacy PICs.
+const FIRST_DYNAMIC_VECTOR: u8 = apic::PIC_VECTOR_BASE + 16;
+
+/// A handler of a vector allocated by a driver.
+type Handler = Arc<dyn Fn() + Send + Sync>;
+
+/// Defines an interrupt handler for each of the given indices of the dynamic
+/// vectors, which calls [dispatch] with the index.
+macro_rules! dynamic_handlers {
+    ($($index:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn handler(interrupt_frame: &InterruptFrame) {
+                dispatch($index, interrupt_frame.is_user_mode());
+            }
+            handler as InterruptHandler
+        },)*]
+    };
+}
+
+/// The interrupt handlers of the dynamic vectors.
+const DYNAMIC_HANDLERS: [InterruptHandler; 32] = dynamic_handlers!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+    16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+);
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler(breakpoint_handler);
         idt.double_fault.set_handler(double_fault_handler);
+        // Double faults get a stack of their own, as they may be caused by the
+        // stack overflowing
+        unsafe { idt.double_fault.set_stack_index(gdt::DOUBLE_FAULT_STACK_INDEX) };
+        idt.general_protection_fault.set_handler(general_protection_fault_handler);
+        idt.page_fault.set_handler(page_fault_handler);
+        for vector in apic::PIC_VECTOR_BASE..FIRST_DYNAMIC_VECTOR {
+            idt.interrupts[vector as usize - 32].set_handler(spurious_handler);
+        }
+        for (index, &handler) in DYNAMIC_HANDLERS.iter().enumerate() {
+            idt.interrupts[FIRST_DYNAMIC_VECTOR as usize - 32 + index].set_handler(handler);
+        }
+        idt.interrupts[apic::SPURIOUS_VECTOR as usize - 32].set_handler(spurious_handler);
         idt
     };
+
+    /// The handlers of the dynamic vectors, by index.
+    static ref HANDLERS: SpinLock<Vec<Option<Handler>>> =
+        SpinLock::new(vec![None; DYNAMIC_HANDLERS.len()]);
 }
 
 pub fn init() {
     // Load the IDT, this is safe because the IDT is static and will exists for as
     // long as the kernel is running.
     IDT.load();
+
+    apic::init();
+    lazy_static::initialize(&HANDLERS);
+    cpu::enable();
+}
+
+/// Allocates a vector for a device to signal interrupts with, which calls the
+/// handler when the interrupt arrives. Returns `None` if every vector is in
+/// use.
+///
+/// The handler runs with interrupts disabled, and must not wait for locks
+/// which may be held by the code it interrupts.
+pub fn allocate_vector(handler: impl Fn() + Send + Sync + 'static) -> Option<u8> {
+    let mut handlers = HANDLERS.lock();
+    let index = handlers.iter().position(|handler| handler.is_none())?;
+    handlers[index] = Some(Arc::new(handler));
+    Some(FIRST_DYNAMIC_VECTOR + index as u8)
+}
+
+/// Frees a vector allocated with [allocate_vector]. The device must no longer
+/// signal interrupts with it.
+pub fn free_vector(vector: u8) {
+    HANDLERS.lock()[(vector - FIRST_DYNAMIC_VECTOR) as usize] = None;
+}
+
+/// Waits until the condition holds, halting the CPU until the next interrupt
+/// every time it does not. The condition is checked with interrupts disabled,
+/// so an interrupt making it hold cannot be missed.
+///
+/// Interrupts are enabled while halting, and are enabled afterwards only if
+/// they were before.
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let enabled = cpu::are_enabled();
+    loop {
+        cpu::disable();
+        if condition() {
+            break;
+        }
+        cpu::enable_and_hlt();
+    }
+    if enabled {
+        cpu::enable();
+    }
+}
+
+/// Calls the handler of a dynamic vector, and then switches threads if the
+/// handler made that necessary. Threads interrupted in user mode are stopped
+/// if their process has exited.
+fn dispatch(index: usize, user_mode: bool) {
+    let handler = HANDLERS.lock()[index].clone();
+    if let Some(handler) = handler {
+        handler();
+    }
+    apic::end_of_interrupt();
+    thread::preempt();
+    if user_mode {
+        process::stop_if_exited();
+    }
 }
 
 extern "x86-interrupt" fn breakpoint_handler(interrupt_frame: &InterruptFrame) {
@@ -26,3 +135,42 @@ extern "x86-interrupt" fn double_fault_handler(
 ) -> ! {
     panic!("DOUBLE FAULT: {:#x?}", interrupt_frame);
 }
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    interrupt_frame: &InterruptFrame,
+    error_code: u64,
+) {
+    if interrupt_frame.is_user_mode() {
+        println!(
+            "General protection fault in user mode at {:#x} (error code {:#x})",
+            interrupt_frame.instruction_pointer(),
+            error_code
+        );
+        process::exit(process::EXIT_CODE_FAULT);
+    }
+    panic!(
+        "GENERAL PROTECTION FAULT (error code {:#x}): {:#x?}",
+        error_code, interrupt_frame
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(interrupt_frame: &InterruptFrame, error_code: u64) {
+    let address = cr2::read();
+    if interrupt_frame.is_user_mode() {
+        println!(
+            "Page fault in user mode at {:#x} accessing {:#x} (error code {:#x})",
+            interrupt_frame.instruction_pointer(),
+            address,
+            error_code
+        );
+        process::exit(process::EXIT_CODE_FAULT);
+    }
+    panic!(
+        "PAGE FAULT accessing {:#x} (error code {:#x}): {:#x?}",
+        address, error_code, interrupt_frame
+    );
+}
+
+/// Handles spurious interrupts, from the local APIC or the masked legacy PICs,
+/// which are not acknowledged.
+extern "x86-interrupt" fn spurious_handler(_interrupt_frame: &InterruptFrame) {}
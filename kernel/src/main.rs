@@ -3,80 +3,206 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(asm)]
+#![feature(naked_functions)]
 
 #[macro_use]
 extern crate alloc;
 #[macro_use]
 extern crate lazy_static;
 
+mod acpi;
+mod apic;
+mod block;
+mod cmdline;
+mod drivers;
+mod fs;
 mod gdt;
 mod graphics;
 mod interrupts;
 mod memory;
+mod modules;
+mod net;
+mod pci;
+mod process;
 mod psf2;
+mod sync;
+mod syscall;
 mod terminal;
+mod thread;
+mod time;
 
 use crate::graphics::Screen;
+use crate::sync::spinlock::SpinLock;
 use crate::terminal::Terminal;
 use core::panic::PanicInfo;
-use spin::Mutex;
-
-/// The data structure passed to the kernel on entry.
-#[repr(C)]
-pub struct EntryData {
-    greeting: u32,
-    fb_base: u64,
-    fb_horizontal_resolution: u32,
-    fb_vertical_resolution: u32,
-    fb_pixels_per_scan_line: u32,
-}
+use rk_bootinfo::BootInfo;
+use spin::Once;
+
+/// The boot information passed by the bootloader, set on entry.
+static BOOT_INFO: Once<BootInfo<'static>> = Once::new();
 
-extern "C" {
-    pub static entry_data: EntryData;
+/// Returns the boot information passed by the bootloader.
+pub fn boot_info() -> &'static BootInfo<'static> {
+    BOOT_INFO.get().expect("Boot information is not set")
 }
 
 lazy_static! {
     pub static ref SCREEN: Screen = unsafe {
         // Initialize a screen from the frame buffer provided by the bootloader, which
-        // should satisfy the safety requirements.
+        // should satisfy the safety requirements. Its presence is checked on entry.
+        let framebuffer = boot_info().framebuffer().unwrap();
         Screen::new(
-            memory::PHYS_MEM_OFFSET | entry_data.fb_base,
-            entry_data.fb_horizontal_resolution,
-            entry_data.fb_vertical_resolution,
-            entry_data.fb_pixels_per_scan_line,
+            memory::PHYS_MEM_OFFSET | framebuffer.base,
+            framebuffer.width,
+            framebuffer.height,
+            framebuffer.stride,
+            framebuffer.pixel_format(),
         )
     };
 
-    // Initialize a text terminal on the screen provided by the bootloader.
-    pub static ref TERMINAL: Mutex<Terminal<'static>> = Mutex::new(Terminal::new(&SCREEN));
+    // Initialize a text terminal on the screen provided by the bootloader. It's
+    // locked with interrupts disabled, so interrupt handlers may print.
+    pub static ref TERMINAL: SpinLock<Terminal<'static>> = SpinLock::new(Terminal::new(&SCREEN));
 }
 
+/// The kernel entry point, called by the bootloader with the virtual address
+/// of the boot information.
 #[no_mangle]
-fn _start() -> ! {
+extern "C" fn _start(boot_info_addr: u64) -> ! {
+    // Without valid boot information there is no frame buffer to report errors
+    // on, so we can only hang. This also rejects bootloaders passing another
+    // version of the boot information.
+    match unsafe { BootInfo::from_ptr(boot_info_addr as *const u8) } {
+        Ok(boot_info) if boot_info.framebuffer().is_some() => {
+            BOOT_INFO.call_once(|| boot_info);
+        }
+        _ => rk_x86_64::hang(),
+    }
+
     memory::init();
     gdt::init();
     interrupts::init();
+    time::init();
+    thread::init();
+    syscall::init();
 
     // Clear the screen
     SCREEN.clear();
 
-    // Print the digits
-    for c in 0..10 {
-        println!("{}", c);
+    println!("Command line: {}", cmdline::CMDLINE.as_str());
+    if let Some(init) = cmdline::CMDLINE.get("init") {
+        println!("init = {}", init);
+    }
+    for module in modules::modules() {
+        println!("Module {}: {} bytes", module.name, module.data.len());
     }
 
-    // Test the kernel heap by using vectors
-    let mut vector1 = vec![10, 20, 30];
-    println!("Vector1 = {:?}", vector1);
-    let mut vector2 = vec![70, 80, 90];
-    vector1.append(&mut vector2);
-    println!("Vector1+2 = {:?}", vector1);
+    pci::init();
+    drivers::init();
+    fs::init();
+    net::init();
 
-    loop {}
+    // List the root directory
+    if let Ok(root) = fs::file::open("/", fs::file::OpenFlags::READ, 0) {
+        while let Ok(Some(entry)) = root.read_dir() {
+            println!("/{} ({:?})", entry.name, entry.file_type);
+        }
+    }
+
+    // Skip the tests below when asked to be quiet
+    if cmdline::CMDLINE.get_bool("quiet") != Some(true) {
+        // Print the digits
+        for c in 0..10 {
+            println!("{}", c);
+        }
+
+        // Test the kernel heap by using vectors
+        let mut vector1 = vec![10, 20, 30];
+        println!("Vector1 = {:?}", vector1);
+        let mut vector2 = vec![70, 80, 90];
+        vector1.append(&mut vector2);
+        println!("Vector1+2 = {:?}", vector1);
+
+        // Test the scheduler by waiting for threads sleeping different times
+        let handles: alloc::vec::Vec<_> = (1..=3)
+            .map(|i| {
+                thread::spawn("test", thread::Priority::Normal, move || {
+                    thread::sleep(core::time::Duration::from_millis(10 * i));
+                    i
+                })
+                .expect("Could not spawn a test thread")
+            })
+            .collect();
+        for handle in handles {
+            println!(
+                "Thread {} returned {:?}",
+                handle.thread().id(),
+                handle.join()
+            );
+        }
+    }
+
+    // Echo TCP connections to the port, which the host can test the network
+    // stack against through a port forwarded by QEMU. The server runs in a
+    // thread of its own, so it keeps running alongside init
+    if let Some(port) = cmdline::CMDLINE.get_parsed::<u16>("echo_port") {
+        thread::spawn("echo", thread::Priority::Normal, move || echo_server(port))
+            .expect("Could not spawn the echo server thread");
+    }
+
+    // Run the program given by the init option
+    if let Some(path) = cmdline::CMDLINE.get("init") {
+        run_init(path);
+    }
+
+    // The other threads keep running, and the idle thread takes over once
+    // they're all blocked
+    thread::exit()
+}
+
+/// Runs the program at the path as the first process, with the console as its
+/// standard input, output, and error, and waits for it to exit.
+fn run_init(path: &str) {
+    let mut files = fs::file::FileTable::new();
+    let flags = fs::file::OpenFlags::READ | fs::file::OpenFlags::WRITE;
+    match fs::file::open("/dev/console", flags, 0) {
+        Ok(console) => {
+            for _ in 0..3 {
+                files.insert(console.clone()).unwrap();
+            }
+        }
+        Err(error) => println!("Could not open the console: {}", error),
+    }
+    match process::loader::spawn(path, &[path], &[], files) {
+        Ok(process) => println!("{} exited with code {}", path, process.wait()),
+        Err(error) => println!("Could not start {}: {}", path, error),
+    }
+}
+
+/// Echoes the data received on TCP connections to the port, serving one
+/// connection at a time.
+fn echo_server(port: u16) -> ! {
+    let listener = net::tcp::TcpListener::bind(port).expect("Could not bind the echo port");
+    println!("Echoing TCP connections on port {}", port);
+    let mut buffer = [0; 1024];
+    loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        while let Ok(length) = stream.read(&mut buffer) {
+            if length == 0 || stream.write_all(&buffer[..length]).is_err() {
+                break;
+            }
+        }
+    }
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // The panic may have happened while printing, and nothing else runs after
+    // it, so the terminal can be taken over
+    unsafe { TERMINAL.force_unlock() };
     println!("{}", info);
 
     loop {}
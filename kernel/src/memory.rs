@@ -1,7 +1,11 @@
+use crate::cmdline::CMDLINE;
+use crate::sync::spinlock::SpinLock;
+use alloc::vec::Vec;
 use core::alloc::{GlobalAlloc, Layout};
-use rk_x86_64::paging::{PageTable, PageTableEntry};
-use rk_x86_64::register::cr3;
-use spin::Mutex;
+use rk_bootinfo::{MemoryRegion, MemoryRegionKind};
+use rk_x86_64::paging::{self, PageTable, PageTableEntry};
+use rk_x86_64::register::{cr3, msr};
+use spin::Once;
 
 //
 //                            x86-64 Memory Map
@@ -11,33 +15,84 @@ use spin::Mutex;
 //
 //        Higher Half
 // (1)    0xFFFF_8000_0000_0000 - 0xFFFF_807F_FFFF_FFFF    (512 GiB)
-// (2)    0xFFFF_8080_0000_0000 - 0xFFFF_FF7F_FFFF_FFFF    (127 TiB)
-// (3)    0xFFFF_FF80_0000_0000 - 0xFFFF_FFFF_FFFF_FFFF    (512 GiB)
+// (2)    0xFFFF_8080_0000_0000 - 0xFFFF_FEFF_FFFF_FFFF    (126.5 TiB)
+// (3)    0xFFFF_FF00_0000_0000 - 0xFFFF_FF7F_FFFF_FFFF    (512 GiB)
+// (4)    0xFFFF_FF80_0000_0000 - 0xFFFF_FFFF_FFFF_FFFF    (512 GiB)
 //
-// (0)    Free
+// (0)    User Processes
 // (1)    Physical Memory Mapping
 // (2)    Free
-// (3)    Kernel                       (Last entry of PML4)
+// (3)    Kernel Stacks
+// (4)    Kernel                       (Last entry of PML4)
 //
 
 pub const PHYS_MEM_OFFSET: u64 = 0xffff_8000_0000_0000;
 
-// Creates a new bump allocator starting at 32 MiB.
-static FRAME_ALLOCATOR: LockedBumpAllocator = unsafe { LockedBumpAllocator::new(0x2000000) };
+/// The address just above the lower half, which user processes map in.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Page table entry flags.
+pub const PAGE_PRESENT: u64 = 1 << 0;
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+/// The page is accessible in user mode.
+pub const PAGE_USER: u64 = 1 << 2;
+/// Instructions cannot be fetched from the page.
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+
+// The frame allocator, which allocates from the usable regions of the memory
+// map once initialized.
+static FRAME_ALLOCATOR: LockedBumpAllocator = LockedBumpAllocator::new();
+
+/// The mapper of the kernel address space, set once it's activated.
+static MAPPER: Once<SpinLock<Mapper<'static, LockedBumpAllocator>>> = Once::new();
+
+/// The physical address of the PML4 of the kernel address space.
+static KERNEL_PML4: Once<u64> = Once::new();
 
 /// The virtual starting address of the kernel heap.
 const HEAP_BASE: u64 = 0xffff_ff80_0000_0000;
 
+/// The size of the kernel heap, 4 MiB.
+const HEAP_SIZE: usize = 0x40_0000;
+
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeapAllocator = LockedHeapAllocator::new(HEAP_BASE);
+static HEAP_ALLOCATOR: LockedHeapAllocator = LockedHeapAllocator::new();
+
+/// The virtual starting address of the kernel stacks.
+const KERNEL_STACKS_BASE: u64 = 0xffff_ff00_0000_0000;
+
+/// The virtual space reserved for each kernel stack. Only the top
+/// [KERNEL_STACK_SIZE] bytes are mapped, so a stack overflowing runs into
+/// unmapped memory rather than another stack.
+const KERNEL_STACK_SLOT_SIZE: u64 = 0x2_0000;
+
+/// The size of a kernel stack, 64 KiB.
+pub const KERNEL_STACK_SIZE: usize = 0x1_0000;
+
+/// The slots of the kernel stacks which have been freed, followed by the
+/// index of the first slot never used.
+static KERNEL_STACK_SLOTS: SpinLock<(Vec<u64>, u64)> = SpinLock::new((Vec::new(), 0));
 
 pub fn init() {
+    let boot_info = crate::boot_info();
+    FRAME_ALLOCATOR.init(boot_info.memory_map());
+
+    // The modules are in memory allocated by the bootloader, which is never
+    // usable, but make sure they're never handed out regardless
+    for module in boot_info.modules() {
+        FRAME_ALLOCATOR
+            .reserve(module.start, module.size)
+            .expect("Too many reserved memory ranges");
+    }
+
+    // Limit the usable physical memory if requested on the command line
+    if let Some(mem) = CMDLINE.get_size("mem") {
+        FRAME_ALLOCATOR.limit(mem);
+    }
+
     let pml4_phys_addr = FRAME_ALLOCATOR
         .allocate(1)
         .expect("Could not allocate memory for PML4");
-    let mapper =
-        Mutex::new(unsafe { Mapper::new(PHYS_MEM_OFFSET | pml4_phys_addr, &FRAME_ALLOCATOR) });
-
     // Zero out the PML4
     unsafe {
         core::ptr::write_bytes(pml4_phys_addr as *mut u64, 0, 512);
@@ -58,24 +113,324 @@ pub fn init() {
         );
     }
 
-    // Activate the new memory mapping
+    // Enable the no-execute flag, and activate the new memory mapping
     unsafe {
+        msr::write(
+            msr::IA32_EFER,
+            msr::read(msr::IA32_EFER) | msr::EFER_NO_EXECUTE_ENABLE,
+        );
         cr3::write(pml4_phys_addr);
     }
-
-    // Allocate and map 80 KiB of memory for the kernel heap
+    KERNEL_PML4.call_once(|| pml4_phys_addr);
+    let mapper = MAPPER.call_once(|| {
+        SpinLock::new(unsafe { Mapper::new(PHYS_MEM_OFFSET | pml4_phys_addr, &FRAME_ALLOCATOR) })
+    });
+
+    // Address spaces of processes copy the higher half of the PML4 when they're
+    // created, so every kernel region needs its PDP before then
+    mapper
+        .lock()
+        .create_pdp(KERNEL_STACKS_BASE)
+        .expect("Could not allocate the PDP of the kernel stacks");
+
+    // Allocate and map memory for the kernel heap
+    let heap_pages = HEAP_SIZE / 4096;
     let heap_phys_addr = FRAME_ALLOCATOR
-        .allocate(20)
+        .allocate(heap_pages)
         .expect("Could not allocate memory for the heap");
-    for i in 0..20 {
+    for i in 0..heap_pages as u64 {
         unsafe {
             mapper
                 .lock()
                 .map(HEAP_BASE + 0x1000 * i, heap_phys_addr + 0x1000 * i, 0b11)
+                .expect("Could not map the heap")
+        }
+    }
+    unsafe {
+        HEAP_ALLOCATOR.init(HEAP_BASE, HEAP_SIZE);
+    }
+}
+
+/// Returns the total size of the usable regions of the memory map.
+pub fn usable_memory() -> u64 {
+    crate::boot_info()
+        .memory_map()
+        .iter()
+        .filter(|region| region.kind() == MemoryRegionKind::Usable)
+        .map(|region| region.size)
+        .sum()
+}
+
+/// Allocates a frame of physical memory, returning its physical address.
+///
+/// The frame is accessible at the same offset from [PHYS_MEM_OFFSET].
+pub fn allocate_frame() -> Result<u64, ()> {
+    FRAME_ALLOCATOR.allocate(1)
+}
+
+/// Frees a frame allocated with [allocate_frame].
+///
+/// # Safety
+/// The frame must not be used after it's freed.
+pub unsafe fn free_frame(addr: u64) {
+    FRAME_ALLOCATOR.free(addr, 1)
+}
+
+/// A stack for a kernel thread, mapped in its own slot of the kernel stack
+/// region.
+pub struct KernelStack {
+    slot: u64,
+}
+
+impl KernelStack {
+    /// Allocates and maps a stack of [KERNEL_STACK_SIZE] bytes.
+    pub fn allocate() -> Result<Self, ()> {
+        let slot = {
+            let mut slots = KERNEL_STACK_SLOTS.lock();
+            match slots.0.pop() {
+                Some(slot) => slot,
+                None => {
+                    slots.1 += 1;
+                    slots.1 - 1
+                }
+            }
+        };
+        // Dropping the stack frees the pages mapped so far if a frame cannot be
+        // allocated
+        let stack = Self { slot };
+        let mut mapper = MAPPER.get().expect("Memory is not initialized").lock();
+        for page in (stack.bottom()..stack.top()).step_by(4096) {
+            let frame = allocate_frame()?;
+            if unsafe { mapper.map(page, frame, PAGE_PRESENT | PAGE_WRITABLE) }.is_err() {
+                unsafe { free_frame(frame) };
+                return Err(());
+            }
+        }
+        Ok(stack)
+    }
+
+    /// Returns the lowest address of the stack.
+    fn bottom(&self) -> u64 {
+        self.top() - KERNEL_STACK_SIZE as u64
+    }
+
+    /// Returns the address just above the stack, where the stack pointer of an
+    /// empty stack points.
+    pub fn top(&self) -> u64 {
+        KERNEL_STACKS_BASE + (self.slot + 1) * KERNEL_STACK_SLOT_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut mapper = MAPPER.get().unwrap().lock();
+        for page in (self.bottom()..self.top()).step_by(4096) {
+            unsafe {
+                if let Some(frame) = mapper.unmap(page) {
+                    free_frame(frame);
+                }
+            }
         }
+        KERNEL_STACK_SLOTS.lock().0.push(self.slot);
     }
 }
 
+/// Switches to the address space with the PML4 at the given physical address,
+/// unless it's active already.
+///
+/// # Safety
+/// The PML4 must map the higher half like the kernel address space.
+pub unsafe fn switch_address_space(pml4_addr: u64) {
+    if cr3::read() & !0xfff != pml4_addr {
+        cr3::write(pml4_addr);
+    }
+}
+
+/// The address space of a user process. It has a PML4 of its own mapping the
+/// lower half, and shares the higher half with the kernel.
+pub struct AddressSpace {
+    /// The physical address of the PML4.
+    pml4_addr: u64,
+    mapper: Mapper<'static, LockedBumpAllocator>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the lower half.
+    pub fn new() -> Result<Self, ()> {
+        let pml4_addr = allocate_frame()?;
+        let kernel_pml4_addr = *KERNEL_PML4.get().expect("Memory is not initialized");
+        unsafe {
+            let pml4 = (PHYS_MEM_OFFSET | pml4_addr) as *mut u64;
+            let kernel_pml4 = (PHYS_MEM_OFFSET | kernel_pml4_addr) as *const u64;
+            core::ptr::write_bytes(pml4, 0, 256);
+            core::ptr::copy_nonoverlapping(kernel_pml4.add(256), pml4.add(256), 256);
+            Ok(Self {
+                pml4_addr,
+                mapper: Mapper::new(PHYS_MEM_OFFSET | pml4_addr, &FRAME_ALLOCATOR),
+            })
+        }
+    }
+
+    /// Returns the physical address of the PML4, which is loaded into CR3 to
+    /// activate the address space.
+    pub fn pml4_addr(&self) -> u64 {
+        self.pml4_addr
+    }
+
+    /// Maps a zeroed page at a page aligned virtual address in the lower half,
+    /// which is present and user accessible in addition to the given flags.
+    /// The page mapped at the address before, if any, is freed.
+    pub fn map_zeroed(&mut self, virt: u64, flags: u64) -> Result<(), ()> {
+        if virt >= USER_END {
+            return Err(());
+        }
+        let frame = allocate_frame()?;
+        unsafe {
+            core::ptr::write_bytes((PHYS_MEM_OFFSET | frame) as *mut u8, 0, 4096);
+            self.unmap(virt);
+            if self
+                .mapper
+                .map(virt, frame, flags | PAGE_PRESENT | PAGE_USER)
+                .is_err()
+            {
+                free_frame(frame);
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps and frees the page at a virtual address in the lower half, if
+    /// one is mapped.
+    ///
+    /// # Safety
+    /// The memory must no longer be accessed, which user processes can only be
+    /// trusted with for their own memory.
+    pub unsafe fn unmap(&mut self, virt: u64) {
+        if virt >= USER_END {
+            return;
+        }
+        if let Some(frame) = self.mapper.unmap(virt) {
+            free_frame(frame);
+        }
+    }
+
+    /// Reads memory mapped in the lower half into the buffer. Fails without
+    /// reading anything if any of the memory is not mapped.
+    pub fn read(&self, virt: u64, buffer: &mut [u8]) -> Result<(), ()> {
+        let end = virt.checked_add(buffer.len() as u64).ok_or(())?;
+        let pages = (virt & !0xfff..end).step_by(4096);
+        if pages
+            .clone()
+            .any(|page| self.translate(page, false).is_none())
+        {
+            return Err(());
+        }
+        let mut read = 0;
+        while read < buffer.len() {
+            let addr = virt + read as u64;
+            let len = ((4096 - (addr & 0xfff)) as usize).min(buffer.len() - read);
+            let phys = self.translate(addr, false).unwrap();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (PHYS_MEM_OFFSET | phys) as *const u8,
+                    buffer[read..].as_mut_ptr(),
+                    len,
+                );
+            }
+            read += len;
+        }
+        Ok(())
+    }
+
+    /// Writes data to memory mapped in the lower half, regardless of whether
+    /// the process may write to it. Fails without writing anything if any of
+    /// the memory is not mapped.
+    pub fn write(&mut self, virt: u64, data: &[u8]) -> Result<(), ()> {
+        let end = virt.checked_add(data.len() as u64).ok_or(())?;
+        let pages = (virt & !0xfff..end).step_by(4096);
+        if pages
+            .clone()
+            .any(|page| self.translate(page, false).is_none())
+        {
+            return Err(());
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let addr = virt + written as u64;
+            let len = ((4096 - (addr & 0xfff)) as usize).min(data.len() - written);
+            let phys = self.translate(addr, false).unwrap();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    (PHYS_MEM_OFFSET | phys) as *mut u8,
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address a virtual address is mapped to, if its
+    /// page is user accessible, and writable if asked for.
+    pub fn translate(&self, virt: u64, writable: bool) -> Option<u64> {
+        let entry = self.mapper.page_entry(virt)?;
+        let flags = if writable {
+            PAGE_USER | PAGE_WRITABLE
+        } else {
+            PAGE_USER
+        };
+        if virt >= USER_END || entry.data() & flags != flags {
+            return None;
+        }
+        self.mapper.translate(virt).ok()
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            // The kernel may still run in the address space, if the process was
+            // the last one to run
+            if cr3::read() & !0xfff == self.pml4_addr {
+                cr3::write(*KERNEL_PML4.get().unwrap());
+            }
+
+            // Free the lower half, along with the page tables mapping it
+            let pml4 = PageTable::new(PHYS_MEM_OFFSET | self.pml4_addr);
+            for i in 0..256 {
+                let entry = pml4.entry(i);
+                if entry.is_present() {
+                    free_table(entry.addr(), 3);
+                }
+            }
+            free_frame(self.pml4_addr);
+        }
+    }
+}
+
+/// Frees a page table, along with the tables and pages it maps. The level is
+/// 3 for a PDP, 2 for a PD, and 1 for a PT.
+///
+/// # Safety
+/// Nothing mapped through the table may be in use.
+unsafe fn free_table(addr: u64, level: u8) {
+    let table = PageTable::new(PHYS_MEM_OFFSET | addr);
+    for i in 0..512 {
+        let entry = table.entry(i);
+        if !entry.is_present() {
+            continue;
+        }
+        if level == 1 {
+            free_frame(entry.addr());
+        } else {
+            free_table(entry.addr(), level - 1);
+        }
+    }
+    free_frame(addr);
+}
+
 /// Maps 512 GiBs of physical memory.
 ///
 /// Assumes the memory is still identity mapped.
@@ -114,31 +469,134 @@ fn map_physical_memory(pml4_addr: u64) {
     }
 }
 
+/// A free block of heap memory, stored in the block itself.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// The granularity of heap allocations, large enough to fit a [FreeBlock] in
+/// every block.
+const HEAP_BLOCK_SIZE: usize = 16;
+
+/// Allocates from a list of free blocks sorted by address, using the first
+/// block large enough.
+///
+/// Freed blocks are merged with adjacent free blocks, so the list stays short
+/// unless the heap is fragmented.
 struct HeapAllocator {
-    start: u64,
-    // TODO: Limit size
+    head: *mut FreeBlock,
 }
 
+// The free blocks are only accessed through the allocator
+unsafe impl Send for HeapAllocator {}
+
 impl HeapAllocator {
+    /// Rounds the size of an allocation up to a whole number of blocks.
+    fn block_size(layout: &Layout) -> usize {
+        let size = core::cmp::max(layout.size(), HEAP_BLOCK_SIZE);
+        (size + HEAP_BLOCK_SIZE - 1) & !(HEAP_BLOCK_SIZE - 1)
+    }
+
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        if self.start % layout.align() as u64 != 0 {
-            self.start += self.start % layout.align() as u64;
+        let size = Self::block_size(&layout);
+        let align = core::cmp::max(layout.align(), HEAP_BLOCK_SIZE);
+
+        let mut previous: *mut FreeBlock = core::ptr::null_mut();
+        let mut block = self.head;
+        while !block.is_null() {
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+            let start = (block_start + align - 1) & !(align - 1);
+            let end = start + size;
+            if end > block_end {
+                previous = block;
+                block = (*block).next;
+                continue;
+            }
+
+            // Keep the space after the allocation free
+            let next = if end < block_end {
+                let rest = end as *mut FreeBlock;
+                rest.write(FreeBlock {
+                    size: block_end - end,
+                    next: (*block).next,
+                });
+                rest
+            } else {
+                (*block).next
+            };
+
+            // Keep the space before the allocation free, which is a whole number
+            // of blocks since both addresses are aligned to the block size
+            if start > block_start {
+                (*block).size = start - block_start;
+                (*block).next = next;
+            } else if previous.is_null() {
+                self.head = next;
+            } else {
+                (*previous).next = next;
+            }
+
+            return start as *mut u8;
         }
-        let addr = self.start;
-        self.start += layout.size() as u64;
-        addr as *mut u8
+
+        core::ptr::null_mut()
     }
 
-    unsafe fn dealloc(&mut self, _ptr: *mut u8, _layout: Layout) {
-        // TODO
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize;
+        let size = Self::block_size(&layout);
+
+        // Find the free blocks surrounding the freed block
+        let mut previous: *mut FreeBlock = core::ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            previous = next;
+            next = (*next).next;
+        }
+
+        let block = ptr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if previous.is_null() {
+            self.head = block;
+        } else if previous as usize + (*previous).size == start {
+            (*previous).size += (*block).size;
+            (*previous).next = (*block).next;
+        } else {
+            (*previous).next = block;
+        }
     }
 }
 
-struct LockedHeapAllocator(Mutex<HeapAllocator>);
+// The scheduler allocates in interrupt handlers, so the heap must not be
+// interrupted while locked
+struct LockedHeapAllocator(SpinLock<HeapAllocator>);
 
 impl LockedHeapAllocator {
-    pub const fn new(start: u64) -> Self {
-        Self(Mutex::new(HeapAllocator { start }))
+    pub const fn new() -> Self {
+        Self(SpinLock::new(HeapAllocator {
+            head: core::ptr::null_mut(),
+        }))
+    }
+
+    /// Starts allocating from the given memory, which must be aligned to
+    /// [HEAP_BLOCK_SIZE].
+    ///
+    /// # Safety
+    /// The memory must be mapped and unused.
+    unsafe fn init(&self, start: u64, size: usize) {
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock {
+            size,
+            next: core::ptr::null_mut(),
+        });
+        self.0.lock().head = block;
     }
 }
 
@@ -205,92 +663,122 @@ impl<'a, A: FrameAllocator> Mapper<'a, A> {
         }
     }
 
+    /// Returns the table referenced by the entry of a table at the index,
+    /// creating it if it's not present. Tables leading to user accessible
+    /// pages are made user accessible as well.
+    ///
+    /// # Safety
+    /// The table must be a valid page table above the level of page tables.
+    unsafe fn next_table(
+        &self,
+        table: &PageTable,
+        index: u16,
+        user: bool,
+    ) -> Result<PageTable, ()> {
+        let entry = table.entry(index);
+        let user_flag = if user { PAGE_USER } else { 0 };
+        if entry.is_present() {
+            if entry.data() & user_flag != user_flag {
+                table.set_entry(
+                    index,
+                    PageTableEntry::new(entry.addr(), entry.data() & 0xfff | user_flag),
+                );
+            }
+            return Ok(PageTable::new(PHYS_MEM_OFFSET | entry.addr()));
+        }
+
+        let addr = self.frame_allocator.allocate(1)?;
+        // Zero out the new table
+        core::ptr::write_bytes((PHYS_MEM_OFFSET | addr) as *mut u64, 0, 512);
+        table.set_entry(
+            index,
+            PageTableEntry::new(addr, PAGE_PRESENT | PAGE_WRITABLE | user_flag),
+        );
+        Ok(PageTable::new(PHYS_MEM_OFFSET | addr))
+    }
+
+    /// Returns the page table holding the entry of a virtual address, if it's
+    /// present.
+    fn page_table(&self, virt: &VirtAddr) -> Option<PageTable> {
+        let lower = |table: &PageTable, index| {
+            let entry = table.entry(index);
+            if entry.is_present() {
+                Some(unsafe { PageTable::new(PHYS_MEM_OFFSET | entry.addr()) })
+            } else {
+                None
+            }
+        };
+        let pdp = lower(&self.pml4, virt.pml4_index())?;
+        let pd = lower(&pdp, virt.pdp_index())?;
+        lower(&pd, virt.pd_index())
+    }
+
+    /// Creates the PDP covering a virtual address, if it's not present.
+    fn create_pdp(&self, virt: u64) -> Result<(), ()> {
+        let virt = VirtAddr::new(virt);
+        unsafe { self.next_table(&self.pml4, virt.pml4_index(), false) }.map(|_| ())
+    }
+
     /// Maps a virtual address to a physical address, allocating space for new
-    /// page tables as necessary.
+    /// page tables as necessary. Fails if a page table cannot be allocated.
     ///
     /// # Safety
     /// Overwrites any existing mappings at the same address, which can break
     /// memory safety.
-    unsafe fn map(&mut self, virt: u64, phys: u64, flags: u64) {
+    unsafe fn map(&mut self, virt: u64, phys: u64, flags: u64) -> Result<(), ()> {
         assert!(virt % 4096 == 0, "virt is not page aligned");
         assert!(phys % 4096 == 0, "phys is not page aligned");
 
         // TODO: Support for different page sizes.
 
-        // TODO: This function (like its bootloader-counterpart) could do with some
-        // cleaning up.
-
         let virt = VirtAddr::new(virt);
+        let user = flags & PAGE_USER != 0;
+        let pdp = self.next_table(&self.pml4, virt.pml4_index(), user)?;
+        let pd = self.next_table(&pdp, virt.pdp_index(), user)?;
+        let pt = self.next_table(&pd, virt.pd_index(), user)?;
 
-        let pdp_entry = self.pml4.entry(virt.pml4_index());
-        let pdp_phys_addr: u64;
-        let pdp_virt_addr: VirtAddr;
-        if pdp_entry.is_present() {
-            pdp_phys_addr = pdp_entry.addr();
-            pdp_virt_addr = VirtAddr::new(PHYS_MEM_OFFSET | pdp_phys_addr);
-        } else {
-            pdp_phys_addr = self
-                .frame_allocator
-                .allocate(1)
-                .expect("Could not allocate frame for PDP");
-            pdp_virt_addr = VirtAddr::new(PHYS_MEM_OFFSET | pdp_phys_addr);
-            // Zero out the PDP
-            core::ptr::write_bytes(pdp_virt_addr.addr() as *mut u64, 0, 512);
-            // Reference the PDP in the PML4
-            self.pml4
-                .set_entry(virt.pml4_index(), PageTableEntry::new(pdp_phys_addr, 0b11));
-        }
-        let pdp = PageTable::new(pdp_virt_addr.addr());
-
-        let pd_entry = pdp.entry(virt.pdp_index());
-        let pd_phys_addr: u64;
-        let pd_virt_addr: VirtAddr;
-        if pd_entry.is_present() {
-            pd_phys_addr = pd_entry.addr();
-            pd_virt_addr = VirtAddr::new(PHYS_MEM_OFFSET | pd_phys_addr);
-        } else {
-            pd_phys_addr = self
-                .frame_allocator
-                .allocate(1)
-                .expect("Could not allocate frame for PD");
-            pd_virt_addr = VirtAddr::new(PHYS_MEM_OFFSET | pd_phys_addr);
-            // Zero out the PD
-            core::ptr::write_bytes(pd_virt_addr.addr() as *mut u64, 0, 512);
-            // Reference the PD in the PDP
-            pdp.set_entry(virt.pdp_index(), PageTableEntry::new(pd_phys_addr, 0b11));
+        // Set the entry in the PT, and flush any previous mapping from the TLB
+        pt.set_entry(virt.pt_index(), PageTableEntry::new(phys, flags));
+        paging::invalidate_page(virt.addr());
+        Ok(())
+    }
+
+    /// Removes the mapping of a virtual address, returning the physical
+    /// address it was mapped to, or `None` if it was not mapped. Page tables
+    /// left empty are not freed.
+    ///
+    /// # Safety
+    /// The memory must no longer be accessed through the address.
+    unsafe fn unmap(&mut self, virt: u64) -> Option<u64> {
+        let virt = VirtAddr::new(virt);
+        let pt = self.page_table(&virt)?;
+        let entry = pt.entry(virt.pt_index());
+        if !entry.is_present() {
+            return None;
         }
-        let pd = PageTable::new(pd_virt_addr.addr());
-
-        let pt_entry = pd.entry(virt.pd_index());
-        let pt_phys_addr: u64;
-        let pt_virt_addr: VirtAddr;
-        if pt_entry.is_present() {
-            pt_phys_addr = pt_entry.addr();
-            pt_virt_addr = VirtAddr::new(PHYS_MEM_OFFSET | pt_phys_addr);
+        pt.set_entry(virt.pt_index(), PageTableEntry::new(0, 0));
+        paging::invalidate_page(virt.addr());
+        Some(entry.addr())
+    }
+
+    /// Returns the entry mapping the page of a virtual address, if it's
+    /// present.
+    fn page_entry(&self, virt: u64) -> Option<PageTableEntry> {
+        let virt = VirtAddr::new(virt);
+        let entry = self.page_table(&virt)?.entry(virt.pt_index());
+        if entry.is_present() {
+            Some(entry)
         } else {
-            pt_phys_addr = self
-                .frame_allocator
-                .allocate(1)
-                .expect("Could not allocate frame for PT");
-            pt_virt_addr = VirtAddr::new(PHYS_MEM_OFFSET | pt_phys_addr);
-            // Zero out the PT
-            core::ptr::write_bytes(pt_virt_addr.addr() as *mut u64, 0, 512);
-            // Reference the PT in the PD
-            pd.set_entry(virt.pd_index(), PageTableEntry::new(pt_phys_addr, 0b11));
+            None
         }
-        let pt = PageTable::new(pt_virt_addr.addr());
-
-        // Set the entry in the PT
-        pt.set_entry(virt.pt_index(), PageTableEntry::new(phys, flags));
-
-        // TODO: Flush the TLB to make sure the new mapping is used
     }
 
     /// Translates a virtual address into it's corresponding physical address.
     ///
     /// Returns an empty error if the address cannot be found.
-    fn translate(&self, _virt: u64) -> Result<u64, ()> {
-        todo!()
+    fn translate(&self, virt: u64) -> Result<u64, ()> {
+        let entry = self.page_entry(virt).ok_or(())?;
+        Ok(entry.addr() | virt & 0xfff)
     }
 }
 
@@ -305,38 +793,145 @@ trait FrameAllocator {
     fn free(&self, addr: u64, count: usize);
 }
 
+/// The maximum number of physical memory ranges which can be reserved.
+const MAX_RESERVED_RANGES: usize = 32;
+
+/// Frames below this address are never allocated, as the first MiB is full of
+/// firmware data structures and memory mapped devices.
+const MIN_FRAME_ADDR: u64 = 0x10_0000;
+
+/// Allocates frames from the usable regions of the memory map in order.
+///
+/// Since the memory map is sorted, frames allocated early are in low memory.
+/// This matters during [init], where the frames are accessed through the
+/// identity mapping of the first 4 GiB.
+///
+/// Freed frames are kept in a list, linked through the first 8 bytes of each
+/// frame, and are reused for allocations of a single frame.
 struct BumpAllocator {
-    // TODO: Use free memory areas, such that we can avoid reserved memory and still allocate free
-    // space around it.
-    // TODO: Implement some kind of end check (this would be included in the above todo).
+    /// The memory map passed by the bootloader.
+    regions: &'static [MemoryRegion],
+    /// The index of the region the next frame is allocated from.
+    region: usize,
     /// The address of the next free frame to be allocated.
     next: u64,
+    /// The address no frames may be allocated at or above.
+    end: u64,
+    /// Ranges of physical memory which are never allocated, as start and end
+    /// addresses.
+    reserved: [(u64, u64); MAX_RESERVED_RANGES],
+    reserved_count: usize,
+    /// The physical address of the first freed frame, or 0 if there are none.
+    free_list: u64,
 }
 
 impl BumpAllocator {
-    /// Creates a new bump allocator with the given physical start address.
-    pub const unsafe fn new(start: u64) -> Self {
-        Self { next: start }
+    /// Creates a new bump allocator without any memory to allocate from.
+    pub const fn new() -> Self {
+        Self {
+            regions: &[],
+            region: 0,
+            next: MIN_FRAME_ADDR,
+            end: u64::MAX,
+            reserved: [(0, 0); MAX_RESERVED_RANGES],
+            reserved_count: 0,
+            free_list: 0,
+        }
     }
 
     fn allocate(&mut self, count: usize) -> Result<u64, ()> {
-        let addr = self.next;
-        self.next += (count * 4096) as u64;
-        Ok(addr)
+        if count == 1 && self.free_list != 0 {
+            let addr = self.free_list;
+            self.free_list = unsafe { core::ptr::read((PHYS_MEM_OFFSET | addr) as *const u64) };
+            return Ok(addr);
+        }
+
+        let size = (count * 4096) as u64;
+
+        while let Some(region) = self.regions.get(self.region) {
+            if region.kind() != MemoryRegionKind::Usable {
+                self.region += 1;
+                continue;
+            }
+
+            // Frames are allocated at page aligned addresses within the region
+            let start = (core::cmp::max(self.next, region.start) + 0xfff) & !0xfff;
+            let end = start.checked_add(size).ok_or(())?;
+            if end > self.end {
+                // The regions are sorted, so none of the following fit either
+                return Err(());
+            }
+            if end > region.end() {
+                self.region += 1;
+                continue;
+            }
+
+            // Skip past any reserved range overlapping the frames
+            let reserved = &self.reserved[..self.reserved_count];
+            if let Some(&(_, reserved_end)) =
+                reserved.iter().find(|&&(reserved_start, reserved_end)| {
+                    start < reserved_end && reserved_start < end
+                })
+            {
+                self.next = reserved_end;
+                continue;
+            }
+
+            self.next = end;
+            return Ok(start);
+        }
+
+        Err(())
     }
 
-    fn free(&mut self, _addr: u64, _count: usize) {
-        unimplemented!("The bump allocator cannot free frames");
-        // Technically we could free all the frames at once, but that situation
-        // arising is highly unlikely, so it's not implemented.
+    /// Prevents the given physical memory range from being allocated.
+    fn reserve(&mut self, start: u64, size: u64) -> Result<(), ()> {
+        if self.reserved_count == MAX_RESERVED_RANGES {
+            return Err(());
+        }
+        self.reserved[self.reserved_count] = (start, start + size);
+        self.reserved_count += 1;
+        Ok(())
+    }
+
+    /// Adds the frames to the list of freed frames.
+    ///
+    /// Must not be called before physical memory is mapped at
+    /// [PHYS_MEM_OFFSET].
+    fn free(&mut self, addr: u64, count: usize) {
+        for i in 0..count as u64 {
+            let frame = addr + 0x1000 * i;
+            unsafe {
+                core::ptr::write((PHYS_MEM_OFFSET | frame) as *mut u64, self.free_list);
+            }
+            self.free_list = frame;
+        }
     }
 }
 
-struct LockedBumpAllocator(Mutex<BumpAllocator>);
+struct LockedBumpAllocator(SpinLock<BumpAllocator>);
 
 impl LockedBumpAllocator {
-    pub const unsafe fn new(addr: u64) -> Self {
-        Self(Mutex::new(BumpAllocator::new(addr)))
+    pub const fn new() -> Self {
+        Self(SpinLock::new(BumpAllocator::new()))
+    }
+
+    /// Starts allocating from the usable regions of the given memory map, which
+    /// must be sorted by address.
+    fn init(&self, regions: &'static [MemoryRegion]) {
+        let mut allocator = self.0.lock();
+        allocator.regions = regions;
+        allocator.region = 0;
+    }
+
+    /// Prevents the given physical memory range from being allocated.
+    fn reserve(&self, start: u64, size: u64) -> Result<(), ()> {
+        self.0.lock().reserve(start, size)
+    }
+
+    /// Prevents frames from being allocated at or above the given address.
+    fn limit(&self, end: u64) {
+        self.0.lock().end = end;
     }
 }
 
@@ -0,0 +1,44 @@
+//! Files loaded by the bootloader alongside the kernel.
+//!
+//! The modules are accessed through the mapping of physical memory, so they're
+//! only available after [memory::init](crate::memory::init). Their frames are
+//! reserved, so they stay intact for the lifetime of the kernel.
+
+use crate::memory::PHYS_MEM_OFFSET;
+
+/// A file loaded by the bootloader.
+#[derive(Copy, Clone)]
+pub struct Module {
+    /// The path of the file on the boot volume, like `\EFI\ROCKHOPPER\INITRD`.
+    pub name: &'static str,
+    /// The contents of the file.
+    pub data: &'static [u8],
+}
+
+impl Module {
+    /// Returns the last component of the path.
+    pub fn file_name(&self) -> &'static str {
+        self.name.rsplit('\\').next().unwrap_or(self.name)
+    }
+}
+
+/// Returns an iterator over all modules.
+pub fn modules() -> impl Iterator<Item = Module> {
+    crate::boot_info().modules().map(|module| Module {
+        name: module.name,
+        data: unsafe {
+            core::slice::from_raw_parts(
+                (PHYS_MEM_OFFSET | module.start) as *const u8,
+                module.size as usize,
+            )
+        },
+    })
+}
+
+/// Returns the module with the given path or file name, ignoring case as the
+/// boot volume is case insensitive.
+pub fn find(name: &str) -> Option<Module> {
+    modules().find(|module| {
+        module.name.eq_ignore_ascii_case(name) || module.file_name().eq_ignore_ascii_case(name)
+    })
+}
@@ -0,0 +1,204 @@
+//! The Address Resolution Protocol (ARP), finding the MAC addresses of IPv4
+//! hosts on the local network.
+//!
+//! Packets to a host whose MAC address is unknown are queued while a request
+//! is sent, and sent once the reply arrives. If no reply arrives after a few
+//! requests, the queued packets are dropped.
+
+use super::ethernet::{self, ETHER_TYPE_ARP, ETHER_TYPE_IPV4};
+use super::ipv4::Ipv4Address;
+use super::{Error, Interface, MacAddress};
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::time::Duration;
+
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+/// The size of an ARP packet for IPv4 over Ethernet.
+const PACKET_SIZE: usize = 28;
+
+/// How long a resolved address is kept.
+const ENTRY_LIFETIME: Duration = Duration::from_secs(300);
+
+/// How long to wait for a reply before sending another request.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of requests sent before giving up on an address.
+const MAX_REQUESTS: u32 = 3;
+
+/// The number of packets queued for an address being resolved, past which
+/// further packets are dropped.
+const MAX_QUEUED_PACKETS: usize = 16;
+
+/// An address being resolved.
+struct Pending {
+    /// The IPv4 packets waiting to be sent.
+    packets: Vec<Vec<u8>>,
+    requests: u32,
+    last_request: Duration,
+}
+
+/// The resolved and pending addresses of an interface.
+#[derive(Default)]
+pub struct Cache {
+    /// The MAC addresses, and when they expire.
+    entries: BTreeMap<Ipv4Address, (MacAddress, Duration)>,
+    pending: BTreeMap<Ipv4Address, Pending>,
+}
+
+/// Sends an ARP packet.
+fn send(
+    interface: &Interface,
+    operation: u16,
+    destination: MacAddress,
+    target_mac: MacAddress,
+    target_address: Ipv4Address,
+) -> Result<(), Error> {
+    let sender_address = interface
+        .config()
+        .map_or(Ipv4Address::UNSPECIFIED, |config| config.address);
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHER_TYPE_IPV4.to_be_bytes());
+    packet.push(6);
+    packet.push(4);
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&interface.mac_address().0);
+    packet.extend_from_slice(&sender_address.0);
+    packet.extend_from_slice(&target_mac.0);
+    packet.extend_from_slice(&target_address.0);
+    ethernet::send(interface, destination, ETHER_TYPE_ARP, &packet)
+}
+
+/// Broadcasts a request for the MAC address of the host with the IPv4
+/// address.
+fn request(interface: &Interface, address: Ipv4Address) -> Result<(), Error> {
+    send(
+        interface,
+        OPERATION_REQUEST,
+        MacAddress::BROADCAST,
+        MacAddress([0; 6]),
+        address,
+    )
+}
+
+/// Sends an IPv4 packet to the host with the given address on the local
+/// network, first resolving its MAC address if it's unknown.
+pub fn send_ipv4(
+    interface: &Interface,
+    next_hop: Ipv4Address,
+    packet: Vec<u8>,
+) -> Result<(), Error> {
+    let now = time::uptime();
+    let mut cache = interface.arp.lock();
+    if let Some(&(mac_address, expires)) = cache.entries.get(&next_hop) {
+        if expires > now {
+            drop(cache);
+            return ethernet::send(interface, mac_address, ETHER_TYPE_IPV4, &packet);
+        }
+        cache.entries.remove(&next_hop);
+    }
+
+    if let Some(pending) = cache.pending.get_mut(&next_hop) {
+        // A request is already on its way
+        if pending.packets.len() < MAX_QUEUED_PACKETS {
+            pending.packets.push(packet);
+        }
+        return Ok(());
+    }
+    cache.pending.insert(
+        next_hop,
+        Pending {
+            packets: vec![packet],
+            requests: 1,
+            last_request: now,
+        },
+    );
+    drop(cache);
+    request(interface, next_hop)
+}
+
+/// Handles an ARP packet received by an interface, learning the address of
+/// the sender and replying to requests for the address of the interface.
+pub fn handle(interface: &Interface, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || u16::from_be_bytes([packet[0], packet[1]]) != HARDWARE_TYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ETHER_TYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+    let sender_address = Ipv4Address(packet[14..18].try_into().unwrap());
+    let target_address = Ipv4Address(packet[24..28].try_into().unwrap());
+
+    let own_address = interface.config().map(|config| config.address);
+    let for_us = Some(target_address) == own_address;
+
+    // Learn the sender if the packet is for us, or refresh it if it's known
+    let mut cache = interface.arp.lock();
+    let waiting = if for_us
+        || cache.entries.contains_key(&sender_address)
+        || cache.pending.contains_key(&sender_address)
+    {
+        let expires = time::uptime() + ENTRY_LIFETIME;
+        cache.entries.insert(sender_address, (sender_mac, expires));
+        cache.pending.remove(&sender_address)
+    } else {
+        None
+    };
+    drop(cache);
+
+    if let Some(pending) = waiting {
+        for packet in pending.packets {
+            // Errors are left to the protocols, like for lost packets
+            let _ = ethernet::send(interface, sender_mac, ETHER_TYPE_IPV4, &packet);
+        }
+    }
+
+    if operation == OPERATION_REQUEST && for_us {
+        let _ = send(
+            interface,
+            OPERATION_REPLY,
+            sender_mac,
+            sender_mac,
+            sender_address,
+        );
+    }
+}
+
+/// Repeats the requests for addresses which have not been resolved, and gives
+/// up on those which have been requested too many times.
+pub fn poll(interface: &Interface) {
+    let now = time::uptime();
+    let mut retry = Vec::new();
+    let mut cache = interface.arp.lock();
+    cache.pending = core::mem::take(&mut cache.pending)
+        .into_iter()
+        .filter_map(|(address, mut pending)| {
+            if now < pending.last_request + REQUEST_INTERVAL {
+                Some((address, pending))
+            } else if pending.requests < MAX_REQUESTS {
+                pending.requests += 1;
+                pending.last_request = now;
+                retry.push(address);
+                Some((address, pending))
+            } else {
+                // The host is unreachable, so drop its packets
+                None
+            }
+        })
+        .collect();
+    drop(cache);
+
+    for address in retry {
+        let _ = request(interface, address);
+    }
+}
@@ -0,0 +1,266 @@
+//! A client of the Dynamic Host Configuration Protocol (DHCP), which gets the
+//! address of an interface from a server on the local network.
+//!
+//! The lease is not renewed when it expires, which is fine for the day long
+//! leases of QEMU user networking.
+
+use super::ipv4::{Config, Ipv4Address, Route};
+use super::udp::{self, UdpSocket};
+use super::{Error, Interface};
+use crate::time;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::time::Duration;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+
+const HARDWARE_TYPE_ETHERNET: u8 = 1;
+
+/// The flag asking the server to broadcast its replies, since the interface
+/// cannot receive packets to the offered address yet.
+const FLAG_BROADCAST: u16 = 1 << 15;
+
+/// The value following the fixed fields, telling options follow.
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// The size of the fixed fields, followed by the magic cookie and options.
+const FIXED_SIZE: usize = 236;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const MESSAGE_DISCOVER: u8 = 1;
+const MESSAGE_OFFER: u8 = 2;
+const MESSAGE_REQUEST: u8 = 3;
+const MESSAGE_ACK: u8 = 5;
+const MESSAGE_NAK: u8 = 6;
+
+/// How long to wait for each reply.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The number of times the exchange is attempted.
+const ATTEMPTS: u32 = 4;
+
+/// An address leased from a server.
+#[derive(Copy, Clone, Debug)]
+pub struct Lease {
+    pub config: Config,
+    pub server: Ipv4Address,
+    /// How long the address may be used.
+    pub duration: Option<Duration>,
+}
+
+/// A reply from a server, with the options the client cares about.
+struct Reply {
+    message_type: u8,
+    address: Ipv4Address,
+    server: Option<Ipv4Address>,
+    netmask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns: Option<Ipv4Address>,
+    lease_time: Option<u32>,
+}
+
+/// Builds a message from the client.
+fn message(
+    interface: &Interface,
+    transaction: u32,
+    message_type: u8,
+    requested: Option<(Ipv4Address, Ipv4Address)>,
+) -> Vec<u8> {
+    let mut message = vec![0; FIXED_SIZE];
+    message[0] = OP_REQUEST;
+    message[1] = HARDWARE_TYPE_ETHERNET;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&transaction.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(&interface.mac_address().0);
+
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+    if let Some((address, server)) = requested {
+        message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+        message.extend_from_slice(&address.0);
+        message.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        message.extend_from_slice(&server.0);
+    }
+    message.extend_from_slice(&[
+        OPTION_PARAMETER_LIST,
+        3,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+    ]);
+    message.push(OPTION_END);
+    message
+}
+
+/// Parses a reply from a server to the transaction, returning `None` for
+/// anything else.
+fn parse_reply(interface: &Interface, transaction: u32, message: &[u8]) -> Option<Reply> {
+    if message.len() < FIXED_SIZE + MAGIC_COOKIE.len()
+        || message[0] != OP_REPLY
+        || message[4..8] != transaction.to_be_bytes()
+        || message[28..34] != interface.mac_address().0
+        || message[FIXED_SIZE..FIXED_SIZE + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let mut reply = Reply {
+        message_type: 0,
+        address: Ipv4Address(message[16..20].try_into().unwrap()),
+        server: None,
+        netmask: None,
+        router: None,
+        dns: None,
+        lease_time: None,
+    };
+
+    let address = |value: &[u8]| {
+        value
+            .get(..4)
+            .map(|value| Ipv4Address(value.try_into().unwrap()))
+    };
+    let mut options = &message[FIXED_SIZE + 4..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&length, rest) = rest.split_first()?;
+        let value = rest.get(..length as usize)?;
+        match code {
+            OPTION_MESSAGE_TYPE => reply.message_type = *value.first()?,
+            OPTION_SERVER_ID => reply.server = address(value),
+            OPTION_SUBNET_MASK => reply.netmask = address(value),
+            OPTION_ROUTER => reply.router = address(value),
+            OPTION_DNS => reply.dns = address(value),
+            OPTION_LEASE_TIME => {
+                reply.lease_time = value
+                    .get(..4)
+                    .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+            }
+            _ => {}
+        }
+        options = &rest[length as usize..];
+    }
+    Some(reply)
+}
+
+/// Broadcasts a message and waits for a reply of one of the given types.
+fn exchange(
+    interface: &Arc<Interface>,
+    socket: &UdpSocket,
+    transaction: u32,
+    message: &[u8],
+    reply_types: &[u8],
+) -> Result<Reply, Error> {
+    let route = Route {
+        interface: interface.clone(),
+        source: Ipv4Address::UNSPECIFIED,
+        next_hop: Ipv4Address::BROADCAST,
+    };
+    udp::send(
+        &route,
+        CLIENT_PORT,
+        Ipv4Address::BROADCAST,
+        SERVER_PORT,
+        message,
+    )?;
+
+    let deadline = time::uptime() + REPLY_TIMEOUT;
+    loop {
+        let remaining = deadline
+            .checked_sub(time::uptime())
+            .ok_or(Error::TimedOut)?;
+        let (data, _, port) = socket.receive_from(Some(remaining))?;
+        if port != SERVER_PORT {
+            continue;
+        }
+        match parse_reply(interface, transaction, &data) {
+            Some(reply) if reply_types.contains(&reply.message_type) => return Ok(reply),
+            _ => {}
+        }
+    }
+}
+
+/// Discovers a server, requests the address it offers, and configures the
+/// interface with it.
+pub fn configure(interface: &Arc<Interface>) -> Result<Lease, Error> {
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let mac_address = interface.mac_address().0;
+    let mut result = Err(Error::TimedOut);
+    for attempt in 0..ATTEMPTS {
+        // Transactions only need to differ between clients and attempts
+        let transaction = u32::from_be_bytes(mac_address[2..6].try_into().unwrap())
+            ^ (time::ticks() as u32).wrapping_add(attempt);
+
+        let discover = message(interface, transaction, MESSAGE_DISCOVER, None);
+        let offer = match exchange(interface, &socket, transaction, &discover, &[MESSAGE_OFFER]) {
+            Ok(offer) => offer,
+            Err(error) => {
+                result = Err(error);
+                continue;
+            }
+        };
+        let server = match offer.server {
+            Some(server) => server,
+            None => continue,
+        };
+
+        let request = message(
+            interface,
+            transaction,
+            MESSAGE_REQUEST,
+            Some((offer.address, server)),
+        );
+        let ack = match exchange(
+            interface,
+            &socket,
+            transaction,
+            &request,
+            &[MESSAGE_ACK, MESSAGE_NAK],
+        ) {
+            Ok(ack) if ack.message_type == MESSAGE_ACK => ack,
+            Ok(_) => continue,
+            Err(error) => {
+                result = Err(error);
+                continue;
+            }
+        };
+
+        let config = Config {
+            address: ack.address,
+            // Without a subnet mask, assume the most common one
+            netmask: ack.netmask.unwrap_or(Ipv4Address([255, 255, 255, 0])),
+            gateway: ack.router,
+            dns: ack.dns,
+        };
+        interface.set_config(Some(config));
+        return Ok(Lease {
+            config,
+            server,
+            duration: ack
+                .lease_time
+                .map(|seconds| Duration::from_secs(seconds as u64)),
+        });
+    }
+    result
+}
@@ -0,0 +1,50 @@
+//! Ethernet framing.
+
+use super::{arp, ipv4, Error, Interface, MacAddress, ETHERNET_HEADER_SIZE};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+pub const ETHER_TYPE_IPV4: u16 = 0x0800;
+pub const ETHER_TYPE_ARP: u16 = 0x0806;
+
+/// The smallest frame, excluding the frame check sequence. Shorter frames are
+/// padded with zeroes.
+const MIN_FRAME_SIZE: usize = 60;
+
+/// Sends a frame with the payload from an interface.
+pub fn send(
+    interface: &Interface,
+    destination: MacAddress,
+    ether_type: u16,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&interface.mac_address().0);
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_FRAME_SIZE {
+        frame.resize(MIN_FRAME_SIZE, 0);
+    }
+    interface.device().send(&frame)
+}
+
+/// Handles a frame received by an interface, passing its payload on to the
+/// protocol given by the EtherType. Frames addressed to other hosts are
+/// dropped.
+pub fn handle(interface: &Interface, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return;
+    }
+    let destination = MacAddress(frame[0..6].try_into().unwrap());
+    if destination != interface.mac_address() && !destination.is_multicast() {
+        return;
+    }
+    let ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+    match ether_type {
+        ETHER_TYPE_ARP => arp::handle(interface, payload),
+        ETHER_TYPE_IPV4 => ipv4::handle(interface, payload),
+        _ => {}
+    }
+}
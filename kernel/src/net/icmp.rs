@@ -0,0 +1,92 @@
+//! The Internet Control Message Protocol (ICMP), for now only answering and
+//! sending echo requests, as used by `ping`.
+
+use super::ipv4::{self, Header, Ipv4Address, PROTOCOL_ICMP};
+use super::Error;
+use crate::net;
+use crate::sync::spinlock::SpinLock;
+use crate::time;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// The size of the header of echo messages, with the identifier and sequence
+/// number.
+const ECHO_HEADER_SIZE: usize = 8;
+
+/// The number of bytes of data sent with an echo request.
+const PING_DATA_SIZE: usize = 32;
+
+/// The identifier of the next echo request.
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+lazy_static! {
+    /// The echo requests waiting for a reply, by their host, identifier, and
+    /// sequence number.
+    static ref OUTSTANDING: SpinLock<BTreeSet<(Ipv4Address, u16, u16)>> = SpinLock::new(BTreeSet::new());
+}
+
+/// Sends an echo message.
+fn send_echo(
+    destination: Ipv4Address,
+    kind: u8,
+    identifier: u16,
+    sequence: u16,
+    data: &[u8],
+) -> Result<(), Error> {
+    let route = ipv4::route(destination)?;
+    let mut message = Vec::with_capacity(ECHO_HEADER_SIZE + data.len());
+    message.push(kind);
+    message.push(0);
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&identifier.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(data);
+    let checksum = ipv4::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    ipv4::send(&route, destination, PROTOCOL_ICMP, &message)
+}
+
+/// Handles an ICMP message, replying to echo requests and recording echo
+/// replies.
+pub fn handle(header: &Header, message: &[u8]) {
+    if message.len() < ECHO_HEADER_SIZE || ipv4::checksum(message) != 0 {
+        return;
+    }
+    let identifier = u16::from_be_bytes([message[4], message[5]]);
+    let sequence = u16::from_be_bytes([message[6], message[7]]);
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // Broadcast requests are not answered
+            if header.destination != Ipv4Address::BROADCAST {
+                let data = &message[ECHO_HEADER_SIZE..];
+                let _ = send_echo(header.source, TYPE_ECHO_REPLY, identifier, sequence, data);
+            }
+        }
+        TYPE_ECHO_REPLY => {
+            OUTSTANDING
+                .lock()
+                .remove(&(header.source, identifier, sequence));
+        }
+        _ => {}
+    }
+}
+
+/// Sends an echo request to a host and waits for the reply, returning the
+/// round-trip time.
+pub fn ping(destination: Ipv4Address, sequence: u16, timeout: Duration) -> Result<Duration, Error> {
+    let identifier = NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    let key = (destination, identifier, sequence);
+    let data: Vec<u8> = (0..PING_DATA_SIZE as u8).collect();
+
+    OUTSTANDING.lock().insert(key);
+    let start = time::uptime();
+    let result = send_echo(destination, TYPE_ECHO_REQUEST, identifier, sequence, &data)
+        .and_then(|()| net::wait_until(|| !OUTSTANDING.lock().contains(&key), Some(timeout)));
+    OUTSTANDING.lock().remove(&key);
+    result.map(|()| time::uptime() - start)
+}
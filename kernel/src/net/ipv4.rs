@@ -0,0 +1,318 @@
+//! The Internet Protocol, version 4.
+//!
+//! Packets are routed to the first interface whose subnet holds the
+//! destination, or else to the gateway of the first interface which has one.
+//! Packets are never fragmented, and received fragments are dropped.
+
+use super::{arp, icmp, tcp, udp, Error, Interface, MacAddress};
+use crate::net;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// The size of a header without options.
+pub const HEADER_SIZE: usize = 20;
+
+/// The time to live of sent packets.
+const DEFAULT_TTL: u8 = 64;
+
+/// The flag telling routers not to fragment the packet.
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+/// The flag telling more fragments follow.
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+/// The identification of the next packet sent.
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// An IPv4 address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+    pub const BROADCAST: Self = Self([255; 4]);
+
+    /// Returns the address as a number.
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// Returns the address with the given number.
+    pub fn from_u32(value: u32) -> Self {
+        Self(value.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Address {
+    type Err = ();
+
+    /// Parses an address in dotted decimal notation, like `10.0.2.15`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut address = [0; 4];
+        let mut parts = s.split('.');
+        for byte in address.iter_mut() {
+            *byte = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(Self(address))
+    }
+}
+
+/// The IPv4 configuration of an interface.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub address: Ipv4Address,
+    pub netmask: Ipv4Address,
+    /// The router packets to other networks are sent to.
+    pub gateway: Option<Ipv4Address>,
+    /// The DNS server, which is only recorded.
+    pub dns: Option<Ipv4Address>,
+}
+
+impl Config {
+    /// Returns the number of leading ones in the netmask.
+    pub fn prefix_length(&self) -> u32 {
+        self.netmask.to_u32().leading_ones()
+    }
+
+    /// Returns whether the address is on the local network.
+    pub fn contains(&self, address: Ipv4Address) -> bool {
+        let netmask = self.netmask.to_u32();
+        address.to_u32() & netmask == self.address.to_u32() & netmask
+    }
+
+    /// Returns the broadcast address of the local network.
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() | !self.netmask.to_u32())
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length())?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        if let Some(dns) = self.dns {
+            write!(f, ", DNS {}", dns)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = ();
+
+    /// Parses an address with a prefix length, optionally followed by a
+    /// gateway, like `10.0.2.15/24,10.0.2.2`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut parts = s.splitn(2, ',');
+        let mut cidr = parts.next().ok_or(())?.splitn(2, '/');
+        let address = cidr.next().ok_or(())?.parse()?;
+        let prefix_length: u32 = cidr.next().ok_or(())?.parse().map_err(|_| ())?;
+        if prefix_length > 32 {
+            return Err(());
+        }
+        let netmask = u32::MAX.checked_shl(32 - prefix_length).unwrap_or(0);
+        let gateway = parts.next().map(|gateway| gateway.parse()).transpose()?;
+        Ok(Self {
+            address,
+            netmask: Ipv4Address::from_u32(netmask),
+            gateway,
+            dns: None,
+        })
+    }
+}
+
+/// The internet checksum, the ones' complement of the ones' complement sum of
+/// 16-bit words.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Checksum(u32);
+
+impl Checksum {
+    /// Adds data to the sum. Only the last data added may have an odd length.
+    pub fn add(&mut self, data: &[u8]) {
+        let mut words = data.chunks_exact(2);
+        for word in &mut words {
+            self.0 += u16::from_be_bytes([word[0], word[1]]) as u32;
+        }
+        if let [last] = words.remainder() {
+            self.0 += (*last as u32) << 8;
+        }
+        // Fold the carries before the sum can overflow
+        self.0 = (self.0 & 0xffff) + (self.0 >> 16);
+    }
+
+    /// Returns the checksum of the data added.
+    pub fn finish(self) -> u16 {
+        let sum = (self.0 & 0xffff) + (self.0 >> 16);
+        !(((sum & 0xffff) + (sum >> 16)) as u16)
+    }
+
+    /// Starts the checksum of a TCP or UDP packet with the pseudo header.
+    pub fn pseudo_header(
+        source: Ipv4Address,
+        destination: Ipv4Address,
+        protocol: u8,
+        length: usize,
+    ) -> Self {
+        let mut checksum = Self::default();
+        checksum.add(&source.0);
+        checksum.add(&destination.0);
+        checksum.add(&[0, protocol]);
+        checksum.add(&(length as u16).to_be_bytes());
+        checksum
+    }
+}
+
+/// Returns the internet checksum of the data.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut checksum = Checksum::default();
+    checksum.add(data);
+    checksum.finish()
+}
+
+/// The fields of a received header needed by the protocols above.
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+}
+
+/// Where packets to a destination are sent.
+#[derive(Clone)]
+pub struct Route {
+    pub interface: Arc<Interface>,
+    /// The address packets are sent from.
+    pub source: Ipv4Address,
+    /// The host on the local network packets are sent to, or a broadcast
+    /// address.
+    pub next_hop: Ipv4Address,
+}
+
+/// Returns the route to a destination.
+pub fn route(destination: Ipv4Address) -> Result<Route, Error> {
+    let interfaces = net::interfaces();
+    let configured = || {
+        interfaces
+            .iter()
+            .filter_map(|interface| Some((interface, interface.config()?)))
+    };
+    let (interface, config, next_hop) = configured()
+        .find(|(_, config)| config.contains(destination) || destination == Ipv4Address::BROADCAST)
+        .map(|(interface, config)| (interface, config, destination))
+        .or_else(|| {
+            configured().find_map(|(interface, config)| Some((interface, config, config.gateway?)))
+        })
+        .ok_or(Error::Unreachable)?;
+    Ok(Route {
+        interface: interface.clone(),
+        source: config.address,
+        next_hop,
+    })
+}
+
+/// Sends a packet with the payload along a route.
+pub fn send(
+    route: &Route,
+    destination: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let length = HEADER_SIZE + payload.len();
+    if length > route.interface.device().mtu() {
+        return Err(Error::InvalidArgument);
+    }
+    let identification = NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+
+    let mut packet = Vec::with_capacity(length);
+    // Version 4, with a header of five words
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    packet.extend_from_slice(&identification.to_be_bytes());
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.push(DEFAULT_TTL);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&route.source.0);
+    packet.extend_from_slice(&destination.0);
+    let header_checksum = checksum(&packet);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    let interface = &route.interface;
+    let is_broadcast = route.next_hop == Ipv4Address::BROADCAST
+        || matches!(interface.config(), Some(config) if route.next_hop == config.broadcast());
+    if is_broadcast {
+        super::ethernet::send(
+            interface,
+            MacAddress::BROADCAST,
+            super::ethernet::ETHER_TYPE_IPV4,
+            &packet,
+        )
+    } else {
+        arp::send_ipv4(interface, route.next_hop, packet)
+    }
+}
+
+/// Handles a packet received by an interface, passing its payload on to the
+/// protocol. Packets addressed to other hosts are dropped, except while the
+/// interface has no address, such that DHCP can receive its replies.
+pub fn handle(interface: &Interface, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_size = (packet[0] & 0xf) as usize * 4;
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_size < HEADER_SIZE || length < header_size || length > packet.len() {
+        return;
+    }
+    if checksum(&packet[..header_size]) != 0 {
+        return;
+    }
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+        return;
+    }
+
+    let header = Header {
+        source: Ipv4Address(packet[12..16].try_into().unwrap()),
+        destination: Ipv4Address(packet[16..20].try_into().unwrap()),
+        protocol: packet[9],
+    };
+    if let Some(config) = interface.config() {
+        if header.destination != config.address
+            && header.destination != config.broadcast()
+            && header.destination != Ipv4Address::BROADCAST
+        {
+            return;
+        }
+    }
+
+    let payload = &packet[header_size..length];
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(&header, payload),
+        PROTOCOL_TCP => tcp::handle(&header, payload),
+        PROTOCOL_UDP => udp::handle(&header, payload),
+        _ => {}
+    }
+}
@@ -0,0 +1,306 @@
+//! Networking over Ethernet, with IPv4, UDP, and TCP.
+//!
+//! Drivers register the interfaces they find with [register_interface], which
+//! makes them available by name, like `eth0`. On boot, [init] configures every
+//! interface with [DHCP](dhcp), or the first one with the address given by the
+//! `ip` command line option.
+//!
+//! There are no threads to process received frames in the background, so
+//! they're processed by [poll], which is called while waiting for a socket.
+//! Protocol timers, like retransmissions, only run while some caller waits.
+
+pub mod arp;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+use crate::cmdline::CMDLINE;
+use crate::println;
+use crate::sync::spinlock::SpinLock;
+use crate::sync::wait_queue::WaitQueue;
+use crate::time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::time::Duration;
+use ipv4::Config;
+
+/// The size of the header of an Ethernet frame, with the destination and
+/// source addresses and the EtherType.
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// The first of the ports handed out to sockets not bound to a specific port.
+const EPHEMERAL_PORTS_START: u16 = 49152;
+
+/// An error returned by network operations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The frame is larger than the interface can send, or smaller than an
+    /// Ethernet header.
+    InvalidFrame,
+    /// The link is down.
+    LinkDown,
+    /// The device reported an error, or did not respond.
+    Io,
+    /// An argument, like the length of a datagram, is invalid.
+    InvalidArgument,
+    /// No interface is configured to reach the address.
+    Unreachable,
+    /// The operation did not complete in time.
+    TimedOut,
+    /// The port is already bound.
+    AddressInUse,
+    /// The remote host refused the connection.
+    ConnectionRefused,
+    /// The remote host reset the connection.
+    ConnectionReset,
+    /// The connection is not established, or was closed.
+    NotConnected,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidFrame => write!(f, "invalid frame size"),
+            Self::LinkDown => write!(f, "link is down"),
+            Self::Io => write!(f, "input/output error"),
+            Self::InvalidArgument => write!(f, "invalid argument"),
+            Self::Unreachable => write!(f, "network is unreachable"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::AddressInUse => write!(f, "address already in use"),
+            Self::ConnectionRefused => write!(f, "connection refused"),
+            Self::ConnectionReset => write!(f, "connection reset by peer"),
+            Self::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+/// An Ethernet MAC address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+
+    /// Returns whether frames sent to the address go to a group of
+    /// interfaces, like the broadcast address.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// A device sending and receiving Ethernet frames.
+///
+/// Frames are passed without the preamble and the frame check sequence, so
+/// they start with the Ethernet header.
+pub trait NetworkInterface: Send + Sync {
+    /// Returns the MAC address of the interface.
+    fn mac_address(&self) -> MacAddress;
+
+    /// Returns whether the link is up.
+    fn link_up(&self) -> bool;
+
+    /// Returns the largest payload of a frame, excluding the Ethernet header.
+    fn mtu(&self) -> usize;
+
+    /// Sends a frame.
+    fn send(&self, frame: &[u8]) -> Result<(), Error>;
+
+    /// Returns the next received frame, or `None` if none is waiting.
+    fn receive(&self) -> Option<Vec<u8>>;
+}
+
+/// A registered network interface, with its IPv4 configuration.
+pub struct Interface {
+    name: String,
+    device: Arc<dyn NetworkInterface>,
+    config: SpinLock<Option<Config>>,
+    arp: SpinLock<arp::Cache>,
+}
+
+impl Interface {
+    /// Returns the name of the interface, like `eth0`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the device sending and receiving the frames.
+    pub fn device(&self) -> &Arc<dyn NetworkInterface> {
+        &self.device
+    }
+
+    /// Returns the MAC address of the device.
+    pub fn mac_address(&self) -> MacAddress {
+        self.device.mac_address()
+    }
+
+    /// Returns the IPv4 configuration, or `None` if the interface has no
+    /// address.
+    pub fn config(&self) -> Option<Config> {
+        *self.config.lock()
+    }
+
+    /// Sets the IPv4 configuration.
+    pub fn set_config(&self, config: Option<Config>) {
+        match config {
+            Some(config) => println!("Network interface {}: {}", self.name, config),
+            None => println!("Network interface {}: unconfigured", self.name),
+        }
+        *self.config.lock() = config;
+    }
+}
+
+/// The registered interfaces.
+static INTERFACES: SpinLock<Vec<Arc<Interface>>> = SpinLock::new(Vec::new());
+
+/// The next ephemeral port to try, shared by all protocols.
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS_START);
+
+/// The threads waiting in [wait_until].
+static WAITERS: WaitQueue = WaitQueue::new();
+
+/// The number of times [notify] was called.
+static EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers an interface found by a driver.
+pub fn register_interface(name: &str, device: Arc<dyn NetworkInterface>) {
+    println!(
+        "Network interface {}: {}, link {}",
+        name,
+        device.mac_address(),
+        if device.link_up() { "up" } else { "down" }
+    );
+    INTERFACES.lock().push(Arc::new(Interface {
+        name: name.into(),
+        device,
+        config: SpinLock::new(None),
+        arp: SpinLock::new(arp::Cache::default()),
+    }));
+}
+
+/// Returns the interface with the given name.
+pub fn find(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .cloned()
+}
+
+/// Returns all interfaces.
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// Returns the names of all interfaces.
+pub fn names() -> Vec<String> {
+    INTERFACES
+        .lock()
+        .iter()
+        .map(|interface| interface.name.clone())
+        .collect()
+}
+
+/// Processes the frames received by every interface, and runs the protocol
+/// timers.
+pub fn poll() {
+    for interface in interfaces() {
+        while let Some(frame) = interface.device.receive() {
+            ethernet::handle(&interface, &frame);
+        }
+        arp::poll(&interface);
+    }
+    tcp::poll();
+}
+
+/// Wakes the threads in [wait_until], to process the frames received and run
+/// the protocol timers. Called by the interrupt handlers of interfaces, and by
+/// the timer on every tick.
+pub fn notify() {
+    EVENTS.fetch_add(1, Ordering::Relaxed);
+    WAITERS.notify_all();
+}
+
+/// Processes received frames until the condition holds, or fails with
+/// [Error::TimedOut] once the timeout has passed.
+///
+/// The thread is blocked between polls, until an interface signals an
+/// interrupt or the next tick.
+pub fn wait_until(
+    mut condition: impl FnMut() -> bool,
+    timeout: Option<Duration>,
+) -> Result<(), Error> {
+    let deadline = timeout.map(|timeout| time::uptime() + timeout);
+    loop {
+        // Anything notified while polling wakes the thread right away
+        let events = EVENTS.load(Ordering::Relaxed);
+        poll();
+        if condition() {
+            return Ok(());
+        }
+        if matches!(deadline, Some(deadline) if time::uptime() >= deadline) {
+            return Err(Error::TimedOut);
+        }
+        WAITERS.wait_until(|| EVENTS.load(Ordering::Relaxed) != events);
+    }
+}
+
+/// Returns an ephemeral port for which `in_use` is false, or `None` if every
+/// one is in use.
+pub fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    for _ in EPHEMERAL_PORTS_START..=u16::MAX {
+        let port = NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed);
+        if port < EPHEMERAL_PORTS_START {
+            // Wrapped around
+            NEXT_EPHEMERAL_PORT.store(EPHEMERAL_PORTS_START, Ordering::Relaxed);
+            continue;
+        }
+        if !in_use(port) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+/// Configures the interfaces.
+///
+/// The `ip` command line option is either `dhcp`, the default, `off`, or an
+/// address with a prefix length and optionally a gateway for the first
+/// interface, like `ip=10.0.2.15/24,10.0.2.2`.
+pub fn init() {
+    let option = CMDLINE.get("ip").unwrap_or("dhcp");
+    match option {
+        "off" => {}
+        "dhcp" => {
+            for interface in interfaces() {
+                if !interface.device.link_up() {
+                    continue;
+                }
+                if let Err(error) = dhcp::configure(&interface) {
+                    println!("DHCP on {} failed: {}", interface.name, error);
+                }
+            }
+        }
+        _ => match (option.parse::<Config>(), interfaces().first()) {
+            (Ok(config), Some(interface)) => interface.set_config(Some(config)),
+            (Err(()), _) => println!("Invalid ip option: {}", option),
+            (_, None) => println!("No network interface to configure"),
+        },
+    }
+}
@@ -0,0 +1,1051 @@
+//! The Transmission Control Protocol (TCP).
+//!
+//! Connections are opened with [TcpStream::connect], or accepted from a
+//! [TcpListener]. Data written to a stream is buffered until the peer
+//! acknowledges it, and sent as the window advertised by the peer allows.
+//! Unacknowledged data is retransmitted after a timeout estimated from the
+//! round-trip time as in RFC 6298, which backs off exponentially until the
+//! connection is given up.
+//!
+//! Segments arriving out of order are dropped rather than buffered, relying on
+//! the peer to retransmit them, and there is no congestion control beyond the
+//! window of the peer. Simultaneous opens are not supported.
+
+use super::ipv4::{self, Checksum, Header, Ipv4Address, PROTOCOL_TCP};
+use super::Error;
+use crate::net;
+use crate::sync::mutex::Mutex;
+use crate::sync::spinlock::SpinLock;
+use crate::time;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+/// The size of a header without options.
+const HEADER_SIZE: usize = 20;
+
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The size of the send and receive buffers of a connection. The free space
+/// in the receive buffer is the window advertised to the peer.
+const BUFFER_SIZE: usize = 32768;
+
+/// The maximum segment size assumed if the peer does not tell its own.
+const DEFAULT_MSS: usize = 536;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// The number of times a segment is retransmitted before the connection is
+/// given up.
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+/// How long a connection closed from this end stays in the TIME-WAIT state,
+/// which is much shorter than the four minutes of RFC 793.
+const TIME_WAIT_DURATION: Duration = Duration::from_secs(10);
+
+/// The number of connections waiting to be accepted by a listener, past which
+/// further connections are refused.
+const BACKLOG: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// Identifies a connection by its local port and the address and port of the
+/// peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    local_port: u16,
+    remote_address: Ipv4Address,
+    remote_port: u16,
+}
+
+/// A received segment.
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: u16,
+    /// The maximum segment size option, sent with SYN segments.
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Returns the number of sequence numbers taken by the segment, where SYN
+    /// and FIN take one each.
+    fn length(&self) -> u32 {
+        self.data.len() as u32
+            + (self.flags & FLAG_SYN != 0) as u32
+            + (self.flags & FLAG_FIN != 0) as u32
+    }
+}
+
+/// The state of a connection.
+struct Connection {
+    state: State,
+    initial_sequence: u32,
+    /// The first sequence number not acknowledged by the peer.
+    send_unacknowledged: u32,
+    /// The next sequence number to send.
+    send_next: u32,
+    /// The window advertised by the peer.
+    send_window: u32,
+    /// The next sequence number expected from the peer.
+    receive_next: u32,
+    /// The largest segment sent.
+    mss: usize,
+    /// The data written but not yet acknowledged, starting at
+    /// `send_unacknowledged`.
+    send_buffer: VecDeque<u8>,
+    /// The data received but not yet read.
+    receive_buffer: VecDeque<u8>,
+    /// The window last advertised to the peer.
+    advertised_window: usize,
+    /// Whether the sending direction is closed, such that a FIN is sent after
+    /// the buffered data.
+    closing: bool,
+    fin_sent: bool,
+    fin_received: bool,
+    /// The retransmission timeout.
+    rto: Duration,
+    /// The smoothed round-trip time and its variation, once measured.
+    rtt: Option<(Duration, Duration)>,
+    /// The sequence number whose acknowledgement gives the next round-trip
+    /// time, and when it was sent.
+    rtt_probe: Option<(u32, Duration)>,
+    /// When unacknowledged data is retransmitted.
+    retransmit_at: Option<Duration>,
+    retransmissions: u32,
+    /// When the TIME-WAIT state ends.
+    time_wait_until: Option<Duration>,
+    /// The error which closed the connection.
+    error: Option<Error>,
+    /// Whether a stream or the queue of a listener refers to the connection.
+    /// Connections which are not owned are removed once closed.
+    owned: bool,
+    /// The port of the listener a connection being opened by a peer is
+    /// queued on once established.
+    listener: Option<u16>,
+}
+
+impl Connection {
+    /// Creates a connection in a state where the SYN is the only segment sent.
+    fn new(state: State, mss: usize) -> Self {
+        let initial_sequence = initial_sequence();
+        Self {
+            state,
+            initial_sequence,
+            send_unacknowledged: initial_sequence,
+            send_next: initial_sequence.wrapping_add(1),
+            send_window: 0,
+            receive_next: 0,
+            mss,
+            send_buffer: VecDeque::new(),
+            receive_buffer: VecDeque::new(),
+            advertised_window: 0,
+            closing: false,
+            fin_sent: false,
+            fin_received: false,
+            rto: INITIAL_RTO,
+            rtt: None,
+            rtt_probe: Some((initial_sequence.wrapping_add(1), time::uptime())),
+            retransmit_at: Some(time::uptime() + INITIAL_RTO),
+            retransmissions: 0,
+            time_wait_until: None,
+            error: None,
+            owned: true,
+            listener: None,
+        }
+    }
+
+    /// Returns the free space in the receive buffer.
+    fn receive_window(&self) -> usize {
+        BUFFER_SIZE - self.receive_buffer.len()
+    }
+
+    /// Returns whether the connection takes data from the peer.
+    fn can_receive(&self) -> bool {
+        matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        )
+    }
+
+    /// Returns whether the connection sends written data.
+    fn can_send(&self) -> bool {
+        matches!(self.state, State::Established | State::CloseWait)
+    }
+
+    /// Closes the connection, with an error to report to its stream.
+    fn close(&mut self, error: Option<Error>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.retransmit_at = None;
+        self.time_wait_until = None;
+        self.send_buffer.clear();
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(time::uptime() + TIME_WAIT_DURATION);
+    }
+
+    /// Updates the retransmission timeout with a measured round-trip time.
+    fn update_rto(&mut self, sample: Duration) {
+        let (srtt, rttvar) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((srtt, rttvar)) => {
+                let difference = if srtt > sample {
+                    srtt - sample
+                } else {
+                    sample - srtt
+                };
+                (srtt * 7 / 8 + sample / 8, rttvar * 3 / 4 + difference / 4)
+            }
+        };
+        self.rtt = Some((srtt, rttvar));
+        // The variation counts for at least the resolution of the clock
+        let resolution = Duration::from_millis(1000 / time::TICK_FREQUENCY);
+        let rto = srtt + core::cmp::max(rttvar * 4, resolution);
+        self.rto = core::cmp::min(core::cmp::max(rto, MIN_RTO), MAX_RTO);
+    }
+
+    /// Handles an acknowledgement of sent data.
+    fn acknowledge(&mut self, acknowledgement: u32) {
+        let acknowledged = acknowledgement.wrapping_sub(self.send_unacknowledged) as usize;
+        let data = core::cmp::min(acknowledged, self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.send_unacknowledged = acknowledgement;
+
+        let now = time::uptime();
+        if let Some((sequence, sent)) = self.rtt_probe {
+            if sequence_le(sequence, acknowledgement) {
+                self.update_rto(now - sent);
+                self.rtt_probe = None;
+            }
+        }
+        self.retransmissions = 0;
+        self.retransmit_at = if self.send_unacknowledged == self.send_next {
+            None
+        } else {
+            Some(now + self.rto)
+        };
+    }
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<BTreeMap<Key, Connection>> = Mutex::new(BTreeMap::new());
+
+    /// The connections waiting to be accepted by each listener, by port.
+    static ref LISTENERS: SpinLock<BTreeMap<u16, VecDeque<Key>>> =
+        SpinLock::new(BTreeMap::new());
+}
+
+/// Returns whether sequence number `a` comes before `b`.
+fn sequence_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Returns whether sequence number `a` comes before or is `b`.
+fn sequence_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// Returns the initial sequence number of a new connection, which follows a
+/// clock ticking every 4 microseconds as suggested by RFC 793.
+fn initial_sequence() -> u32 {
+    static OFFSET: AtomicU32 = AtomicU32::new(0);
+    let clock = (time::uptime().as_micros() / 4) as u32;
+    clock.wrapping_add(OFFSET.fetch_add(64000, Ordering::Relaxed))
+}
+
+/// Returns the largest segment which can be sent to a host without
+/// fragmentation.
+fn local_mss(address: Ipv4Address) -> Result<usize, Error> {
+    let route = ipv4::route(address)?;
+    Ok(route.interface.device().mtu() - ipv4::HEADER_SIZE - HEADER_SIZE)
+}
+
+/// Parses and verifies a received segment.
+fn parse<'a>(header: &Header, packet: &'a [u8]) -> Option<Segment<'a>> {
+    if packet.len() < HEADER_SIZE {
+        return None;
+    }
+    let mut checksum = Checksum::pseudo_header(
+        header.source,
+        header.destination,
+        PROTOCOL_TCP,
+        packet.len(),
+    );
+    checksum.add(packet);
+    if checksum.finish() != 0 {
+        return None;
+    }
+    let data_offset = (packet[12] >> 4) as usize * 4;
+    if data_offset < HEADER_SIZE || data_offset > packet.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let mut options = &packet[HEADER_SIZE..data_offset];
+    while let Some((&kind, rest)) = options.split_first() {
+        match kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let length = *rest.first()? as usize;
+                if length < 2 || length > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && length == 4 {
+                    mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[length..];
+            }
+        }
+    }
+
+    Some(Segment {
+        source_port: u16::from_be_bytes([packet[0], packet[1]]),
+        destination_port: u16::from_be_bytes([packet[2], packet[3]]),
+        sequence: u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+        acknowledgement: u32::from_be_bytes(packet[8..12].try_into().unwrap()),
+        flags: packet[13],
+        window: u16::from_be_bytes([packet[14], packet[15]]),
+        mss,
+        data: &packet[data_offset..],
+    })
+}
+
+/// Sends a segment to the peer of a connection.
+fn send_segment(
+    key: &Key,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: usize,
+    mss: Option<usize>,
+    data: &[u8],
+) -> Result<(), Error> {
+    let route = ipv4::route(key.remote_address)?;
+    let header_size = HEADER_SIZE + if mss.is_some() { 4 } else { 0 };
+    let mut segment = Vec::with_capacity(header_size + data.len());
+    segment.extend_from_slice(&key.local_port.to_be_bytes());
+    segment.extend_from_slice(&key.remote_port.to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&acknowledgement.to_be_bytes());
+    segment.push((header_size as u8 / 4) << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&(core::cmp::min(window, u16::MAX as usize) as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        segment.extend_from_slice(&[OPTION_MSS, 4]);
+        segment.extend_from_slice(&(mss as u16).to_be_bytes());
+    }
+    segment.extend_from_slice(data);
+
+    let mut checksum = Checksum::pseudo_header(
+        route.source,
+        key.remote_address,
+        PROTOCOL_TCP,
+        segment.len(),
+    );
+    checksum.add(&segment);
+    segment[16..18].copy_from_slice(&checksum.finish().to_be_bytes());
+    ipv4::send(&route, key.remote_address, PROTOCOL_TCP, &segment)
+}
+
+/// Sends a segment on a connection, acknowledging the data received and
+/// advertising the free space in the receive buffer.
+fn send(key: &Key, connection: &mut Connection, sequence: u32, flags: u8, data: &[u8]) {
+    let window = connection.receive_window();
+    connection.advertised_window = window;
+    let mss = if flags & FLAG_SYN != 0 {
+        Some(connection.mss)
+    } else {
+        None
+    };
+    // Lost segments are recovered by retransmission
+    let _ = send_segment(
+        key,
+        sequence,
+        connection.receive_next,
+        flags | FLAG_ACK,
+        window,
+        mss,
+        data,
+    );
+}
+
+/// Sends the SYN of a connection, which has an acknowledgement only in reply
+/// to the SYN of the peer.
+fn send_syn(key: &Key, connection: &mut Connection) {
+    let window = connection.receive_window();
+    connection.advertised_window = window;
+    let (flags, acknowledgement) = if connection.state == State::SynReceived {
+        (FLAG_SYN | FLAG_ACK, connection.receive_next)
+    } else {
+        (FLAG_SYN, 0)
+    };
+    let _ = send_segment(
+        key,
+        connection.initial_sequence,
+        acknowledgement,
+        flags,
+        window,
+        Some(connection.mss),
+        &[],
+    );
+}
+
+/// Answers a segment which does not belong to a connection with a reset.
+fn send_reset(key: &Key, segment: &Segment) {
+    if segment.flags & FLAG_RST != 0 {
+        return;
+    }
+    let _ = if segment.flags & FLAG_ACK != 0 {
+        send_segment(key, segment.acknowledgement, 0, FLAG_RST, 0, None, &[])
+    } else {
+        let acknowledgement = segment.sequence.wrapping_add(segment.length());
+        send_segment(key, 0, acknowledgement, FLAG_RST | FLAG_ACK, 0, None, &[])
+    };
+}
+
+/// Resets a connection, telling the peer it's gone.
+fn reset(key: &Key, connection: &mut Connection, error: Option<Error>) {
+    let _ = send_segment(key, connection.send_next, 0, FLAG_RST, 0, None, &[]);
+    connection.close(error);
+}
+
+/// Sends as much of the buffered data as the window of the peer allows,
+/// followed by a FIN once the sending direction is closed.
+fn transmit(key: &Key, connection: &mut Connection) {
+    if !connection.can_send() {
+        return;
+    }
+    let now = time::uptime();
+    loop {
+        let in_flight = connection
+            .send_next
+            .wrapping_sub(connection.send_unacknowledged) as usize;
+        let unsent = connection.send_buffer.len() - in_flight;
+        let window = (connection.send_window as usize).saturating_sub(in_flight);
+        let length = core::cmp::min(core::cmp::min(unsent, window), connection.mss);
+        if length == 0 {
+            break;
+        }
+        let data: Vec<u8> = connection
+            .send_buffer
+            .iter()
+            .skip(in_flight)
+            .take(length)
+            .copied()
+            .collect();
+        let sequence = connection.send_next;
+        send(key, connection, sequence, FLAG_PSH, &data);
+        connection.send_next = sequence.wrapping_add(length as u32);
+        if connection.rtt_probe.is_none() {
+            connection.rtt_probe = Some((connection.send_next, now));
+        }
+        if connection.retransmit_at.is_none() {
+            connection.retransmit_at = Some(now + connection.rto);
+        }
+    }
+
+    let in_flight = connection
+        .send_next
+        .wrapping_sub(connection.send_unacknowledged) as usize;
+    if connection.closing && in_flight == connection.send_buffer.len() {
+        let sequence = connection.send_next;
+        send(key, connection, sequence, FLAG_FIN, &[]);
+        connection.send_next = sequence.wrapping_add(1);
+        connection.fin_sent = true;
+        connection.state = match connection.state {
+            State::CloseWait => State::LastAck,
+            _ => State::FinWait1,
+        };
+        if connection.retransmit_at.is_none() {
+            connection.retransmit_at = Some(now + connection.rto);
+        }
+    } else if connection.retransmit_at.is_none() && in_flight < connection.send_buffer.len() {
+        // The window of the peer is closed, so probe it until it opens
+        connection.retransmit_at = Some(now + connection.rto);
+    }
+}
+
+/// Retransmits the first unacknowledged segment of a connection, or probes a
+/// closed window.
+fn retransmit(key: &Key, connection: &mut Connection) {
+    let now = time::uptime();
+    let in_flight = connection
+        .send_next
+        .wrapping_sub(connection.send_unacknowledged) as usize;
+    if in_flight == 0 {
+        // Nothing is lost, but the window of the peer is closed. An old
+        // sequence number makes the peer acknowledge with its current window.
+        let sequence = connection.send_next.wrapping_sub(1);
+        send(key, connection, sequence, 0, &[]);
+        connection.retransmit_at = Some(now + connection.rto);
+        return;
+    }
+
+    connection.retransmissions += 1;
+    if connection.retransmissions > MAX_RETRANSMISSIONS {
+        reset(key, connection, Some(Error::TimedOut));
+        return;
+    }
+    connection.rto = core::cmp::min(connection.rto * 2, MAX_RTO);
+    // Acknowledgements of retransmitted segments give no round-trip time
+    connection.rtt_probe = None;
+
+    match connection.state {
+        State::SynSent | State::SynReceived => send_syn(key, connection),
+        _ => {
+            let sequence = connection.send_unacknowledged;
+            let data_in_flight = core::cmp::min(in_flight, connection.send_buffer.len());
+            if data_in_flight > 0 {
+                let length = core::cmp::min(data_in_flight, connection.mss);
+                let data: Vec<u8> = connection
+                    .send_buffer
+                    .iter()
+                    .take(length)
+                    .copied()
+                    .collect();
+                send(key, connection, sequence, FLAG_PSH, &data);
+            } else {
+                send(key, connection, sequence, FLAG_FIN, &[]);
+            }
+        }
+    }
+    connection.retransmit_at = Some(now + connection.rto);
+}
+
+/// Handles a SYN to a port with a listener, returning the new connection.
+fn open_passive(
+    connections: &BTreeMap<Key, Connection>,
+    key: &Key,
+    segment: &Segment,
+) -> Option<Connection> {
+    if segment.flags & (FLAG_SYN | FLAG_ACK | FLAG_RST) != FLAG_SYN {
+        return None;
+    }
+    let queued = LISTENERS.lock().get(&key.local_port)?.len();
+    let opening = connections
+        .values()
+        .filter(|connection| connection.listener == Some(key.local_port))
+        .count();
+    if queued + opening >= BACKLOG {
+        return None;
+    }
+
+    let mss = local_mss(key.remote_address).ok()?;
+    let mut connection = Connection::new(State::SynReceived, mss);
+    connection.receive_next = segment.sequence.wrapping_add(1);
+    connection.send_window = segment.window as u32;
+    connection.mss = core::cmp::min(mss, segment.mss.map_or(DEFAULT_MSS, usize::from));
+    connection.owned = false;
+    connection.listener = Some(key.local_port);
+    send_syn(key, &mut connection);
+    Some(connection)
+}
+
+/// Handles a segment on a connection in the SYN-SENT state, which waits for
+/// the SYN of the peer.
+fn process_syn_sent(key: &Key, connection: &mut Connection, segment: &Segment) {
+    let has_ack = segment.flags & FLAG_ACK != 0;
+    if has_ack && segment.acknowledgement != connection.send_next {
+        send_reset(key, segment);
+        return;
+    }
+    if segment.flags & FLAG_RST != 0 {
+        if has_ack {
+            connection.close(Some(Error::ConnectionRefused));
+        }
+        return;
+    }
+    if segment.flags & FLAG_SYN == 0 || !has_ack {
+        return;
+    }
+
+    connection.receive_next = segment.sequence.wrapping_add(1);
+    connection.send_window = segment.window as u32;
+    connection.mss = core::cmp::min(connection.mss, segment.mss.map_or(DEFAULT_MSS, usize::from));
+    connection.acknowledge(segment.acknowledgement);
+    connection.state = State::Established;
+    let sequence = connection.send_next;
+    send(key, connection, sequence, 0, &[]);
+}
+
+/// Handles a segment on a connection which is synchronized with the peer.
+fn process(key: &Key, connection: &mut Connection, segment: &Segment) {
+    if connection.state == State::Closed {
+        send_reset(key, segment);
+        return;
+    }
+    if connection.state == State::SynSent {
+        process_syn_sent(key, connection, segment);
+        return;
+    }
+    if segment.flags & FLAG_SYN != 0 {
+        // A retransmission of the SYN of the peer, whose acknowledgement was
+        // lost
+        if connection.state == State::SynReceived {
+            send_syn(key, connection);
+        } else {
+            let sequence = connection.send_next;
+            send(key, connection, sequence, 0, &[]);
+        }
+        return;
+    }
+
+    // How far the segment starts before the next expected sequence number
+    let before = connection.receive_next.wrapping_sub(segment.sequence) as i32;
+    let has_fin = segment.flags & FLAG_FIN != 0;
+    if before < 0 || before as usize > segment.data.len() + has_fin as usize {
+        // Out of order, or entirely received before. A duplicate
+        // acknowledgement tells the peer what's expected.
+        if segment.flags & FLAG_RST == 0 {
+            let sequence = connection.send_next;
+            send(key, connection, sequence, 0, &[]);
+        }
+        return;
+    }
+    let before = before as usize;
+
+    if segment.flags & FLAG_RST != 0 {
+        // Only a reset at exactly the expected sequence number is accepted,
+        // which protects against blind resets
+        if before == 0 {
+            let error = if connection.owned {
+                Some(Error::ConnectionReset)
+            } else {
+                None
+            };
+            connection.close(error);
+        }
+        return;
+    }
+    if segment.flags & FLAG_ACK == 0 {
+        return;
+    }
+
+    if connection.state == State::SynReceived {
+        if segment.acknowledgement != connection.send_next {
+            send_reset(key, segment);
+            return;
+        }
+        connection.acknowledge(segment.acknowledgement);
+        connection.send_window = segment.window as u32;
+        connection.state = State::Established;
+        // Hand the connection to its listener, or refuse it if the listener
+        // is gone
+        let port = connection.listener.take().unwrap();
+        match LISTENERS.lock().get_mut(&port) {
+            Some(queue) => queue.push_back(*key),
+            None => {
+                reset(key, connection, None);
+                return;
+            }
+        }
+        connection.owned = true;
+    } else if sequence_lt(connection.send_unacknowledged, segment.acknowledgement)
+        && sequence_le(segment.acknowledgement, connection.send_next)
+    {
+        connection.acknowledge(segment.acknowledgement);
+        connection.send_window = segment.window as u32;
+    } else if segment.acknowledgement == connection.send_unacknowledged {
+        connection.send_window = segment.window as u32;
+    } else if sequence_lt(connection.send_next, segment.acknowledgement) {
+        // Acknowledges data which was never sent
+        let sequence = connection.send_next;
+        send(key, connection, sequence, 0, &[]);
+        return;
+    }
+
+    // Our FIN is acknowledged
+    if connection.fin_sent && connection.send_unacknowledged == connection.send_next {
+        match connection.state {
+            State::FinWait1 => connection.state = State::FinWait2,
+            State::Closing => connection.enter_time_wait(),
+            State::LastAck => {
+                connection.close(None);
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    // Take the data not received before, as far as there is space for it
+    let data = &segment.data[core::cmp::min(before, segment.data.len())..];
+    let mut has_fin = has_fin && before <= segment.data.len();
+    let mut needs_ack = before > 0;
+    if !data.is_empty() {
+        if connection.can_receive() {
+            let length = core::cmp::min(data.len(), connection.receive_window());
+            connection.receive_buffer.extend(&data[..length]);
+            connection.receive_next = connection.receive_next.wrapping_add(length as u32);
+            has_fin = has_fin && length == data.len();
+        } else {
+            has_fin = false;
+        }
+        needs_ack = true;
+    }
+    if has_fin && !connection.fin_received {
+        connection.receive_next = connection.receive_next.wrapping_add(1);
+        connection.fin_received = true;
+        match connection.state {
+            State::SynReceived | State::Established => connection.state = State::CloseWait,
+            State::FinWait1 => connection.state = State::Closing,
+            State::FinWait2 => connection.enter_time_wait(),
+            _ => {}
+        }
+        needs_ack = true;
+    }
+    if needs_ack {
+        let sequence = connection.send_next;
+        send(key, connection, sequence, 0, &[]);
+    }
+
+    // The acknowledgement may have opened the window
+    transmit(key, connection);
+}
+
+/// Handles a received segment.
+pub fn handle(header: &Header, packet: &[u8]) {
+    let segment = match parse(header, packet) {
+        Some(segment) => segment,
+        None => return,
+    };
+    let key = Key {
+        local_port: segment.destination_port,
+        remote_address: header.source,
+        remote_port: segment.source_port,
+    };
+
+    let mut connections = CONNECTIONS.lock();
+    if let Some(connection) = connections.get_mut(&key) {
+        process(&key, connection, &segment);
+    } else if let Some(connection) = open_passive(&connections, &key, &segment) {
+        connections.insert(key, connection);
+    } else {
+        send_reset(&key, &segment);
+    }
+}
+
+/// Runs the retransmission and TIME-WAIT timers, and removes the closed
+/// connections no longer referred to.
+pub fn poll() {
+    let now = time::uptime();
+    let mut connections = CONNECTIONS.lock();
+    let mut removed = Vec::new();
+    for (key, connection) in connections.iter_mut() {
+        if matches!(connection.retransmit_at, Some(at) if at <= now) {
+            retransmit(key, connection);
+        }
+        if matches!(connection.time_wait_until, Some(until) if until <= now) {
+            connection.close(None);
+        }
+        if connection.state == State::Closed && !connection.owned {
+            removed.push(*key);
+        }
+    }
+    for key in removed {
+        connections.remove(&key);
+    }
+}
+
+/// A TCP connection.
+///
+/// Dropping the stream closes the connection, which lingers until the data
+/// written is delivered.
+pub struct TcpStream {
+    key: Key,
+    timeout: Option<Duration>,
+}
+
+impl TcpStream {
+    /// Opens a connection to a port of a host, waiting for at most the
+    /// timeout.
+    pub fn connect(
+        address: Ipv4Address,
+        port: u16,
+        timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let mss = local_mss(address)?;
+        let mut connections = CONNECTIONS.lock();
+        let local_port = {
+            let listeners = LISTENERS.lock();
+            net::ephemeral_port(|port| {
+                listeners.contains_key(&port)
+                    || connections.keys().any(|key| key.local_port == port)
+            })
+            .ok_or(Error::AddressInUse)?
+        };
+        let key = Key {
+            local_port,
+            remote_address: address,
+            remote_port: port,
+        };
+        let mut connection = Connection::new(State::SynSent, mss);
+        send_syn(&key, &mut connection);
+        connections.insert(key, connection);
+        drop(connections);
+
+        // Dropping the stream on failure removes the connection
+        let stream = Self { key, timeout };
+        net::wait_until(
+            || stream.with_connection(|connection| connection.state != State::SynSent),
+            timeout,
+        )?;
+        stream.with_connection(|connection| match connection.state {
+            State::Closed => Err(connection.error.unwrap_or(Error::NotConnected)),
+            _ => Ok(()),
+        })?;
+        Ok(stream)
+    }
+
+    /// Sets how long reads and writes wait, or `None` to wait indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the local port of the connection.
+    pub fn local_port(&self) -> u16 {
+        self.key.local_port
+    }
+
+    /// Returns the address and port of the peer.
+    pub fn peer(&self) -> (Ipv4Address, u16) {
+        (self.key.remote_address, self.key.remote_port)
+    }
+
+    /// Calls a closure with the connection of the stream.
+    fn with_connection<R>(&self, f: impl FnOnce(&mut Connection) -> R) -> R {
+        let mut connections = CONNECTIONS.lock();
+        // Connections are not removed while a stream refers to them
+        let connection = connections.get_mut(&self.key).unwrap();
+        f(connection)
+    }
+
+    /// Waits for data, and reads it into the buffer. Returns the number of
+    /// bytes read, which is 0 once the peer has closed the connection and all
+    /// data is read.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let key = self.key;
+        let mut result = None;
+        net::wait_until(
+            || {
+                result = self.with_connection(|connection| {
+                    if !connection.receive_buffer.is_empty() {
+                        let length = core::cmp::min(buffer.len(), connection.receive_buffer.len());
+                        for (byte, received) in buffer
+                            .iter_mut()
+                            .zip(connection.receive_buffer.drain(..length))
+                        {
+                            *byte = received;
+                        }
+                        // Tell the peer once a good part of the buffer is free
+                        // again, rather than after every read
+                        if connection.can_receive()
+                            && connection.advertised_window < BUFFER_SIZE / 2
+                            && connection.receive_window() >= BUFFER_SIZE / 2
+                        {
+                            let sequence = connection.send_next;
+                            send(&key, connection, sequence, 0, &[]);
+                        }
+                        Some(Ok(length))
+                    } else if connection.fin_received {
+                        Some(Ok(0))
+                    } else if let Some(error) = connection.error {
+                        Some(Err(error))
+                    } else if connection.state == State::Closed {
+                        Some(Err(Error::NotConnected))
+                    } else {
+                        None
+                    }
+                });
+                result.is_some()
+            },
+            self.timeout,
+        )?;
+        result.unwrap()
+    }
+
+    /// Waits for space in the send buffer, and writes as much of the data as
+    /// fits. Returns the number of bytes written.
+    pub fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let key = self.key;
+        let mut result = None;
+        net::wait_until(
+            || {
+                result = self.with_connection(|connection| {
+                    if let Some(error) = connection.error {
+                        return Some(Err(error));
+                    }
+                    if connection.closing || !connection.can_send() {
+                        return Some(Err(Error::NotConnected));
+                    }
+                    let length =
+                        core::cmp::min(data.len(), BUFFER_SIZE - connection.send_buffer.len());
+                    if length == 0 {
+                        return None;
+                    }
+                    connection.send_buffer.extend(&data[..length]);
+                    transmit(&key, connection);
+                    Some(Ok(length))
+                });
+                result.is_some()
+            },
+            self.timeout,
+        )?;
+        result.unwrap()
+    }
+
+    /// Writes all of the data, waiting for space in the send buffer as
+    /// needed.
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let length = self.write(data)?;
+            data = &data[length..];
+        }
+        Ok(())
+    }
+
+    /// Closes the sending direction of the connection, such that the peer
+    /// reads the end of the data once it's delivered. The stream can still be
+    /// read from.
+    pub fn shutdown(&self) {
+        let key = self.key;
+        self.with_connection(|connection| match connection.state {
+            State::SynSent | State::SynReceived => connection.close(None),
+            State::Established | State::CloseWait if !connection.closing => {
+                connection.closing = true;
+                transmit(&key, connection);
+            }
+            _ => {}
+        });
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let key = self.key;
+        let mut connections = CONNECTIONS.lock();
+        let connection = connections.get_mut(&key).unwrap();
+        if !connection.receive_buffer.is_empty() && connection.state != State::Closed {
+            // Data the peer sent would be lost, so it must not take the close
+            // as a successful delivery
+            reset(&key, connection, None);
+        } else if matches!(connection.state, State::SynSent | State::SynReceived) {
+            connection.close(None);
+        } else if connection.can_send() && !connection.closing {
+            connection.closing = true;
+            transmit(&key, connection);
+        }
+        connection.owned = false;
+        if connection.state == State::Closed {
+            connections.remove(&key);
+        }
+    }
+}
+
+/// A socket accepting TCP connections on a local port.
+pub struct TcpListener {
+    port: u16,
+    timeout: Option<Duration>,
+}
+
+impl TcpListener {
+    /// Starts listening on a port, or on an unused one if the port is 0.
+    pub fn bind(port: u16) -> Result<Self, Error> {
+        let mut listeners = LISTENERS.lock();
+        let port = match port {
+            0 => net::ephemeral_port(|port| listeners.contains_key(&port))
+                .ok_or(Error::AddressInUse)?,
+            port if listeners.contains_key(&port) => return Err(Error::AddressInUse),
+            port => port,
+        };
+        listeners.insert(port, VecDeque::new());
+        Ok(Self {
+            port,
+            timeout: None,
+        })
+    }
+
+    /// Sets how long [accept](Self::accept) waits, or `None` to wait
+    /// indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the port the listener is bound to.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for a connection to be established, and returns it.
+    pub fn accept(&self) -> Result<TcpStream, Error> {
+        let mut key = None;
+        net::wait_until(
+            || {
+                key = LISTENERS
+                    .lock()
+                    .get_mut(&self.port)
+                    .and_then(|queue| queue.pop_front());
+                key.is_some()
+            },
+            self.timeout,
+        )?;
+        Ok(TcpStream {
+            key: key.unwrap(),
+            timeout: None,
+        })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock();
+        let queue = LISTENERS.lock().remove(&self.port).unwrap_or_default();
+        // Reset the connections which were never accepted
+        for key in queue {
+            if let Some(mut connection) = connections.remove(&key) {
+                if connection.state != State::Closed {
+                    reset(&key, &mut connection, None);
+                }
+            }
+        }
+    }
+}
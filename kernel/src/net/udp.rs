@@ -0,0 +1,140 @@
+//! The User Datagram Protocol (UDP).
+//!
+//! Received datagrams are queued on the socket bound to their destination
+//! port until read, and dropped if there is no such socket or its queue is
+//! full.
+
+use super::ipv4::{self, Checksum, Header, Ipv4Address, Route, PROTOCOL_UDP};
+use super::Error;
+use crate::net;
+use crate::sync::spinlock::SpinLock;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::time::Duration;
+
+const HEADER_SIZE: usize = 8;
+
+/// The number of datagrams queued on a socket, past which further datagrams
+/// are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+/// A received datagram, with the address and port it was sent from.
+type Datagram = (Vec<u8>, Ipv4Address, u16);
+
+lazy_static! {
+    /// The queues of received datagrams of the bound sockets, by port.
+    static ref SOCKETS: SpinLock<BTreeMap<u16, VecDeque<Datagram>>> = SpinLock::new(BTreeMap::new());
+}
+
+/// A UDP socket bound to a local port.
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds a socket to a port, or to an unused one if the port is 0.
+    pub fn bind(port: u16) -> Result<Self, Error> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => net::ephemeral_port(|port| sockets.contains_key(&port))
+                .ok_or(Error::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(Error::AddressInUse),
+            port => port,
+        };
+        sockets.insert(port, VecDeque::new());
+        Ok(Self { port })
+    }
+
+    /// Returns the port the socket is bound to.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends a datagram to a port of a host.
+    pub fn send_to(&self, data: &[u8], address: Ipv4Address, port: u16) -> Result<(), Error> {
+        let route = ipv4::route(address)?;
+        send(&route, self.port, address, port, data)
+    }
+
+    /// Waits for a datagram, and returns it with the address and port it was
+    /// sent from.
+    pub fn receive_from(&self, timeout: Option<Duration>) -> Result<Datagram, Error> {
+        let mut datagram = None;
+        net::wait_until(
+            || {
+                datagram = SOCKETS
+                    .lock()
+                    .get_mut(&self.port)
+                    .and_then(|queue| queue.pop_front());
+                datagram.is_some()
+            },
+            timeout,
+        )?;
+        Ok(datagram.unwrap())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Sends a datagram along a route, from the given local port.
+pub fn send(
+    route: &Route,
+    source_port: u16,
+    destination: Ipv4Address,
+    destination_port: u16,
+    data: &[u8],
+) -> Result<(), Error> {
+    let length = HEADER_SIZE + data.len();
+    if length > u16::MAX as usize {
+        return Err(Error::InvalidArgument);
+    }
+    let mut datagram = Vec::with_capacity(length);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination_port.to_be_bytes());
+    datagram.extend_from_slice(&(length as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+
+    let mut checksum = Checksum::pseudo_header(route.source, destination, PROTOCOL_UDP, length);
+    checksum.add(&datagram);
+    // A checksum of zero means there is none, so it's sent as all ones
+    let checksum = match checksum.finish() {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    ipv4::send(route, destination, PROTOCOL_UDP, &datagram)
+}
+
+/// Handles a received datagram, queueing it on the socket bound to its
+/// destination port.
+pub fn handle(header: &Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let length = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if length < HEADER_SIZE || length > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..length];
+    if datagram[6..8] != [0, 0] {
+        let mut checksum =
+            Checksum::pseudo_header(header.source, header.destination, PROTOCOL_UDP, length);
+        checksum.add(datagram);
+        if checksum.finish() != 0 {
+            return;
+        }
+    }
+
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    if let Some(queue) = SOCKETS.lock().get_mut(&destination_port) {
+        if queue.len() < MAX_QUEUED_DATAGRAMS {
+            queue.push_back((datagram[HEADER_SIZE..].to_vec(), header.source, source_port));
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Access to the configuration space of PCI functions.
+//!
+//! The configuration space is accessed through the memory mapped regions
+//! described by the ACPI MCFG table (ECAM) when there is one, which also gives
+//! access to the extended configuration space past the first 256 bytes.
+//! Otherwise, or for functions outside those regions, the legacy ports
+//! `0xcf8` and `0xcfc` are used, which only reach segment 0.
+//!
+//! The regions are accessed through the direct map of physical memory, which
+//! relies on the firmware having made them uncacheable with the MTRRs.
+
+use crate::acpi;
+use crate::cmdline::CMDLINE;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::sync::spinlock::SpinLock;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+use rk_x86_64::port;
+use spin::Once;
+
+/// The port selecting the register accessed through [DATA_PORT].
+const ADDRESS_PORT: u16 = 0xcf8;
+
+/// The port holding the selected register.
+const DATA_PORT: u16 = 0xcfc;
+
+/// The size of the configuration space of a function with ECAM.
+const ECAM_FUNCTION_SIZE: u16 = 4096;
+
+/// The size of the configuration space reachable through the legacy ports.
+const LEGACY_FUNCTION_SIZE: u16 = 256;
+
+/// The direct map only covers the first 512 GiB of physical memory.
+const DIRECT_MAP_SIZE: u64 = 512 << 30;
+
+/// The address of a PCI function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A memory mapped configuration region, covering a range of buses in a
+/// segment.
+#[derive(Copy, Clone, Debug)]
+pub struct EcamRegion {
+    /// The physical address of the configuration space of bus 0, even if the
+    /// region starts at a later bus.
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    /// Returns the virtual address of a register, if the function is in the
+    /// region.
+    fn register(&self, address: Address, offset: u16) -> Option<u64> {
+        if address.segment != self.segment
+            || address.bus < self.start_bus
+            || address.bus > self.end_bus
+        {
+            return None;
+        }
+        let function = (address.bus as u64) << 20
+            | (address.device as u64) << 15
+            | (address.function as u64) << 12;
+        Some(PHYS_MEM_OFFSET | (self.base + function + offset as u64))
+    }
+}
+
+/// The memory mapped configuration regions, empty when only the legacy ports
+/// are used.
+static REGIONS: Once<Vec<EcamRegion>> = Once::new();
+
+/// Serializes the selection and access of registers through the legacy ports.
+static LEGACY_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// Reads the memory mapped configuration regions from the MCFG table, unless
+/// disabled with the `pci_ecam=false` command line option.
+pub fn init() {
+    REGIONS.call_once(|| {
+        if CMDLINE.get_bool("pci_ecam") == Some(false) {
+            return Vec::new();
+        }
+        let mcfg = match acpi::find_table(b"MCFG") {
+            Some(mcfg) => mcfg,
+            None => return Vec::new(),
+        };
+        // The entries follow the header and 8 reserved bytes
+        mcfg[acpi::HEADER_SIZE + 8..]
+            .chunks_exact(16)
+            .map(|entry| EcamRegion {
+                base: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                segment: u16::from_le_bytes(entry[8..10].try_into().unwrap()),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+            .filter(|region| {
+                region.start_bus <= region.end_bus
+                    && region.base + ((region.end_bus as u64 + 1) << 20) <= DIRECT_MAP_SIZE
+            })
+            .collect()
+    });
+}
+
+/// Returns the memory mapped configuration regions.
+pub fn regions() -> &'static [EcamRegion] {
+    REGIONS
+        .get()
+        .map(|regions| regions.as_slice())
+        .unwrap_or(&[])
+}
+
+/// Returns the size of the configuration space of a function that can be
+/// accessed.
+pub fn function_size(address: Address) -> u16 {
+    if ecam_register(address, 0).is_some() {
+        ECAM_FUNCTION_SIZE
+    } else {
+        LEGACY_FUNCTION_SIZE
+    }
+}
+
+/// Returns the virtual address of a register if the function is in a memory
+/// mapped region.
+fn ecam_register(address: Address, offset: u16) -> Option<u64> {
+    regions()
+        .iter()
+        .find_map(|region| region.register(address, offset))
+}
+
+/// Selects the double word containing a register through the legacy address
+/// port, and returns the data port to access the register through. Returns
+/// `None` if the register cannot be reached through the ports.
+///
+/// # Safety
+/// The legacy lock must be held until the register has been accessed.
+unsafe fn legacy_select(address: Address, offset: u16) -> Option<u16> {
+    if address.segment != 0 || offset >= LEGACY_FUNCTION_SIZE {
+        return None;
+    }
+    let value = 1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xfc);
+    port::write_u32(ADDRESS_PORT, value);
+    Some(DATA_PORT + (offset & 3))
+}
+
+/// Reads a byte from the configuration space. Registers which cannot be
+/// reached read as all ones, like those of absent functions.
+pub fn read_u8(address: Address, offset: u16) -> u8 {
+    if let Some(register) = ecam_register(address, offset) {
+        return unsafe { core::ptr::read_volatile(register as *const u8) };
+    }
+    let _lock = LEGACY_LOCK.lock();
+    unsafe {
+        match legacy_select(address, offset) {
+            Some(data_port) => port::read_u8(data_port),
+            None => u8::MAX,
+        }
+    }
+}
+
+/// Reads a word from the configuration space at an offset aligned to two
+/// bytes.
+pub fn read_u16(address: Address, offset: u16) -> u16 {
+    debug_assert!(offset & 1 == 0);
+    if let Some(register) = ecam_register(address, offset) {
+        return unsafe { core::ptr::read_volatile(register as *const u16) };
+    }
+    let _lock = LEGACY_LOCK.lock();
+    unsafe {
+        match legacy_select(address, offset) {
+            Some(data_port) => port::read_u16(data_port),
+            None => u16::MAX,
+        }
+    }
+}
+
+/// Reads a double word from the configuration space at an offset aligned to
+/// four bytes.
+pub fn read_u32(address: Address, offset: u16) -> u32 {
+    debug_assert!(offset & 3 == 0);
+    if let Some(register) = ecam_register(address, offset) {
+        return unsafe { core::ptr::read_volatile(register as *const u32) };
+    }
+    let _lock = LEGACY_LOCK.lock();
+    unsafe {
+        match legacy_select(address, offset) {
+            Some(data_port) => port::read_u32(data_port),
+            None => u32::MAX,
+        }
+    }
+}
+
+/// Writes a byte to the configuration space. Writes to registers which cannot
+/// be reached are ignored.
+pub fn write_u8(address: Address, offset: u16, value: u8) {
+    if let Some(register) = ecam_register(address, offset) {
+        unsafe { core::ptr::write_volatile(register as *mut u8, value) };
+        return;
+    }
+    let _lock = LEGACY_LOCK.lock();
+    unsafe {
+        if let Some(data_port) = legacy_select(address, offset) {
+            port::write_u8(data_port, value);
+        }
+    }
+}
+
+/// Writes a word to the configuration space at an offset aligned to two
+/// bytes.
+pub fn write_u16(address: Address, offset: u16, value: u16) {
+    debug_assert!(offset & 1 == 0);
+    if let Some(register) = ecam_register(address, offset) {
+        unsafe { core::ptr::write_volatile(register as *mut u16, value) };
+        return;
+    }
+    let _lock = LEGACY_LOCK.lock();
+    unsafe {
+        if let Some(data_port) = legacy_select(address, offset) {
+            port::write_u16(data_port, value);
+        }
+    }
+}
+
+/// Writes a double word to the configuration space at an offset aligned to
+/// four bytes.
+pub fn write_u32(address: Address, offset: u16, value: u32) {
+    debug_assert!(offset & 3 == 0);
+    if let Some(register) = ecam_register(address, offset) {
+        unsafe { core::ptr::write_volatile(register as *mut u32, value) };
+        return;
+    }
+    let _lock = LEGACY_LOCK.lock();
+    unsafe {
+        if let Some(data_port) = legacy_select(address, offset) {
+            port::write_u32(data_port, value);
+        }
+    }
+}
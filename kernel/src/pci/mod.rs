@@ -0,0 +1,458 @@
+//! The PCI bus.
+//!
+//! On [init] every bus is enumerated, starting from the host bridges and
+//! following PCI-to-PCI bridges, and the functions found are kept as
+//! [Device]s. Drivers register themselves with [register_driver], and are
+//! then probed for every device they match which is not claimed by another
+//! driver.
+
+pub mod config;
+pub mod msi;
+
+use crate::println;
+use crate::sync::spinlock::SpinLock;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use config::Address;
+use core::fmt;
+use msi::{Msi, MsiX};
+use spin::Once;
+
+/// Enables responses to accesses to I/O space BARs.
+pub const COMMAND_IO: u16 = 1 << 0;
+/// Enables responses to accesses to memory space BARs.
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+/// Allows the function to access memory by itself, which is needed for DMA
+/// and message signaled interrupts.
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Stops the function from asserting its interrupt line.
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// The status bit telling the function has a list of capabilities.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The class and subclass of PCI-to-PCI bridges.
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// A base address register, mapping a range of memory or I/O space to the
+/// function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        /// The physical address of the range.
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64_bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Memory {
+                address,
+                size,
+                prefetchable,
+                is_64_bit,
+            } => write!(
+                f,
+                "memory at {:#x} ({} bytes{}{})",
+                address,
+                size,
+                if *is_64_bit { ", 64-bit" } else { "" },
+                if *prefetchable { ", prefetchable" } else { "" }
+            ),
+            Self::Io { port, size } => write!(f, "I/O at {:#x} ({} ports)", port, size),
+        }
+    }
+}
+
+/// A PCI function.
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// The layout of the configuration space, without the multi-function bit.
+    pub header_type: u8,
+    /// The BARs, by index. The upper half of a 64-bit BAR is `None`, as are
+    /// unused BARs.
+    pub bars: [Option<Bar>; 6],
+    /// The legacy interrupt line routed to the function by the firmware.
+    pub interrupt_line: u8,
+    /// The interrupt pin used by the function, from 1 for INTA to 4 for INTD,
+    /// or 0 if it does not use one.
+    pub interrupt_pin: u8,
+    /// The identifiers and offsets of the capabilities in the list.
+    pub capabilities: Vec<(u8, u16)>,
+    pub msi: Option<Msi>,
+    pub msi_x: Option<MsiX>,
+    /// The name of the driver which claimed the device.
+    driver: SpinLock<Option<&'static str>>,
+}
+
+impl Device {
+    /// Reads the function at the given address, or returns `None` if there is
+    /// none.
+    fn read(address: Address) -> Option<Self> {
+        let id = config::read_u32(address, 0x00);
+        let vendor_id = id as u16;
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let class = config::read_u32(address, 0x08);
+        let header_type = config::read_u8(address, 0x0e) & 0x7f;
+        let interrupt = config::read_u16(address, 0x3c);
+
+        let mut device = Self {
+            address,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars: [None; 6],
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            capabilities: Vec::new(),
+            msi: None,
+            msi_x: None,
+            driver: SpinLock::new(None),
+        };
+        device.read_bars();
+        device.read_capabilities();
+        Some(device)
+    }
+
+    /// Reads and sizes the BARs. Decoding is disabled while sizing, since the
+    /// BARs briefly hold invalid addresses.
+    fn read_bars(&mut self) {
+        let count = match self.header_type {
+            0 => 6,
+            1 => 2,
+            _ => return,
+        };
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO | COMMAND_MEMORY));
+
+        let mut index = 0;
+        while index < count {
+            let offset = 0x10 + index as u16 * 4;
+            let (bar, width) = self.size_bar(offset, index + 1 < count);
+            self.bars[index] = bar;
+            index += width;
+        }
+
+        self.set_command(command);
+    }
+
+    /// Returns the BAR at the given offset, if it's used, and the number of
+    /// registers it takes.
+    fn size_bar(&self, offset: u16, may_be_64_bit: bool) -> (Option<Bar>, usize) {
+        let address = self.address;
+        let original = config::read_u32(address, offset);
+        config::write_u32(address, offset, u32::MAX);
+        let mask = config::read_u32(address, offset);
+        config::write_u32(address, offset, original);
+
+        if original & 1 == 1 {
+            let mask = mask & !3 & 0xffff;
+            if mask == 0 {
+                return (None, 1);
+            }
+            let bar = Bar::Io {
+                port: (original & !3) as u16,
+                size: (!mask + 1) as u16,
+            };
+            return (Some(bar), 1);
+        }
+
+        let is_64_bit = (original >> 1) & 3 == 2 && may_be_64_bit;
+        let mut base = (original & !0xf) as u64;
+        let mut mask = (mask & !0xf) as u64;
+        if is_64_bit {
+            let original_high = config::read_u32(address, offset + 4);
+            config::write_u32(address, offset + 4, u32::MAX);
+            let mask_high = config::read_u32(address, offset + 4);
+            config::write_u32(address, offset + 4, original_high);
+            base |= (original_high as u64) << 32;
+            mask |= (mask_high as u64) << 32;
+        } else {
+            mask |= 0xffff_ffff_0000_0000;
+        }
+
+        let width = if is_64_bit { 2 } else { 1 };
+        if mask & 0xffff_ffff == 0 && (!is_64_bit || mask == 0) {
+            return (None, width);
+        }
+        let bar = Bar::Memory {
+            address: base,
+            size: !mask + 1,
+            prefetchable: original & 0x8 != 0,
+            is_64_bit,
+        };
+        (Some(bar), width)
+    }
+
+    /// Walks the list of capabilities, if the function has one.
+    fn read_capabilities(&mut self) {
+        if config::read_u16(self.address, 0x06) & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+        let mut offset = (config::read_u8(self.address, 0x34) & 0xfc) as u16;
+        // Each capability takes at least 4 bytes of the 192 bytes following
+        // the header, which bounds a list looping back on itself
+        while offset >= 0x40 && self.capabilities.len() < 48 {
+            let id = config::read_u8(self.address, offset);
+            self.capabilities.push((id, offset));
+            match id {
+                msi::CAPABILITY_MSI => self.msi = Some(Msi::parse(self.address, offset)),
+                msi::CAPABILITY_MSI_X => {
+                    self.msi_x = Some(MsiX::parse(self.address, offset, &self.bars))
+                }
+                _ => {}
+            }
+            offset = (config::read_u8(self.address, offset + 1) & 0xfc) as u16;
+        }
+    }
+
+    /// Returns the offset of the first capability with the given identifier.
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        self.capabilities
+            .iter()
+            .find(|&&(capability_id, _)| capability_id == id)
+            .map(|&(_, offset)| offset)
+    }
+
+    /// Returns the command register.
+    pub fn command(&self) -> u16 {
+        config::read_u16(self.address, 0x04)
+    }
+
+    /// Sets the command register to the given `COMMAND_*` bits.
+    pub fn set_command(&self, command: u16) {
+        config::write_u16(self.address, 0x04, command);
+    }
+
+    /// Sets the given `COMMAND_*` bits in the command register, leaving the
+    /// others as they are.
+    pub fn enable(&self, bits: u16) {
+        self.set_command(self.command() | bits);
+    }
+
+    /// Returns whether the function is a PCI-to-PCI bridge.
+    fn is_bridge(&self) -> bool {
+        self.header_type == 1 && self.class == CLASS_BRIDGE && self.subclass == SUBCLASS_PCI_BRIDGE
+    }
+
+    /// Returns the name of the driver which claimed the device.
+    pub fn driver(&self) -> Option<&'static str> {
+        *self.driver.lock()
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}{:02x}{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )?;
+        if (1..=4).contains(&self.interrupt_pin) {
+            write!(
+                f,
+                " INT{} irq {}",
+                (b'A' + self.interrupt_pin - 1) as char,
+                self.interrupt_line
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What a driver supports, matched against devices.
+#[derive(Copy, Clone, Debug)]
+pub enum Match {
+    /// A device with the given vendor and device identifiers.
+    Id { vendor_id: u16, device_id: u16 },
+    /// Any device of the given class and subclass, and programming interface
+    /// if given.
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+
+impl Match {
+    fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Self::Id {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device.device_id == device_id,
+            Self::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+/// A driver of PCI devices.
+pub struct Driver {
+    pub name: &'static str,
+    /// The devices the driver supports.
+    pub matches: &'static [Match],
+    /// Sets up a device matched by the driver, which claims it unless an error
+    /// is returned.
+    pub probe: fn(&Arc<Device>) -> Result<(), &'static str>,
+}
+
+/// The devices found by enumeration.
+static DEVICES: Once<Vec<Arc<Device>>> = Once::new();
+
+/// The registered drivers.
+static DRIVERS: SpinLock<Vec<&'static Driver>> = SpinLock::new(Vec::new());
+
+/// Returns every device found.
+pub fn devices() -> &'static [Arc<Device>] {
+    DEVICES
+        .get()
+        .map(|devices| devices.as_slice())
+        .unwrap_or(&[])
+}
+
+/// Probes a driver for a device if it matches and is not claimed yet.
+fn probe(driver: &'static Driver, device: &Arc<Device>) {
+    if !driver.matches.iter().any(|m| m.matches(device)) {
+        return;
+    }
+    {
+        let mut claimed = device.driver.lock();
+        if claimed.is_some() {
+            return;
+        }
+        *claimed = Some(driver.name);
+    }
+    if let Err(error) = (driver.probe)(device) {
+        println!(
+            "PCI {}: {} driver failed: {}",
+            device.address, driver.name, error
+        );
+        *device.driver.lock() = None;
+    }
+}
+
+/// Registers a driver, and probes it for the matching devices.
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        probe(driver, device);
+    }
+}
+
+/// Finds the functions on a bus and behind the bridges on it.
+fn scan_bus(segment: u16, bus: u8, visited: &mut BTreeSet<(u16, u8)>, found: &mut Vec<Device>) {
+    if !visited.insert((segment, bus)) {
+        return;
+    }
+    for device in 0..32 {
+        for function in 0..8 {
+            let address = Address::new(segment, bus, device, function);
+            // Functions other than 0 only exist on multi-function devices
+            if function > 0
+                && config::read_u8(Address::new(segment, bus, device, 0), 0x0e) & 0x80 == 0
+            {
+                break;
+            }
+            let found_device = match Device::read(address) {
+                Some(found_device) => found_device,
+                None if function == 0 => break,
+                None => continue,
+            };
+            let secondary_bus = if found_device.is_bridge() {
+                Some(config::read_u8(address, 0x19))
+            } else {
+                None
+            };
+            found.push(found_device);
+            if let Some(secondary_bus) = secondary_bus {
+                if secondary_bus > bus {
+                    scan_bus(segment, secondary_bus, visited, found);
+                }
+            }
+        }
+    }
+}
+
+/// Enumerates the devices and probes the registered drivers for them.
+pub fn init() {
+    config::init();
+
+    let mut visited = BTreeSet::new();
+    let mut found = Vec::new();
+    // Each segment reached through memory mapped regions is scanned from its
+    // first bus, and segment 0 through the legacy ports otherwise
+    let mut roots: Vec<(u16, u8)> = config::regions()
+        .iter()
+        .map(|region| (region.segment, region.start_bus))
+        .collect();
+    if !roots.iter().any(|&(segment, _)| segment == 0) {
+        roots.push((0, 0));
+    }
+    for (segment, bus) in roots {
+        // A multi-function host bridge has a function for each host bus
+        let host = Address::new(segment, bus, 0, 0);
+        if config::read_u8(host, 0x0e) & 0x80 != 0 {
+            for function in 0..8 {
+                let present =
+                    config::read_u16(Address::new(segment, bus, 0, function), 0x00) != 0xffff;
+                if let (true, Some(host_bus)) = (present, bus.checked_add(function)) {
+                    scan_bus(segment, host_bus, &mut visited, &mut found);
+                }
+            }
+        } else {
+            scan_bus(segment, bus, &mut visited, &mut found);
+        }
+    }
+
+    let devices = DEVICES.call_once(|| found.into_iter().map(Arc::new).collect());
+    if config::regions().is_empty() {
+        println!("PCI: {} devices, using I/O ports", devices.len());
+    } else {
+        println!("PCI: {} devices, using ECAM", devices.len());
+    }
+    for device in devices {
+        println!("PCI {}", device);
+        for (index, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                println!("    BAR{}: {}", index, bar);
+            }
+        }
+    }
+
+    let drivers = DRIVERS.lock().clone();
+    for device in devices {
+        for &driver in &drivers {
+            probe(driver, device);
+        }
+    }
+}
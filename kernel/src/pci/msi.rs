@@ -0,0 +1,240 @@
+//! Message signaled interrupts, with the MSI and MSI-X capabilities.
+//!
+//! Instead of asserting an interrupt line, a function signals an interrupt by
+//! writing the data of a message to its address. On x86 the address selects
+//! the local APIC to deliver the interrupt to, and the data holds the vector,
+//! as returned by [message].
+
+use super::config::{self, Address};
+use super::Bar;
+use crate::memory::PHYS_MEM_OFFSET;
+
+/// The identifier of the MSI capability.
+pub const CAPABILITY_MSI: u8 = 0x05;
+
+/// The identifier of the MSI-X capability.
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+/// Returns the address and data of a message delivering a fixed,
+/// edge-triggered interrupt with the given vector to the local APIC with the
+/// given identifier.
+pub fn message(apic_id: u8, vector: u8) -> (u64, u32) {
+    (0xfee0_0000 | (apic_id as u64) << 12, vector as u32)
+}
+
+/// The MSI capability of a function, which signals up to 32 consecutive
+/// vectors sharing an address.
+#[derive(Copy, Clone, Debug)]
+pub struct Msi {
+    address: Address,
+    /// The offset of the capability in the configuration space.
+    offset: u16,
+    /// The message control register, as read when parsed.
+    control: u16,
+}
+
+impl Msi {
+    const ENABLE: u16 = 1 << 0;
+    const IS_64_BIT: u16 = 1 << 7;
+    const PER_VECTOR_MASK: u16 = 1 << 8;
+
+    pub(super) fn parse(address: Address, offset: u16) -> Self {
+        Self {
+            address,
+            offset,
+            control: config::read_u16(address, offset + 2),
+        }
+    }
+
+    /// Returns whether the message address may be above 4 GiB.
+    pub fn is_64_bit(&self) -> bool {
+        self.control & Self::IS_64_BIT != 0
+    }
+
+    /// Returns whether vectors can be masked individually.
+    pub fn has_per_vector_mask(&self) -> bool {
+        self.control & Self::PER_VECTOR_MASK != 0
+    }
+
+    /// Returns the number of vectors the function requests.
+    pub fn max_vectors(&self) -> u8 {
+        1 << ((self.control >> 1) & 7).min(5)
+    }
+
+    /// Returns the offset of the data register, which depends on the size of
+    /// the address.
+    fn data_offset(&self) -> u16 {
+        if self.is_64_bit() {
+            self.offset + 0xc
+        } else {
+            self.offset + 0x8
+        }
+    }
+
+    /// Enables the given number of vectors, which must be a power of two no
+    /// larger than [max_vectors](Self::max_vectors). The function sets the
+    /// low bits of the data to the index of the vector, so those bits of
+    /// `data` must be clear.
+    pub fn enable(&self, message_address: u64, data: u32, vectors: u8) -> Result<(), ()> {
+        if !vectors.is_power_of_two()
+            || vectors > self.max_vectors()
+            || data & (vectors as u32 - 1) != 0
+            || data > u16::MAX as u32
+            || (message_address >> 32 != 0 && !self.is_64_bit())
+        {
+            return Err(());
+        }
+
+        config::write_u32(self.address, self.offset + 4, message_address as u32);
+        if self.is_64_bit() {
+            config::write_u32(
+                self.address,
+                self.offset + 8,
+                (message_address >> 32) as u32,
+            );
+        }
+        config::write_u16(self.address, self.data_offset(), data as u16);
+
+        let enabled = vectors.trailing_zeros() as u16;
+        let control = config::read_u16(self.address, self.offset + 2) & !(7 << 4);
+        config::write_u16(
+            self.address,
+            self.offset + 2,
+            control | enabled << 4 | Self::ENABLE,
+        );
+        Ok(())
+    }
+
+    /// Stops the function from signaling interrupts with messages.
+    pub fn disable(&self) {
+        let control = config::read_u16(self.address, self.offset + 2);
+        config::write_u16(self.address, self.offset + 2, control & !Self::ENABLE);
+    }
+
+    /// Masks or unmasks a vector, if vectors can be masked individually.
+    pub fn set_mask(&self, vector: u8, masked: bool) -> Result<(), ()> {
+        if !self.has_per_vector_mask() || vector >= 32 {
+            return Err(());
+        }
+        let offset = self.data_offset() + 4;
+        let mask = config::read_u32(self.address, offset);
+        let mask = if masked {
+            mask | 1 << vector
+        } else {
+            mask & !(1 << vector)
+        };
+        config::write_u32(self.address, offset, mask);
+        Ok(())
+    }
+}
+
+/// The MSI-X capability of a function, which signals up to 2048 vectors, each
+/// with its own address and data in a table in the memory of the function.
+#[derive(Copy, Clone, Debug)]
+pub struct MsiX {
+    address: Address,
+    /// The offset of the capability in the configuration space.
+    offset: u16,
+    table_size: u16,
+    /// The virtual address of the table, if it's in a memory BAR.
+    table: Option<u64>,
+    /// The virtual address of the pending bit array, if it's in a memory BAR.
+    pending: Option<u64>,
+}
+
+impl MsiX {
+    const FUNCTION_MASK: u16 = 1 << 14;
+    const ENABLE: u16 = 1 << 15;
+
+    /// The size of an entry in the table.
+    const ENTRY_SIZE: u64 = 16;
+
+    pub(super) fn parse(address: Address, offset: u16, bars: &[Option<Bar>]) -> Self {
+        let control = config::read_u16(address, offset + 2);
+        // Each location is the offset into a BAR, with the index of the BAR in
+        // the low bits
+        let locate = |register: u32| match bars.get((register & 7) as usize) {
+            Some(Some(Bar::Memory { address, .. })) => {
+                Some(PHYS_MEM_OFFSET | (address + (register & !7) as u64))
+            }
+            _ => None,
+        };
+        Self {
+            address,
+            offset,
+            table_size: (control & 0x7ff) + 1,
+            table: locate(config::read_u32(address, offset + 4)),
+            pending: locate(config::read_u32(address, offset + 8)),
+        }
+    }
+
+    /// Returns the number of entries in the table.
+    pub fn table_size(&self) -> u16 {
+        self.table_size
+    }
+
+    /// Returns the virtual address of an entry in the table.
+    fn entry(&self, index: u16) -> Result<u64, ()> {
+        match self.table {
+            Some(table) if index < self.table_size => Ok(table + index as u64 * Self::ENTRY_SIZE),
+            _ => Err(()),
+        }
+    }
+
+    fn set_control(&self, set: u16, clear: u16) {
+        let control = config::read_u16(self.address, self.offset + 2);
+        config::write_u16(self.address, self.offset + 2, (control | set) & !clear);
+    }
+
+    /// Enables MSI-X with every vector masked, to be unmasked as they are set
+    /// up with [set_vector](Self::set_vector). Memory decoding must be enabled
+    /// for the function.
+    pub fn enable(&self) -> Result<(), ()> {
+        self.entry(0)?;
+        self.set_control(Self::ENABLE | Self::FUNCTION_MASK, 0);
+        for index in 0..self.table_size {
+            self.set_mask(index, true)?;
+        }
+        self.set_control(0, Self::FUNCTION_MASK);
+        Ok(())
+    }
+
+    /// Stops the function from signaling interrupts with messages.
+    pub fn disable(&self) {
+        self.set_control(0, Self::ENABLE);
+    }
+
+    /// Sets the message of a vector and unmasks it.
+    pub fn set_vector(&self, index: u16, message_address: u64, data: u32) -> Result<(), ()> {
+        let entry = self.entry(index)?;
+        self.set_mask(index, true)?;
+        unsafe {
+            core::ptr::write_volatile(entry as *mut u32, message_address as u32);
+            core::ptr::write_volatile((entry + 4) as *mut u32, (message_address >> 32) as u32);
+            core::ptr::write_volatile((entry + 8) as *mut u32, data);
+        }
+        self.set_mask(index, false)
+    }
+
+    /// Masks or unmasks a vector.
+    pub fn set_mask(&self, index: u16, masked: bool) -> Result<(), ()> {
+        let control = (self.entry(index)? + 12) as *mut u32;
+        unsafe {
+            let value = core::ptr::read_volatile(control);
+            let value = if masked { value | 1 } else { value & !1 };
+            core::ptr::write_volatile(control, value);
+        }
+        Ok(())
+    }
+
+    /// Returns whether a masked vector has an interrupt pending.
+    pub fn is_pending(&self, index: u16) -> Result<bool, ()> {
+        match self.pending {
+            Some(pending) if index < self.table_size => {
+                let word = (pending + (index / 64) as u64 * 8) as *const u64;
+                Ok(unsafe { core::ptr::read_volatile(word) } & 1 << (index % 64) != 0)
+            }
+            _ => Err(()),
+        }
+    }
+}
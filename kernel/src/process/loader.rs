@@ -0,0 +1,444 @@
+//! Loading user programs from ELF executables.
+//!
+//! Executables must be statically linked. Position-dependent executables are
+//! loaded at the addresses they're linked at, while position-independent ones
+//! are loaded at [PIE_BASE] and have their dynamic relocations applied. Every
+//! loadable segment is mapped with the permissions of its flags, except that
+//! memory is always readable, and a page shared by two segments gets the
+//! permissions of both. The file is read straight into the memory of the
+//! program a page at a time, so the kernel heap doesn't limit its size.
+//!
+//! The main thread starts at the entry point with the stack laid out as the
+//! System V ABI specifies: the argument count at the stack pointer, followed
+//! by the argument pointers, the environment pointers, and the auxiliary
+//! vector, with the strings they refer to above them.
+
+use super::Process;
+use crate::fs;
+use crate::fs::file::{self, FileTable, OpenFlags};
+use crate::fs::Inode;
+use crate::memory::{AddressSpace, PAGE_NO_EXECUTE, PAGE_WRITABLE};
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use rk_elf64::relocation::{self, Dynamic, Image, InvalidAddress, RelocationError};
+use rk_elf64::{FileHeader, FileType, ParseError, ProgramFlags, ProgramHeader, ProgramType};
+
+/// The top of the stack of the main thread, at the end of the memory programs
+/// may map.
+const STACK_TOP: u64 = super::MAP_END;
+const STACK_SIZE: u64 = 64 * 1024;
+
+/// The address position-independent executables are loaded at.
+const PIE_BASE: u64 = 0x0000_0000_0040_0000;
+
+/// The size of the largest program header table accepted, which leaves room
+/// for far more segments than any executable has.
+const MAX_PROGRAM_HEADERS_SIZE: u64 = 4096;
+
+/// The types of the entries of the auxiliary vector.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// An error loading an executable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The executable could not be read.
+    Fs(fs::Error),
+    /// The executable is not a valid ELF file for x86-64.
+    Parse(ParseError),
+    /// The file ends before the data of a segment.
+    Truncated,
+    /// The ELF file is not an executable.
+    NotExecutable,
+    /// The executable needs an interpreter to be dynamically linked.
+    DynamicallyLinked,
+    /// A segment is outside the part of the lower half programs are loaded
+    /// into.
+    InvalidSegment,
+    /// The dynamic relocations could not be applied.
+    Relocation(RelocationError),
+    /// The arguments and environment don't fit on the stack.
+    TooManyArguments,
+    OutOfMemory,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fs(error) => write!(f, "{}", error),
+            Self::Parse(error) => write!(f, "{}", error),
+            Self::Truncated => write!(f, "file truncated"),
+            Self::NotExecutable => write!(f, "not an executable"),
+            Self::DynamicallyLinked => {
+                write!(f, "dynamically linked executables are not supported")
+            }
+            Self::InvalidSegment => write!(f, "segment outside of user memory"),
+            Self::Relocation(error) => write!(f, "{}", error),
+            Self::TooManyArguments => write!(f, "arguments and environment too large"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+impl From<fs::Error> for Error {
+    fn from(error: fs::Error) -> Self {
+        Self::Fs(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+impl From<RelocationError> for Error {
+    fn from(error: RelocationError) -> Self {
+        Self::Relocation(error)
+    }
+}
+
+/// Starts a process running the executable at the path, with the arguments,
+/// environment, and open files. The first argument is by convention the path
+/// of the executable. The environment variables are of the form `KEY=value`.
+pub fn spawn(
+    path: &str,
+    args: &[&str],
+    env: &[&str],
+    files: FileTable,
+) -> Result<Arc<Process>, Error> {
+    let file = file::open(path, OpenFlags::READ, 0)?;
+    let inode = file.dentry().inode().as_ref();
+    let mut header = [0; core::mem::size_of::<FileHeader>()];
+    let count = read_at(inode, 0, &mut header)?;
+    let header = FileHeader::parse(&header[..count])?;
+    // Position-independent executables are shared objects with an entry point
+    let base = match header.e_type {
+        FileType::ET_EXEC => 0,
+        FileType::ET_DYN if header.e_entry != 0 => PIE_BASE,
+        _ => return Err(Error::NotExecutable),
+    };
+    let program_headers = read_program_headers(inode, &header, file.stat().size)?;
+    if program_headers
+        .iter()
+        .any(|program_header| program_header.p_type == ProgramType::PT_INTERP)
+    {
+        return Err(Error::DynamicallyLinked);
+    }
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let process = Process::new(name).map_err(|()| Error::OutOfMemory)?;
+    let stack_pointer = {
+        let mut address_space = process.address_space();
+        load_segments(&mut address_space, inode, &program_headers, base)?;
+        if header.e_type == FileType::ET_DYN {
+            relocate(&mut address_space, &program_headers, base)?;
+        }
+        build_stack(
+            &mut address_space,
+            &header,
+            &program_headers,
+            base,
+            args,
+            env,
+        )?
+    };
+    *process.files() = files;
+    process
+        .spawn_thread(base + header.e_entry, stack_pointer)
+        .map_err(|()| Error::OutOfMemory)?;
+    Ok(process)
+}
+
+/// Reads from the file at the offset until the buffer is full or the end of
+/// the file is reached, returning the number of bytes read.
+fn read_at(inode: &dyn Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, fs::Error> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match inode.read_at(offset + read as u64, &mut buffer[read..])? {
+            0 => break,
+            count => read += count,
+        }
+    }
+    Ok(read)
+}
+
+/// Reads the program header table, and validates the loadable segments.
+fn read_program_headers(
+    inode: &dyn Inode,
+    header: &FileHeader,
+    file_size: u64,
+) -> Result<Vec<ProgramHeader>, Error> {
+    let size = header.program_headers_size();
+    if size > MAX_PROGRAM_HEADERS_SIZE {
+        return Err(ParseError::InvalidFileHeader.into());
+    }
+    let mut table = vec![0; size as usize];
+    if read_at(inode, header.e_phoff, &mut table)? < table.len() {
+        return Err(ParseError::InvalidFileHeader.into());
+    }
+    let program_headers: Vec<_> = ProgramHeader::read_table(&table).collect();
+    for (index, program_header) in program_headers.iter().enumerate() {
+        if program_header.p_type == ProgramType::PT_LOAD && !program_header.is_valid(file_size) {
+            return Err(ParseError::InvalidProgramHeader { index }.into());
+        }
+    }
+    Ok(program_headers)
+}
+
+/// Returns the loadable segments which occupy memory.
+fn loadable_segments(
+    program_headers: &[ProgramHeader],
+) -> impl Iterator<Item = &ProgramHeader> + '_ {
+    program_headers.iter().filter(|program_header| {
+        program_header.p_type == ProgramType::PT_LOAD && program_header.p_memsz > 0
+    })
+}
+
+/// Maps the loadable segments `base` bytes above the addresses they're linked
+/// at, and copies their data from the file.
+///
+/// The segments are mapped one at a time. They may not overlap, so only the
+/// pages at their boundaries can be shared, and only the permissions of those
+/// are merged.
+fn load_segments(
+    address_space: &mut AddressSpace,
+    inode: &dyn Inode,
+    program_headers: &[ProgramHeader],
+    base: u64,
+) -> Result<(), Error> {
+    let mut segments: Vec<_> = loadable_segments(program_headers).collect();
+    segments.sort_by_key(|segment| segment.p_vaddr);
+
+    // Where each segment is loaded, and whether its pages are writable and
+    // executable
+    let mut ranges = Vec::with_capacity(segments.len());
+    // The merged permissions of the pages at the boundaries of the segments
+    let mut boundaries = BTreeMap::new();
+    let mut previous_end = 0;
+    for segment in segments.iter() {
+        // The stack is below the last page of the lower half
+        let start = segment.p_vaddr.checked_add(base);
+        let end = start.and_then(|start| start.checked_add(segment.p_memsz));
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) if start >= previous_end && end <= STACK_TOP - STACK_SIZE => {
+                (start, end)
+            }
+            _ => return Err(Error::InvalidSegment),
+        };
+        previous_end = end;
+        let permissions = (
+            segment.p_flags & ProgramFlags::PF_W != 0,
+            segment.p_flags & ProgramFlags::PF_X != 0,
+        );
+        for &page in [start & !0xfff, (end - 1) & !0xfff].iter() {
+            let merged = boundaries.entry(page).or_insert((false, false));
+            merged.0 |= permissions.0;
+            merged.1 |= permissions.1;
+        }
+        ranges.push((start, end, permissions));
+    }
+
+    for &(start, end, permissions) in ranges.iter() {
+        for page in (start & !0xfff..end).step_by(4096) {
+            // The first page may be shared with the previous segment
+            if address_space.translate(page, false).is_some() {
+                continue;
+            }
+            let (writable, executable) = boundaries.get(&page).copied().unwrap_or(permissions);
+            let mut flags = 0;
+            if writable {
+                flags |= PAGE_WRITABLE;
+            }
+            if !executable {
+                flags |= PAGE_NO_EXECUTE;
+            }
+            address_space
+                .map_zeroed(page, flags)
+                .map_err(|()| Error::OutOfMemory)?;
+        }
+    }
+
+    let mut buffer = vec![0; 4096];
+    for (segment, &(start, _, _)) in segments.iter().zip(ranges.iter()) {
+        let mut copied = 0;
+        while copied < segment.p_filesz {
+            let len = (segment.p_filesz - copied).min(4096) as usize;
+            if read_at(inode, segment.p_offset + copied, &mut buffer[..len])? < len {
+                return Err(Error::Truncated);
+            }
+            address_space
+                .write(start + copied, &buffer[..len])
+                .expect("A segment was not mapped");
+            copied += len as u64;
+        }
+    }
+    Ok(())
+}
+
+/// The memory of a program being loaded, which its relocations are applied
+/// to.
+struct LoadedImage<'a> {
+    address_space: &'a mut AddressSpace,
+    base: u64,
+}
+
+impl Image for LoadedImage<'_> {
+    fn read(&mut self, addr: u64, buffer: &mut [u8]) -> Result<(), InvalidAddress> {
+        self.address_space
+            .read(self.base.wrapping_add(addr), buffer)
+            .map_err(|()| InvalidAddress)
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), InvalidAddress> {
+        self.address_space
+            .write(self.base.wrapping_add(addr), data)
+            .map_err(|()| InvalidAddress)
+    }
+}
+
+/// Applies the dynamic relocations of a position-independent executable
+/// loaded at the base.
+fn relocate(
+    address_space: &mut AddressSpace,
+    program_headers: &[ProgramHeader],
+    base: u64,
+) -> Result<(), Error> {
+    let segment = match program_headers
+        .iter()
+        .find(|program_header| program_header.p_type == ProgramType::PT_DYNAMIC)
+    {
+        Some(segment) => segment,
+        None => return Ok(()),
+    };
+    let mut image = LoadedImage {
+        address_space,
+        base,
+    };
+    let dynamic = Dynamic::read(&mut image, segment.p_vaddr, segment.p_memsz)?;
+    // There are no shared objects to find symbols in
+    relocation::apply_relocations(&mut image, &dynamic, base, |_, _| None)?;
+    Ok(())
+}
+
+/// Maps the stack of the main thread, and fills it with the arguments,
+/// environment, and auxiliary vector. Returns the initial stack pointer.
+fn build_stack(
+    address_space: &mut AddressSpace,
+    header: &FileHeader,
+    program_headers: &[ProgramHeader],
+    base: u64,
+    args: &[&str],
+    env: &[&str],
+) -> Result<u64, Error> {
+    let bottom = STACK_TOP - STACK_SIZE;
+    for page in (bottom..STACK_TOP).step_by(4096) {
+        address_space
+            .map_zeroed(page, PAGE_WRITABLE | PAGE_NO_EXECUTE)
+            .map_err(|()| Error::OutOfMemory)?;
+    }
+
+    // The strings go at the top, so their addresses are known before the
+    // vectors referring to them are built
+    let strings_len = 16
+        + args
+            .iter()
+            .chain(env.iter())
+            .map(|string| string.len() as u64 + 1)
+            .sum::<u64>();
+    if strings_len > STACK_SIZE {
+        return Err(Error::TooManyArguments);
+    }
+    let strings_start = (STACK_TOP - strings_len) & !0xf;
+    let mut strings = Vec::new();
+    let mut string_addrs = Vec::new();
+    for string in args.iter().chain(env.iter()) {
+        string_addrs.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let random_addr = strings_start + strings.len() as u64;
+    strings.extend_from_slice(&random_bytes());
+
+    let mut words = vec![args.len() as u64];
+    words.extend_from_slice(&string_addrs[..args.len()]);
+    words.push(0);
+    words.extend_from_slice(&string_addrs[args.len()..]);
+    words.push(0);
+    if let Some(addr) = program_headers_addr(header, program_headers) {
+        words.extend_from_slice(&[AT_PHDR, base + addr]);
+    }
+    words.extend_from_slice(&[
+        AT_PHENT,
+        header.e_phentsize as u64,
+        AT_PHNUM,
+        header.e_phnum as u64,
+        AT_PAGESZ,
+        4096,
+        AT_ENTRY,
+        base + header.e_entry,
+        AT_RANDOM,
+        random_addr,
+        AT_NULL,
+        0,
+    ]);
+
+    // The stack pointer is aligned to 16 bytes at the argument count
+    let stack_pointer = match strings_start.checked_sub(words.len() as u64 * 8) {
+        Some(addr) if addr & !0xf >= bottom => addr & !0xf,
+        _ => return Err(Error::TooManyArguments),
+    };
+    let mut vectors = Vec::with_capacity(words.len() * 8);
+    for word in words {
+        vectors.extend_from_slice(&word.to_le_bytes());
+    }
+    address_space
+        .write(strings_start, &strings)
+        .and_then(|()| address_space.write(stack_pointer, &vectors))
+        .expect("The stack was not mapped");
+    Ok(stack_pointer)
+}
+
+/// Returns the address the program header table is linked at, if it's loaded.
+fn program_headers_addr(header: &FileHeader, program_headers: &[ProgramHeader]) -> Option<u64> {
+    let offset = header.e_phoff;
+    if let Some(table) = program_headers
+        .iter()
+        .find(|program_header| program_header.p_type == ProgramType::PT_PHDR)
+    {
+        return Some(table.p_vaddr);
+    }
+    loadable_segments(program_headers)
+        .find(|segment| segment.p_offset <= offset && offset - segment.p_offset < segment.p_filesz)
+        .map(|segment| segment.p_vaddr + (offset - segment.p_offset))
+}
+
+/// Returns the bytes the auxiliary vector points programs to for seeding
+/// their random number generators and stack protectors.
+///
+/// TODO: Use a real source of randomness, these are only derived from the
+/// time since boot.
+fn random_bytes() -> [u8; 16] {
+    // SplitMix64
+    let mut state = time::ticks();
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&next().to_le_bytes());
+    bytes[8..].copy_from_slice(&next().to_le_bytes());
+    bytes
+}
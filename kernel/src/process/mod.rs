@@ -0,0 +1,244 @@
+//! User processes.
+//!
+//! A process has an address space of its own, whose lower half holds the
+//! memory of the user program, while the higher half is shared with the
+//! kernel. Its threads are kernel threads which enter user mode, and return to
+//! the kernel on their kernel stacks when interrupted. The address space of a
+//! process is activated by the scheduler whenever one of its threads runs.
+
+pub mod loader;
+
+use crate::fs::file::FileTable;
+use crate::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::{self, AddressSpace};
+use crate::sync::mutex::{Mutex, MutexGuard};
+use crate::sync::spinlock::SpinLock;
+use crate::sync::wait_queue::WaitQueue;
+use crate::thread::{self, Priority, Thread};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rk_x86_64::register::rflags;
+
+/// The exit code of a process killed because of an exception raised by its
+/// program.
+pub const EXIT_CODE_FAULT: i32 = -1;
+
+/// The end of the memory programs may map. The last page of the lower half is
+/// left unmapped, so that a `syscall` instruction can't be at its very end,
+/// which would make `sysret` return to a non-canonical address.
+pub const MAP_END: u64 = memory::USER_END - 0x1000;
+
+/// The address the memory mapped without a requested address is placed from,
+/// far away from where programs are loaded.
+const MMAP_BASE: u64 = 0x0000_4000_0000_0000;
+
+/// The reserved bit 1 of RFLAGS, which is always set.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// The ID of the next process created. IDs start at 1.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct Process {
+    id: u64,
+    name: String,
+    /// The physical address of the PML4 of the address space, which the
+    /// scheduler reads without locking it.
+    pml4_addr: u64,
+    address_space: Mutex<AddressSpace>,
+    /// The address the next region reserved by [reserve_region] starts at.
+    ///
+    /// [reserve_region]: Self::reserve_region
+    mmap_next: SpinLock<u64>,
+    files: Mutex<FileTable>,
+    threads: SpinLock<Vec<Weak<Thread>>>,
+    /// The exit code, set once the process has exited.
+    exit_code: SpinLock<Option<i32>>,
+    /// The threads waiting for the process to exit.
+    waiters: WaitQueue,
+}
+
+impl Process {
+    /// Creates a process with an empty address space and no threads. Fails if
+    /// no memory can be allocated for the address space.
+    pub fn new(name: &str) -> Result<Arc<Self>, ()> {
+        let address_space = AddressSpace::new()?;
+        Ok(Arc::new(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name: name.to_string(),
+            pml4_addr: address_space.pml4_addr(),
+            address_space: Mutex::new(address_space),
+            mmap_next: SpinLock::new(MMAP_BASE),
+            files: Mutex::new(FileTable::new()),
+            threads: SpinLock::new(Vec::new()),
+            exit_code: SpinLock::new(None),
+            waiters: WaitQueue::new(),
+        }))
+    }
+
+    /// Returns the unique ID of the process.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the physical address of the PML4 of the address space.
+    pub fn pml4_addr(&self) -> u64 {
+        self.pml4_addr
+    }
+
+    pub fn address_space(&self) -> MutexGuard<'_, AddressSpace> {
+        self.address_space.lock()
+    }
+
+    /// Reserves a page aligned region of the lower half of the address space
+    /// of at least the given size, which no other reserved region overlaps.
+    /// Returns `None` once the memory below [MAP_END] is used up.
+    pub fn reserve_region(&self, size: u64) -> Option<u64> {
+        let size = size.checked_add(0xfff)? & !0xfff;
+        let mut next = self.mmap_next.lock();
+        let start = *next;
+        let end = start.checked_add(size).filter(|&end| end <= MAP_END)?;
+        *next = end;
+        Some(start)
+    }
+
+    /// Returns the table of the files the process has open.
+    pub fn files(&self) -> MutexGuard<'_, FileTable> {
+        self.files.lock()
+    }
+
+    /// Returns the threads of the process which have not been freed.
+    pub fn threads(&self) -> Vec<Arc<Thread>> {
+        self.threads
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+
+    /// Returns the exit code, or `None` if the process has not exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        *self.exit_code.lock()
+    }
+
+    /// Starts a thread in the process, which enters user mode at the entry
+    /// point with the given stack pointer. Both must point to memory mapped in
+    /// the address space, or the process is killed by the fault. Fails if no
+    /// kernel stack can be allocated for the thread.
+    pub fn spawn_thread(
+        self: &Arc<Self>,
+        entry: u64,
+        stack_pointer: u64,
+    ) -> Result<Arc<Thread>, ()> {
+        let thread = thread::spawn_user(
+            &self.name,
+            Priority::Normal,
+            self.clone(),
+            entry,
+            stack_pointer,
+        )?;
+        let mut threads = self.threads.lock();
+        threads.retain(|thread| thread.strong_count() > 0);
+        threads.push(Arc::downgrade(&thread));
+        Ok(thread)
+    }
+
+    /// Waits for the process to exit, returning its exit code.
+    pub fn wait(&self) -> i32 {
+        self.waiters.wait_until(|| self.exit_code.lock().is_some());
+        self.exit_code().unwrap()
+    }
+}
+
+/// Returns the process of the running thread, or `None` for a kernel thread.
+pub fn current() -> Option<Arc<Process>> {
+    thread::try_current()?.process().cloned()
+}
+
+/// Ends the process of the running thread with the exit code, closing its
+/// files and waking the threads waiting for it. The other threads of the
+/// process stop the next time they would return to user mode, see
+/// [stop_if_exited], and the address space is freed along with the last of
+/// them.
+///
+/// May be called by the handler of an exception raised in user mode, which
+/// holds no locks.
+pub fn exit(code: i32) -> ! {
+    {
+        let process = current().expect("Exiting outside of a process");
+        {
+            let mut exit_code = process.exit_code.lock();
+            if exit_code.is_none() {
+                *exit_code = Some(code);
+            }
+        }
+        *process.files.lock() = FileTable::new();
+        process.waiters.notify_all();
+    }
+    thread::exit()
+}
+
+/// Ends the running thread if its process has exited. Called before returning
+/// to user mode from a system call or an interrupt, so threads blocked in the
+/// kernel stop once they're done, and those running in user mode at their
+/// next interrupt.
+///
+/// No locks may be held, as the thread never continues.
+pub fn stop_if_exited() {
+    let exited = match current() {
+        Some(process) => process.exit_code().is_some(),
+        None => false,
+    };
+    if exited {
+        thread::exit();
+    }
+}
+
+/// Enters user mode at the entry point with the given stack pointer, by
+/// returning from a made up interrupt with `iretq`. Interrupts are enabled in
+/// user mode, and the general purpose registers are cleared so no kernel data
+/// leaks.
+///
+/// # Safety
+/// The address space of a process must be active, and the kernel stack of the
+/// running thread must be set in the TSS, which the scheduler does for
+/// threads of a process.
+pub(crate) unsafe fn enter_user_mode(entry: u64, stack_pointer: u64) -> ! {
+    asm!(
+        "mov ds, ax",
+        "mov es, ax",
+        // The frame of the interrupt to return from: SS, RSP, RFLAGS, CS, RIP
+        "push rax",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rdi",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        in("rax") USER_DATA_SELECTOR as u64,
+        in("rsi") stack_pointer,
+        in("rdx") rflags::INTERRUPT_FLAG | RFLAGS_RESERVED,
+        in("rcx") USER_CODE_SELECTOR as u64,
+        in("rdi") entry,
+        options(noreturn)
+    );
+}
@@ -0,0 +1,59 @@
+use super::mutex::MutexGuard;
+use super::wait_queue::WaitQueue;
+use rk_x86_64::interrupts as cpu;
+
+/// A condition variable, which threads wait on for a condition protected by a
+/// [Mutex](super::Mutex) to change.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the lock and blocks until notified, then acquires the lock
+    /// again. A notification sent after the lock is released is not missed.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        cpu::without_interrupts(|| {
+            // Nothing can notify the condition variable until the thread blocks,
+            // as interrupts are disabled
+            drop(guard);
+            self.waiters.wait();
+        });
+        mutex.lock()
+    }
+
+    /// Waits until the condition no longer holds for the data, checking it
+    /// with the lock held every time the thread is notified.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes one of the waiting threads, returning whether there was one.
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+
+    /// Wakes every waiting thread, returning how many there were.
+    pub fn notify_all(&self) -> usize {
+        self.waiters.notify_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
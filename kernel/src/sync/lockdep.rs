@@ -0,0 +1,113 @@
+//! Checking the order locks are acquired in, in debug builds.
+//!
+//! Every time a lock is acquired while others are held, the order is
+//! recorded. Acquiring a lock which has been held while acquiring a lock held
+//! now could deadlock, as could acquiring a lock held already, so both panic
+//! instead, even if no deadlock happens this time. Locks are identified by
+//! their address.
+
+use super::spinlock::SpinLock;
+use crate::thread;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+
+#[derive(Default)]
+struct State {
+    /// The locks held by each thread, by the ID of the thread.
+    held: BTreeMap<u64, Vec<usize>>,
+    /// The locks which have been acquired while holding each lock.
+    after: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+impl State {
+    /// Returns whether a lock has been acquired after another, directly or
+    /// through other locks.
+    fn is_after(&self, lock: usize, other: usize) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut unvisited = vec![other];
+        while let Some(current) = unvisited.pop() {
+            if current == lock {
+                return true;
+            }
+            if visited.insert(current) {
+                if let Some(after) = self.after.get(&current) {
+                    unvisited.extend(after.iter().copied());
+                }
+            }
+        }
+        false
+    }
+}
+
+lazy_static! {
+    static ref STATE: SpinLock<State> = SpinLock::new(State::default());
+}
+
+/// Returns the ID of the current thread, or 0 before there are threads.
+fn current_thread() -> u64 {
+    thread::try_current().map_or(0, |thread| thread.id())
+}
+
+/// Records that the current thread is about to acquire the lock. If it's
+/// going to wait for the lock, this panics if that could deadlock.
+pub fn acquire(lock: usize, waiting: bool) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let thread = current_thread();
+    let result = {
+        let mut state = STATE.lock();
+        let mut held = state.held.remove(&thread).unwrap_or_default();
+        let result = if !waiting {
+            Ok(())
+        } else if held.contains(&lock) {
+            Err("the lock is already held by this thread")
+        } else if held.iter().any(|&other| state.is_after(other, lock)) {
+            Err("the lock has been acquired before another lock held now")
+        } else {
+            Ok(())
+        };
+        // Locks acquired without waiting cannot deadlock
+        if waiting {
+            for &other in &held {
+                state.after.entry(other).or_default().insert(lock);
+            }
+        }
+        held.push(lock);
+        state.held.insert(thread, held);
+        result
+    };
+    if let Err(reason) = result {
+        panic!("Possible deadlock acquiring lock {:#x}: {}", lock, reason);
+    }
+}
+
+/// Records that the current thread released the lock.
+pub fn release(lock: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let thread = current_thread();
+    let mut state = STATE.lock();
+    if let Some(held) = state.held.get_mut(&thread) {
+        if let Some(index) = held.iter().rposition(|&other| other == lock) {
+            held.remove(index);
+        }
+        if held.is_empty() {
+            state.held.remove(&thread);
+        }
+    }
+}
+
+/// Forgets the order of a lock which is dropped, as another lock may take its
+/// address.
+pub fn forget(lock: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mut state = STATE.lock();
+    state.after.remove(&lock);
+    for after in state.after.values_mut() {
+        after.remove(&lock);
+    }
+}
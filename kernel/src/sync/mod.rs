@@ -0,0 +1,21 @@
+//! Synchronization primitives.
+//!
+//! A [spin lock](spinlock::SpinLock) disables interrupts while held, so it's
+//! safe to share data with interrupt handlers, but it must only be held
+//! briefly and never while blocking. The other primitives block the waiting
+//! threads through the scheduler instead of spinning, so they may be held for
+//! long, but must not be used by interrupt handlers, which may only notify a
+//! [wait queue](wait_queue::WaitQueue) or release a
+//! [semaphore](semaphore::Semaphore) to wake threads waiting for a device.
+//!
+//! In debug builds, [mutexes](mutex::Mutex) and [RwLocks](rwlock::RwLock)
+//! check the order they're locked in, and panic as soon as locking could
+//! deadlock rather than hanging.
+
+pub mod condvar;
+mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait_queue;
@@ -0,0 +1,147 @@
+use super::lockdep;
+use super::spinlock::SpinLock;
+use super::wait_queue::WaitQueue;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+/// The holders of a [RwLock].
+struct State {
+    readers: usize,
+    writer: bool,
+    /// The number of threads waiting to write, which new readers wait for so
+    /// that writers are not starved.
+    waiting_writers: usize,
+}
+
+/// A lock which is either held by any number of readers, or by one writer,
+/// blocking the threads waiting for it.
+pub struct RwLock<T: ?Sized> {
+    state: SpinLock<State>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: SpinLock::new(State {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+            }),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    /// Blocks until the lock is acquired for reading, which waits for writers
+    /// holding or waiting for the lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        lockdep::acquire(self.id(), true);
+        self.waiters.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.waiting_writers > 0 {
+                return false;
+            }
+            state.readers += 1;
+            true
+        });
+        RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Blocks until the lock is acquired for writing.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        lockdep::acquire(self.id(), true);
+        self.state.lock().waiting_writers += 1;
+        self.waiters.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.readers > 0 {
+                return false;
+            }
+            state.writer = true;
+            state.waiting_writers -= 1;
+            true
+        });
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+/// Shared access to the data of a [RwLock], which releases it when dropped.
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            drop(state);
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+/// Exclusive access to the data of a [RwLock], which releases it when dropped.
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        self.lock.state.lock().writer = false;
+        self.lock.waiters.notify_all();
+    }
+}
@@ -0,0 +1,40 @@
+use super::spinlock::SpinLock;
+use super::wait_queue::WaitQueue;
+
+/// A counting semaphore, blocking the threads acquiring it while the count is
+/// zero.
+pub struct Semaphore {
+    count: SpinLock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: SpinLock::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Decrements the count if it's not zero.
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    /// Blocks until the count is not zero, and decrements it.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Increments the count, waking a thread waiting for it. May be called by
+    /// interrupt handlers.
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.waiters.notify_one();
+    }
+}
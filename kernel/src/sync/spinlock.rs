@@ -0,0 +1,115 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use rk_x86_64::interrupts as cpu;
+
+/// A lock which spins until it's released, with interrupts disabled while it's
+/// held. An interrupt handler can thus never wait for the code it interrupts.
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Disables interrupts and spins until the lock is acquired. Interrupts are
+    /// enabled again when the guard is dropped, if they were enabled before.
+    ///
+    /// There is a single CPU, so the lock can only be held by the code already
+    /// running with interrupts disabled. Debug builds panic rather than
+    /// spinning forever.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_enabled = cpu::are_enabled();
+        cpu::disable();
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if cfg!(debug_assertions) {
+                panic!("Deadlock: the spin lock is already held");
+            }
+            core::hint::spin_loop();
+        }
+        SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    /// Acquires the lock if it's not held.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_enabled = cpu::are_enabled();
+        cpu::disable();
+        match self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => Some(SpinLockGuard {
+                lock: self,
+                interrupts_enabled,
+            }),
+            Err(_) => {
+                if interrupts_enabled {
+                    cpu::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard, like when panicking while it's held.
+    ///
+    /// # Safety
+    /// The data must not be in use, and the guard of whoever held the lock
+    /// must never be dropped.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+/// Access to the data of a locked [SpinLock], which releases the lock when
+/// dropped.
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    /// Whether interrupts were enabled before the lock was acquired.
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            cpu::enable();
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use super::spinlock::SpinLock;
+use crate::thread::{self, Thread};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use rk_x86_64::interrupts as cpu;
+
+/// A queue of threads blocked until they're notified, in the order they
+/// started waiting.
+pub struct WaitQueue {
+    threads: SpinLock<Vec<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            threads: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Blocks the current thread until it's notified.
+    ///
+    /// Interrupts must be disabled, such that a notification sent after the
+    /// caller decided to wait cannot be missed. They stay disabled.
+    pub fn wait(&self) {
+        debug_assert!(!cpu::are_enabled(), "Waiting with interrupts enabled");
+        self.threads.lock().push(thread::current());
+        thread::block();
+    }
+
+    /// Blocks the current thread until the condition holds, checking it again
+    /// every time the thread is notified. The condition is checked with
+    /// interrupts disabled.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        cpu::without_interrupts(|| {
+            while !condition() {
+                self.wait();
+            }
+        });
+    }
+
+    /// Wakes the thread waiting the longest, returning whether there was one.
+    pub fn notify_one(&self) -> bool {
+        let thread = {
+            let mut threads = self.threads.lock();
+            if threads.is_empty() {
+                None
+            } else {
+                Some(threads.remove(0))
+            }
+        };
+        match thread {
+            Some(thread) => {
+                thread::wake(&thread);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread, returning how many there were.
+    pub fn notify_all(&self) -> usize {
+        let threads = core::mem::take(&mut *self.threads.lock());
+        for thread in &threads {
+            thread::wake(thread);
+        }
+        threads.len()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,375 @@
+//! System calls, made by user programs with the `syscall` instruction.
+//!
+//! The number of the system call is passed in `rax`, and up to six arguments
+//! in `rdi`, `rsi`, `rdx`, `r10`, `r8`, and `r9`. The result is returned in
+//! `rax`, where a value between -4095 and -1 is the negated number of an
+//! [Error]. `rcx` and `r11` are overwritten by the `syscall` instruction, and
+//! every other register is preserved.
+//!
+//! | Number | Call | Arguments |
+//! |--------|------|-----------|
+//! | 0 | [exit] | exit code |
+//! | 1 | [read] | file descriptor, buffer, length |
+//! | 2 | [write] | file descriptor, buffer, length |
+//! | 3 | [open] | path, path length, [flags](OpenFlags), permissions |
+//! | 4 | [close] | file descriptor |
+//! | 5 | [mmap] | address or 0, length, protection |
+//! | 6 | [getpid] | |
+//! | 7 | [yield](sched_yield) | |
+//! | 8 | [clock_gettime] | clock, pointer to the seconds and nanoseconds |
+
+pub mod user;
+
+use crate::fs;
+use crate::fs::file::{self, OpenFlags};
+use crate::gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::{PAGE_NO_EXECUTE, PAGE_WRITABLE};
+use crate::process::{self, Process};
+use crate::thread;
+use crate::time;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rk_x86_64::register::{msr, rflags};
+
+/// The most bytes read or written by a single call, such that a large request
+/// doesn't need a large kernel buffer. Programs have to handle short reads and
+/// writes anyway.
+const MAX_IO_SIZE: usize = 64 * 1024;
+
+/// The longest path accepted, in bytes.
+const MAX_PATH_LEN: usize = 4096;
+
+/// The memory protection flags of [mmap].
+const PROT_WRITE: u64 = 1 << 1;
+const PROT_EXEC: u64 = 1 << 2;
+/// The protection flags which are accepted. Memory is always readable.
+const PROT_ALL: u64 = 0b111;
+
+/// The clock counting the time since boot, which is the only clock supported
+/// by [clock_gettime] without a real time clock.
+const CLOCK_MONOTONIC: u64 = 1;
+
+/// The top of the kernel stack of the running thread, which system calls
+/// switch to. Set by the scheduler.
+static KERNEL_STACK: AtomicU64 = AtomicU64::new(0);
+
+/// Where the stack pointer of the program is kept while switching stacks on
+/// a system call.
+static USER_STACK: AtomicU64 = AtomicU64::new(0);
+
+/// An error returned by a system call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// There's no system call with the number.
+    NoSuchSystemCall,
+    /// A pointer refers to memory the process may not access.
+    BadAddress,
+    /// An argument is invalid.
+    InvalidArgument,
+    /// There's not enough memory for the operation.
+    OutOfMemory,
+    /// A file system operation failed.
+    Fs(fs::Error),
+}
+
+impl Error {
+    /// Returns the number of the error, which is negated when returned. The
+    /// numbers are those of Linux, for the sake of ported programs.
+    fn number(&self) -> u64 {
+        match self {
+            Self::NoSuchSystemCall => 38,
+            Self::BadAddress => 14,
+            Self::InvalidArgument => 22,
+            Self::OutOfMemory => 12,
+            Self::Fs(error) => match error {
+                fs::Error::NotFound => 2,
+                fs::Error::NotADirectory => 20,
+                fs::Error::IsADirectory => 21,
+                fs::Error::AlreadyExists => 17,
+                fs::Error::NotEmpty => 39,
+                fs::Error::ReadOnly => 30,
+                fs::Error::NotSupported => 95,
+                fs::Error::InvalidArgument => 22,
+                fs::Error::BadFileDescriptor => 9,
+                fs::Error::TooManyOpenFiles => 24,
+                fs::Error::SymlinkLoop => 40,
+                fs::Error::CrossDevice => 18,
+                fs::Error::Busy => 16,
+                fs::Error::NoSpace => 28,
+                fs::Error::FileTooLarge => 27,
+                fs::Error::Io => 5,
+            },
+        }
+    }
+}
+
+impl From<fs::Error> for Error {
+    fn from(error: fs::Error) -> Self {
+        Self::Fs(error)
+    }
+}
+
+/// A system call, which gets the process making it and the arguments.
+type SystemCall = fn(Arc<Process>, [u64; 6]) -> Result<u64, Error>;
+
+/// The system calls, by number.
+const SYSTEM_CALLS: [SystemCall; 9] = [
+    exit,
+    read,
+    write,
+    open,
+    close,
+    mmap,
+    getpid,
+    sched_yield,
+    clock_gettime,
+];
+
+/// Enables the `syscall` instruction, which enters the kernel at [entry].
+pub fn init() {
+    unsafe {
+        // `sysret` loads the user data and code selectors from the two
+        // following the base selector, with the privilege level of user mode.
+        // `syscall` loads the kernel code selector and the data selector
+        // following it
+        let sysret_base = (USER_DATA_SELECTOR & !3) - 8;
+        msr::write(
+            msr::IA32_STAR,
+            (sysret_base as u64) << 48 | (KERNEL_CODE_SELECTOR as u64) << 32,
+        );
+        msr::write(
+            msr::IA32_LSTAR,
+            entry as unsafe extern "C" fn() as usize as u64,
+        );
+        // Enter the kernel with interrupts disabled until the stack is switched
+        msr::write(
+            msr::IA32_FMASK,
+            rflags::INTERRUPT_FLAG
+                | rflags::TRAP_FLAG
+                | rflags::DIRECTION_FLAG
+                | rflags::ALIGNMENT_CHECK_FLAG,
+        );
+        msr::write(
+            msr::IA32_EFER,
+            msr::read(msr::IA32_EFER) | msr::EFER_SYSCALL_ENABLE,
+        );
+    }
+}
+
+/// Sets the kernel stack system calls switch to, which is the stack of the
+/// running thread.
+pub fn set_kernel_stack(top: u64) {
+    KERNEL_STACK.store(top, Ordering::Relaxed);
+}
+
+/// The registers of a program making a system call, as saved by [entry].
+#[repr(C)]
+struct Frame {
+    rax: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    r10: u64,
+    r8: u64,
+    r9: u64,
+    /// The flags of the program, saved by `syscall`.
+    r11: u64,
+    /// The return address, saved by `syscall`.
+    rcx: u64,
+    rsp: u64,
+}
+
+/// Where `syscall` enters the kernel, with interrupts disabled and still on the
+/// stack of the program. Switches to the kernel stack of the thread, saving the
+/// registers the handler may clobber, and returns to the program with `sysret`
+/// once [dispatch] has replaced `rax` with the result.
+///
+/// `sysret` to a non-canonical address raises a general protection fault in
+/// kernel mode, but with the stack pointer of the program already loaded,
+/// letting the program choose where the exception frame is written. Such
+/// returns go through `iretq` instead, which faults before switching stacks.
+///
+/// The callee-saved registers are preserved by the handler itself.
+#[naked]
+unsafe extern "C" fn entry() {
+    asm!(
+        "mov [rip + {user_stack}], rsp",
+        "mov rsp, [rip + {kernel_stack}]",
+        "push qword ptr [rip + {user_stack}]",
+        "push rcx",
+        "push r11",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+        // The stack is still aligned to 16 bytes, as ten registers were pushed
+        "mov rdi, rsp",
+        "sti",
+        "call {dispatch}",
+        "cli",
+        "pop rax",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop r11",
+        "pop rcx",
+        // Any address with bit 47 set is either non-canonical or in the higher
+        // half
+        "bt rcx, 47",
+        "jc 2f",
+        "pop rsp",
+        "sysretq",
+        "2:",
+        "pop qword ptr [rip + {user_stack}]",
+        "push {user_data}",
+        "push qword ptr [rip + {user_stack}]",
+        "push r11",
+        "push {user_code}",
+        "push rcx",
+        "iretq",
+        user_stack = sym USER_STACK,
+        kernel_stack = sym KERNEL_STACK,
+        dispatch = sym dispatch,
+        user_data = const USER_DATA_SELECTOR,
+        user_code = const USER_CODE_SELECTOR,
+        options(noreturn)
+    );
+}
+
+/// Runs the system call of a frame saved by [entry], with interrupts enabled,
+/// and stores its result in the frame.
+extern "C" fn dispatch(frame: &mut Frame) {
+    let result = {
+        let process = process::current().expect("System call outside of a process");
+        let args = [
+            frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+        ];
+        match SYSTEM_CALLS.get(frame.rax as usize) {
+            Some(system_call) => system_call(process, args),
+            None => Err(Error::NoSuchSystemCall),
+        }
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => error.number().wrapping_neg(),
+    };
+    // Another thread may have exited the process during the call
+    process::stop_if_exited();
+}
+
+/// Ends the process with the exit code.
+fn exit(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    // Nothing is dropped once the thread has exited
+    drop(process);
+    process::exit(args[0] as i32)
+}
+
+/// Reads from an open file into the buffer, returning the number of bytes
+/// read.
+fn read(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    let [fd, addr, len, ..] = args;
+    let file = process.files().get(fd as usize)?;
+    let mut buffer = vec![0; (len as usize).min(MAX_IO_SIZE)];
+    // Don't lose the data read if the buffer turns out to be bad
+    user::check(&process.address_space(), addr, buffer.len(), true)?;
+    let count = file.read(&mut buffer)?;
+    user::copy_to_user(&process.address_space(), addr, &buffer[..count])?;
+    Ok(count as u64)
+}
+
+/// Writes the buffer to an open file, returning the number of bytes written.
+fn write(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    let [fd, addr, len, ..] = args;
+    let file = process.files().get(fd as usize)?;
+    let mut buffer = vec![0; (len as usize).min(MAX_IO_SIZE)];
+    user::copy_from_user(&process.address_space(), addr, &mut buffer)?;
+    Ok(file.write(&buffer)? as u64)
+}
+
+/// Opens the file at the path, returning a new file descriptor for it.
+fn open(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    let [addr, len, flags, permissions, ..] = args;
+    if len as usize > MAX_PATH_LEN || flags > u32::MAX as u64 {
+        return Err(Error::InvalidArgument);
+    }
+    let path = user::read_string(&process.address_space(), addr, len as usize)?;
+    let flags = OpenFlags::from_bits(flags as u32);
+    let file = file::open(&path, flags, permissions as u32 & 0o7777)?;
+    Ok(process.files().insert(file)? as u64)
+}
+
+/// Closes a file descriptor.
+fn close(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    process.files().close(args[0] as usize)?;
+    Ok(0)
+}
+
+/// Maps zeroed memory of the length, returning its address. The memory is
+/// placed at the address if it's not 0, replacing what was mapped there.
+fn mmap(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    let [addr, len, protection, ..] = args;
+    if len == 0 || protection & !PROT_ALL != 0 || addr & 0xfff != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let len = len.checked_add(0xfff).ok_or(Error::InvalidArgument)? & !0xfff;
+    let addr = if addr == 0 {
+        process.reserve_region(len).ok_or(Error::OutOfMemory)?
+    } else {
+        match addr.checked_add(len) {
+            Some(end) if end <= process::MAP_END => addr,
+            _ => return Err(Error::InvalidArgument),
+        }
+    };
+
+    let mut flags = 0;
+    if protection & PROT_WRITE != 0 {
+        flags |= PAGE_WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PAGE_NO_EXECUTE;
+    }
+    let mut address_space = process.address_space();
+    for page in (addr..addr + len).step_by(0x1000) {
+        if address_space.map_zeroed(page, flags).is_err() {
+            for page in (addr..page).step_by(0x1000) {
+                // Safety: The memory was just mapped, so the kernel doesn't use it
+                unsafe { address_space.unmap(page) };
+            }
+            return Err(Error::OutOfMemory);
+        }
+    }
+    Ok(addr)
+}
+
+/// Returns the ID of the process.
+fn getpid(process: Arc<Process>, _args: [u64; 6]) -> Result<u64, Error> {
+    Ok(process.id())
+}
+
+/// Lets the other threads of the same priority run first.
+fn sched_yield(_process: Arc<Process>, _args: [u64; 6]) -> Result<u64, Error> {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// Writes the time of a clock, as seconds followed by nanoseconds, both 64-bit
+/// integers.
+fn clock_gettime(process: Arc<Process>, args: [u64; 6]) -> Result<u64, Error> {
+    let [clock, addr, ..] = args;
+    if clock != CLOCK_MONOTONIC {
+        return Err(Error::InvalidArgument);
+    }
+    let uptime = time::uptime();
+    let mut timespec = [0; 16];
+    timespec[..8].copy_from_slice(&uptime.as_secs().to_le_bytes());
+    timespec[8..].copy_from_slice(&(uptime.subsec_nanos() as u64).to_le_bytes());
+    user::copy_to_user(&process.address_space(), addr, &timespec)?;
+    Ok(0)
+}
@@ -0,0 +1,86 @@
+//! Copying to and from the memory of user processes.
+//!
+//! The pointers passed to system calls cannot be trusted, so user memory is
+//! never accessed through them. Instead each page is looked up in the address
+//! space of the process, and accessed through the mapping of physical memory
+//! if the process may access it. A bad pointer then makes the system call fail
+//! instead of faulting in the kernel.
+
+use super::Error;
+use crate::memory::{AddressSpace, PHYS_MEM_OFFSET};
+use alloc::string::String;
+use alloc::vec;
+
+/// Calls the function with the physical address and length of each part of
+/// the range of user memory in a page, in order. Fails without calling it if
+/// any page of the range is not accessible, or not writable if asked for.
+fn for_each_page(
+    address_space: &AddressSpace,
+    addr: u64,
+    len: usize,
+    writable: bool,
+    mut f: impl FnMut(u64, usize),
+) -> Result<(), Error> {
+    let end = addr.checked_add(len as u64).ok_or(Error::BadAddress)?;
+    let mut parts = vec![];
+    let mut part_addr = addr;
+    while part_addr < end {
+        let part_len = (0x1000 - (part_addr & 0xfff)).min(end - part_addr);
+        let phys = address_space
+            .translate(part_addr, writable)
+            .ok_or(Error::BadAddress)?;
+        parts.push((phys, part_len as usize));
+        part_addr += part_len;
+    }
+    for (phys, part_len) in parts {
+        f(phys, part_len);
+    }
+    Ok(())
+}
+
+/// Fails if the range of user memory is not accessible, or not writable if
+/// asked for.
+pub fn check(
+    address_space: &AddressSpace,
+    addr: u64,
+    len: usize,
+    writable: bool,
+) -> Result<(), Error> {
+    for_each_page(address_space, addr, len, writable, |_, _| {})
+}
+
+/// Copies user memory at the address into the buffer.
+pub fn copy_from_user(
+    address_space: &AddressSpace,
+    addr: u64,
+    buffer: &mut [u8],
+) -> Result<(), Error> {
+    let mut copied = 0;
+    for_each_page(address_space, addr, buffer.len(), false, |phys, len| {
+        let source = (PHYS_MEM_OFFSET | phys) as *const u8;
+        // Safety: The page is mapped in the address space of the process, so
+        // it's not used by the kernel
+        let source = unsafe { core::slice::from_raw_parts(source, len) };
+        buffer[copied..copied + len].copy_from_slice(source);
+        copied += len;
+    })
+}
+
+/// Copies the data into user memory at the address.
+pub fn copy_to_user(address_space: &AddressSpace, addr: u64, data: &[u8]) -> Result<(), Error> {
+    let mut copied = 0;
+    for_each_page(address_space, addr, data.len(), true, |phys, len| {
+        let destination = (PHYS_MEM_OFFSET | phys) as *mut u8;
+        // Safety: As above
+        let destination = unsafe { core::slice::from_raw_parts_mut(destination, len) };
+        destination.copy_from_slice(&data[copied..copied + len]);
+        copied += len;
+    })
+}
+
+/// Copies a UTF-8 string of the given length in bytes out of user memory.
+pub fn read_string(address_space: &AddressSpace, addr: u64, len: usize) -> Result<String, Error> {
+    let mut buffer = vec![0; len];
+    copy_from_user(address_space, addr, &mut buffer)?;
+    String::from_utf8(buffer).map_err(|_| Error::InvalidArgument)
+}
@@ -0,0 +1,134 @@
+//! Saving and restoring the registers of threads.
+//!
+//! Threads only switch by calling [switch], so only the registers preserved
+//! across calls by the System V ABI are saved: the callee-saved general purpose
+//! registers on the stack of the thread, and the x87 FPU and SSE registers with
+//! `fxsave`. The kernel itself is compiled without them, but the threads of
+//! user programs will use them.
+
+use alloc::boxed::Box;
+use rk_x86_64::register::{cr0, cr4};
+
+/// CR0: Monitor coprocessor, which together with the cleared emulation flag
+/// enables the x87 FPU.
+const CR0_MONITOR_COPROCESSOR: u64 = 1 << 1;
+/// CR0: Emulation, which makes x87 FPU and SSE instructions fault.
+const CR0_EMULATION: u64 = 1 << 2;
+
+/// CR4: The OS supports `fxsave` and `fxrstor`, which enables SSE.
+const CR4_OSFXSR: u64 = 1 << 9;
+/// CR4: The OS handles SIMD floating point exceptions.
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+/// The x87 FPU control word after `fninit`, with every exception masked.
+const DEFAULT_FCW: u16 = 0x037f;
+/// The MXCSR after reset, with every exception masked.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Enables the x87 FPU and SSE, such that their registers can be saved.
+pub fn init() {
+    unsafe {
+        cr0::write(cr0::read() & !CR0_EMULATION | CR0_MONITOR_COPROCESSOR);
+        cr4::write(cr4::read() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+    }
+}
+
+/// The x87 FPU and SSE registers, in the format of `fxsave`.
+#[repr(C, align(16))]
+struct FxState([u8; 512]);
+
+impl FxState {
+    /// Returns the registers of a thread which has not used them yet.
+    fn new() -> Self {
+        let mut state = Self([0; 512]);
+        state.0[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        state.0[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+}
+
+/// The saved registers of a thread.
+pub struct Context {
+    /// The stack pointer of the thread while it's not running, pointing at the
+    /// callee-saved registers pushed by [switch_stacks].
+    stack_pointer: u64,
+    fx_state: Box<FxState>,
+}
+
+impl Context {
+    /// Returns the context of the thread which is already running, which is
+    /// filled in when it's first switched away from.
+    pub fn current() -> Self {
+        Self {
+            stack_pointer: 0,
+            fx_state: Box::new(FxState::new()),
+        }
+    }
+
+    /// Returns the context of a new thread, which starts by jumping to the
+    /// function on the stack with the given top.
+    ///
+    /// # Safety
+    /// The stack must be mapped, unused, and aligned to 16 bytes.
+    pub unsafe fn new(stack_top: u64, entry: extern "C" fn() -> !) -> Self {
+        // The registers popped by switch_stacks, which then returns into the
+        // entry function with the stack aligned as if it had been called
+        let frame: [u64; 8] = [0, 0, 0, 0, 0, 0, entry as usize as u64, 0];
+        let stack_pointer = stack_top - core::mem::size_of_val(&frame) as u64;
+        core::ptr::write(stack_pointer as *mut [u64; 8], frame);
+        Self {
+            stack_pointer,
+            fx_state: Box::new(FxState::new()),
+        }
+    }
+
+    /// Loads the saved x87 FPU and SSE registers, which a new thread does
+    /// itself as it did not get switched to by [switch].
+    ///
+    /// # Safety
+    /// Must be called by the thread of the context.
+    pub unsafe fn restore_fx_state(&self) {
+        asm!("fxrstor64 [{}]", in(reg) &*self.fx_state, options(nostack));
+    }
+}
+
+/// Saves the registers of the running thread in `old`, and continues the
+/// thread whose registers are in `new`. Returns once a thread switches back to
+/// `old`.
+///
+/// # Safety
+/// Interrupts must be disabled, `old` must belong to the running thread and
+/// `new` to a thread which is not running. Both must stay valid until the
+/// switch completes.
+pub unsafe fn switch(old: *mut Context, new: *const Context) {
+    let fx_state = &mut *(*old).fx_state as *mut FxState;
+    asm!("fxsave64 [{}]", in(reg) fx_state, options(nostack));
+    switch_stacks(&mut (*old).stack_pointer, (*new).stack_pointer);
+    asm!("fxrstor64 [{}]", in(reg) fx_state, options(nostack));
+}
+
+/// Pushes the callee-saved registers, stores the stack pointer at `old`, and
+/// switches to the stack pointer `new`, popping the registers pushed when its
+/// thread was switched away from.
+#[naked]
+unsafe extern "sysv64" fn switch_stacks(_old: *mut u64, _new: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // The System V ABI passes the arguments in rdi and rsi
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    );
+}
@@ -0,0 +1,491 @@
+//! Kernel threads, and a preemptive scheduler running them.
+//!
+//! The ready threads wait in a queue per priority. The scheduler runs a ready
+//! thread of the highest priority, and threads of the same priority take
+//! turns: each runs for a time slice of [TIME_SLICE_TICKS] timer ticks before
+//! it's preempted, unless it blocks or yields first. Threads of a lower
+//! priority only run while every thread of a higher priority is blocked, and
+//! an idle thread halts the CPU while no thread is ready.
+//!
+//! The scheduler is also used by the timer interrupt, so it's only locked with
+//! interrupts disabled, and threads are switched with interrupts disabled.
+//! A thread switched to enables them again as it returns from the code which
+//! switched away from it, or when it starts.
+
+mod context;
+
+use self::context::Context;
+use crate::gdt;
+use crate::memory::{self, KernelStack};
+use crate::process::{self, Process};
+use crate::sync::spinlock::SpinLock;
+use crate::sync::wait_queue::WaitQueue;
+use crate::syscall;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use rk_x86_64::interrupts as cpu;
+use spin::Once;
+
+/// The number of ticks a thread runs before a thread of the same priority
+/// gets a turn.
+const TIME_SLICE_TICKS: u64 = 5;
+
+/// The priority of a thread.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// The number of priorities.
+    const COUNT: usize = 3;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    /// Waiting in a run queue.
+    Ready,
+    Running,
+    /// Waiting to be woken by [wake].
+    Blocked,
+    /// Waiting for a tick, to be woken by [tick].
+    Sleeping,
+    Exited,
+}
+
+/// What a new thread runs once it starts.
+enum Entry {
+    /// A function, after which the thread exits.
+    Kernel(Box<dyn FnOnce() + Send>),
+    /// The program of the process of the thread, which is entered in user mode
+    /// at the instruction pointer with the stack pointer.
+    User {
+        instruction_pointer: u64,
+        stack_pointer: u64,
+    },
+}
+
+/// The ID of the next thread created.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A kernel thread.
+pub struct Thread {
+    id: u64,
+    name: String,
+    priority: Priority,
+    state: SpinLock<State>,
+    /// The saved registers, only accessed while switching threads.
+    context: UnsafeCell<Context>,
+    /// The stack, or `None` for the thread the kernel booted on.
+    stack: Option<KernelStack>,
+    /// What a new thread runs, taken when it starts.
+    entry: SpinLock<Option<Entry>>,
+    /// The threads waiting for this one to exit.
+    joiners: WaitQueue,
+    /// The process the thread belongs to, whose address space is active while
+    /// it runs, or `None` for a kernel thread.
+    process: Option<Arc<Process>>,
+}
+
+// The context is only accessed while switching threads, with interrupts
+// disabled
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(
+        name: &str,
+        priority: Priority,
+        context: Context,
+        stack: Option<KernelStack>,
+        entry: Option<Entry>,
+        process: Option<Arc<Process>>,
+    ) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name: name.to_string(),
+            priority,
+            state: SpinLock::new(State::Ready),
+            context: UnsafeCell::new(context),
+            stack,
+            entry: SpinLock::new(entry),
+            joiners: WaitQueue::new(),
+            process,
+        }
+    }
+
+    /// Returns the unique ID of the thread.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+}
+
+/// A thread spawned with [spawn], which can be waited for.
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<SpinLock<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// Waits for the thread to exit, returning the value its function
+    /// returned, or `None` if it called [exit] instead.
+    pub fn join(self) -> Option<T> {
+        self.thread
+            .joiners
+            .wait_until(|| *self.thread.state.lock() == State::Exited);
+        reap();
+        self.result.lock().take()
+    }
+}
+
+struct Scheduler {
+    /// The ready threads, by priority.
+    queues: [VecDeque<Arc<Thread>>; Priority::COUNT],
+    current: Arc<Thread>,
+    /// The thread running while no other thread is ready, which is never
+    /// queued.
+    idle: Arc<Thread>,
+    /// The sleeping threads, with the tick they wake up at.
+    sleeping: Vec<(u64, Arc<Thread>)>,
+    /// The threads which have exited, which are freed by [reap].
+    exited: Vec<Arc<Thread>>,
+    /// The number of ticks left of the time slice of the current thread.
+    slice_left: u64,
+}
+
+impl Scheduler {
+    /// Queues a thread to run, asking for the current thread to be preempted
+    /// if the thread has a higher priority.
+    fn ready(&mut self, thread: Arc<Thread>) {
+        *thread.state.lock() = State::Ready;
+        if thread.priority > self.current.priority || Arc::ptr_eq(&self.current, &self.idle) {
+            NEED_RESCHEDULE.store(true, Ordering::Relaxed);
+        }
+        self.queues[thread.priority as usize].push_back(thread);
+    }
+
+    /// Takes the next thread to run out of the run queues, if there is one of
+    /// at least the given priority.
+    fn next(&mut self, priority: Priority) -> Option<Arc<Thread>> {
+        self.queues[priority as usize..]
+            .iter_mut()
+            .rev()
+            .find_map(VecDeque::pop_front)
+    }
+}
+
+static SCHEDULER: Once<SpinLock<Scheduler>> = Once::new();
+
+/// Whether the current thread should be preempted at the end of the interrupt
+/// handler.
+static NEED_RESCHEDULE: AtomicBool = AtomicBool::new(false);
+
+fn scheduler() -> &'static SpinLock<Scheduler> {
+    SCHEDULER.get().expect("Threads are not initialized")
+}
+
+/// Turns the code the kernel booted on into the first thread, named `main`,
+/// and starts scheduling threads.
+pub fn init() {
+    context::init();
+
+    let main = Arc::new(Thread::new(
+        "main",
+        Priority::Normal,
+        Context::current(),
+        None,
+        None,
+        None,
+    ));
+    *main.state.lock() = State::Running;
+
+    let stack = KernelStack::allocate().expect("Could not allocate the idle stack");
+    let context = unsafe { Context::new(stack.top(), start) };
+    let idle = Arc::new(Thread::new(
+        "idle",
+        Priority::Low,
+        context,
+        Some(stack),
+        Some(Entry::Kernel(Box::new(idle))),
+        None,
+    ));
+
+    SCHEDULER.call_once(|| {
+        SpinLock::new(Scheduler {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            current: main,
+            idle,
+            sleeping: Vec::new(),
+            exited: Vec::new(),
+            slice_left: TIME_SLICE_TICKS,
+        })
+    });
+}
+
+/// Starts a thread running the function, returning a handle to wait for it
+/// with. Fails if no stack can be allocated for it.
+pub fn spawn<F, T>(name: &str, priority: Priority, f: F) -> Result<JoinHandle<T>, ()>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(SpinLock::new(None));
+    let thread_result = result.clone();
+    let entry = Entry::Kernel(Box::new(move || {
+        *thread_result.lock() = Some(f());
+    }));
+    let thread = start_thread(name, priority, None, entry)?;
+    Ok(JoinHandle { thread, result })
+}
+
+/// Starts a thread of the process, which enters user mode at the instruction
+/// pointer with the stack pointer as soon as it runs.
+pub(crate) fn spawn_user(
+    name: &str,
+    priority: Priority,
+    process: Arc<Process>,
+    instruction_pointer: u64,
+    stack_pointer: u64,
+) -> Result<Arc<Thread>, ()> {
+    let entry = Entry::User {
+        instruction_pointer,
+        stack_pointer,
+    };
+    start_thread(name, priority, Some(process), entry)
+}
+
+/// Creates a thread with a stack of its own, and queues it to run.
+fn start_thread(
+    name: &str,
+    priority: Priority,
+    process: Option<Arc<Process>>,
+    entry: Entry,
+) -> Result<Arc<Thread>, ()> {
+    reap();
+    let stack = KernelStack::allocate()?;
+    let context = unsafe { Context::new(stack.top(), start) };
+    let thread = Arc::new(Thread::new(
+        name,
+        priority,
+        context,
+        Some(stack),
+        Some(entry),
+        process,
+    ));
+    scheduler().lock().ready(thread.clone());
+    Ok(thread)
+}
+
+/// Returns the running thread.
+pub fn current() -> Arc<Thread> {
+    try_current().expect("Threads are not initialized")
+}
+
+/// Returns the running thread, or `None` before threads are initialized.
+pub fn try_current() -> Option<Arc<Thread>> {
+    let scheduler = SCHEDULER.get()?;
+    Some(scheduler.lock().current.clone())
+}
+
+/// Lets the other threads of the same priority run before the current one
+/// continues.
+pub fn yield_now() {
+    cpu::without_interrupts(schedule);
+}
+
+/// Blocks the current thread for at least the duration, with the resolution of
+/// a tick.
+pub fn sleep(duration: Duration) {
+    // Round the duration up to whole ticks, and since the current tick is
+    // partly over, sleep until the end of one more
+    let ticks = (duration.as_micros() * time::TICK_FREQUENCY as u128 + 999_999) / 1_000_000;
+    let wake_tick = time::ticks() + ticks as u64 + 1;
+    cpu::without_interrupts(|| {
+        let thread = current();
+        *thread.state.lock() = State::Sleeping;
+        scheduler().lock().sleeping.push((wake_tick, thread));
+        schedule();
+    });
+}
+
+/// Blocks the current thread until it's passed to [wake].
+///
+/// Interrupts must be disabled, such that the thread cannot be woken between
+/// deciding to block and blocking. Whatever wakes the thread must already
+/// have a reference to it.
+pub fn block() {
+    debug_assert!(!cpu::are_enabled(), "Blocking with interrupts enabled");
+    *current().state.lock() = State::Blocked;
+    schedule();
+}
+
+/// Makes a thread blocked by [block] ready to run again. Does nothing if the
+/// thread is not blocked.
+pub fn wake(thread: &Arc<Thread>) {
+    let mut scheduler = scheduler().lock();
+    if *thread.state.lock() == State::Blocked {
+        scheduler.ready(thread.clone());
+    }
+}
+
+/// Ends the current thread, waking the threads waiting for it.
+pub fn exit() -> ! {
+    cpu::disable();
+    {
+        let thread = current();
+        *thread.state.lock() = State::Exited;
+        thread.joiners.notify_all();
+    }
+    schedule();
+    unreachable!("An exited thread was switched to");
+}
+
+/// Counts a tick of the timer, waking the sleeping threads which are due and
+/// ending the time slice of the current thread. Called by the timer interrupt.
+pub fn tick() {
+    let mut scheduler = match SCHEDULER.get() {
+        Some(scheduler) => scheduler.lock(),
+        None => return,
+    };
+    let now = time::ticks();
+    let mut i = 0;
+    while i < scheduler.sleeping.len() {
+        if scheduler.sleeping[i].0 <= now {
+            let (_, thread) = scheduler.sleeping.swap_remove(i);
+            scheduler.ready(thread);
+        } else {
+            i += 1;
+        }
+    }
+
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    let priority = scheduler.current.priority;
+    if scheduler.slice_left == 0 && !scheduler.queues[priority as usize].is_empty() {
+        NEED_RESCHEDULE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches threads if the current one should give up the CPU, because its
+/// time slice is over or a thread of a higher priority is ready. Called at the
+/// end of interrupt handlers, with interrupts disabled.
+pub fn preempt() {
+    if NEED_RESCHEDULE.load(Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+/// Switches to the next thread to run, if any. The current thread keeps
+/// running if no other thread of at least its priority is ready, unless it's
+/// no longer running, in which case something else must reference it.
+///
+/// Must be called with interrupts disabled.
+fn schedule() {
+    NEED_RESCHEDULE.store(false, Ordering::Relaxed);
+    let (old, new) = {
+        let mut scheduler = scheduler().lock();
+        let current = scheduler.current.clone();
+        let is_idle = Arc::ptr_eq(&current, &scheduler.idle);
+        let state = *current.state.lock();
+        let runnable = state == State::Running && !is_idle;
+
+        let minimum = if runnable {
+            current.priority
+        } else {
+            Priority::Low
+        };
+        let next = match scheduler.next(minimum) {
+            Some(next) => next,
+            None if runnable || is_idle => return,
+            None => scheduler.idle.clone(),
+        };
+
+        if runnable {
+            scheduler.ready(current.clone());
+        } else if state == State::Exited {
+            scheduler.exited.push(current.clone());
+        }
+        *next.state.lock() = State::Running;
+        scheduler.current = next.clone();
+        scheduler.slice_left = TIME_SLICE_TICKS;
+        NEED_RESCHEDULE.store(false, Ordering::Relaxed);
+        (Arc::as_ptr(&current), Arc::as_ptr(&next))
+    };
+
+    // Both threads stay referenced by the scheduler, or by whatever is to wake
+    // the old one
+    unsafe {
+        // Kernel threads run in whichever address space is active, as they only
+        // use the shared higher half
+        if let Some(process) = &(*new).process {
+            memory::switch_address_space(process.pml4_addr());
+        }
+        if let Some(stack) = &(*new).stack {
+            gdt::set_kernel_stack(stack.top());
+            syscall::set_kernel_stack(stack.top());
+        }
+        context::switch((*old).context.get(), (*new).context.get())
+    }
+}
+
+/// Frees the threads which have exited. This is not done by the scheduler, as
+/// it switches away from an exited thread while still on its stack.
+fn reap() {
+    let exited = core::mem::take(&mut scheduler().lock().exited);
+    drop(exited);
+}
+
+/// Where new threads start, with interrupts disabled by the thread which
+/// switched to them.
+extern "C" fn start() -> ! {
+    let entry = {
+        let thread = current();
+        unsafe { (*thread.context.get()).restore_fx_state() };
+        let entry = thread.entry.lock().take();
+        entry
+    };
+    cpu::enable();
+    match entry {
+        Some(Entry::Kernel(f)) => f(),
+        // Nothing is left to free once the thread is in user mode, where it
+        // stays until it exits. The address space of the process was
+        // activated when the thread was switched to
+        Some(Entry::User {
+            instruction_pointer,
+            stack_pointer,
+        }) => unsafe { process::enter_user_mode(instruction_pointer, stack_pointer) },
+        None => {}
+    }
+    exit()
+}
+
+/// Halts the CPU until an interrupt makes a thread ready, which preempts the
+/// idle thread at the end of the interrupt handler.
+fn idle() {
+    loop {
+        cpu::enable_and_hlt();
+    }
+}
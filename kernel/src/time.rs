@@ -0,0 +1,110 @@
+//! Keeping time with the timer of the local APIC.
+//!
+//! The frequency of the timer is unknown, so it's measured against channel 2
+//! of the legacy programmable interval timer (PIT), which runs at a fixed
+//! frequency. The timer then interrupts [TICK_FREQUENCY] times per second,
+//! counting ticks since boot. Shorter waits, like those of drivers, busy-wait
+//! on the count of the timer with [delay].
+
+use crate::apic;
+use crate::interrupts;
+use crate::net;
+use crate::thread;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use rk_x86_64::port;
+
+/// The number of ticks per second.
+pub const TICK_FREQUENCY: u64 = 100;
+
+/// The frequency of the PIT in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// The port controlling the gate of PIT channel 2, and reading its output.
+const PIT_GATE: u16 = 0x61;
+
+const GATE_ENABLE: u8 = 1 << 0;
+const GATE_SPEAKER: u8 = 1 << 1;
+const GATE_OUTPUT: u8 = 1 << 5;
+
+/// The duration the timer is measured over, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// The number of ticks since the timer was started.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The measured frequency of the timer, or 0 before it's started.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of times the timer counts down per second, measured
+/// against the PIT.
+fn measure_timer_frequency() -> u64 {
+    let pit_count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    unsafe {
+        // Enable the gate of channel 2 without sounding the speaker
+        let gate = port::read_u8(PIT_GATE) & !GATE_SPEAKER;
+        port::write_u8(PIT_GATE, gate | GATE_ENABLE);
+        // Channel 2, low and high bytes, interrupt on terminal count
+        port::write_u8(PIT_COMMAND, 0b1011_0000);
+        port::write_u8(PIT_CHANNEL_2, pit_count as u8);
+        port::write_u8(PIT_CHANNEL_2, (pit_count >> 8) as u8);
+        // Restart the count with a rising edge of the gate
+        port::write_u8(PIT_GATE, gate & !GATE_ENABLE);
+        port::write_u8(PIT_GATE, gate | GATE_ENABLE);
+
+        apic::start_timer_masked(u32::MAX);
+        while port::read_u8(PIT_GATE) & GATE_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+    }
+    let elapsed = u32::MAX - apic::timer_count();
+    elapsed as u64 * 1000 / CALIBRATION_MS
+}
+
+/// Measures the frequency of the timer and starts it ticking, which also drives
+/// the scheduler and the timers of the network protocols.
+pub fn init() {
+    let frequency = measure_timer_frequency();
+    let vector = interrupts::allocate_vector(|| {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        thread::tick();
+        net::notify();
+    })
+    .expect("No vector for the timer");
+    TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    apic::start_timer_periodic(vector, (frequency / TICK_FREQUENCY) as u32);
+}
+
+/// Busy-waits for at least the duration, by following the count of the timer.
+/// Works with interrupts disabled, unlike [thread::sleep].
+pub fn delay(duration: Duration) {
+    let frequency = TIMER_FREQUENCY.load(Ordering::Relaxed);
+    assert!(frequency != 0, "The timer is not started");
+    // The count is reloaded with the period every tick
+    let period = frequency / TICK_FREQUENCY;
+    let mut remaining = (duration.as_nanos() * frequency as u128 / 1_000_000_000) as u64 + 1;
+    let mut last = apic::timer_count() as u64;
+    while remaining > 0 {
+        core::hint::spin_loop();
+        let count = apic::timer_count() as u64;
+        let elapsed = if count <= last {
+            last - count
+        } else {
+            last + period - count
+        };
+        remaining = remaining.saturating_sub(elapsed);
+        last = count;
+    }
+}
+
+/// Returns the number of ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since boot, with the resolution of a tick.
+pub fn uptime() -> Duration {
+    Duration::from_millis(ticks() * 1000 / TICK_FREQUENCY)
+}
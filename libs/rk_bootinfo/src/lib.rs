@@ -0,0 +1,602 @@
+//! The boot information passed from the bootloader to the kernel.
+//!
+//! The boot information starts with a header, identifying the structure and
+//! its version, followed by a list of tags. Each tag starts with its type and
+//! size, and is aligned to 8 bytes. The list is terminated by an end tag.
+//!
+//! The bootloader writes the boot information using a [Builder], and passes
+//! its address to the kernel in `rdi`. The kernel reads it with
+//! [BootInfo::from_ptr], which rejects boot information from a bootloader
+//! with a different version.
+
+#![no_std]
+
+#[cfg(test)]
+mod tests;
+
+use core::fmt;
+use core::mem::size_of;
+
+/// Identifies the boot information, "RKBI" in little endian.
+pub const MAGIC: u32 = 0x4942_4b52;
+
+/// The version of the boot information layout, incremented on any change.
+pub const VERSION: u32 = 1;
+
+const TAG_END: u32 = 0;
+const TAG_MEMORY_MAP: u32 = 1;
+const TAG_FRAMEBUFFER: u32 = 2;
+const TAG_RSDP: u32 = 3;
+const TAG_COMMAND_LINE: u32 = 4;
+const TAG_MODULE: u32 = 5;
+const TAG_KERNEL_SYMBOLS: u32 = 6;
+
+/// The header at the start of the boot information.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct Header {
+    magic: u32,
+    version: u32,
+    /// The size of the whole boot information in bytes, including the header.
+    size: u32,
+    _reserved: u32,
+}
+
+/// The header at the start of each tag.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct TagHeader {
+    kind: u32,
+    /// The size of the tag in bytes, including the header but excluding any
+    /// padding following the tag.
+    size: u32,
+}
+
+const HEADER_SIZE: usize = size_of::<Header>();
+const TAG_HEADER_SIZE: usize = size_of::<TagHeader>();
+
+/// Rounds up to the alignment of tags.
+const fn align_up(value: usize) -> usize {
+    (value + 7) & !7
+}
+
+/// The kind of memory in a region of the memory map.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryRegionKind {
+    /// Free memory.
+    Usable = 1,
+    /// Memory which must not be used.
+    Reserved = 2,
+    /// Memory holding ACPI tables, usable once they have been read.
+    AcpiReclaimable = 3,
+    /// Memory which must be preserved for the firmware across sleep states.
+    AcpiNvs = 4,
+    /// Memory with errors.
+    Unusable = 5,
+    /// Memory allocated by the bootloader, holding the kernel, its stack and
+    /// page tables, the boot information, and loaded modules.
+    Bootloader = 6,
+}
+
+/// A contiguous region of physical memory.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct MemoryRegion {
+    /// The physical start address of the region.
+    pub start: u64,
+    /// The size of the region in bytes.
+    pub size: u64,
+    kind: u32,
+    _reserved: u32,
+}
+
+impl MemoryRegion {
+    /// Creates a memory region.
+    pub fn new(start: u64, size: u64, kind: MemoryRegionKind) -> Self {
+        Self {
+            start,
+            size,
+            kind: kind as u32,
+            _reserved: 0,
+        }
+    }
+
+    /// Returns the physical address following the region.
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Returns the kind of memory in the region, unknown kinds are treated as
+    /// reserved.
+    pub fn kind(&self) -> MemoryRegionKind {
+        match self.kind {
+            1 => MemoryRegionKind::Usable,
+            3 => MemoryRegionKind::AcpiReclaimable,
+            4 => MemoryRegionKind::AcpiNvs,
+            5 => MemoryRegionKind::Unusable,
+            6 => MemoryRegionKind::Bootloader,
+            _ => MemoryRegionKind::Reserved,
+        }
+    }
+}
+
+/// The layout of a pixel in the frame buffer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PixelFormat {
+    /// Each pixel is 32 bits with red in the lowest byte.
+    Rgb = 0,
+    /// Each pixel is 32 bits with blue in the lowest byte.
+    Bgr = 1,
+}
+
+/// A linear frame buffer with 32 bit pixels.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Framebuffer {
+    /// The physical address of the frame buffer.
+    pub base: u64,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The number of pixels in each row, which may be larger than the width.
+    pub stride: u32,
+    pixel_format: u32,
+}
+
+impl Framebuffer {
+    /// Creates a frame buffer description.
+    pub fn new(base: u64, width: u32, height: u32, stride: u32, format: PixelFormat) -> Self {
+        Self {
+            base,
+            width,
+            height,
+            stride,
+            pixel_format: format as u32,
+        }
+    }
+
+    /// Returns the pixel format, unknown formats are treated as BGR, the format
+    /// used by most firmware.
+    pub fn pixel_format(&self) -> PixelFormat {
+        match self.pixel_format {
+            0 => PixelFormat::Rgb,
+            _ => PixelFormat::Bgr,
+        }
+    }
+}
+
+/// The location of the symbol and string tables of the kernel ELF file.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct KernelSymbols {
+    /// The physical address of the symbol table.
+    pub symtab_addr: u64,
+    /// The size of the symbol table in bytes.
+    pub symtab_size: u64,
+    /// The physical address of the string table.
+    pub strtab_addr: u64,
+    /// The size of the string table in bytes.
+    pub strtab_size: u64,
+}
+
+/// The fixed size part of a module tag, followed by the name.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct ModuleHeader {
+    start: u64,
+    size: u64,
+}
+
+/// A file loaded by the bootloader alongside the kernel.
+#[derive(Copy, Clone, Debug)]
+pub struct Module<'a> {
+    /// The physical start address of the module.
+    pub start: u64,
+    /// The size of the module in bytes.
+    pub size: u64,
+    /// The name of the module, usually its path on the boot volume.
+    pub name: &'a str,
+}
+
+/// A tag of the boot information.
+#[derive(Copy, Clone, Debug)]
+pub enum Tag<'a> {
+    /// The physical memory map, sorted by address.
+    MemoryMap(&'a [MemoryRegion]),
+    /// The frame buffer the kernel can draw to.
+    Framebuffer(&'a Framebuffer),
+    /// The physical address of the ACPI RSDP.
+    Rsdp(u64),
+    /// The kernel command line.
+    CommandLine(&'a str),
+    /// A module loaded alongside the kernel.
+    Module(Module<'a>),
+    /// The location of the kernel symbols.
+    KernelSymbols(&'a KernelSymbols),
+    /// A tag of an unknown type.
+    Unknown(u32),
+}
+
+/// An error in the boot information.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// The boot information is not aligned to 8 bytes.
+    Misaligned,
+    /// The magic number is wrong, so this is not boot information.
+    BadMagic(u32),
+    /// The boot information was written by a bootloader using another version.
+    UnsupportedVersion(u32),
+    /// The size in the header is too small.
+    InvalidSize(u32),
+    /// The tag at the given offset is malformed.
+    InvalidTag { offset: usize },
+    /// The tag list is not terminated by an end tag.
+    MissingEndTag,
+}
+
+impl fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Misaligned => write!(f, "boot information is misaligned"),
+            Self::BadMagic(magic) => write!(f, "bad boot information magic {:#010x}", magic),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported boot information version {}, expected {}",
+                version, VERSION
+            ),
+            Self::InvalidSize(size) => write!(f, "invalid boot information size {}", size),
+            Self::InvalidTag { offset } => write!(f, "invalid boot information tag at {}", offset),
+            Self::MissingEndTag => write!(f, "boot information is missing the end tag"),
+        }
+    }
+}
+
+/// Validated boot information.
+#[derive(Copy, Clone)]
+pub struct BootInfo<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> BootInfo<'a> {
+    /// Reads and validates the boot information at the given address.
+    ///
+    /// # Safety
+    /// `ptr` must point to memory which is valid for reads of the size in the
+    /// header, and which is not modified during `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, BootInfoError> {
+        if ptr as usize & 7 != 0 {
+            return Err(BootInfoError::Misaligned);
+        }
+
+        let header = *(ptr as *const Header);
+        if header.magic != MAGIC {
+            return Err(BootInfoError::BadMagic(header.magic));
+        }
+        if header.version != VERSION {
+            return Err(BootInfoError::UnsupportedVersion(header.version));
+        }
+        if (header.size as usize) < HEADER_SIZE + TAG_HEADER_SIZE {
+            return Err(BootInfoError::InvalidSize(header.size));
+        }
+
+        let boot_info = Self {
+            bytes: core::slice::from_raw_parts(ptr, header.size as usize),
+        };
+        boot_info.validate()?;
+        Ok(boot_info)
+    }
+
+    /// Checks that every tag fits within the boot information and has a valid
+    /// payload, and that the list ends with an end tag.
+    fn validate(&self) -> Result<(), BootInfoError> {
+        let mut offset = HEADER_SIZE;
+        while offset + TAG_HEADER_SIZE <= self.bytes.len() {
+            let header = self.tag_header(offset);
+            let size = header.size as usize;
+            if size < TAG_HEADER_SIZE || offset + size > self.bytes.len() {
+                return Err(BootInfoError::InvalidTag { offset });
+            }
+            let payload = &self.bytes[offset + TAG_HEADER_SIZE..offset + size];
+
+            let valid = match header.kind {
+                TAG_END => return Ok(()),
+                TAG_MEMORY_MAP => {
+                    let count = payload.len() / size_of::<MemoryRegion>();
+                    count * size_of::<MemoryRegion>() == payload.len()
+                }
+                TAG_FRAMEBUFFER => payload.len() >= size_of::<Framebuffer>(),
+                TAG_RSDP => payload.len() >= size_of::<u64>(),
+                TAG_COMMAND_LINE => core::str::from_utf8(payload).is_ok(),
+                TAG_MODULE => {
+                    payload.len() >= size_of::<ModuleHeader>()
+                        && core::str::from_utf8(&payload[size_of::<ModuleHeader>()..]).is_ok()
+                }
+                TAG_KERNEL_SYMBOLS => payload.len() >= size_of::<KernelSymbols>(),
+                _ => true,
+            };
+            if !valid {
+                return Err(BootInfoError::InvalidTag { offset });
+            }
+
+            offset = align_up(offset + size);
+        }
+        Err(BootInfoError::MissingEndTag)
+    }
+
+    fn tag_header(&self, offset: usize) -> TagHeader {
+        unsafe { *(self.bytes.as_ptr().add(offset) as *const TagHeader) }
+    }
+
+    /// Returns the size of the boot information in bytes.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns an iterator over all tags.
+    pub fn tags(&self) -> Tags<'a> {
+        Tags {
+            boot_info: *self,
+            offset: HEADER_SIZE,
+        }
+    }
+
+    /// Returns the memory map, or an empty slice if there is none.
+    pub fn memory_map(&self) -> &'a [MemoryRegion] {
+        self.tags()
+            .find_map(|tag| match tag {
+                Tag::MemoryMap(regions) => Some(regions),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    /// Returns the frame buffer.
+    pub fn framebuffer(&self) -> Option<&'a Framebuffer> {
+        self.tags().find_map(|tag| match tag {
+            Tag::Framebuffer(framebuffer) => Some(framebuffer),
+            _ => None,
+        })
+    }
+
+    /// Returns the physical address of the ACPI RSDP.
+    pub fn rsdp(&self) -> Option<u64> {
+        self.tags().find_map(|tag| match tag {
+            Tag::Rsdp(address) => Some(address),
+            _ => None,
+        })
+    }
+
+    /// Returns the kernel command line, or an empty string if there is none.
+    pub fn command_line(&self) -> &'a str {
+        self.tags()
+            .find_map(|tag| match tag {
+                Tag::CommandLine(command_line) => Some(command_line),
+                _ => None,
+            })
+            .unwrap_or("")
+    }
+
+    /// Returns an iterator over the loaded modules.
+    pub fn modules(&self) -> impl Iterator<Item = Module<'a>> {
+        self.tags().filter_map(|tag| match tag {
+            Tag::Module(module) => Some(module),
+            _ => None,
+        })
+    }
+
+    /// Returns the location of the kernel symbols.
+    pub fn kernel_symbols(&self) -> Option<&'a KernelSymbols> {
+        self.tags().find_map(|tag| match tag {
+            Tag::KernelSymbols(symbols) => Some(symbols),
+            _ => None,
+        })
+    }
+}
+
+/// An iterator over the tags of validated boot information.
+pub struct Tags<'a> {
+    boot_info: BootInfo<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The tags have been validated, so they're all in bounds with valid
+        // payloads, and the list is terminated by an end tag
+        let bytes = self.boot_info.bytes;
+        let header = self.boot_info.tag_header(self.offset);
+        let payload = &bytes[self.offset + TAG_HEADER_SIZE..self.offset + header.size as usize];
+        let ptr = payload.as_ptr();
+
+        let tag = unsafe {
+            match header.kind {
+                TAG_END => return None,
+                TAG_MEMORY_MAP => Tag::MemoryMap(core::slice::from_raw_parts(
+                    ptr as *const MemoryRegion,
+                    payload.len() / size_of::<MemoryRegion>(),
+                )),
+                TAG_FRAMEBUFFER => Tag::Framebuffer(&*(ptr as *const Framebuffer)),
+                TAG_RSDP => Tag::Rsdp(*(ptr as *const u64)),
+                TAG_COMMAND_LINE => Tag::CommandLine(core::str::from_utf8_unchecked(payload)),
+                TAG_MODULE => {
+                    let module = *(ptr as *const ModuleHeader);
+                    Tag::Module(Module {
+                        start: module.start,
+                        size: module.size,
+                        name: core::str::from_utf8_unchecked(&payload[size_of::<ModuleHeader>()..]),
+                    })
+                }
+                TAG_KERNEL_SYMBOLS => Tag::KernelSymbols(&*(ptr as *const KernelSymbols)),
+                kind => Tag::Unknown(kind),
+            }
+        };
+
+        self.offset = align_up(self.offset + header.size as usize);
+        Some(tag)
+    }
+}
+
+/// An error which can occur when writing boot information.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The buffer is not aligned to 8 bytes.
+    Misaligned,
+    /// The buffer cannot fit another tag.
+    BufferTooSmall,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Misaligned => write!(f, "boot information buffer is misaligned"),
+            Self::BufferTooSmall => write!(f, "boot information buffer is too small"),
+        }
+    }
+}
+
+/// Writes boot information to a buffer.
+pub struct Builder<'a> {
+    buffer: &'a mut [u8],
+    /// The number of bytes written so far, always aligned to 8 bytes.
+    len: usize,
+}
+
+impl<'a> Builder<'a> {
+    /// Creates a builder writing to the given buffer, which must be aligned to
+    /// 8 bytes.
+    pub fn new(buffer: &'a mut [u8]) -> Result<Self, BuildError> {
+        if buffer.as_ptr() as usize & 7 != 0 {
+            return Err(BuildError::Misaligned);
+        }
+        if buffer.len() < HEADER_SIZE {
+            return Err(BuildError::BufferTooSmall);
+        }
+        Ok(Self {
+            buffer,
+            len: HEADER_SIZE,
+        })
+    }
+
+    /// Appends a tag with a payload made up of the given parts.
+    fn push_tag(&mut self, kind: u32, parts: &[&[u8]]) -> Result<(), BuildError> {
+        let payload_size: usize = parts.iter().map(|part| part.len()).sum();
+        let size = TAG_HEADER_SIZE + payload_size;
+        let end = align_up(self.len + size);
+        if end > self.buffer.len() || size > u32::MAX as usize {
+            return Err(BuildError::BufferTooSmall);
+        }
+
+        let header = TagHeader {
+            kind,
+            size: size as u32,
+        };
+        let mut offset = self.len;
+        for part in [as_bytes(&header)].iter().chain(parts.iter()) {
+            self.buffer[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        // Zero the padding
+        for byte in self.buffer[offset..end].iter_mut() {
+            *byte = 0;
+        }
+
+        self.len = end;
+        Ok(())
+    }
+
+    /// Adds the memory map.
+    ///
+    /// Adjacent regions of the same kind are merged, and the regions must be
+    /// sorted by address. This does not allocate, so it can be called after the
+    /// firmware's boot services have been exited.
+    pub fn add_memory_map<I>(&mut self, regions: I) -> Result<(), BuildError>
+    where
+        I: IntoIterator<Item = MemoryRegion>,
+    {
+        let tag_offset = self.len;
+        self.push_tag(TAG_MEMORY_MAP, &[])?;
+
+        let region_size = size_of::<MemoryRegion>();
+        let mut end = tag_offset + TAG_HEADER_SIZE;
+        let mut last: Option<MemoryRegion> = None;
+        for region in regions {
+            if let Some(last) = last.as_mut() {
+                if last.end() == region.start && last.kind == region.kind {
+                    last.size += region.size;
+                    self.buffer[end - region_size..end].copy_from_slice(as_bytes(last));
+                    continue;
+                }
+            }
+
+            if end + region_size > self.buffer.len() {
+                return Err(BuildError::BufferTooSmall);
+            }
+            self.buffer[end..end + region_size].copy_from_slice(as_bytes(&region));
+            end += region_size;
+            last = Some(region);
+        }
+
+        // Update the size of the tag now that we know it
+        let size = (end - tag_offset) as u32;
+        self.buffer[tag_offset + 4..tag_offset + 8].copy_from_slice(&size.to_le_bytes());
+        self.len = align_up(end);
+        Ok(())
+    }
+
+    /// Adds the frame buffer.
+    pub fn add_framebuffer(&mut self, framebuffer: &Framebuffer) -> Result<(), BuildError> {
+        self.push_tag(TAG_FRAMEBUFFER, &[as_bytes(framebuffer)])
+    }
+
+    /// Adds the physical address of the ACPI RSDP.
+    pub fn add_rsdp(&mut self, address: u64) -> Result<(), BuildError> {
+        self.push_tag(TAG_RSDP, &[&address.to_le_bytes()])
+    }
+
+    /// Adds the kernel command line.
+    pub fn add_command_line(&mut self, command_line: &str) -> Result<(), BuildError> {
+        self.push_tag(TAG_COMMAND_LINE, &[command_line.as_bytes()])
+    }
+
+    /// Adds a module loaded at the given physical address.
+    pub fn add_module(&mut self, start: u64, size: u64, name: &str) -> Result<(), BuildError> {
+        let header = ModuleHeader { start, size };
+        self.push_tag(TAG_MODULE, &[as_bytes(&header), name.as_bytes()])
+    }
+
+    /// Adds the location of the kernel symbols.
+    pub fn add_kernel_symbols(&mut self, symbols: &KernelSymbols) -> Result<(), BuildError> {
+        self.push_tag(TAG_KERNEL_SYMBOLS, &[as_bytes(symbols)])
+    }
+
+    /// Terminates the tag list and writes the header, returning the size of
+    /// the boot information in bytes.
+    pub fn finish(mut self) -> Result<usize, BuildError> {
+        self.push_tag(TAG_END, &[])?;
+        if self.len > u32::MAX as usize {
+            return Err(BuildError::BufferTooSmall);
+        }
+
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            size: self.len as u32,
+            _reserved: 0,
+        };
+        self.buffer[..HEADER_SIZE].copy_from_slice(as_bytes(&header));
+        Ok(self.len)
+    }
+}
+
+/// Returns the bytes of a value.
+///
+/// Only used with `repr(C)` types without padding, so every byte is
+/// initialized.
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
@@ -0,0 +1,285 @@
+//! Boot information is written with the [Builder] into an aligned buffer, and
+//! read back with [BootInfo::from_ptr] like the kernel does. The rejection
+//! tests patch single fields of otherwise valid boot information.
+
+use super::*;
+
+/// A buffer with the alignment the builder requires.
+#[repr(align(8))]
+struct Buffer([u8; 512]);
+
+impl Buffer {
+    fn new() -> Self {
+        Self([0; 512])
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.0[offset],
+            self.0[offset + 1],
+            self.0[offset + 2],
+            self.0[offset + 3],
+        ])
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn parse(&self) -> Result<BootInfo<'_>, BootInfoError> {
+        unsafe { BootInfo::from_ptr(self.0.as_ptr()) }
+    }
+}
+
+/// Writes boot information with an RSDP tag first, followed by a command line,
+/// returning its size.
+fn build_small(buffer: &mut Buffer) -> usize {
+    let mut builder = Builder::new(&mut buffer.0).unwrap();
+    builder.add_rsdp(0xe0000).unwrap();
+    builder.add_command_line("quiet").unwrap();
+    builder.finish().unwrap()
+}
+
+/// The offset of the RSDP tag written by [build_small].
+const RSDP_OFFSET: usize = HEADER_SIZE;
+
+#[test]
+fn round_trip() {
+    let mut buffer = Buffer::new();
+    let mut builder = Builder::new(&mut buffer.0).unwrap();
+    builder
+        .add_memory_map(
+            [
+                MemoryRegion::new(0, 0x1000, MemoryRegionKind::Reserved),
+                MemoryRegion::new(0x1000, 0x9_f000, MemoryRegionKind::Usable),
+                MemoryRegion::new(0x10_0000, 0x10_0000, MemoryRegionKind::Usable),
+                // Adjacent to the previous region, so they're merged
+                MemoryRegion::new(0x20_0000, 0x20_0000, MemoryRegionKind::Usable),
+                MemoryRegion::new(0x40_0000, 0x1000, MemoryRegionKind::AcpiNvs),
+            ]
+            .iter()
+            .copied(),
+        )
+        .unwrap();
+    let framebuffer = Framebuffer::new(0x8000_0000, 800, 600, 832, PixelFormat::Rgb);
+    builder.add_framebuffer(&framebuffer).unwrap();
+    builder.add_rsdp(0xe0000).unwrap();
+    builder.add_command_line("init=/bin/sh ip=dhcp").unwrap();
+    builder.add_module(0x50_0000, 0x1234, "initrd.tar").unwrap();
+    builder.add_module(0x60_0000, 7, "").unwrap();
+    let symbols = KernelSymbols {
+        symtab_addr: 0x70_0000,
+        symtab_size: 0x600,
+        strtab_addr: 0x70_1000,
+        strtab_size: 0x321,
+    };
+    builder.add_kernel_symbols(&symbols).unwrap();
+    let size = builder.finish().unwrap();
+    assert_eq!(size % 8, 0);
+
+    let boot_info = buffer.parse().unwrap();
+    assert_eq!(boot_info.size(), size);
+
+    let regions = boot_info.memory_map();
+    let expected = [
+        (0, 0x1000, MemoryRegionKind::Reserved),
+        (0x1000, 0x9_f000, MemoryRegionKind::Usable),
+        (0x10_0000, 0x30_0000, MemoryRegionKind::Usable),
+        (0x40_0000, 0x1000, MemoryRegionKind::AcpiNvs),
+    ];
+    assert_eq!(regions.len(), expected.len());
+    for (region, &(start, size, kind)) in regions.iter().zip(expected.iter()) {
+        assert_eq!(
+            (region.start, region.size, region.kind()),
+            (start, size, kind)
+        );
+    }
+
+    let read_framebuffer = boot_info.framebuffer().unwrap();
+    assert_eq!(read_framebuffer.base, 0x8000_0000);
+    assert_eq!(
+        (
+            read_framebuffer.width,
+            read_framebuffer.height,
+            read_framebuffer.stride
+        ),
+        (800, 600, 832)
+    );
+    assert_eq!(read_framebuffer.pixel_format(), PixelFormat::Rgb);
+
+    assert_eq!(boot_info.rsdp(), Some(0xe0000));
+    assert_eq!(boot_info.command_line(), "init=/bin/sh ip=dhcp");
+
+    let mut modules = boot_info.modules();
+    let module = modules.next().unwrap();
+    assert_eq!(
+        (module.start, module.size, module.name),
+        (0x50_0000, 0x1234, "initrd.tar")
+    );
+    let module = modules.next().unwrap();
+    assert_eq!((module.start, module.size, module.name), (0x60_0000, 7, ""));
+    assert!(modules.next().is_none());
+
+    let read_symbols = boot_info.kernel_symbols().unwrap();
+    assert_eq!(read_symbols.symtab_addr, 0x70_0000);
+    assert_eq!(read_symbols.strtab_size, 0x321);
+    assert_eq!(boot_info.tags().count(), 7);
+}
+
+#[test]
+fn missing_tags() {
+    let mut buffer = Buffer::new();
+    let size = Builder::new(&mut buffer.0).unwrap().finish().unwrap();
+    assert_eq!(size, HEADER_SIZE + TAG_HEADER_SIZE);
+
+    let boot_info = buffer.parse().unwrap();
+    assert!(boot_info.memory_map().is_empty());
+    assert!(boot_info.framebuffer().is_none());
+    assert_eq!(boot_info.rsdp(), None);
+    assert_eq!(boot_info.command_line(), "");
+    assert_eq!(boot_info.modules().count(), 0);
+}
+
+#[test]
+fn unknown_tags_are_skipped() {
+    let mut buffer = Buffer::new();
+    build_small(&mut buffer);
+    buffer.write_u32(RSDP_OFFSET, 0x100);
+
+    let boot_info = buffer.parse().unwrap();
+    let mut tags = boot_info.tags();
+    assert!(matches!(tags.next(), Some(Tag::Unknown(0x100))));
+    assert!(matches!(tags.next(), Some(Tag::CommandLine("quiet"))));
+    assert!(tags.next().is_none());
+    assert_eq!(boot_info.rsdp(), None);
+}
+
+#[test]
+fn builder_rejects_small_buffers() {
+    let mut buffer = Buffer::new();
+    assert!(matches!(
+        Builder::new(&mut buffer.0[..HEADER_SIZE - 8]),
+        Err(BuildError::BufferTooSmall)
+    ));
+    assert!(matches!(
+        Builder::new(&mut buffer.0[1..]),
+        Err(BuildError::Misaligned)
+    ));
+
+    // Room for the header and the RSDP tag, but not the end tag
+    let mut builder = Builder::new(&mut buffer.0[..HEADER_SIZE + 16]).unwrap();
+    builder.add_rsdp(0xe0000).unwrap();
+    assert_eq!(
+        builder.add_command_line("a").unwrap_err(),
+        BuildError::BufferTooSmall
+    );
+    assert_eq!(builder.finish().unwrap_err(), BuildError::BufferTooSmall);
+}
+
+#[test]
+fn rejects_bad_magic() {
+    let mut buffer = Buffer::new();
+    build_small(&mut buffer);
+    buffer.write_u32(0, 0x464c_457f);
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::BadMagic(0x464c_457f))
+    );
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut buffer = Buffer::new();
+    build_small(&mut buffer);
+    buffer.write_u32(4, VERSION + 1);
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::UnsupportedVersion(VERSION + 1))
+    );
+}
+
+#[test]
+fn rejects_small_sizes() {
+    let mut buffer = Buffer::new();
+    build_small(&mut buffer);
+    buffer.write_u32(8, HEADER_SIZE as u32);
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::InvalidSize(HEADER_SIZE as u32))
+    );
+}
+
+#[test]
+fn rejects_misaligned_pointers() {
+    let mut buffer = Buffer::new();
+    build_small(&mut buffer);
+    let result = unsafe { BootInfo::from_ptr(buffer.0.as_ptr().add(4)) };
+    assert_eq!(result.err(), Some(BootInfoError::Misaligned));
+}
+
+#[test]
+fn rejects_truncated_tags() {
+    // The RSDP tag is too short for an address
+    let mut buffer = Buffer::new();
+    build_small(&mut buffer);
+    buffer.write_u32(RSDP_OFFSET + 4, (TAG_HEADER_SIZE + 4) as u32);
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::InvalidTag {
+            offset: RSDP_OFFSET
+        })
+    );
+
+    // And too short for its own header
+    buffer.write_u32(RSDP_OFFSET + 4, 4);
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::InvalidTag {
+            offset: RSDP_OFFSET
+        })
+    );
+}
+
+#[test]
+fn rejects_tags_past_the_end() {
+    let mut buffer = Buffer::new();
+    let size = build_small(&mut buffer);
+    // The command line follows the RSDP tag, and now runs past the end tag
+    let offset = RSDP_OFFSET + align_up(TAG_HEADER_SIZE + 8);
+    assert_eq!(buffer.read_u32(offset), TAG_COMMAND_LINE);
+    buffer.write_u32(offset + 4, (size - offset + 1) as u32);
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::InvalidTag { offset })
+    );
+}
+
+#[test]
+fn rejects_invalid_payloads() {
+    let mut buffer = Buffer::new();
+    let mut builder = Builder::new(&mut buffer.0).unwrap();
+    builder.add_command_line("quiet").unwrap();
+    builder.finish().unwrap();
+    // The command line is not UTF-8
+    buffer.0[HEADER_SIZE + TAG_HEADER_SIZE] = 0xff;
+    assert_eq!(
+        buffer.parse().err(),
+        Some(BootInfoError::InvalidTag {
+            offset: HEADER_SIZE
+        })
+    );
+}
+
+#[test]
+fn rejects_missing_end_tag() {
+    let mut buffer = Buffer::new();
+    let size = build_small(&mut buffer);
+    // Leave the end tag out of the total size
+    buffer.write_u32(8, (size - TAG_HEADER_SIZE) as u32);
+    assert_eq!(buffer.parse().err(), Some(BootInfoError::MissingEndTag));
+
+    // Or turn it into an unknown tag
+    buffer.write_u32(8, size as u32);
+    buffer.write_u32(size - TAG_HEADER_SIZE, 0x100);
+    assert_eq!(buffer.parse().err(), Some(BootInfoError::MissingEndTag));
+}
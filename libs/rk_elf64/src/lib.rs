@@ -1,5 +1,21 @@
 #![no_std]
 
+pub mod relocation;
+#[cfg(test)]
+mod tests;
+
+use core::fmt;
+
+/// The magic number at the start of every ELF file.
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+/// The values of `e_ident[EI_CLASS]`, `e_ident[EI_DATA]`, and
+/// `e_ident[EI_VERSION]` of the files supported: 64-bit, little endian, and
+/// the current version.
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
 /// A 64-bit ELF file header.
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
@@ -45,10 +61,100 @@ pub struct ProgramHeader {
     pub p_align: u64,
 }
 
+impl FileHeader {
+    /// Validates the file header at the start of the data, which is the only
+    /// part of the file it looks at.
+    pub fn parse(data: &[u8]) -> Result<Self, ParseError> {
+        let header: Self = read(data, 0).ok_or(ParseError::NotElf)?;
+        if header.e_ident[0..4] != ELF_MAGIC {
+            return Err(ParseError::NotElf);
+        }
+        if header.e_ident[4] != ELFCLASS64
+            || header.e_ident[5] != ELFDATA2LSB
+            || header.e_ident[6] != EV_CURRENT
+        {
+            return Err(ParseError::UnsupportedFormat);
+        }
+        if header.e_machine != Machine::EM_X86_64 {
+            return Err(ParseError::UnsupportedMachine);
+        }
+        if header.e_phnum > 0
+            && header.e_phentsize as usize != core::mem::size_of::<ProgramHeader>()
+        {
+            return Err(ParseError::InvalidFileHeader);
+        }
+        if header.e_shnum > 0
+            && header.e_shentsize as usize != core::mem::size_of::<SectionHeader>()
+        {
+            return Err(ParseError::InvalidFileHeader);
+        }
+        Ok(header)
+    }
+
+    /// Returns the size of the program header table in bytes.
+    pub fn program_headers_size(&self) -> u64 {
+        self.e_phnum as u64 * core::mem::size_of::<ProgramHeader>() as u64
+    }
+}
+
+impl ProgramHeader {
+    /// Returns an iterator over the program headers of a table read from a
+    /// file. Any bytes past the last whole header are ignored.
+    pub fn read_table(table: &[u8]) -> impl Iterator<Item = ProgramHeader> + '_ {
+        table
+            .chunks_exact(core::mem::size_of::<ProgramHeader>())
+            .map(|bytes| read(bytes, 0).unwrap())
+    }
+
+    /// Returns whether the data of the segment is within a file of the size and
+    /// no larger than its memory, and its memory doesn't extend past the end of
+    /// the address space. Every loadable segment must be valid.
+    pub fn is_valid(&self, file_size: u64) -> bool {
+        let file_end = self.p_offset.checked_add(self.p_filesz);
+        let memory_end = self.p_vaddr.checked_add(self.p_memsz);
+        match (file_end, memory_end) {
+            (Some(file_end), Some(_)) => file_end <= file_size && self.p_filesz <= self.p_memsz,
+            _ => false,
+        }
+    }
+}
+
+/// The values of `e_type`.
+pub struct FileType;
+
+impl FileType {
+    /// An executable loaded at a fixed address.
+    pub const ET_EXEC: u16 = 2;
+    /// A shared object, or a position-independent executable.
+    pub const ET_DYN: u16 = 3;
+}
+
+/// The values of `e_machine`.
+pub struct Machine;
+
+impl Machine {
+    pub const EM_X86_64: u16 = 62;
+}
+
 pub struct ProgramType(u32);
 
 impl ProgramType {
     pub const PT_LOAD: u32 = 0x0000_0001;
+    /// The dynamic section, see [relocation::Dynamic].
+    pub const PT_DYNAMIC: u32 = 0x0000_0002;
+    /// The path of the interpreter of a dynamically linked executable.
+    pub const PT_INTERP: u32 = 0x0000_0003;
+    /// The program header table itself.
+    pub const PT_PHDR: u32 = 0x0000_0006;
+}
+
+/// The bits of `p_flags`.
+pub struct ProgramFlags;
+
+impl ProgramFlags {
+    pub const PF_X: u32 = 1 << 0;
+    pub const PF_W: u32 = 1 << 1;
+    pub const PF_R: u32 = 1 << 2;
 }
 
 /// A 64-bit ELF section header.
@@ -72,6 +178,20 @@ pub struct SectionHeader {
     pub sh_entsize: u64,
 }
 
+/// The values of `sh_type`.
+pub struct SectionType;
+
+impl SectionType {
+    pub const SHT_SYMTAB: u32 = 2;
+    pub const SHT_STRTAB: u32 = 3;
+    /// Relocations with addends, see [relocation::Rela].
+    pub const SHT_RELA: u32 = 4;
+    pub const SHT_DYNSYM: u32 = 11;
+}
+
+/// The section index of undefined symbols.
+pub const SHN_UNDEF: u16 = 0;
+
 /// A 64-bit ELF symbol.
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
@@ -90,6 +210,206 @@ pub struct Symbol {
     pub st_size: u64,
 }
 
+impl Symbol {
+    /// The binding of weak symbols, which may be left undefined.
+    pub const STB_WEAK: u8 = 2;
+
+    /// Returns the binding, which is in the upper four bits of `st_info`.
+    pub fn binding(&self) -> u8 {
+        self.st_info >> 4
+    }
+
+    /// Returns whether the symbol is defined by the file, rather than referring
+    /// to a symbol of another file.
+    pub fn is_defined(&self) -> bool {
+        self.st_shndx != SHN_UNDEF
+    }
+}
+
+/// Types which any bytes of the right size are a valid value of, such that
+/// they can be read from a file.
+///
+/// # Safety
+/// The type must not have any invalid values.
+unsafe trait Plain: Copy {}
+
+unsafe impl Plain for FileHeader {}
+unsafe impl Plain for ProgramHeader {}
+unsafe impl Plain for SectionHeader {}
+unsafe impl Plain for Symbol {}
+
+/// Reads a value at an offset into the data, or returns `None` if it doesn't
+/// fit.
+fn read<T: Plain>(data: &[u8], offset: u64) -> Option<T> {
+    let end = offset.checked_add(core::mem::size_of::<T>() as u64)?;
+    if end > data.len() as u64 {
+        return None;
+    }
+    // Safety: The value is within the data, and any bytes are a valid value
+    Some(unsafe { core::ptr::read_unaligned(data.as_ptr().add(offset as usize) as *const T) })
+}
+
+/// An error in an ELF file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The file doesn't start with the ELF magic number.
+    NotElf,
+    /// The file is not a 64-bit little endian file of the current version.
+    UnsupportedFormat,
+    /// The file is not for x86-64.
+    UnsupportedMachine,
+    /// The file header is truncated or inconsistent.
+    InvalidFileHeader,
+    /// The program header at the index is truncated or inconsistent.
+    InvalidProgramHeader { index: usize },
+    /// The dynamic section, or a table it refers to, is truncated or
+    /// inconsistent.
+    InvalidDynamicSection,
+    /// The section at the index is truncated or inconsistent, or refers to an
+    /// invalid section.
+    InvalidSection { index: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotElf => write!(f, "not an ELF file"),
+            Self::UnsupportedFormat => write!(f, "not a 64-bit little endian ELF file"),
+            Self::UnsupportedMachine => write!(f, "not an x86-64 ELF file"),
+            Self::InvalidFileHeader => write!(f, "invalid file header"),
+            Self::InvalidProgramHeader { index } => {
+                write!(f, "invalid program header {}", index)
+            }
+            Self::InvalidDynamicSection => write!(f, "invalid dynamic section"),
+            Self::InvalidSection { index } => write!(f, "invalid section {}", index),
+        }
+    }
+}
+
+/// An ELF file for x86-64, whose headers have been validated.
+///
+/// Every program and section header is within the file, as is the data of
+/// every segment, and the memory of no segment extends past the end of the
+/// address space.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: FileHeader,
+}
+
+impl<'a> Elf<'a> {
+    /// Validates the headers of an ELF file.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let header = FileHeader::parse(data)?;
+        match header.e_phoff.checked_add(header.program_headers_size()) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ParseError::InvalidFileHeader),
+        }
+
+        let table_size = header.e_shnum as u64 * core::mem::size_of::<SectionHeader>() as u64;
+        match header.e_shoff.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ParseError::InvalidFileHeader),
+        }
+
+        let elf = Self { data, header };
+        for (index, program_header) in elf.program_headers().enumerate() {
+            if program_header.p_type == ProgramType::PT_LOAD
+                && !program_header.is_valid(data.len() as u64)
+            {
+                return Err(ParseError::InvalidProgramHeader { index });
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Returns the contents of the file.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn file_header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Returns an iterator over the program headers.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        // The table was checked to be within the data
+        let start = self.header.e_phoff as usize;
+        let end = start + self.header.program_headers_size() as usize;
+        ProgramHeader::read_table(&self.data[start..end])
+    }
+
+    /// Returns an iterator over the section headers.
+    pub fn section_headers(&self) -> impl Iterator<Item = SectionHeader> + 'a {
+        let data = self.data;
+        let offset = self.header.e_shoff;
+        let size = core::mem::size_of::<SectionHeader>() as u64;
+        // The table was checked to be within the data
+        (0..self.header.e_shnum as u64).map(move |i| read(data, offset + i * size).unwrap())
+    }
+
+    /// Returns the section header at the index.
+    pub fn section_header(&self, index: usize) -> Option<SectionHeader> {
+        self.section_headers().nth(index)
+    }
+
+    /// Returns the contents of a section in the file, or `None` if they're not
+    /// within the file.
+    pub fn section_data(&self, section_header: &SectionHeader) -> Option<&'a [u8]> {
+        let end = section_header
+            .sh_offset
+            .checked_add(section_header.sh_size)?;
+        self.data
+            .get(section_header.sh_offset as usize..end as usize)
+    }
+
+    /// Returns the name of a section, or `None` if the section header string
+    /// table or the name is invalid.
+    pub fn section_name(&self, section_header: &SectionHeader) -> Option<&'a str> {
+        let strings = self.section_header(self.header.e_shstrndx as usize)?;
+        string(self.section_data(&strings)?, section_header.sh_name)
+    }
+
+    /// Returns the first section with the given name.
+    pub fn find_section(&self, name: &str) -> Option<SectionHeader> {
+        self.section_headers()
+            .find(|section_header| self.section_name(section_header) == Some(name))
+    }
+
+    /// Returns the offset in the file of the data at a virtual address, if
+    /// the address is in the file data of a loadable segment. The offset is
+    /// followed by at least `size` bytes of the same segment.
+    pub fn file_offset(&self, addr: u64, size: u64) -> Option<u64> {
+        self.program_headers()
+            .filter(|program_header| program_header.p_type == ProgramType::PT_LOAD)
+            .find(|segment| {
+                let segment_end = segment.p_vaddr + segment.p_filesz;
+                addr >= segment.p_vaddr
+                    && matches!(addr.checked_add(size), Some(end) if end <= segment_end)
+            })
+            .map(|segment| segment.p_offset + (addr - segment.p_vaddr))
+    }
+
+    /// Returns the data of a segment in the file, which is followed by zeroes
+    /// up to its size in memory. Returns `None` if the data is not within the
+    /// file, which is only possible for segments which are not loadable.
+    pub fn segment_data(&self, program_header: &ProgramHeader) -> Option<&'a [u8]> {
+        let end = program_header
+            .p_offset
+            .checked_add(program_header.p_filesz)?;
+        self.data
+            .get(program_header.p_offset as usize..end as usize)
+    }
+}
+
+/// Returns the NUL terminated string at an offset into a string table, or
+/// `None` if it's not within the table or not valid UTF-8.
+fn string(table: &[u8], offset: u32) -> Option<&str> {
+    let bytes = table.get(offset as usize..)?;
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
 /// Tries to find the section header for the section with the given name.
 ///
 /// Returns `Err` if no section with the given name can be found.
@@ -0,0 +1,540 @@
+//! Relocations, which adjust the loaded contents of a file to the address it's
+//! loaded at and to the addresses of the symbols it refers to.
+//!
+//! Position-independent executables and shared objects list the relocations
+//! to apply when loaded in their [dynamic section](Dynamic). They're applied
+//! with [apply_relocations] to the memory of a loaded file, an [Image], or
+//! with [Elf::apply_dynamic_relocations] when the whole file is at hand.
+//!
+//! The relocations of `.rela.*` sections, as found in relocatable object files,
+//! are read with [Elf::section_relocations] and computed with [Rela::compute],
+//! as only the caller knows where each section is placed.
+//!
+//! Only relocations with addends are supported, which is all x86-64 uses.
+
+use super::{read, string, Elf, ParseError, Plain, ProgramType, SectionType, Symbol, SHN_UNDEF};
+use core::convert::TryFrom;
+use core::fmt;
+
+/// The section index of absolute symbols, which are not relocated.
+pub const SHN_ABS: u16 = 0xfff1;
+
+/// A relocation with an addend.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Rela {
+    /// The location to relocate, as a virtual address for executables and
+    /// shared objects, or as an offset into the relocated section for
+    /// relocatable object files.
+    pub r_offset: u64,
+    /// The index of the symbol in the upper 32 bits, and the type of the
+    /// relocation in the lower 32 bits.
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
+unsafe impl Plain for Rela {}
+
+/// The values of the relocation type of [Rela::r_info].
+pub struct RelocationType;
+
+impl RelocationType {
+    pub const R_X86_64_NONE: u32 = 0;
+    /// The address of the symbol plus the addend.
+    pub const R_X86_64_64: u32 = 1;
+    /// The address of the symbol plus the addend, relative to the location,
+    /// as a signed 32-bit value.
+    pub const R_X86_64_PC32: u32 = 2;
+    /// The address of the symbol, in the global offset table.
+    pub const R_X86_64_GLOB_DAT: u32 = 6;
+    /// The address of the symbol, in the procedure linkage table.
+    pub const R_X86_64_JUMP_SLOT: u32 = 7;
+    /// The load base plus the addend.
+    pub const R_X86_64_RELATIVE: u32 = 8;
+}
+
+/// The value of a relocation, which is written at the relocated location in
+/// little endian.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    U64(u64),
+    U32(u32),
+}
+
+impl Rela {
+    /// Returns the index of the symbol in the symbol table, or 0 if the
+    /// relocation doesn't refer to a symbol.
+    pub fn symbol_index(&self) -> u32 {
+        (self.r_info >> 32) as u32
+    }
+
+    pub fn relocation_type(&self) -> u32 {
+        self.r_info as u32
+    }
+
+    /// Computes the value of the relocation, given the difference between the
+    /// address the file is loaded at and the address it's linked at, the
+    /// address of the relocated location, and the address of the symbol (0 if
+    /// there is none).
+    ///
+    /// Returns `None` if there's nothing to write.
+    pub fn compute(
+        &self,
+        base: u64,
+        place: u64,
+        symbol: u64,
+    ) -> Result<Option<Value>, RelocationError> {
+        let addend = self.r_addend as u64;
+        let value = match self.relocation_type() {
+            RelocationType::R_X86_64_NONE => return Ok(None),
+            RelocationType::R_X86_64_64 => Value::U64(symbol.wrapping_add(addend)),
+            RelocationType::R_X86_64_PC32 => {
+                let value = symbol.wrapping_add(addend).wrapping_sub(place) as i64;
+                let value = i32::try_from(value).map_err(|_| RelocationError::Overflow {
+                    offset: self.r_offset,
+                })?;
+                Value::U32(value as u32)
+            }
+            RelocationType::R_X86_64_GLOB_DAT | RelocationType::R_X86_64_JUMP_SLOT => {
+                Value::U64(symbol)
+            }
+            RelocationType::R_X86_64_RELATIVE => Value::U64(base.wrapping_add(addend)),
+            other => return Err(RelocationError::UnsupportedType(other)),
+        };
+        Ok(Some(value))
+    }
+}
+
+/// An entry of the dynamic section.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+unsafe impl Plain for Dyn {}
+
+/// The values of [Dyn::d_tag] used for relocations.
+pub struct DynamicTag;
+
+impl DynamicTag {
+    /// Marks the end of the dynamic section.
+    pub const DT_NULL: i64 = 0;
+    pub const DT_PLTRELSZ: i64 = 2;
+    pub const DT_STRTAB: i64 = 5;
+    pub const DT_SYMTAB: i64 = 6;
+    pub const DT_RELA: i64 = 7;
+    pub const DT_RELASZ: i64 = 8;
+    pub const DT_RELAENT: i64 = 9;
+    pub const DT_STRSZ: i64 = 10;
+    pub const DT_SYMENT: i64 = 11;
+    pub const DT_PLTREL: i64 = 20;
+    pub const DT_JMPREL: i64 = 23;
+}
+
+/// The tables of the dynamic section used for relocations, by virtual address
+/// and size in bytes. The address of a table which is absent is 0.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Dynamic {
+    pub rela: u64,
+    pub rela_size: u64,
+    /// The size of an entry of the relocation tables, or 0 if not given.
+    pub rela_entry_size: u64,
+    /// The relocations of the procedure linkage table.
+    pub jmprel: u64,
+    pub jmprel_size: u64,
+    /// The tag of the type of the relocations of the procedure linkage
+    /// table, which must be [DT_RELA](DynamicTag::DT_RELA).
+    pub plt_rel: u64,
+    pub symtab: u64,
+    /// The size of an entry of the symbol table, or 0 if not given.
+    pub symbol_entry_size: u64,
+    pub strtab: u64,
+    pub strtab_size: u64,
+}
+
+impl Dynamic {
+    /// Collects the tables from the entries of a dynamic section, up to the
+    /// `DT_NULL` entry ending it. An entry which is `None` is invalid.
+    pub fn parse(entries: impl Iterator<Item = Option<Dyn>>) -> Result<Self, ParseError> {
+        let mut dynamic = Self::default();
+        for entry in entries {
+            let entry = entry.ok_or(ParseError::InvalidDynamicSection)?;
+            let field = match entry.d_tag {
+                DynamicTag::DT_NULL => return Ok(dynamic),
+                DynamicTag::DT_PLTRELSZ => &mut dynamic.jmprel_size,
+                DynamicTag::DT_STRTAB => &mut dynamic.strtab,
+                DynamicTag::DT_SYMTAB => &mut dynamic.symtab,
+                DynamicTag::DT_RELA => &mut dynamic.rela,
+                DynamicTag::DT_RELASZ => &mut dynamic.rela_size,
+                DynamicTag::DT_RELAENT => &mut dynamic.rela_entry_size,
+                DynamicTag::DT_STRSZ => &mut dynamic.strtab_size,
+                DynamicTag::DT_SYMENT => &mut dynamic.symbol_entry_size,
+                DynamicTag::DT_PLTREL => &mut dynamic.plt_rel,
+                DynamicTag::DT_JMPREL => &mut dynamic.jmprel,
+                _ => continue,
+            };
+            *field = entry.d_val;
+        }
+        // The section must end with DT_NULL
+        Err(ParseError::InvalidDynamicSection)
+    }
+
+    /// Reads the tables from the dynamic section at an address of an image,
+    /// which is at most `size` bytes long.
+    pub fn read(image: &mut impl Image, addr: u64, size: u64) -> Result<Self, ParseError> {
+        let entry_size = core::mem::size_of::<Dyn>() as u64;
+        Self::parse(
+            (0..size / entry_size).map(|i| read_value(image, addr.checked_add(i * entry_size)?)),
+        )
+    }
+
+    /// Reads the symbol at the index of the symbol table from an image.
+    pub fn read_symbol(&self, image: &mut impl Image, index: u32) -> Result<Symbol, ParseError> {
+        let entry_size = table_entry_size::<Symbol>(self.symbol_entry_size)
+            .ok_or(ParseError::InvalidDynamicSection)?;
+        (index as u64)
+            .checked_mul(entry_size)
+            .and_then(|offset| self.symtab.checked_add(offset))
+            .and_then(|addr| read_value(image, addr))
+            .ok_or(ParseError::InvalidDynamicSection)
+    }
+}
+
+impl Symbol {
+    /// Returns the address of the symbol in a file loaded `base` bytes above
+    /// the addresses it's linked at, or `None` if the file doesn't define it.
+    pub fn relocated_address(&self, base: u64) -> Option<u64> {
+        match self.st_shndx {
+            SHN_UNDEF => None,
+            SHN_ABS => Some(self.st_value),
+            _ => Some(base.wrapping_add(self.st_value)),
+        }
+    }
+}
+
+/// The memory of a loaded file, by the virtual addresses it's linked at.
+pub trait Image {
+    /// Reads from the memory at an address. Fails if any of it is not part of
+    /// the file.
+    fn read(&mut self, addr: u64, buffer: &mut [u8]) -> Result<(), InvalidAddress>;
+
+    /// Writes to the memory at an address. Fails if any of it is not part of
+    /// the file.
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), InvalidAddress>;
+}
+
+/// An access to memory which is not part of an [Image].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InvalidAddress;
+
+/// Reads a value at an address of an image.
+fn read_value<T: Plain>(image: &mut impl Image, addr: u64) -> Option<T> {
+    // Large enough for any of the values read
+    let mut buffer = [0; 32];
+    let buffer = &mut buffer[..core::mem::size_of::<T>()];
+    image.read(addr, buffer).ok()?;
+    read(buffer, 0)
+}
+
+/// Applies the relocations of the dynamic section of an image, loaded `base`
+/// bytes above the addresses it's linked at.
+///
+/// Symbols defined by the file are relocated along with it, while the
+/// addresses of the others are looked up with `resolve`, given their index in
+/// the symbol table. Weak symbols which are not found are 0.
+pub fn apply_relocations<I: Image>(
+    image: &mut I,
+    dynamic: &Dynamic,
+    base: u64,
+    mut resolve: impl FnMut(u32, &Symbol) -> Option<u64>,
+) -> Result<(), RelocationError> {
+    if dynamic.jmprel_size > 0 && dynamic.plt_rel != DynamicTag::DT_RELA as u64 {
+        return Err(ParseError::InvalidDynamicSection.into());
+    }
+    let entry_size = table_entry_size::<Rela>(dynamic.rela_entry_size)
+        .ok_or(ParseError::InvalidDynamicSection)?;
+    let tables = [
+        (dynamic.rela, dynamic.rela_size),
+        (dynamic.jmprel, dynamic.jmprel_size),
+    ];
+    for &(table, size) in tables.iter() {
+        for i in 0..size / entry_size {
+            let rela: Rela = table
+                .checked_add(i * entry_size)
+                .and_then(|addr| read_value(image, addr))
+                .ok_or(ParseError::InvalidDynamicSection)?;
+            let symbol = match rela.symbol_index() {
+                0 => 0,
+                index => {
+                    let symbol = dynamic.read_symbol(image, index)?;
+                    match symbol.relocated_address(base) {
+                        Some(addr) => addr,
+                        None => match resolve(index, &symbol) {
+                            Some(addr) => addr,
+                            None if symbol.binding() == Symbol::STB_WEAK => 0,
+                            None => return Err(RelocationError::UndefinedSymbol { index }),
+                        },
+                    }
+                }
+            };
+
+            let place = base.wrapping_add(rela.r_offset);
+            let written = match rela.compute(base, place, symbol)? {
+                Some(Value::U64(value)) => image.write(rela.r_offset, &value.to_le_bytes()),
+                Some(Value::U32(value)) => image.write(rela.r_offset, &value.to_le_bytes()),
+                None => Ok(()),
+            };
+            written.map_err(|InvalidAddress| RelocationError::InvalidOffset {
+                offset: rela.r_offset,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// The image of a file read from its loadable segments, whose relocations are
+/// written with a function at the address the file is loaded at.
+struct FileImage<'e, 'a, W> {
+    elf: &'e Elf<'a>,
+    base: u64,
+    write: W,
+}
+
+impl<W: FnMut(u64, &[u8]) -> Result<(), ()>> Image for FileImage<'_, '_, W> {
+    fn read(&mut self, addr: u64, buffer: &mut [u8]) -> Result<(), InvalidAddress> {
+        let offset = self
+            .elf
+            .file_offset(addr, buffer.len() as u64)
+            .ok_or(InvalidAddress)? as usize;
+        buffer.copy_from_slice(&self.elf.data[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), InvalidAddress> {
+        (self.write)(self.base.wrapping_add(addr), data).map_err(|()| InvalidAddress)
+    }
+}
+
+/// An error applying relocations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RelocationError {
+    /// A table the relocations are read from is invalid.
+    Parse(ParseError),
+    /// The relocation type is not supported.
+    UnsupportedType(u32),
+    /// No address was found for the symbol at the index of the symbol table.
+    UndefinedSymbol { index: u32 },
+    /// The value of the relocation of the location at the offset doesn't fit.
+    Overflow { offset: u64 },
+    /// The location at the offset is not part of the loaded file.
+    InvalidOffset { offset: u64 },
+}
+
+impl From<ParseError> for RelocationError {
+    fn from(error: ParseError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+impl fmt::Display for RelocationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(error) => write!(f, "{}", error),
+            Self::UnsupportedType(relocation_type) => {
+                write!(f, "unsupported relocation type {}", relocation_type)
+            }
+            Self::UndefinedSymbol { index } => write!(f, "undefined symbol {}", index),
+            Self::Overflow { offset } => write!(f, "relocation at {:#x} overflows", offset),
+            Self::InvalidOffset { offset } => {
+                write!(f, "relocation at {:#x} outside of the file", offset)
+            }
+        }
+    }
+}
+
+/// Returns the entry size of a table given by the file, which is the size of
+/// the entry type if not given, and must be at least that large.
+fn table_entry_size<T>(given: u64) -> Option<u64> {
+    let size = core::mem::size_of::<T>() as u64;
+    match given {
+        0 => Some(size),
+        given if given >= size => Some(given),
+        _ => None,
+    }
+}
+
+impl<'a> Elf<'a> {
+    /// Returns the tables of the dynamic section, or `None` if the file
+    /// doesn't have one.
+    pub fn dynamic(&self) -> Result<Option<Dynamic>, ParseError> {
+        let segment = match self
+            .program_headers()
+            .find(|program_header| program_header.p_type == ProgramType::PT_DYNAMIC)
+        {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let data = self
+            .segment_data(&segment)
+            .ok_or(ParseError::InvalidDynamicSection)?;
+        let entry_size = core::mem::size_of::<Dyn>() as u64;
+        let entries = (0..data.len() as u64 / entry_size).map(|i| read(data, i * entry_size));
+        Dynamic::parse(entries).map(Some)
+    }
+
+    /// Returns the relocations of a table of the dynamic section.
+    fn dynamic_table(
+        &self,
+        addr: u64,
+        size: u64,
+        entry_size: u64,
+    ) -> Result<impl Iterator<Item = Rela> + 'a, ParseError> {
+        let entry_size =
+            table_entry_size::<Rela>(entry_size).ok_or(ParseError::InvalidDynamicSection)?;
+        let data = if size == 0 {
+            &[][..]
+        } else {
+            let offset = self
+                .file_offset(addr, size)
+                .ok_or(ParseError::InvalidDynamicSection)?;
+            &self.data[offset as usize..(offset + size) as usize]
+        };
+        Ok((0..size / entry_size).map(move |i| read(data, i * entry_size).unwrap()))
+    }
+
+    /// Returns the relocations listed in the dynamic section, both the general
+    /// ones and those of the procedure linkage table.
+    pub fn dynamic_relocations(
+        &self,
+        dynamic: &Dynamic,
+    ) -> Result<impl Iterator<Item = Rela> + 'a, ParseError> {
+        if dynamic.jmprel_size > 0 && dynamic.plt_rel != DynamicTag::DT_RELA as u64 {
+            return Err(ParseError::InvalidDynamicSection);
+        }
+        let relocations =
+            self.dynamic_table(dynamic.rela, dynamic.rela_size, dynamic.rela_entry_size)?;
+        let plt_relocations =
+            self.dynamic_table(dynamic.jmprel, dynamic.jmprel_size, dynamic.rela_entry_size)?;
+        Ok(relocations.chain(plt_relocations))
+    }
+
+    /// Returns the symbol at the index of the dynamic symbol table, with its
+    /// name.
+    pub fn dynamic_symbol(
+        &self,
+        dynamic: &Dynamic,
+        index: u32,
+    ) -> Result<(Symbol, &'a str), ParseError> {
+        let entry_size = table_entry_size::<Symbol>(dynamic.symbol_entry_size)
+            .ok_or(ParseError::InvalidDynamicSection)?;
+        let offset = (index as u64)
+            .checked_mul(entry_size)
+            .and_then(|offset| dynamic.symtab.checked_add(offset))
+            .and_then(|addr| self.file_offset(addr, core::mem::size_of::<Symbol>() as u64))
+            .ok_or(ParseError::InvalidDynamicSection)?;
+        let symbol: Symbol = read(self.data, offset).unwrap();
+
+        let strings = self
+            .file_offset(dynamic.strtab, dynamic.strtab_size)
+            .ok_or(ParseError::InvalidDynamicSection)?;
+        let strings = &self.data[strings as usize..(strings + dynamic.strtab_size) as usize];
+        let name = string(strings, symbol.st_name).ok_or(ParseError::InvalidDynamicSection)?;
+        Ok((symbol, name))
+    }
+
+    /// Applies the relocations of the dynamic section to the file, loaded
+    /// `base` bytes above the addresses it's linked at. Does nothing if the
+    /// file doesn't have a dynamic section.
+    ///
+    /// Symbols defined by the file are relocated along with it, while the
+    /// addresses of the others are looked up by name with `resolve`. Weak
+    /// symbols which are not found are 0. `write` writes the value of a
+    /// relocation at an address in the loaded file, and fails if the address
+    /// is not part of it.
+    pub fn apply_dynamic_relocations(
+        &self,
+        base: u64,
+        mut resolve: impl FnMut(&str) -> Option<u64>,
+        write: impl FnMut(u64, &[u8]) -> Result<(), ()>,
+    ) -> Result<(), RelocationError> {
+        let dynamic = match self.dynamic()? {
+            Some(dynamic) => dynamic,
+            None => return Ok(()),
+        };
+        let mut image = FileImage {
+            elf: self,
+            base,
+            write,
+        };
+        apply_relocations(&mut image, &dynamic, base, |index, _| {
+            let (_, name) = self.dynamic_symbol(&dynamic, index).ok()?;
+            resolve(name)
+        })
+    }
+
+    /// Returns the indices of the sections holding relocations, the `.rela.*`
+    /// sections.
+    pub fn relocation_sections(&self) -> impl Iterator<Item = usize> + 'a {
+        self.section_headers()
+            .enumerate()
+            .filter(|(_, section_header)| section_header.sh_type == SectionType::SHT_RELA)
+            .map(|(index, _)| index)
+    }
+
+    /// Returns the relocations of the `.rela.*` section at the index. The
+    /// section the relocations apply to is given by its `sh_info`.
+    pub fn section_relocations(
+        &self,
+        index: usize,
+    ) -> Result<impl Iterator<Item = Rela> + 'a, ParseError> {
+        let error = ParseError::InvalidSection { index };
+        let section_header = self.section_header(index).ok_or(error)?;
+        if section_header.sh_type != SectionType::SHT_RELA {
+            return Err(error);
+        }
+        let data = self.section_data(&section_header).ok_or(error)?;
+        let entry_size = table_entry_size::<Rela>(section_header.sh_entsize).ok_or(error)?;
+        Ok((0..data.len() as u64 / entry_size).map(move |i| read(data, i * entry_size).unwrap()))
+    }
+
+    /// Returns a symbol referred to by the relocations of the `.rela.*` section
+    /// at the index, with its name. The symbol table is the section given by
+    /// the `sh_link` of the relocation section.
+    pub fn section_symbol(
+        &self,
+        index: usize,
+        symbol_index: u32,
+    ) -> Result<(Symbol, &'a str), ParseError> {
+        let section_header = self
+            .section_header(index)
+            .ok_or(ParseError::InvalidSection { index })?;
+        let symbols_index = section_header.sh_link as usize;
+        let error = ParseError::InvalidSection {
+            index: symbols_index,
+        };
+        let symbols = self.section_header(symbols_index).ok_or(error)?;
+        if symbols.sh_type != SectionType::SHT_SYMTAB && symbols.sh_type != SectionType::SHT_DYNSYM
+        {
+            return Err(error);
+        }
+        let entry_size = table_entry_size::<Symbol>(symbols.sh_entsize).ok_or(error)?;
+        let symbol: Symbol = (symbol_index as u64)
+            .checked_mul(entry_size)
+            .filter(|&offset| offset < symbols.sh_size)
+            .and_then(|offset| read(self.section_data(&symbols)?, offset))
+            .ok_or(error)?;
+
+        let strings_index = symbols.sh_link as usize;
+        let error = ParseError::InvalidSection {
+            index: strings_index,
+        };
+        let strings = self
+            .section_header(strings_index)
+            .and_then(|strings| self.section_data(&strings))
+            .ok_or(error)?;
+        let name = string(strings, symbol.st_name).ok_or(error)?;
+        Ok((symbol, name))
+    }
+}
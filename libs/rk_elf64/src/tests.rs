@@ -0,0 +1,372 @@
+//! The file tested against is a position-independent executable put together
+//! by [build], with a single loadable segment spanning the whole file. Its
+//! dynamic section lists a relocation of each kind found in executables, which
+//! write over a run of garbage bytes.
+
+extern crate std;
+
+use crate::relocation::{Dyn, DynamicTag, Rela, RelocationError, RelocationType, Value, SHN_ABS};
+use crate::{
+    Elf, FileHeader, FileType, Machine, ParseError, ProgramHeader, ProgramType, Symbol, ELF_MAGIC,
+};
+use core::convert::TryInto;
+use std::string::String;
+use std::vec;
+use std::vec::Vec;
+
+/// The address the file is linked at, which is where its only loadable segment
+/// starts. The file offset of a linked address is the address minus this.
+const LINKED: u64 = 0x1000;
+
+const FILE_SIZE: usize = 0x400;
+const DYNAMIC_OFFSET: usize = 0x100;
+const RELA_OFFSET: usize = 0x200;
+const JMPREL_OFFSET: usize = 0x280;
+const SYMTAB_OFFSET: usize = 0x2a0;
+const STRTAB_OFFSET: usize = 0x340;
+/// Where the relocations are written.
+const DATA_OFFSET: usize = 0x380;
+
+const STRINGS: &[u8] = b"\0local\0external\0weak\0";
+const LOCAL_NAME: u32 = 1;
+const EXTERNAL_NAME: u32 = 7;
+const WEAK_NAME: u32 = 16;
+
+/// The binding of global symbols.
+const STB_GLOBAL: u8 = 1;
+
+const ADDRESS_OF_EXTERNAL: u64 = 0xdead_0000;
+
+/// Writes a value at an offset into the file.
+fn put<T: Copy>(file: &mut [u8], offset: usize, value: T) {
+    assert!(offset + core::mem::size_of::<T>() <= file.len());
+    unsafe { core::ptr::write_unaligned(file.as_mut_ptr().add(offset) as *mut T, value) };
+}
+
+fn rela(offset: usize, symbol: u32, relocation_type: u32, addend: i64) -> Rela {
+    Rela {
+        r_offset: LINKED + offset as u64,
+        r_info: (symbol as u64) << 32 | relocation_type as u64,
+        r_addend: addend,
+    }
+}
+
+fn symbol(name: u32, binding: u8, section: u16, value: u64) -> Symbol {
+    Symbol {
+        st_name: name,
+        st_info: binding << 4,
+        st_other: 0,
+        st_shndx: section,
+        st_value: value,
+        st_size: 0,
+    }
+}
+
+/// Returns the file, with a dynamic section listing the relocations unless
+/// `with_dynamic` is false.
+fn build(with_dynamic: bool) -> Vec<u8> {
+    let mut file = vec![0; FILE_SIZE];
+    let mut ident = [0; 16];
+    ident[..4].copy_from_slice(&ELF_MAGIC);
+    ident[4] = 2;
+    ident[5] = 1;
+    ident[6] = 1;
+    let program_header_count = if with_dynamic { 2 } else { 1 };
+    put(
+        &mut file,
+        0,
+        FileHeader {
+            e_ident: ident,
+            e_type: FileType::ET_DYN,
+            e_machine: Machine::EM_X86_64,
+            e_version: 1,
+            e_entry: LINKED,
+            e_phoff: 64,
+            e_shoff: 0,
+            e_flags: 0,
+            e_ehsize: 64,
+            e_phentsize: core::mem::size_of::<ProgramHeader>() as u16,
+            e_phnum: program_header_count,
+            e_shentsize: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        },
+    );
+    let segment = |p_type, offset: usize, size: usize| ProgramHeader {
+        p_type,
+        p_flags: 0,
+        p_offset: offset as u64,
+        p_vaddr: LINKED + offset as u64,
+        p_paddr: 0,
+        p_filesz: size as u64,
+        p_memsz: size as u64,
+        p_align: 0x1000,
+    };
+    put(&mut file, 64, segment(ProgramType::PT_LOAD, 0, FILE_SIZE));
+    let entries = [
+        (DynamicTag::DT_RELA, LINKED + RELA_OFFSET as u64),
+        (DynamicTag::DT_RELASZ, 3 * 24),
+        (DynamicTag::DT_RELAENT, 24),
+        (DynamicTag::DT_JMPREL, LINKED + JMPREL_OFFSET as u64),
+        (DynamicTag::DT_PLTRELSZ, 24),
+        (DynamicTag::DT_PLTREL, DynamicTag::DT_RELA as u64),
+        (DynamicTag::DT_SYMTAB, LINKED + SYMTAB_OFFSET as u64),
+        (DynamicTag::DT_SYMENT, 24),
+        (DynamicTag::DT_STRTAB, LINKED + STRTAB_OFFSET as u64),
+        (DynamicTag::DT_STRSZ, STRINGS.len() as u64),
+        (DynamicTag::DT_NULL, 0),
+    ];
+    if with_dynamic {
+        put(
+            &mut file,
+            64 + 56,
+            segment(ProgramType::PT_DYNAMIC, DYNAMIC_OFFSET, entries.len() * 16),
+        );
+    }
+    for (i, &(d_tag, d_val)) in entries.iter().enumerate() {
+        put(&mut file, DYNAMIC_OFFSET + i * 16, Dyn { d_tag, d_val });
+    }
+
+    let relocations = [
+        rela(DATA_OFFSET, 0, RelocationType::R_X86_64_RELATIVE, 0x1234),
+        rela(DATA_OFFSET + 8, 1, RelocationType::R_X86_64_64, 8),
+        rela(DATA_OFFSET + 16, 2, RelocationType::R_X86_64_GLOB_DAT, 0),
+    ];
+    for (i, &relocation) in relocations.iter().enumerate() {
+        put(&mut file, RELA_OFFSET + i * 24, relocation);
+    }
+    let plt_relocation = rela(DATA_OFFSET + 24, 3, RelocationType::R_X86_64_JUMP_SLOT, 0);
+    put(&mut file, JMPREL_OFFSET, plt_relocation);
+
+    let symbols = [
+        symbol(0, 0, 0, 0),
+        symbol(LOCAL_NAME, STB_GLOBAL, 1, LINKED + 0x400),
+        symbol(EXTERNAL_NAME, STB_GLOBAL, 0, 0),
+        symbol(WEAK_NAME, Symbol::STB_WEAK, 0, 0),
+    ];
+    for (i, &symbol) in symbols.iter().enumerate() {
+        put(&mut file, SYMTAB_OFFSET + i * 24, symbol);
+    }
+    file[STRTAB_OFFSET..STRTAB_OFFSET + STRINGS.len()].copy_from_slice(STRINGS);
+    // Garbage where the relocations are written
+    for byte in file[DATA_OFFSET..DATA_OFFSET + 32].iter_mut() {
+        *byte = 0xaa;
+    }
+    file
+}
+
+#[test]
+fn compute() {
+    let base = 0x40_0000;
+    let compute = |relocation_type, addend, place, symbol| {
+        rela(0, 0, relocation_type, addend).compute(base, place, symbol)
+    };
+    assert_eq!(compute(RelocationType::R_X86_64_NONE, 0, 0, 0), Ok(None));
+    assert_eq!(
+        compute(RelocationType::R_X86_64_64, -8, 0, 0x5000),
+        Ok(Some(Value::U64(0x4ff8)))
+    );
+    assert_eq!(
+        compute(RelocationType::R_X86_64_GLOB_DAT, 8, 0, 0x5000),
+        Ok(Some(Value::U64(0x5000)))
+    );
+    assert_eq!(
+        compute(RelocationType::R_X86_64_RELATIVE, 0x1234, 0, 0),
+        Ok(Some(Value::U64(0x40_1234)))
+    );
+    assert_eq!(
+        compute(0x25, 0, 0, 0),
+        Err(RelocationError::UnsupportedType(0x25))
+    );
+}
+
+#[test]
+fn compute_pc32() {
+    let compute = |addend, place, symbol| {
+        rela(0x10, 0, RelocationType::R_X86_64_PC32, addend).compute(0, place, symbol)
+    };
+    // Backwards, with the usual addend of -4
+    assert_eq!(
+        compute(-4, 0x2000, 0x1000),
+        Ok(Some(Value::U32((-0x1004i32) as u32)))
+    );
+    assert_eq!(
+        compute(0, 0, i32::MAX as u64),
+        Ok(Some(Value::U32(i32::MAX as u32)))
+    );
+    assert_eq!(
+        compute(0, 0x8000_0000, 0),
+        Ok(Some(Value::U32(i32::MIN as u32)))
+    );
+
+    let overflow = Err(RelocationError::Overflow {
+        offset: LINKED + 0x10,
+    });
+    assert_eq!(compute(0, 0, 0x8000_0000), overflow);
+    assert_eq!(compute(0, 0x8000_0001, 0), overflow);
+    assert_eq!(compute(0, 0, 0x1_0000_0000), overflow);
+    assert_eq!(compute(0, 0xffff_8000_0000_0000, 0x1000), overflow);
+    // Close when wrapping around the address space
+    assert_eq!(
+        compute(-4, 0, 0xffff_ffff_ffff_f000),
+        Ok(Some(Value::U32(-0x1004i32 as u32)))
+    );
+}
+
+#[test]
+fn file_offset() {
+    let file = build(true);
+    let elf = Elf::parse(&file).unwrap();
+    assert_eq!(elf.file_offset(LINKED, 8), Some(0));
+    assert_eq!(elf.file_offset(LINKED + 0x123, 8), Some(0x123));
+    assert_eq!(
+        elf.file_offset(LINKED + FILE_SIZE as u64 - 8, 8),
+        Some(FILE_SIZE as u64 - 8)
+    );
+    // Crossing the end or the start of the segment
+    assert_eq!(elf.file_offset(LINKED + FILE_SIZE as u64 - 4, 8), None);
+    assert_eq!(elf.file_offset(LINKED - 4, 8), None);
+    assert_eq!(elf.file_offset(0, 8), None);
+    assert_eq!(elf.file_offset(u64::MAX - 4, 8), None);
+    assert_eq!(elf.file_offset(LINKED, u64::MAX), None);
+}
+
+#[test]
+fn dynamic() {
+    let file = build(true);
+    let elf = Elf::parse(&file).unwrap();
+    let dynamic = elf.dynamic().unwrap().unwrap();
+    assert_eq!(dynamic.rela, LINKED + RELA_OFFSET as u64);
+    assert_eq!(dynamic.rela_size, 72);
+    assert_eq!(dynamic.rela_entry_size, 24);
+    assert_eq!(dynamic.jmprel, LINKED + JMPREL_OFFSET as u64);
+    assert_eq!(dynamic.jmprel_size, 24);
+    assert_eq!(dynamic.plt_rel, DynamicTag::DT_RELA as u64);
+    assert_eq!(dynamic.symtab, LINKED + SYMTAB_OFFSET as u64);
+    assert_eq!(dynamic.symbol_entry_size, 24);
+    assert_eq!(dynamic.strtab, LINKED + STRTAB_OFFSET as u64);
+    assert_eq!(dynamic.strtab_size, STRINGS.len() as u64);
+
+    let (symbol, name) = elf.dynamic_symbol(&dynamic, 2).unwrap();
+    assert_eq!(name, "external");
+    assert!(!symbol.is_defined());
+    assert_eq!(elf.dynamic_relocations(&dynamic).unwrap().count(), 4);
+
+    let file = build(false);
+    assert!(Elf::parse(&file).unwrap().dynamic().unwrap().is_none());
+
+    // Without DT_NULL at the end
+    let mut file = build(true);
+    put(
+        &mut file,
+        DYNAMIC_OFFSET + 10 * 16,
+        Dyn {
+            d_tag: DynamicTag::DT_STRSZ,
+            d_val: 0,
+        },
+    );
+    assert_eq!(
+        Elf::parse(&file).unwrap().dynamic().unwrap_err(),
+        ParseError::InvalidDynamicSection
+    );
+}
+
+#[test]
+fn relocated_address() {
+    let base = 0x40_0000;
+    assert_eq!(
+        symbol(0, STB_GLOBAL, 0, 0x1000).relocated_address(base),
+        None
+    );
+    assert_eq!(
+        symbol(0, STB_GLOBAL, 1, 0x1000).relocated_address(base),
+        Some(0x40_1000)
+    );
+    assert_eq!(
+        symbol(0, STB_GLOBAL, SHN_ABS, 0x1000).relocated_address(base),
+        Some(0x1000)
+    );
+}
+
+#[test]
+fn apply_dynamic_relocations() {
+    let file = build(true);
+    let elf = Elf::parse(&file).unwrap();
+    let base = 0x40_0000;
+    // The file loaded at the base
+    let mut memory = file.clone();
+    let mut resolved = Vec::new();
+    elf.apply_dynamic_relocations(
+        base,
+        |name| {
+            resolved.push(String::from(name));
+            match name {
+                "external" => Some(ADDRESS_OF_EXTERNAL),
+                _ => None,
+            }
+        },
+        |addr, data| {
+            let offset = addr.checked_sub(base + LINKED).ok_or(())? as usize;
+            let location = memory.get_mut(offset..offset + data.len()).ok_or(())?;
+            location.copy_from_slice(data);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    assert_eq!(resolved, ["external", "weak"]);
+    let value = |i: usize| {
+        let offset = DATA_OFFSET + i * 8;
+        u64::from_le_bytes(memory[offset..offset + 8].try_into().unwrap())
+    };
+    assert_eq!(value(0), base + 0x1234);
+    assert_eq!(value(1), base + LINKED + 0x408);
+    assert_eq!(value(2), ADDRESS_OF_EXTERNAL);
+    // Undefined weak symbols are 0
+    assert_eq!(value(3), 0);
+    // Nothing else is written
+    assert_eq!(memory[..DATA_OFFSET], file[..DATA_OFFSET]);
+    assert_eq!(memory[DATA_OFFSET + 32..], file[DATA_OFFSET + 32..]);
+}
+
+#[test]
+fn apply_dynamic_relocations_errors() {
+    let file = build(true);
+    let elf = Elf::parse(&file).unwrap();
+    assert_eq!(
+        elf.apply_dynamic_relocations(0, |_| None, |_, _| Ok(())),
+        Err(RelocationError::UndefinedSymbol { index: 2 })
+    );
+    assert_eq!(
+        elf.apply_dynamic_relocations(0, |_| Some(ADDRESS_OF_EXTERNAL), |_, _| Err(())),
+        Err(RelocationError::InvalidOffset {
+            offset: LINKED + DATA_OFFSET as u64
+        })
+    );
+
+    // A table outside of the file
+    let mut file = build(true);
+    put(
+        &mut file,
+        DYNAMIC_OFFSET,
+        Dyn {
+            d_tag: DynamicTag::DT_RELA,
+            d_val: LINKED + FILE_SIZE as u64,
+        },
+    );
+    assert_eq!(
+        Elf::parse(&file)
+            .unwrap()
+            .apply_dynamic_relocations(0, |_| None, |_, _| Ok(())),
+        Err(RelocationError::Parse(ParseError::InvalidDynamicSection))
+    );
+
+    // Nothing is relocated without a dynamic section
+    let file = build(false);
+    assert_eq!(
+        Elf::parse(&file)
+            .unwrap()
+            .apply_dynamic_relocations(0, |_| None, |_, _| Err(())),
+        Ok(())
+    );
+}
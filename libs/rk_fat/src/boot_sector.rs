@@ -0,0 +1,163 @@
+//! Parsing of the boot sector, which describes the layout of the file system.
+
+use crate::{div_round_up, Error};
+use core::convert::TryInto;
+
+/// The most clusters of FAT32, such that no cluster number reaches the
+/// reserved values at the top of the range of entries. The clusters of larger
+/// file systems past the last one are left unused.
+const MAX_FAT32_CLUSTERS: u32 = 0x0fff_fff5 - 2;
+
+/// The variant of FAT, given by the number of clusters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// The layout of a file system, as described by its boot sector.
+#[derive(Copy, Clone, Debug)]
+pub struct Layout {
+    pub fat_type: FatType,
+    /// The size of a cluster in bytes.
+    pub cluster_size: u32,
+    /// The number of copies of the FAT.
+    pub fat_count: u32,
+    /// The byte offset of the first FAT.
+    pub fat_offset: u64,
+    /// The size of each FAT in bytes.
+    pub fat_size: u64,
+    /// The byte offset of the fixed root directory of FAT12 and FAT16.
+    pub root_offset: u64,
+    /// The number of entries in the fixed root directory of FAT12 and FAT16.
+    pub root_entry_count: u32,
+    /// The first cluster of the root directory of FAT32.
+    pub root_cluster: u32,
+    /// The byte offset of cluster 2, the first cluster.
+    pub data_offset: u64,
+    /// The number of clusters, which are numbered from 2.
+    pub cluster_count: u32,
+    /// The byte offset of the FSInfo sector of FAT32.
+    pub fs_info_offset: Option<u64>,
+}
+
+impl Layout {
+    /// Parses the boot sector, validating the layout against the size of the
+    /// device in bytes.
+    pub fn parse(sector: &[u8; 512], device_size: u64) -> Result<Self, Error> {
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(sector[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+
+        if sector[510] != 0x55 || sector[511] != 0xaa {
+            return Err(Error::InvalidFileSystem);
+        }
+
+        let bytes_per_sector = u16_at(11) as u32;
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved_sectors = u16_at(14) as u32;
+        let fat_count = sector[16] as u32;
+        let root_entry_count = u16_at(17) as u32;
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            count => count as u32,
+        };
+        let sectors_per_fat = match u16_at(22) {
+            0 => u32_at(36),
+            count => count as u32,
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || sectors_per_fat == 0
+        {
+            return Err(Error::InvalidFileSystem);
+        }
+
+        // Computed in 64 bits, as the fields are not validated against each
+        // other yet
+        let root_sectors = div_round_up(root_entry_count as u64 * 32, bytes_per_sector as u64);
+        let data_sector =
+            reserved_sectors as u64 + fat_count as u64 * sectors_per_fat as u64 + root_sectors;
+        if data_sector >= total_sectors as u64
+            || total_sectors as u64 * bytes_per_sector as u64 > device_size
+        {
+            return Err(Error::InvalidFileSystem);
+        }
+        let mut cluster_count = (total_sectors - data_sector as u32) / sectors_per_cluster;
+
+        // The type is determined by the number of clusters alone
+        let fat_type = if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            cluster_count = cluster_count.min(MAX_FAT32_CLUSTERS);
+            FatType::Fat32
+        };
+
+        let fat_entry_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let sector_size = bytes_per_sector as u64;
+        let fat_size = sectors_per_fat as u64 * sector_size;
+        if (cluster_count as u64 + 2) * fat_entry_bits > fat_size * 8 {
+            return Err(Error::InvalidFileSystem);
+        }
+
+        let (root_cluster, fs_info_offset) = if fat_type == FatType::Fat32 {
+            if root_entry_count != 0 {
+                return Err(Error::InvalidFileSystem);
+            }
+            let root_cluster = u32_at(44);
+            if root_cluster < 2 || root_cluster >= cluster_count + 2 {
+                return Err(Error::InvalidFileSystem);
+            }
+            let fs_info_sector = u16_at(48) as u32;
+            let fs_info_offset = if fs_info_sector != 0 && fs_info_sector < reserved_sectors {
+                Some(fs_info_sector as u64 * sector_size)
+            } else {
+                None
+            };
+            (root_cluster, fs_info_offset)
+        } else {
+            if root_entry_count == 0 {
+                return Err(Error::InvalidFileSystem);
+            }
+            (0, None)
+        };
+
+        let fat_offset = reserved_sectors as u64 * sector_size;
+        Ok(Self {
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_count,
+            fat_offset,
+            fat_size,
+            root_offset: fat_offset + fat_count as u64 * fat_size,
+            root_entry_count,
+            root_cluster,
+            data_offset: data_sector * sector_size,
+            cluster_count,
+            fs_info_offset,
+        })
+    }
+
+    /// Returns the byte offset of the data of a cluster.
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+
+    /// Returns whether the cluster number refers to a cluster of the data
+    /// region.
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+}
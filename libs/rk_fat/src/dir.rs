@@ -0,0 +1,452 @@
+//! Directory entries and file names.
+//!
+//! Every file has a short entry with an 8.3 name, which holds its attributes,
+//! first cluster, and size. Files with names which don't fit the 8.3 format
+//! are preceded by a sequence of long name entries, each holding up to 13
+//! UTF-16 code units of the name, in reverse order.
+
+use crate::{div_round_up, Error};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+/// The size of a directory entry in bytes.
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes of a long name entry.
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// The first byte of the name of a free entry.
+pub const ENTRY_FREE: u8 = 0xe5;
+/// The first byte of the name of the entry after the last entry.
+pub const ENTRY_END: u8 = 0x00;
+
+/// Marks the last long name entry of a sequence, which is stored first.
+const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// The maximum length of a long name in UTF-16 code units.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// The number of UTF-16 code units in each long name entry.
+const CHARS_PER_LONG_ENTRY: usize = 13;
+
+/// The offsets of the UTF-16 code units in a long name entry.
+const LONG_ENTRY_CHAR_OFFSETS: [usize; CHARS_PER_LONG_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Set in the reserved byte of a short entry if the base name is lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+/// Set in the reserved byte of a short entry if the extension is lower case.
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// The date written to new entries, 1980-01-01, as there is no clock.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// A directory, identified by its first cluster.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Dir {
+    /// The first cluster, or 0 for the fixed root directory of FAT12 and
+    /// FAT16.
+    pub(crate) cluster: u32,
+}
+
+/// A file or directory, as found in its parent directory.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// The long name, or the short name if there is no long name.
+    pub name: String,
+    /// The attributes, a combination of the `ATTR_` flags.
+    pub attributes: u8,
+    /// The first cluster, or 0 for an empty file.
+    pub first_cluster: u32,
+    /// The size in bytes, always 0 for directories.
+    pub size: u32,
+    /// The 8.3 name stored in the short entry.
+    pub(crate) short_name: [u8; 11],
+    /// The location of the entry in its directory.
+    pub(crate) location: Location,
+}
+
+impl DirEntry {
+    /// Returns whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Returns the entry as a directory, if it is one.
+    pub fn as_dir(&self) -> Option<Dir> {
+        if self.is_dir() {
+            Some(Dir {
+                cluster: self.first_cluster,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the byte offset of the short entry on the device, which is
+    /// unique for every file.
+    pub fn offset(&self) -> u64 {
+        self.location.offset
+    }
+}
+
+/// The location of an entry within its directory.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Location {
+    /// The directory containing the entry.
+    pub dir: Dir,
+    /// The index of the first long name entry, or the short entry if there are
+    /// none.
+    pub first_slot: usize,
+    /// The index of the short entry.
+    pub slot: usize,
+    /// The byte offset of the short entry on the device.
+    pub offset: u64,
+}
+
+/// The raw short entry of a file.
+#[derive(Copy, Clone)]
+pub(crate) struct ShortEntry(pub [u8; ENTRY_SIZE]);
+
+impl ShortEntry {
+    /// Creates an entry with the given 8.3 name and attributes.
+    pub fn new(name: &[u8; 11], attributes: u8, first_cluster: u32) -> Self {
+        let mut entry = Self([0; ENTRY_SIZE]);
+        entry.0[..11].copy_from_slice(name);
+        entry.0[11] = attributes;
+        // Creation, access, and modification dates
+        for &offset in [16, 18, 24].iter() {
+            entry.0[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        }
+        entry.set_first_cluster(first_cluster);
+        entry
+    }
+
+    pub fn name(&self) -> [u8; 11] {
+        self.0[..11].try_into().unwrap()
+    }
+
+    /// Sets the 8.3 name, and the `LOWER_CASE_` flags telling how to display
+    /// it.
+    pub fn set_name(&mut self, name: &[u8; 11], case_flags: u8) {
+        self.0[..11].copy_from_slice(name);
+        self.0[12] = case_flags;
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.0[11]
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        let high = u16::from_le_bytes([self.0[20], self.0[21]]) as u32;
+        let low = u16::from_le_bytes([self.0[26], self.0[27]]) as u32;
+        (high << 16) | low
+    }
+
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        self.0[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        self.0[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_le_bytes(self.0[28..32].try_into().unwrap())
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        self.0[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    /// Returns whether the entry is `.` or `..`.
+    pub fn is_dot(&self) -> bool {
+        &self.0[..11] == b".          " || &self.0[..11] == b"..         "
+    }
+
+    /// Returns the name in its displayed form, like `README.TXT`.
+    pub fn display_name(&self) -> String {
+        let lower_base = self.0[12] & LOWER_CASE_BASE != 0;
+        let lower_extension = self.0[12] & LOWER_CASE_EXTENSION != 0;
+        let mut name = String::new();
+        for (i, &byte) in self.0[..11].iter().enumerate() {
+            if byte == b' ' {
+                continue;
+            }
+            if i == 8 {
+                name.push('.');
+            }
+            // The first byte 0x05 stands for 0xe5, which marks free entries
+            let byte = if i == 0 && byte == 0x05 { 0xe5 } else { byte };
+            let lower = if i < 8 { lower_base } else { lower_extension };
+            name.push(decode_oem(if lower {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            }));
+        }
+        name
+    }
+}
+
+/// Returns the character for a byte of a short name.
+///
+/// Bytes outside of ASCII depend on the OEM code page, which is unknown, and
+/// are decoded as Latin-1.
+fn decode_oem(byte: u8) -> char {
+    byte as char
+}
+
+/// Collects the long name entries preceding a short entry.
+#[derive(Default)]
+pub(crate) struct LongNameBuilder {
+    /// The name, in UTF-16 code units.
+    chars: Vec<u16>,
+    /// The checksum of the short name, stored in every entry.
+    checksum: u8,
+    /// The order of the next entry, counting down to 1.
+    next: u8,
+    /// The slot of the first entry of the sequence.
+    first_slot: usize,
+}
+
+impl LongNameBuilder {
+    /// Adds a long name entry found at the given slot.
+    pub fn push(&mut self, slot: usize, entry: &[u8]) {
+        let order = entry[0] & !LAST_LONG_ENTRY;
+        if entry[0] & LAST_LONG_ENTRY != 0 {
+            // Start of a new sequence
+            if order == 0 || order as usize * CHARS_PER_LONG_ENTRY > MAX_NAME_LENGTH + 13 {
+                self.clear();
+                return;
+            }
+            self.chars = alloc::vec![0; order as usize * CHARS_PER_LONG_ENTRY];
+            self.checksum = entry[13];
+            self.next = order;
+            self.first_slot = slot;
+        } else if self.next == 0 || order != self.next || entry[13] != self.checksum {
+            // An entry out of sequence, orphaning the preceding entries
+            self.clear();
+            return;
+        }
+
+        let start = (order as usize - 1) * CHARS_PER_LONG_ENTRY;
+        for (i, &offset) in LONG_ENTRY_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+        }
+        self.next = order - 1;
+    }
+
+    /// Returns the long name and the slot of its first entry if a complete
+    /// sequence belonging to the short entry has been collected, and resets
+    /// the builder.
+    pub fn finish(&mut self, short_entry: &ShortEntry) -> Option<(String, usize)> {
+        let complete = !self.chars.is_empty()
+            && self.next == 0
+            && self.checksum == checksum(&short_entry.name());
+        let result = if complete {
+            let end = self
+                .chars
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(self.chars.len());
+            let name: String = core::char::decode_utf16(self.chars[..end].iter().cloned())
+                .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                .collect();
+            Some((name, self.first_slot))
+        } else {
+            None
+        };
+        self.clear();
+        result
+    }
+
+    pub fn clear(&mut self) {
+        self.chars.clear();
+        self.next = 0;
+    }
+}
+
+/// Returns the checksum of a short name, which links it to its long name
+/// entries.
+pub(crate) fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Returns the long name entries for a name, in the order they're stored.
+pub(crate) fn long_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let chars: Vec<u16> = name.encode_utf16().collect();
+    let count = div_round_up(chars.len() as u64, CHARS_PER_LONG_ENTRY as u64) as usize;
+    let checksum = checksum(short_name);
+
+    (1..=count)
+        .rev()
+        .map(|order| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = order as u8;
+            if order == count {
+                entry[0] |= LAST_LONG_ENTRY;
+            }
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let start = (order - 1) * CHARS_PER_LONG_ENTRY;
+            for (i, &offset) in LONG_ENTRY_CHAR_OFFSETS.iter().enumerate() {
+                // The name is terminated by a null if it doesn't fill the last
+                // entry, and padded with 0xffff after that
+                let c = match (start + i).cmp(&chars.len()) {
+                    core::cmp::Ordering::Less => chars[start + i],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Checks that a name can be used for a file.
+pub(crate) fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= MAX_NAME_LENGTH
+        && !name.ends_with(' ')
+        && !name.ends_with('.')
+        && !name
+            .chars()
+            .any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidName)
+    }
+}
+
+/// Returns whether a byte can be used in a short name.
+fn is_short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_uppercase()
+        || byte.is_ascii_digit()
+        || byte >= 0x80
+        || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+/// Returns the 8.3 name for a name if it's a valid short name, and can be
+/// stored without long name entries, with the case flags of the entry.
+///
+/// The base and extension must each be either upper or lower case, as only
+/// the case of the whole part is recorded.
+pub(crate) fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    // Returns the upper cased part, and whether it was lower case
+    let convert = |part: &str| -> Option<(String, bool)> {
+        let upper = part.to_ascii_uppercase();
+        if !upper.bytes().all(|b| b < 0x80 && is_short_name_byte(b)) {
+            return None;
+        }
+        if part == upper {
+            Some((upper, false))
+        } else if part == part.to_ascii_lowercase() {
+            Some((upper, true))
+        } else {
+            None
+        }
+    };
+    let (base, lower_base) = convert(base)?;
+    let (extension, lower_extension) = convert(extension)?;
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    let mut case_flags = 0;
+    if lower_base {
+        case_flags |= LOWER_CASE_BASE;
+    }
+    if lower_extension {
+        case_flags |= LOWER_CASE_EXTENSION;
+    }
+    Some((short_name, case_flags))
+}
+
+/// Returns the basis of the short name generated for a long name, with the
+/// length of the base part.
+///
+/// The name is upper cased, invalid characters are replaced, and spaces and
+/// dots other than the last one are removed. A numeric tail is added to the
+/// basis to make it unique.
+pub(crate) fn short_name_basis(name: &str) -> ([u8; 11], usize) {
+    let convert = |c: char| -> Option<u8> {
+        match c {
+            ' ' | '.' => None,
+            c if c.is_ascii() && is_short_name_byte(c.to_ascii_uppercase() as u8) => {
+                Some(c.to_ascii_uppercase() as u8)
+            }
+            _ => Some(b'_'),
+        }
+    };
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+
+    let mut short_name = [b' '; 11];
+    let mut base_length = 0;
+    for byte in base.chars().filter_map(convert).take(8) {
+        short_name[base_length] = byte;
+        base_length += 1;
+    }
+    if base_length == 0 {
+        short_name[0] = b'_';
+        base_length = 1;
+    }
+    for (i, byte) in extension.chars().filter_map(convert).take(3).enumerate() {
+        short_name[8 + i] = byte;
+    }
+    (short_name, base_length)
+}
+
+/// Adds a numeric tail like `~1` to the basis of a short name.
+pub(crate) fn with_numeric_tail(basis: &[u8; 11], base_length: usize, number: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut digit_count = 0;
+    let mut n = number;
+    loop {
+        digits[digit_count] = b'0' + (n % 10) as u8;
+        digit_count += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+
+    let mut short_name = *basis;
+    let start = core::cmp::min(base_length, 8 - 1 - digit_count);
+    short_name[start] = b'~';
+    for i in 0..digit_count {
+        short_name[start + 1 + i] = digits[digit_count - 1 - i];
+    }
+    for byte in short_name[start + 1 + digit_count..8].iter_mut() {
+        *byte = b' ';
+    }
+    short_name
+}
+
+/// Compares names the way FAT does, ignoring case.
+pub(crate) fn names_equal(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
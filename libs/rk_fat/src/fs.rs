@@ -0,0 +1,883 @@
+//! Access to the clusters, directories, and files of a file system.
+
+use crate::boot_sector::{FatType, Layout};
+use crate::dir::{self, Dir, DirEntry, Location, LongNameBuilder, ShortEntry, ENTRY_SIZE};
+use crate::{div_round_up, BlockDevice, Error};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+/// The value written to the FAT for the last cluster of a chain, truncated to
+/// the size of an entry.
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+
+/// Signatures identifying the FSInfo sector of FAT32.
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+
+/// The number of numeric tails tried when generating a unique short name.
+const MAX_NUMERIC_TAIL: u32 = 999_999;
+
+/// A FAT file system on a block device.
+pub struct FileSystem<D: BlockDevice> {
+    device: D,
+    layout: Layout,
+    /// The cluster the search for a free cluster starts from.
+    next_free: u32,
+    /// The number of free clusters, if known.
+    free_count: Option<u32>,
+}
+
+/// The contents of a directory, as read from the device.
+struct DirData {
+    data: Vec<u8>,
+    /// The byte offset on the device and the length of each contiguous part
+    /// of the directory.
+    regions: Vec<(u64, usize)>,
+}
+
+impl DirData {
+    fn slot_count(&self) -> usize {
+        self.data.len() / ENTRY_SIZE
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        &self.data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+    }
+
+    /// Returns the byte offset of a slot on the device.
+    fn slot_offset(&self, index: usize) -> u64 {
+        let mut position = index * ENTRY_SIZE;
+        for &(offset, length) in self.regions.iter() {
+            if position < length {
+                return offset + position as u64;
+            }
+            position -= length;
+        }
+        panic!("Directory slot out of range");
+    }
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Opens the file system on the device.
+    pub fn new(device: D) -> Result<Self, Error> {
+        let block_size = device.block_size();
+        let mut boot_sector = vec![0; div_round_up(512, block_size as u64) as usize * block_size];
+        device.read_blocks(0, &mut boot_sector)?;
+        let device_size = device.block_count() * block_size as u64;
+        let layout = Layout::parse(boot_sector[..512].try_into().unwrap(), device_size)?;
+
+        let mut fs = Self {
+            device,
+            layout,
+            next_free: 2,
+            free_count: None,
+        };
+
+        // The FSInfo sector of FAT32 keeps track of free clusters, but it's
+        // only a hint and may be missing
+        if let Some(offset) = fs.layout.fs_info_offset {
+            let mut fs_info = [0; 512];
+            fs.read_bytes(offset, &mut fs_info)?;
+            let u32_at =
+                |offset: usize| u32::from_le_bytes(fs_info[offset..offset + 4].try_into().unwrap());
+            if u32_at(0) == FS_INFO_LEAD_SIGNATURE && u32_at(484) == FS_INFO_STRUCT_SIGNATURE {
+                let free_count = u32_at(488);
+                if free_count <= fs.layout.cluster_count {
+                    fs.free_count = Some(free_count);
+                }
+                let next_free = u32_at(492);
+                if fs.layout.is_valid_cluster(next_free) {
+                    fs.next_free = next_free;
+                }
+            } else {
+                fs.layout.fs_info_offset = None;
+            }
+        }
+
+        Ok(fs)
+    }
+
+    /// Returns the variant of FAT.
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    /// Returns the size of a cluster in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.layout.cluster_size
+    }
+
+    /// Returns the total number of clusters.
+    pub fn cluster_count(&self) -> u32 {
+        self.layout.cluster_count
+    }
+
+    /// Returns the number of free clusters, counting them if necessary.
+    pub fn free_clusters(&mut self) -> Result<u32, Error> {
+        if let Some(count) = self.free_count {
+            return Ok(count);
+        }
+        let mut count = 0;
+        for cluster in 2..self.layout.cluster_count + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                count += 1;
+            }
+        }
+        self.free_count = Some(count);
+        Ok(count)
+    }
+
+    /// Returns the device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the root directory.
+    pub fn root_dir(&self) -> Dir {
+        Dir {
+            cluster: match self.layout.fat_type {
+                FatType::Fat32 => self.layout.root_cluster,
+                _ => 0,
+            },
+        }
+    }
+
+    /// Writes the free cluster hints of FAT32 and flushes the device.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(offset) = self.layout.fs_info_offset {
+            let mut hints = [0; 8];
+            hints[..4].copy_from_slice(&self.free_count.unwrap_or(u32::MAX).to_le_bytes());
+            hints[4..].copy_from_slice(&self.next_free.to_le_bytes());
+            self.write_bytes(offset + 488, &hints)?;
+        }
+        self.device.flush()
+    }
+
+    /// Reads bytes at any offset from the device.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let block_size = self.device.block_size();
+        let mut block = vec![0; block_size];
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let block_index = position / block_size as u64;
+            let block_offset = (position % block_size as u64) as usize;
+
+            // Read whole blocks directly into the buffer
+            let whole_blocks = (buffer.len() - done) / block_size;
+            if block_offset == 0 && whole_blocks > 0 {
+                let length = whole_blocks * block_size;
+                self.device
+                    .read_blocks(block_index, &mut buffer[done..done + length])?;
+                done += length;
+                continue;
+            }
+
+            let length = core::cmp::min(buffer.len() - done, block_size - block_offset);
+            self.device.read_blocks(block_index, &mut block)?;
+            buffer[done..done + length]
+                .copy_from_slice(&block[block_offset..block_offset + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Writes bytes at any offset to the device, reading the blocks which are
+    /// only partially written first.
+    fn write_bytes(&self, offset: u64, buffer: &[u8]) -> Result<(), Error> {
+        let block_size = self.device.block_size();
+        let mut block = vec![0; block_size];
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let block_index = position / block_size as u64;
+            let block_offset = (position % block_size as u64) as usize;
+
+            let whole_blocks = (buffer.len() - done) / block_size;
+            if block_offset == 0 && whole_blocks > 0 {
+                let length = whole_blocks * block_size;
+                self.device
+                    .write_blocks(block_index, &buffer[done..done + length])?;
+                done += length;
+                continue;
+            }
+
+            let length = core::cmp::min(buffer.len() - done, block_size - block_offset);
+            self.device.read_blocks(block_index, &mut block)?;
+            block[block_offset..block_offset + length]
+                .copy_from_slice(&buffer[done..done + length]);
+            self.device.write_blocks(block_index, &block)?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Returns the byte offset of the entry for a cluster within a FAT.
+    fn fat_entry_offset(&self, cluster: u32) -> u64 {
+        match self.layout.fat_type {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        }
+    }
+
+    /// Reads the entry for a cluster from the first FAT.
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+        let offset = self.layout.fat_offset + self.fat_entry_offset(cluster);
+        let mut bytes = [0; 4];
+        match self.layout.fat_type {
+            FatType::Fat12 => {
+                self.read_bytes(offset, &mut bytes[..2])?;
+                let value = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+                // Odd clusters use the high 12 bits of the 16 bits read
+                Ok(if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                })
+            }
+            FatType::Fat16 => {
+                self.read_bytes(offset, &mut bytes[..2])?;
+                Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+            }
+            FatType::Fat32 => {
+                self.read_bytes(offset, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    /// Writes the entry for a cluster to every FAT.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+        let entry_offset = self.fat_entry_offset(cluster);
+        for fat in 0..self.layout.fat_count as u64 {
+            let offset = self.layout.fat_offset + fat * self.layout.fat_size + entry_offset;
+            let mut bytes = [0; 4];
+            match self.layout.fat_type {
+                FatType::Fat12 => {
+                    // Entries share a byte with their neighbours
+                    self.read_bytes(offset, &mut bytes[..2])?;
+                    let old = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    let value = (value & 0xfff) as u16;
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000f) | (value << 4)
+                    } else {
+                        (old & 0xf000) | value
+                    };
+                    self.write_bytes(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => {
+                    self.write_bytes(offset, &(value as u16).to_le_bytes())?;
+                }
+                FatType::Fat32 => {
+                    // The high 4 bits are reserved, and must be preserved
+                    self.read_bytes(offset, &mut bytes)?;
+                    let old = u32::from_le_bytes(bytes);
+                    let new = (old & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.write_bytes(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the cluster following the given cluster in its chain, or `None`
+    /// if it's the last cluster.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Error> {
+        let value = self.fat_entry(cluster)?;
+        let end_of_chain = match self.layout.fat_type {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        };
+        if value >= end_of_chain {
+            Ok(None)
+        } else if self.layout.is_valid_cluster(value) {
+            Ok(Some(value))
+        } else {
+            // Free or bad clusters are never part of a chain
+            Err(Error::Corrupted)
+        }
+    }
+
+    /// Returns the clusters of the chain starting at the given cluster, which
+    /// is empty for cluster 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, Error> {
+        let mut clusters = Vec::new();
+        if first == 0 {
+            return Ok(clusters);
+        }
+        if !self.layout.is_valid_cluster(first) {
+            return Err(Error::Corrupted);
+        }
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            // A chain longer than the number of clusters contains a loop
+            if clusters.len() == self.layout.cluster_count as usize {
+                return Err(Error::Corrupted);
+            }
+            clusters.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Allocates a free cluster, appending it to the chain ending at
+    /// `previous` if given.
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, Error> {
+        if self.free_count == Some(0) {
+            return Err(Error::NoSpace);
+        }
+
+        let count = self.layout.cluster_count;
+        let start = if self.layout.is_valid_cluster(self.next_free) {
+            self.next_free
+        } else {
+            2
+        };
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+
+            self.set_fat_entry(cluster, END_OF_CHAIN)?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            self.next_free = if cluster + 1 < count + 2 {
+                cluster + 1
+            } else {
+                2
+            };
+            if let Some(free_count) = self.free_count.as_mut() {
+                *free_count = free_count.saturating_sub(1);
+            }
+            return Ok(cluster);
+        }
+
+        self.free_count = Some(0);
+        Err(Error::NoSpace)
+    }
+
+    /// Allocates a cluster filled with zeroes.
+    fn allocate_zeroed_cluster(&mut self, previous: Option<u32>) -> Result<u32, Error> {
+        let cluster = self.allocate_cluster(previous)?;
+        let zeroes = vec![0; self.layout.cluster_size as usize];
+        self.write_bytes(self.layout.cluster_offset(cluster), &zeroes)?;
+        Ok(cluster)
+    }
+
+    /// Frees the given clusters.
+    fn free_clusters_of(&mut self, clusters: &[u32]) -> Result<(), Error> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        if let Some(free_count) = self.free_count.as_mut() {
+            *free_count += clusters.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Returns the first cluster of a directory, or 0 for the fixed root
+    /// directory.
+    fn dir_cluster(&self, dir: Dir) -> u32 {
+        // Entries for `..` refer to the root directory as cluster 0, even on
+        // FAT32
+        if dir.cluster == 0 {
+            self.root_dir().cluster
+        } else {
+            dir.cluster
+        }
+    }
+
+    /// Reads the contents of a directory.
+    fn read_dir_data(&self, dir: Dir) -> Result<DirData, Error> {
+        let cluster = self.dir_cluster(dir);
+        let regions: Vec<(u64, usize)> = if cluster == 0 {
+            vec![(
+                self.layout.root_offset,
+                self.layout.root_entry_count as usize * ENTRY_SIZE,
+            )]
+        } else {
+            let cluster_size = self.layout.cluster_size as usize;
+            self.chain(cluster)?
+                .iter()
+                .map(|&cluster| (self.layout.cluster_offset(cluster), cluster_size))
+                .collect()
+        };
+
+        let length = regions.iter().map(|&(_, length)| length).sum();
+        let mut data = vec![0; length];
+        let mut position = 0;
+        for &(offset, length) in regions.iter() {
+            self.read_bytes(offset, &mut data[position..position + length])?;
+            position += length;
+        }
+        Ok(DirData { data, regions })
+    }
+
+    /// Returns the entries of a directory, except `.` and `..`.
+    pub fn read_dir(&self, dir: Dir) -> Result<Vec<DirEntry>, Error> {
+        let data = self.read_dir_data(dir)?;
+        let mut entries = Vec::new();
+        let mut long_name = LongNameBuilder::default();
+
+        for slot in 0..data.slot_count() {
+            let raw = data.slot(slot);
+            match raw[0] {
+                dir::ENTRY_END => break,
+                dir::ENTRY_FREE => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & 0x3f == dir::ATTR_LONG_NAME {
+                long_name.push(slot, raw);
+                continue;
+            }
+
+            let short_entry = ShortEntry(raw.try_into().unwrap());
+            if short_entry.attributes() & dir::ATTR_VOLUME_ID != 0 || short_entry.is_dot() {
+                long_name.clear();
+                continue;
+            }
+            let (name, first_slot) = long_name
+                .finish(&short_entry)
+                .unwrap_or_else(|| (short_entry.display_name(), slot));
+            entries.push(DirEntry {
+                name,
+                attributes: short_entry.attributes(),
+                first_cluster: short_entry.first_cluster(),
+                size: short_entry.size(),
+                short_name: short_entry.name(),
+                location: Location {
+                    dir,
+                    first_slot,
+                    slot,
+                    offset: data.slot_offset(slot),
+                },
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns the entry with the given name in a directory, ignoring case.
+    /// Both the long and the short name of an entry match.
+    pub fn find(&self, dir: Dir, name: &str) -> Result<DirEntry, Error> {
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|entry| matches_name(entry, name))
+            .ok_or(Error::NotFound)
+    }
+
+    /// Finds a run of free slots in a directory, extending it if necessary,
+    /// and returns the directory data with the index of the first slot.
+    fn find_free_slots(&mut self, dir: Dir, count: usize) -> Result<(DirData, usize), Error> {
+        loop {
+            let data = self.read_dir_data(dir)?;
+            let mut run = 0;
+            for slot in 0..data.slot_count() {
+                match data.slot(slot)[0] {
+                    dir::ENTRY_END => {
+                        // Every slot after the end is free
+                        if data.slot_count() - slot >= count - run {
+                            return Ok((data, slot - run));
+                        }
+                        break;
+                    }
+                    dir::ENTRY_FREE => {
+                        run += 1;
+                        if run == count {
+                            return Ok((data, slot + 1 - run));
+                        }
+                    }
+                    _ => run = 0,
+                }
+            }
+
+            // The fixed root directory cannot grow
+            let cluster = self.dir_cluster(dir);
+            if cluster == 0 {
+                return Err(Error::NoSpace);
+            }
+            let last = *self.chain(cluster)?.last().unwrap();
+            self.allocate_zeroed_cluster(Some(last))?;
+        }
+    }
+
+    /// Adds an entry to a directory, with the attributes, first cluster, and
+    /// size of the given short entry.
+    ///
+    /// The entry at the offset `ignore` is ignored when checking whether the
+    /// name is already in use, which allows changing the case of a name.
+    fn add_entry(
+        &mut self,
+        dir: Dir,
+        name: &str,
+        mut short_entry: ShortEntry,
+        ignore: Option<u64>,
+    ) -> Result<DirEntry, Error> {
+        dir::validate_name(name)?;
+        let entries = self.read_dir(dir)?;
+        let entries: Vec<&DirEntry> = entries
+            .iter()
+            .filter(|entry| Some(entry.offset()) != ignore)
+            .collect();
+        if entries.iter().any(|entry| matches_name(entry, name)) {
+            return Err(Error::AlreadyExists);
+        }
+
+        // Names which are valid short names, ignoring case, are stored as is,
+        // other names get a unique short name and long name entries
+        let short_name_used =
+            |short_name: &[u8; 11]| entries.iter().any(|entry| &entry.short_name == short_name);
+        let (short_name, case_flags, long_entries) = match dir::exact_short_name(name) {
+            Some((short_name, case_flags)) if !short_name_used(&short_name) => {
+                (short_name, case_flags, Vec::new())
+            }
+            _ => {
+                let (basis, base_length) = dir::short_name_basis(name);
+                let short_name = (1..=MAX_NUMERIC_TAIL)
+                    .map(|number| dir::with_numeric_tail(&basis, base_length, number))
+                    .find(|short_name| !short_name_used(short_name))
+                    .ok_or(Error::NoSpace)?;
+                (short_name, 0, dir::long_entries(name, &short_name))
+            }
+        };
+        short_entry.set_name(&short_name, case_flags);
+
+        let (data, first_slot) = self.find_free_slots(dir, long_entries.len() + 1)?;
+        for (i, long_entry) in long_entries.iter().enumerate() {
+            self.write_bytes(data.slot_offset(first_slot + i), long_entry)?;
+        }
+        let slot = first_slot + long_entries.len();
+        let offset = data.slot_offset(slot);
+        self.write_bytes(offset, &short_entry.0)?;
+
+        Ok(DirEntry {
+            name: name.into(),
+            attributes: short_entry.attributes(),
+            first_cluster: short_entry.first_cluster(),
+            size: short_entry.size(),
+            short_name,
+            location: Location {
+                dir,
+                first_slot,
+                slot,
+                offset,
+            },
+        })
+    }
+
+    /// Marks the slots of an entry as free.
+    fn remove_entry(&mut self, entry: &DirEntry) -> Result<(), Error> {
+        let location = entry.location;
+        let data = self.read_dir_data(location.dir)?;
+        for slot in location.first_slot..=location.slot {
+            self.write_bytes(data.slot_offset(slot), &[dir::ENTRY_FREE])?;
+        }
+        Ok(())
+    }
+
+    /// Reads the short entry of an entry from the device.
+    fn read_short_entry(&self, entry: &DirEntry) -> Result<ShortEntry, Error> {
+        let mut raw = [0; ENTRY_SIZE];
+        self.read_bytes(entry.location.offset, &mut raw)?;
+        Ok(ShortEntry(raw))
+    }
+
+    /// Writes the first cluster and size of an entry to the device.
+    fn update_entry(&mut self, entry: &DirEntry) -> Result<(), Error> {
+        let mut short_entry = self.read_short_entry(entry)?;
+        short_entry.set_first_cluster(entry.first_cluster);
+        short_entry.set_size(entry.size);
+        self.write_bytes(entry.location.offset, &short_entry.0)
+    }
+
+    /// Creates an empty file in a directory.
+    pub fn create_file(&mut self, dir: Dir, name: &str) -> Result<DirEntry, Error> {
+        let short_entry = ShortEntry::new(&[b' '; 11], dir::ATTR_ARCHIVE, 0);
+        self.add_entry(dir, name, short_entry, None)
+    }
+
+    /// Creates an empty directory in a directory.
+    pub fn create_dir(&mut self, dir: Dir, name: &str) -> Result<DirEntry, Error> {
+        let cluster = self.allocate_zeroed_cluster(None)?;
+
+        // Every directory starts with entries for itself and its parent, where
+        // the root directory is always cluster 0
+        let parent_cluster = match self.dir_cluster(dir) {
+            cluster if cluster == self.root_dir().cluster => 0,
+            cluster => cluster,
+        };
+        let dot = ShortEntry::new(b".          ", dir::ATTR_DIRECTORY, cluster);
+        let dot_dot = ShortEntry::new(b"..         ", dir::ATTR_DIRECTORY, parent_cluster);
+        let offset = self.layout.cluster_offset(cluster);
+        let result = self
+            .write_bytes(offset, &dot.0)
+            .and_then(|_| self.write_bytes(offset + ENTRY_SIZE as u64, &dot_dot.0))
+            .and_then(|_| {
+                let short_entry = ShortEntry::new(&[b' '; 11], dir::ATTR_DIRECTORY, cluster);
+                self.add_entry(dir, name, short_entry, None)
+            });
+
+        if result.is_err() {
+            self.free_clusters_of(&[cluster])?;
+        }
+        result
+    }
+
+    /// Removes a file or an empty directory from a directory, freeing its
+    /// clusters.
+    pub fn remove(&mut self, dir: Dir, name: &str) -> Result<(), Error> {
+        let entry = self.find(dir, name)?;
+        if let Some(entry_dir) = entry.as_dir() {
+            if !self.read_dir(entry_dir)?.is_empty() {
+                return Err(Error::NotEmpty);
+            }
+        }
+        self.remove_entry(&entry)?;
+        let clusters = self.chain(entry.first_cluster)?;
+        self.free_clusters_of(&clusters)
+    }
+
+    /// Returns the `..` entry of a directory other than the root, and its
+    /// offset on the device.
+    fn dot_dot_entry(&self, dir: Dir) -> Result<(ShortEntry, u64), Error> {
+        let data = self.read_dir_data(dir)?;
+        // Some implementations store long names for `.` and `..`, so the entry
+        // is not necessarily in the second slot
+        for slot in 0..data.slot_count() {
+            let raw = data.slot(slot);
+            if raw[0] == dir::ENTRY_END {
+                break;
+            }
+            if raw[0] == dir::ENTRY_FREE || raw[11] & 0x3f == dir::ATTR_LONG_NAME {
+                continue;
+            }
+            let short_entry = ShortEntry(raw.try_into().unwrap());
+            if &short_entry.name() == b"..         " {
+                return Ok((short_entry, data.slot_offset(slot)));
+            }
+            if !short_entry.is_dot() {
+                break;
+            }
+        }
+        Err(Error::Corrupted)
+    }
+
+    /// Returns the parent of a directory other than the root.
+    fn parent_dir(&self, dir: Dir) -> Result<Dir, Error> {
+        let (dot_dot, _) = self.dot_dot_entry(dir)?;
+        Ok(Dir {
+            cluster: dot_dot.first_cluster(),
+        })
+    }
+
+    /// Moves the entry with the given name to `new_name` in `new_dir`,
+    /// replacing an existing file, or an empty directory if the entry is a
+    /// directory. Returns the moved entry.
+    pub fn rename(
+        &mut self,
+        dir: Dir,
+        name: &str,
+        new_dir: Dir,
+        new_name: &str,
+    ) -> Result<DirEntry, Error> {
+        let entry = self.find(dir, name)?;
+        dir::validate_name(new_name)?;
+        let moves_dir = self.dir_cluster(dir) != self.dir_cluster(new_dir);
+
+        // A directory cannot be moved into itself or its subdirectories
+        if let (Some(entry_dir), true) = (entry.as_dir(), moves_dir) {
+            let root = self.root_dir().cluster;
+            let mut ancestor = self.dir_cluster(new_dir);
+            let mut depth = 0;
+            while ancestor != root {
+                if ancestor == entry_dir.cluster {
+                    return Err(Error::InvalidMove);
+                }
+                depth += 1;
+                if depth > self.layout.cluster_count {
+                    return Err(Error::Corrupted);
+                }
+                ancestor = self.dir_cluster(self.parent_dir(Dir { cluster: ancestor })?);
+            }
+        }
+
+        match self.find(new_dir, new_name) {
+            Ok(existing) if existing.offset() == entry.offset() => {}
+            Ok(existing) => {
+                match (entry.is_dir(), existing.is_dir()) {
+                    (true, false) => return Err(Error::NotADirectory),
+                    (false, true) => return Err(Error::IsADirectory),
+                    _ => {}
+                }
+                self.remove(new_dir, &existing.name)?;
+            }
+            Err(Error::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        // Add the new entry before removing the old one, such that the file is
+        // never lost
+        let short_entry = self.read_short_entry(&entry)?;
+        let new_entry = self.add_entry(new_dir, new_name, short_entry, Some(entry.offset()))?;
+        self.remove_entry(&entry)?;
+
+        if let (Some(entry_dir), true) = (entry.as_dir(), moves_dir) {
+            let parent_cluster = match self.dir_cluster(new_dir) {
+                cluster if cluster == self.root_dir().cluster => 0,
+                cluster => cluster,
+            };
+            let (mut dot_dot, offset) = self.dot_dot_entry(entry_dir)?;
+            dot_dot.set_first_cluster(parent_cluster);
+            self.write_bytes(offset, &dot_dot.0)?;
+        }
+
+        Ok(new_entry)
+    }
+
+    /// Reads from a file starting at the given offset, returning the number of
+    /// bytes read.
+    pub fn read(&self, entry: &DirEntry, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        if entry.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        let size = entry.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+
+        let clusters = self.chain(entry.first_cluster)?;
+        let cluster_size = self.layout.cluster_size as u64;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / cluster_size) as usize)
+                .ok_or(Error::Corrupted)?;
+            let cluster_offset = position % cluster_size;
+            let length = core::cmp::min(count - done, (cluster_size - cluster_offset) as usize);
+            self.read_bytes(
+                self.layout.cluster_offset(cluster) + cluster_offset,
+                &mut buffer[done..done + length],
+            )?;
+            done += length;
+        }
+        Ok(count)
+    }
+
+    /// Writes to a file starting at the given offset, allocating clusters as
+    /// needed, and returns the number of bytes written. Writing past the end
+    /// fills the gap with zeroes.
+    ///
+    /// Writes less than the whole buffer if the file system runs out of space
+    /// after writing some of it.
+    pub fn write(
+        &mut self,
+        entry: &mut DirEntry,
+        offset: u64,
+        buffer: &[u8],
+    ) -> Result<usize, Error> {
+        if entry.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        if offset + buffer.len() as u64 > u32::MAX as u64 {
+            return Err(Error::FileTooLarge);
+        }
+        if offset > entry.size as u64 {
+            self.fill_zeroes(entry, offset)?;
+        }
+
+        // Allocate the clusters covering the written range
+        let cluster_size = self.layout.cluster_size as u64;
+        let end = offset + buffer.len() as u64;
+        let mut clusters = self.chain(entry.first_cluster)?;
+        while (clusters.len() as u64) * cluster_size < end {
+            match self.allocate_cluster(clusters.last().copied()) {
+                Ok(cluster) => clusters.push(cluster),
+                Err(Error::NoSpace) => break,
+                Err(error) => return Err(error),
+            }
+        }
+        if let Some(&first) = clusters.first() {
+            entry.first_cluster = first;
+        }
+        let end = core::cmp::min(end, clusters.len() as u64 * cluster_size);
+        if end <= offset && !buffer.is_empty() {
+            return Err(Error::NoSpace);
+        }
+
+        let count = (end - offset) as usize;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let cluster = clusters[(position / cluster_size) as usize];
+            let cluster_offset = position % cluster_size;
+            let length = core::cmp::min(count - done, (cluster_size - cluster_offset) as usize);
+            self.write_bytes(
+                self.layout.cluster_offset(cluster) + cluster_offset,
+                &buffer[done..done + length],
+            )?;
+            done += length;
+        }
+
+        entry.size = core::cmp::max(entry.size, end as u32);
+        self.update_entry(entry)?;
+        Ok(count)
+    }
+
+    /// Extends a file with zeroes up to the given size.
+    fn fill_zeroes(&mut self, entry: &mut DirEntry, size: u64) -> Result<(), Error> {
+        let zeroes = vec![0; self.layout.cluster_size as usize];
+        while (entry.size as u64) < size {
+            let length = core::cmp::min(zeroes.len() as u64, size - entry.size as u64) as usize;
+            let written = self.write(entry, entry.size as u64, &zeroes[..length])?;
+            if written < length {
+                return Err(Error::NoSpace);
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the size of a file, freeing the clusters past the new size or
+    /// filling the new space with zeroes.
+    pub fn truncate(&mut self, entry: &mut DirEntry, size: u32) -> Result<(), Error> {
+        if entry.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        if size > entry.size {
+            return self.fill_zeroes(entry, size as u64);
+        }
+
+        let cluster_size = self.layout.cluster_size;
+        let keep = div_round_up(size as u64, cluster_size as u64) as usize;
+        let clusters = self.chain(entry.first_cluster)?;
+        if keep < clusters.len() {
+            if keep == 0 {
+                entry.first_cluster = 0;
+            } else {
+                self.set_fat_entry(clusters[keep - 1], END_OF_CHAIN)?;
+            }
+            self.free_clusters_of(&clusters[keep..])?;
+        }
+
+        entry.size = size;
+        self.update_entry(entry)
+    }
+}
+
+/// Returns whether the long or short name of an entry is the given name.
+fn matches_name(entry: &DirEntry, name: &str) -> bool {
+    dir::names_equal(&entry.name, name)
+        || dir::names_equal(
+            &ShortEntry::new(&entry.short_name, 0, 0).display_name(),
+            name,
+        )
+}
@@ -0,0 +1,111 @@
+//! A driver for FAT12, FAT16, and FAT32 file systems, with long file names.
+//!
+//! The file system is accessed through a [BlockDevice], which is all that's
+//! needed to use the driver, so it can be used both in the kernel and on the
+//! host with an image file.
+//!
+//! Files and directories are identified by their [DirEntry], as FAT has no
+//! inodes. Operations changing a file, like writing to it, update both the
+//! entry on disk and the given [DirEntry].
+
+#![no_std]
+
+extern crate alloc;
+
+mod boot_sector;
+mod dir;
+mod fs;
+#[cfg(test)]
+mod tests;
+
+pub use boot_sector::FatType;
+pub use dir::{
+    Dir, DirEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM,
+};
+pub use fs::FileSystem;
+
+use core::fmt;
+
+/// A device storing data in fixed size blocks, like a disk or partition.
+pub trait BlockDevice {
+    /// Returns the size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of blocks.
+    fn block_count(&self) -> u64;
+
+    /// Reads consecutive blocks, starting at the given block, into the buffer.
+    /// The length of the buffer is a multiple of the block size.
+    ///
+    /// Devices report failures as [Error::Io].
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes the buffer to consecutive blocks, starting at the given block.
+    /// The length of the buffer is a multiple of the block size.
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Makes sure all written blocks are stored on the device.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An error returned by file system operations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Reading from or writing to the device failed.
+    Io,
+    /// The device does not contain a FAT file system.
+    InvalidFileSystem,
+    /// The file system is inconsistent, like a cluster chain containing a
+    /// free cluster.
+    Corrupted,
+    /// No entry with the name exists in the directory.
+    NotFound,
+    /// An entry with the name already exists in the directory.
+    AlreadyExists,
+    /// The entry is not a directory.
+    NotADirectory,
+    /// The entry is a directory.
+    IsADirectory,
+    /// The directory to remove is not empty.
+    NotEmpty,
+    /// The name cannot be used for a file, as it's empty, too long, or
+    /// contains invalid characters.
+    InvalidName,
+    /// There are no free clusters, or no space in the root directory.
+    NoSpace,
+    /// The file would grow beyond 4 GiB.
+    FileTooLarge,
+    /// The operation would move a directory into itself.
+    InvalidMove,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io => write!(f, "device error"),
+            Self::InvalidFileSystem => write!(f, "not a FAT file system"),
+            Self::Corrupted => write!(f, "file system is corrupted"),
+            Self::NotFound => write!(f, "no such file or directory"),
+            Self::AlreadyExists => write!(f, "file exists"),
+            Self::NotADirectory => write!(f, "not a directory"),
+            Self::IsADirectory => write!(f, "is a directory"),
+            Self::NotEmpty => write!(f, "directory not empty"),
+            Self::InvalidName => write!(f, "invalid file name"),
+            Self::NoSpace => write!(f, "no space left on device"),
+            Self::FileTooLarge => write!(f, "file too large"),
+            Self::InvalidMove => write!(f, "cannot move a directory into itself"),
+        }
+    }
+}
+
+/// Divides, rounding up.
+fn div_round_up(value: u64, divisor: u64) -> u64 {
+    let quotient = value / divisor;
+    if quotient * divisor < value {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
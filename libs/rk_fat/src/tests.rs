@@ -0,0 +1,423 @@
+//! The file system is exercised on the host against images kept in memory,
+//! which [format] lays out as the smallest file system of each variant of FAT.
+
+use crate::boot_sector::Layout;
+use crate::dir;
+use crate::{BlockDevice, Error, FatType, FileSystem};
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+const SECTOR_SIZE: usize = 512;
+
+const FAT_TYPES: [FatType; 3] = [FatType::Fat12, FatType::Fat16, FatType::Fat32];
+
+/// A device backed by an image in memory.
+struct MemoryDevice(RefCell<Vec<u8>>);
+
+impl BlockDevice for MemoryDevice {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.0.borrow().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let image = self.0.borrow();
+        let start = block as usize * SECTOR_SIZE;
+        let blocks = image.get(start..start + buffer.len()).ok_or(Error::Io)?;
+        buffer.copy_from_slice(blocks);
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), Error> {
+        let mut image = self.0.borrow_mut();
+        let start = block as usize * SECTOR_SIZE;
+        let blocks = image
+            .get_mut(start..start + buffer.len())
+            .ok_or(Error::Io)?;
+        blocks.copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// The layout of the test image of a variant, with clusters of one sector and
+/// two FATs. The number of clusters is just enough for the variant.
+struct Format {
+    total_sectors: u32,
+    reserved_sectors: u16,
+    root_entry_count: u16,
+    sectors_per_fat: u32,
+}
+
+fn format_of(fat_type: FatType) -> Format {
+    match fat_type {
+        // 2847 clusters
+        FatType::Fat12 => Format {
+            total_sectors: 2880,
+            reserved_sectors: 1,
+            root_entry_count: 224,
+            sectors_per_fat: 9,
+        },
+        // 8095 clusters
+        FatType::Fat16 => Format {
+            total_sectors: 8192,
+            reserved_sectors: 1,
+            root_entry_count: 512,
+            sectors_per_fat: 32,
+        },
+        // 66000 clusters
+        FatType::Fat32 => Format {
+            total_sectors: 67064,
+            reserved_sectors: 32,
+            root_entry_count: 0,
+            sectors_per_fat: 516,
+        },
+    }
+}
+
+/// Writes the entry for a cluster to both FATs of an image, bypassing the
+/// file system.
+fn set_fat_entry(device: &MemoryDevice, fat_type: FatType, cluster: u32, value: u32) {
+    let format = format_of(fat_type);
+    let mut image = device.0.borrow_mut();
+    for fat in 0..2 {
+        let start = (format.reserved_sectors as usize + fat * format.sectors_per_fat as usize)
+            * SECTOR_SIZE;
+        match fat_type {
+            FatType::Fat12 => {
+                let offset = start + cluster as usize * 3 / 2;
+                let old = u16::from_le_bytes([image[offset], image[offset + 1]]);
+                let value = (value & 0xfff) as u16;
+                let new = if cluster & 1 == 1 {
+                    (old & 0x000f) | (value << 4)
+                } else {
+                    (old & 0xf000) | value
+                };
+                image[offset..offset + 2].copy_from_slice(&new.to_le_bytes());
+            }
+            FatType::Fat16 => {
+                let offset = start + cluster as usize * 2;
+                image[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+            }
+            FatType::Fat32 => {
+                let offset = start + cluster as usize * 4;
+                image[offset..offset + 4].copy_from_slice(&(value & 0x0fff_ffff).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Returns an empty image of the variant.
+fn format(fat_type: FatType) -> MemoryDevice {
+    let format = format_of(fat_type);
+    let mut image = vec![0; format.total_sectors as usize * SECTOR_SIZE];
+    {
+        let sector = &mut image[..SECTOR_SIZE];
+        sector[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        sector[13] = 1;
+        sector[14..16].copy_from_slice(&format.reserved_sectors.to_le_bytes());
+        sector[16] = 2;
+        sector[17..19].copy_from_slice(&format.root_entry_count.to_le_bytes());
+        sector[21] = 0xf8;
+        if fat_type == FatType::Fat32 {
+            sector[32..36].copy_from_slice(&format.total_sectors.to_le_bytes());
+            sector[36..40].copy_from_slice(&format.sectors_per_fat.to_le_bytes());
+            // The root directory is in cluster 2, and FSInfo in sector 1
+            sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+            sector[48..50].copy_from_slice(&1u16.to_le_bytes());
+        } else {
+            sector[19..21].copy_from_slice(&(format.total_sectors as u16).to_le_bytes());
+            sector[22..24].copy_from_slice(&(format.sectors_per_fat as u16).to_le_bytes());
+        }
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+    }
+    if fat_type == FatType::Fat32 {
+        // Signatures, and unknown free cluster hints
+        let fs_info = &mut image[SECTOR_SIZE..2 * SECTOR_SIZE];
+        fs_info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fs_info[488..496].copy_from_slice(&[0xff; 8]);
+        fs_info[510] = 0x55;
+        fs_info[511] = 0xaa;
+    }
+
+    let device = MemoryDevice(RefCell::new(image));
+    set_fat_entry(&device, fat_type, 0, 0x0fff_fff8);
+    set_fat_entry(&device, fat_type, 1, 0x0fff_ffff);
+    if fat_type == FatType::Fat32 {
+        set_fat_entry(&device, fat_type, 2, 0x0fff_ffff);
+    }
+    device
+}
+
+fn open(fat_type: FatType) -> FileSystem<MemoryDevice> {
+    FileSystem::new(format(fat_type)).unwrap()
+}
+
+/// Opens a copy of the device of a file system, to check what was written to
+/// the device.
+fn reopen(fs: &FileSystem<MemoryDevice>) -> FileSystem<MemoryDevice> {
+    let image = fs.device().0.borrow().clone();
+    FileSystem::new(MemoryDevice(RefCell::new(image))).unwrap()
+}
+
+/// Returns data which differs between every cluster it spans.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn detects_fat_type() {
+    for &fat_type in FAT_TYPES.iter() {
+        assert_eq!(open(fat_type).fat_type(), fat_type);
+    }
+}
+
+#[test]
+fn caps_fat32_cluster_count() {
+    let mut sector = [0; 512];
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[13] = 1;
+    sector[14..16].copy_from_slice(&32u16.to_le_bytes());
+    sector[16] = 1;
+    sector[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    // Enough for the capped number of clusters
+    sector[36..40].copy_from_slice(&0x20_0000u32.to_le_bytes());
+    sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    let layout = Layout::parse(&sector, u32::MAX as u64 * 512).unwrap();
+    assert_eq!(layout.fat_type, FatType::Fat32);
+    assert_eq!(layout.cluster_count, 0x0fff_fff3);
+    assert!(!layout.is_valid_cluster(0x0fff_fff5));
+}
+
+#[test]
+fn write_and_read() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let mut file = fs.create_file(root, "data.bin").unwrap();
+        let data = pattern(3 * SECTOR_SIZE + 100);
+        assert_eq!(fs.write(&mut file, 0, &data), Ok(data.len()));
+
+        let fs = reopen(&fs);
+        let file = fs.find(root, "DATA.BIN").unwrap();
+        assert_eq!(file.size as usize, data.len());
+        let mut buffer = vec![0; data.len() + 10];
+        assert_eq!(fs.read(&file, 0, &mut buffer), Ok(data.len()));
+        assert_eq!(&buffer[..data.len()], &data[..]);
+        let mut buffer = vec![0; 100];
+        assert_eq!(fs.read(&file, 500, &mut buffer), Ok(100));
+        assert_eq!(&buffer[..], &data[500..600]);
+        assert_eq!(fs.read(&file, data.len() as u64, &mut buffer), Ok(0));
+    }
+}
+
+#[test]
+fn write_extends_with_zeroes() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let mut file = fs.create_file(root, "sparse").unwrap();
+        assert_eq!(fs.write(&mut file, 0, b"start"), Ok(5));
+        assert_eq!(fs.write(&mut file, 2000, b"end"), Ok(3));
+        assert_eq!(file.size, 2003);
+
+        let fs = reopen(&fs);
+        let file = fs.find(root, "sparse").unwrap();
+        let mut buffer = vec![0xff; 2003];
+        assert_eq!(fs.read(&file, 0, &mut buffer), Ok(2003));
+        assert_eq!(&buffer[..5], b"start");
+        assert!(buffer[5..2000].iter().all(|&byte| byte == 0));
+        assert_eq!(&buffer[2000..], b"end");
+    }
+}
+
+#[test]
+fn truncate() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let free = fs.free_clusters().unwrap();
+        let mut file = fs.create_file(root, "file").unwrap();
+        let data = pattern(4 * SECTOR_SIZE);
+        fs.write(&mut file, 0, &data).unwrap();
+        assert_eq!(fs.free_clusters(), Ok(free - 4));
+
+        fs.truncate(&mut file, 700).unwrap();
+        assert_eq!(fs.free_clusters(), Ok(free - 2));
+        let mut buffer = vec![0; 1000];
+        assert_eq!(fs.read(&file, 0, &mut buffer), Ok(700));
+        assert_eq!(&buffer[..700], &data[..700]);
+
+        // The data past the old size doesn't reappear
+        fs.truncate(&mut file, 1000).unwrap();
+        assert_eq!(fs.read(&file, 0, &mut buffer), Ok(1000));
+        assert_eq!(&buffer[..700], &data[..700]);
+        assert!(buffer[700..].iter().all(|&byte| byte == 0));
+
+        fs.truncate(&mut file, 0).unwrap();
+        assert_eq!(file.first_cluster, 0);
+        assert_eq!(fs.free_clusters(), Ok(free));
+        let file = reopen(&fs).find(root, "file").unwrap();
+        assert_eq!((file.size, file.first_cluster), (0, 0));
+    }
+}
+
+#[test]
+fn create_and_remove_dir() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let free = fs.free_clusters().unwrap();
+        let sub = fs.create_dir(root, "sub").unwrap().as_dir().unwrap();
+        assert_eq!(
+            fs.create_dir(root, "SUB").unwrap_err(),
+            Error::AlreadyExists
+        );
+
+        // More entries than fit in a cluster, which grows the directory
+        for i in 0..20 {
+            fs.create_file(sub, &format!("file number {}", i)).unwrap();
+        }
+        let fs = &mut reopen(&fs);
+        let entries = fs.read_dir(sub).unwrap();
+        assert_eq!(entries.len(), 20);
+        assert!(entries.iter().any(|entry| entry.name == "file number 19"));
+        assert_eq!(fs.remove(root, "sub"), Err(Error::NotEmpty));
+
+        for i in 0..20 {
+            fs.remove(sub, &format!("file number {}", i)).unwrap();
+        }
+        fs.remove(root, "sub").unwrap();
+        assert_eq!(fs.find(root, "sub").unwrap_err(), Error::NotFound);
+        // The clusters of the directory, and those it grew by, are freed
+        assert_eq!(fs.free_clusters(), Ok(free));
+    }
+}
+
+#[test]
+fn rename() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let mut file = fs.create_file(root, "old.txt").unwrap();
+        fs.write(&mut file, 0, b"contents").unwrap();
+        let a = fs.create_dir(root, "a").unwrap().as_dir().unwrap();
+        let b = fs.create_dir(a, "b").unwrap().as_dir().unwrap();
+
+        fs.rename(root, "old.txt", root, "new.txt").unwrap();
+        assert_eq!(fs.find(root, "old.txt").unwrap_err(), Error::NotFound);
+        // Only the case changes
+        fs.rename(root, "new.txt", root, "NEW.txt").unwrap();
+        assert_eq!(fs.find(root, "new.txt").unwrap().name, "NEW.txt");
+
+        let file = fs.rename(root, "new.txt", b, "moved.txt").unwrap();
+        let mut buffer = [0; 8];
+        assert_eq!(fs.read(&file, 0, &mut buffer), Ok(8));
+        assert_eq!(&buffer, b"contents");
+        assert_eq!(fs.read_dir(root).unwrap().len(), 1);
+
+        // Replacing an existing file frees its clusters
+        let mut other = fs.create_file(b, "other").unwrap();
+        fs.write(&mut other, 0, b"other").unwrap();
+        let free = fs.free_clusters().unwrap();
+        fs.rename(b, "moved.txt", b, "other").unwrap();
+        assert_eq!(fs.free_clusters(), Ok(free + 1));
+        assert_eq!(fs.read_dir(b).unwrap().len(), 1);
+
+        assert_eq!(
+            fs.rename(root, "a", a, "a").unwrap_err(),
+            Error::InvalidMove
+        );
+        assert_eq!(
+            fs.rename(root, "a", b, "a").unwrap_err(),
+            Error::InvalidMove
+        );
+
+        // Moving `b` to the root updates its `..`, so `a` can then be moved
+        // into it
+        fs.rename(a, "b", root, "b").unwrap();
+        let mut fs = reopen(&fs);
+        fs.rename(root, "a", b, "a").unwrap();
+        assert_eq!(
+            fs.rename(root, "b", a, "b").unwrap_err(),
+            Error::InvalidMove
+        );
+        assert_eq!(fs.read_dir(root).unwrap().len(), 1);
+    }
+}
+
+#[test]
+fn long_file_names() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let file = fs.create_file(root, "A long file name.txt").unwrap();
+        assert_eq!(&file.short_name, b"ALONGF~1TXT");
+        let second = fs.create_file(root, "A long file name 2.txt").unwrap();
+        assert_eq!(&second.short_name, b"ALONGF~2TXT");
+
+        let fs = reopen(&fs);
+        let found = fs.find(root, "a LONG file NAME.txt").unwrap();
+        assert_eq!(found.name, "A long file name.txt");
+        assert_eq!(found.offset(), file.offset());
+        let found = fs.find(root, "ALONGF~1.TXT").unwrap();
+        assert_eq!(found.offset(), file.offset());
+    }
+}
+
+#[test]
+fn long_file_name_checksum() {
+    assert_eq!(dir::checksum(b"ALONGF~1TXT"), 0x02);
+
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let file = fs.create_file(root, "A long file name.txt").unwrap();
+        // The last long entry is stored right before the short entry
+        let checksum = file.offset() as usize - 32 + 13;
+        assert_eq!(fs.device().0.borrow()[checksum], 0x02);
+
+        fs.device().0.borrow_mut()[checksum] ^= 0xff;
+        let fs = reopen(&fs);
+        let entries = fs.read_dir(root).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "ALONGF~1.TXT");
+        assert_eq!(
+            fs.find(root, "A long file name.txt").unwrap_err(),
+            Error::NotFound
+        );
+    }
+}
+
+#[test]
+fn detects_cluster_chain_loops() {
+    for &fat_type in FAT_TYPES.iter() {
+        let mut fs = open(fat_type);
+        let root = fs.root_dir();
+        let mut file = fs.create_file(root, "loop").unwrap();
+        fs.write(&mut file, 0, &pattern(2 * SECTOR_SIZE)).unwrap();
+        set_fat_entry(
+            fs.device(),
+            fat_type,
+            file.first_cluster,
+            file.first_cluster,
+        );
+
+        let mut fs = reopen(&fs);
+        let mut file = fs.find(root, "loop").unwrap();
+        let mut buffer = vec![0; 2 * SECTOR_SIZE];
+        assert_eq!(fs.read(&file, 0, &mut buffer), Err(Error::Corrupted));
+        assert_eq!(fs.write(&mut file, 0, &buffer), Err(Error::Corrupted));
+        assert_eq!(fs.truncate(&mut file, 0), Err(Error::Corrupted));
+        assert_eq!(fs.remove(root, "loop"), Err(Error::Corrupted));
+    }
+}
@@ -0,0 +1,80 @@
+//! A global allocator backed by the boot services pool allocation.
+//!
+//! Register it with `#[global_allocator]` to use the `alloc` crate in a UEFI
+//! application. Once boot services have been exited the allocator is disabled,
+//! allocations fail and deallocations are ignored, since the pool memory then
+//! belongs to the operating system.
+
+use crate::data_types::EfiMemoryType;
+use crate::system_table;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The alignment of every allocation made by `allocate_pool`.
+const POOL_ALIGN: usize = 8;
+
+/// Whether boot services are still available, and thus whether the allocator
+/// can be used.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Disables the allocator, must be called once boot services are exited.
+pub(crate) fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Returns whether the allocator can still be used.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Allocates memory through `allocate_pool`.
+pub struct Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !is_enabled() {
+            return core::ptr::null_mut();
+        }
+
+        let boot_services = system_table().boot_services();
+
+        if layout.align() <= POOL_ALIGN {
+            return boot_services
+                .allocate_pool(EfiMemoryType::EfiLoaderData, layout.size())
+                .map_or(core::ptr::null_mut(), |ptr| ptr as *mut u8);
+        }
+
+        // Larger alignments are handled by allocating enough extra space to find an
+        // aligned address within the allocation. Since the pool pointer is 8-byte
+        // aligned, there is always room right before the aligned address to store
+        // the pool pointer, which is needed when freeing.
+        let size = match layout.size().checked_add(layout.align()) {
+            Some(size) => size,
+            None => return core::ptr::null_mut(),
+        };
+        let ptr = match boot_services.allocate_pool(EfiMemoryType::EfiLoaderData, size) {
+            Ok(ptr) => ptr as *mut u8,
+            Err(_) => return core::ptr::null_mut(),
+        };
+        let aligned = ptr.add(layout.align() - ptr as usize % layout.align());
+        (aligned as *mut *mut u8).sub(1).write(ptr);
+        aligned
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // The memory cannot be freed after boot services have been exited
+        if !is_enabled() {
+            return;
+        }
+
+        // Recover the pool pointer for over-aligned allocations
+        let ptr = if layout.align() <= POOL_ALIGN {
+            ptr
+        } else {
+            (ptr as *mut *mut u8).sub(1).read()
+        };
+
+        system_table().boot_services().free_pool(ptr as *mut c_void);
+    }
+}
@@ -1,17 +1,63 @@
 //! Common UEFI data types from Table 5 of the UEFI Specification, Version 2.8.
 
+use core::convert::TryFrom;
 use core::ffi::c_void;
-use core::fmt;
+use core::fmt::{self, Write};
 
 /// A 1-byte character using the ISO-Latin-1 character set.
 #[repr(transparent)]
 pub struct Char8(pub u8);
 
 /// A 2-byte character stored in the UCS-2 encoding format.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct Char16(pub u16);
 
+impl Char16 {
+    /// The null character, which terminates every UCS-2 string.
+    pub const NUL: Char16 = Char16(0);
+
+    /// The Unicode replacement character, used in place of characters which
+    /// cannot be represented.
+    pub const REPLACEMENT_CHARACTER: Char16 = Char16(0xfffd);
+
+    /// Returns the character as a Rust `char`, or `None` if it's a surrogate
+    /// code unit (which UCS-2 does not allow).
+    pub fn to_char(self) -> Option<char> {
+        core::char::from_u32(self.0 as u32)
+    }
+}
+
+impl TryFrom<char> for Char16 {
+    type Error = char;
+
+    /// Converts a `char` into a UCS-2 character, failing if it lies outside
+    /// the Basic Multilingual Plane.
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        if (c as u32) <= 0xffff {
+            Ok(Char16(c as u16))
+        } else {
+            Err(c)
+        }
+    }
+}
+
+impl fmt::Display for Char16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char(self.to_char().unwrap_or(core::char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl fmt::Debug for Char16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.to_char() {
+            Some(c) => write!(f, "{:?}", c),
+            None => write!(f, "Char16({:#06x})", self.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct EfiGuid(pub u32, pub u16, pub u16, pub [u8; 8]);
 
@@ -139,8 +185,18 @@ impl fmt::Debug for EfiStatus {
 #[repr(transparent)]
 pub struct EfiHandle(pub *const c_void);
 
+#[derive(Copy, Clone)]
 #[repr(transparent)]
-pub struct EfiEvent(*const c_void);
+pub struct EfiEvent(pub(crate) *const c_void);
 
+/// A task priority level.
+#[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct EfiTpl(usize);
+
+impl EfiTpl {
+    pub const TPL_APPLICATION: EfiTpl = EfiTpl(4);
+    pub const TPL_CALLBACK: EfiTpl = EfiTpl(8);
+    pub const TPL_NOTIFY: EfiTpl = EfiTpl(16);
+    pub const TPL_HIGH_LEVEL: EfiTpl = EfiTpl(31);
+}
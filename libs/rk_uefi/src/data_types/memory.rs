@@ -38,5 +38,30 @@ pub struct EfiMemoryDescriptor {
     attribute: u64,
 }
 
+impl EfiMemoryDescriptor {
+    /// Returns the type of the memory region, as the raw value of an
+    /// [EfiMemoryType].
+    ///
+    /// The raw value is returned as the firmware may use types unknown to us.
+    pub fn memory_type(&self) -> u32 {
+        self.type1
+    }
+
+    /// Returns the physical address of the first byte in the region.
+    pub fn physical_start(&self) -> u64 {
+        self.physical_start.0
+    }
+
+    /// Returns the number of 4 KiB pages in the region.
+    pub fn number_of_pages(&self) -> u64 {
+        self.number_of_pages
+    }
+
+    /// Returns the attributes describing the capabilities of the region.
+    pub fn attribute(&self) -> u64 {
+        self.attribute
+    }
+}
+
 #[repr(transparent)]
 pub struct EfiVirtualAddress(u64);
@@ -4,6 +4,9 @@ pub use self::common::*;
 mod memory;
 pub use self::memory::*;
 
+mod strings;
+pub use self::strings::*;
+
 #[repr(C)]
 pub struct EfiTime {
     year: u16,
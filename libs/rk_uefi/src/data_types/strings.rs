@@ -0,0 +1,426 @@
+//! Null-terminated UCS-2 strings, as used throughout the UEFI interfaces.
+
+use crate::data_types::Char16;
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+
+#[cfg(feature = "alloc")]
+use alloc::{borrow::ToOwned, vec, vec::Vec};
+#[cfg(feature = "alloc")]
+use core::{borrow::Borrow, ops::Deref, str::FromStr};
+
+/// An error which can occur when encoding a string as UCS-2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The input is not valid UTF-8, only the bytes up to `valid_up_to` could
+    /// be decoded.
+    InvalidUtf8 { valid_up_to: usize },
+    /// The character starting at byte `index` lies outside the Basic
+    /// Multilingual Plane and cannot be represented in UCS-2.
+    UnsupportedChar { index: usize, character: char },
+    /// The input contains a null character at byte `index`.
+    InteriorNul { index: usize },
+    /// The output buffer is too small, `required` characters (including the
+    /// terminating null) are needed.
+    BufferTooSmall { required: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::InvalidUtf8 { valid_up_to } => {
+                write!(f, "invalid UTF-8 after byte {}", valid_up_to)
+            }
+            Self::UnsupportedChar { index, character } => write!(
+                f,
+                "character {:?} at byte {} cannot be represented in UCS-2",
+                character, index
+            ),
+            Self::InteriorNul { index } => write!(f, "unexpected null character at byte {}", index),
+            Self::BufferTooSmall { required } => {
+                write!(f, "buffer too small, {} characters are required", required)
+            }
+        }
+    }
+}
+
+/// An error which can occur when creating a [CStr16] from a slice of
+/// characters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FromCharsWithNulError {
+    /// The slice contains a null character at `index` before its end.
+    InteriorNul { index: usize },
+    /// The slice does not end with a null character.
+    NotNulTerminated,
+}
+
+/// Encodes a single character, reporting the byte `index` on failure.
+fn encode_char(index: usize, character: char) -> Result<Char16, EncodeError> {
+    if character == '\0' {
+        return Err(EncodeError::InteriorNul { index });
+    }
+    Char16::try_from(character).map_err(|_| EncodeError::UnsupportedChar { index, character })
+}
+
+/// Encodes `s` as a null-terminated UCS-2 string into `buf`.
+///
+/// Returns the number of characters written, not including the terminating
+/// null. Nothing is written unless the whole string can be encoded.
+pub fn encode_ucs2(s: &str, buf: &mut [Char16]) -> Result<usize, EncodeError> {
+    // Validate the whole string before touching the buffer, such that the
+    // required size can be reported
+    let mut len = 0;
+    for (index, c) in s.char_indices() {
+        encode_char(index, c)?;
+        len += 1;
+    }
+    if len + 1 > buf.len() {
+        return Err(EncodeError::BufferTooSmall { required: len + 1 });
+    }
+
+    for (dst, c) in buf.iter_mut().zip(s.chars()) {
+        // Cannot fail, every character was validated above
+        *dst = Char16(c as u16);
+    }
+    buf[len] = Char16::NUL;
+
+    Ok(len)
+}
+
+/// A borrowed null-terminated UCS-2 string.
+///
+/// The terminating null character is part of the underlying slice, which
+/// contains no other null characters.
+#[repr(transparent)]
+pub struct CStr16([Char16]);
+
+impl CStr16 {
+    /// Wraps a raw null-terminated string.
+    ///
+    /// # Safety
+    /// `ptr` must point to a valid null-terminated UCS-2 string, which must
+    /// outlive the returned reference and not be modified while it exists.
+    pub unsafe fn from_ptr<'a>(ptr: *const Char16) -> &'a CStr16 {
+        let mut len = 0;
+        while *ptr.add(len) != Char16::NUL {
+            len += 1;
+        }
+        Self::from_chars_with_nul_unchecked(core::slice::from_raw_parts(ptr, len + 1))
+    }
+
+    /// Wraps a slice of characters which ends with a null character and
+    /// contains no other null characters.
+    pub fn from_chars_with_nul(chars: &[Char16]) -> Result<&CStr16, FromCharsWithNulError> {
+        match chars.iter().position(|&c| c == Char16::NUL) {
+            Some(index) if index + 1 == chars.len() => {
+                Ok(unsafe { Self::from_chars_with_nul_unchecked(chars) })
+            }
+            Some(index) => Err(FromCharsWithNulError::InteriorNul { index }),
+            None => Err(FromCharsWithNulError::NotNulTerminated),
+        }
+    }
+
+    /// Wraps a slice of characters without checking it.
+    ///
+    /// # Safety
+    /// The slice must end with a null character, and contain no other null
+    /// characters.
+    pub unsafe fn from_chars_with_nul_unchecked(chars: &[Char16]) -> &CStr16 {
+        &*(chars as *const [Char16] as *const CStr16)
+    }
+
+    /// Encodes a UTF-8 string into the given buffer and returns it as a UCS-2
+    /// string.
+    pub fn from_str_with_buf<'a>(
+        s: &str,
+        buf: &'a mut [Char16],
+    ) -> Result<&'a CStr16, EncodeError> {
+        let len = encode_ucs2(s, buf)?;
+        Ok(unsafe { Self::from_chars_with_nul_unchecked(&buf[..len + 1]) })
+    }
+
+    /// Validates and encodes UTF-8 bytes into the given buffer and returns them
+    /// as a UCS-2 string.
+    pub fn from_utf8_with_buf<'a>(
+        bytes: &[u8],
+        buf: &'a mut [Char16],
+    ) -> Result<&'a CStr16, EncodeError> {
+        let s = core::str::from_utf8(bytes).map_err(|e| EncodeError::InvalidUtf8 {
+            valid_up_to: e.valid_up_to(),
+        })?;
+        Self::from_str_with_buf(s, buf)
+    }
+
+    /// Returns a pointer to the first character, suitable for passing to the
+    /// firmware.
+    pub fn as_ptr(&self) -> *const Char16 {
+        self.0.as_ptr()
+    }
+
+    /// Returns a reference to the first character, which is how the firmware
+    /// interfaces expect strings to be passed.
+    pub fn as_char16(&self) -> &Char16 {
+        &self.0[0]
+    }
+
+    /// Returns the characters of the string, without the terminating null.
+    pub fn to_chars(&self) -> &[Char16] {
+        &self.0[..self.len()]
+    }
+
+    /// Returns the characters of the string, including the terminating null.
+    pub fn to_chars_with_nul(&self) -> &[Char16] {
+        &self.0
+    }
+
+    /// Returns the number of characters, not including the terminating null.
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    /// Returns whether the string contains no characters.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the characters, decoded into `char`s.
+    ///
+    /// Surrogate code units, which are not valid UCS-2, are replaced with the
+    /// Unicode replacement character.
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.to_chars()
+            .iter()
+            .map(|c| c.to_char().unwrap_or(core::char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl PartialEq for CStr16 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for CStr16 {}
+
+impl fmt::Display for CStr16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.chars() {
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CStr16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.chars() {
+            for e in c.escape_debug() {
+                f.write_char(e)?;
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// An owned null-terminated UCS-2 string.
+#[cfg(feature = "alloc")]
+#[derive(Clone, PartialEq, Eq)]
+pub struct CString16(Vec<Char16>);
+
+#[cfg(feature = "alloc")]
+impl CString16 {
+    /// Creates an empty string.
+    pub fn new() -> Self {
+        Self(vec![Char16::NUL])
+    }
+
+    /// Validates and encodes UTF-8 bytes into a new UCS-2 string.
+    pub fn from_utf8(bytes: &[u8]) -> Result<Self, EncodeError> {
+        let s = core::str::from_utf8(bytes).map_err(|e| EncodeError::InvalidUtf8 {
+            valid_up_to: e.valid_up_to(),
+        })?;
+        s.parse()
+    }
+
+    /// Appends a character to the end of the string.
+    ///
+    /// Like for every other input, the index of an error is a byte offset into
+    /// the input, which is always 0 for a single character.
+    pub fn push(&mut self, c: char) -> Result<(), EncodeError> {
+        let c = encode_char(0, c)?;
+        let end = self.0.len() - 1;
+        self.0.insert(end, c);
+        Ok(())
+    }
+
+    /// Appends a UTF-8 string to the end of the string.
+    pub fn push_str(&mut self, s: &str) -> Result<(), EncodeError> {
+        // Validate everything first, such that the string is left untouched on
+        // failure
+        for (index, c) in s.char_indices() {
+            encode_char(index, c)?;
+        }
+        for c in s.chars() {
+            self.push(c)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the last character of the string and returns it, or `None` if
+    /// the string is empty.
+    pub fn pop(&mut self) -> Option<Char16> {
+        if self.is_empty() {
+            None
+        } else {
+            let end = self.0.len() - 2;
+            Some(self.0.remove(end))
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Default for CString16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl FromStr for CString16 {
+    type Err = EncodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = Vec::with_capacity(s.len() + 1);
+        for (index, c) in s.char_indices() {
+            chars.push(encode_char(index, c)?);
+        }
+        chars.push(Char16::NUL);
+        Ok(Self(chars))
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl TryFrom<&str> for CString16 {
+    type Error = EncodeError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Deref for CString16 {
+    type Target = CStr16;
+
+    fn deref(&self) -> &CStr16 {
+        // The vector is always null-terminated without interior nulls
+        unsafe { CStr16::from_chars_with_nul_unchecked(&self.0) }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl AsRef<CStr16> for CString16 {
+    fn as_ref(&self) -> &CStr16 {
+        self
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Borrow<CStr16> for CString16 {
+    fn borrow(&self) -> &CStr16 {
+        self
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl ToOwned for CStr16 {
+    type Owned = CString16;
+
+    fn to_owned(&self) -> CString16 {
+        CString16(self.0.to_vec())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl fmt::Display for CString16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl fmt::Debug for CString16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Decodes the UTF-8 character starting at byte `i`, returning the code point
+/// and its length in bytes.
+///
+/// The input is assumed to be valid UTF-8, as it always comes from a `str`.
+const fn decode_utf8(bytes: &[u8], i: usize) -> (u32, usize) {
+    let b = bytes[i] as u32;
+    if b < 0x80 {
+        (b, 1)
+    } else if b < 0xe0 {
+        (((b & 0x1f) << 6) | (bytes[i + 1] as u32 & 0x3f), 2)
+    } else if b < 0xf0 {
+        (
+            ((b & 0x0f) << 12) | ((bytes[i + 1] as u32 & 0x3f) << 6) | (bytes[i + 2] as u32 & 0x3f),
+            3,
+        )
+    } else {
+        (
+            ((b & 0x07) << 18)
+                | ((bytes[i + 1] as u32 & 0x3f) << 12)
+                | ((bytes[i + 2] as u32 & 0x3f) << 6)
+                | (bytes[i + 3] as u32 & 0x3f),
+            4,
+        )
+    }
+}
+
+/// Returns the number of UCS-2 characters, including the terminating null,
+/// needed to encode `s`.
+///
+/// Used by the [cstr16](crate::cstr16) macro, fails compilation if `s` cannot
+/// be encoded.
+#[doc(hidden)]
+pub const fn ucs2_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    let mut len = 0;
+    while i < bytes.len() {
+        let (c, width) = decode_utf8(bytes, i);
+        if c == 0 {
+            panic!("string literal contains a null character");
+        }
+        if c > 0xffff {
+            panic!("string literal contains a character outside the Basic Multilingual Plane");
+        }
+        i += width;
+        len += 1;
+    }
+    len + 1
+}
+
+/// Encodes `s` into a null-terminated UCS-2 array of exactly `N` characters.
+///
+/// Used by the [cstr16](crate::cstr16) macro, `N` must be the value returned by
+/// [ucs2_len] for the same string.
+#[doc(hidden)]
+pub const fn ucs2_encode<const N: usize>(s: &str) -> [Char16; N] {
+    let bytes = s.as_bytes();
+    let mut chars = [Char16::NUL; N];
+    let mut i = 0;
+    let mut j = 0;
+    while i < bytes.len() {
+        let (c, width) = decode_utf8(bytes, i);
+        chars[j] = Char16(c as u16);
+        i += width;
+        j += 1;
+    }
+    chars
+}
@@ -32,3 +32,18 @@ pub const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: EfiGuid = EfiGuid(
     0x11d2,
     [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
 );
+
+/// Identifies the configuration table pointing to the ACPI 2.0 (or later) RSDP.
+pub const EFI_ACPI_20_TABLE_GUID: EfiGuid = EfiGuid(
+    0x8868e871,
+    0xe4f1,
+    0x11d3,
+    [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+);
+/// Identifies the configuration table pointing to the ACPI 1.0 RSDP.
+pub const EFI_ACPI_10_TABLE_GUID: EfiGuid = EfiGuid(
+    0xeb9d2d30,
+    0x2d88,
+    0x11d3,
+    [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+);
@@ -1,12 +1,19 @@
 #![no_std]
 #![feature(abi_efiapi)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[macro_use]
 pub mod macros;
+#[cfg(feature = "allocator")]
+pub mod allocator;
 pub mod data_types;
 pub mod guid;
 pub mod protocol;
 pub mod table;
+#[cfg(test)]
+mod tests;
 
 use crate::data_types::EfiHandle;
 use crate::table::EfiSystemTable;
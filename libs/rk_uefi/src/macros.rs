@@ -1,5 +1,6 @@
-use crate::data_types::Char16;
+use crate::data_types::{CStr16, Char16};
 use crate::system_table;
+use core::convert::TryFrom;
 use core::fmt::{self, Write};
 
 #[macro_export]
@@ -13,27 +14,103 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-pub struct Writer;
+/// Creates a `&'static CStr16` from a string literal, encoded at compile time.
+///
+/// Compilation fails if the literal contains a null character or a character
+/// outside the Basic Multilingual Plane.
+#[macro_export]
+macro_rules! cstr16 {
+    ($s:literal) => {{
+        const LEN: usize = $crate::data_types::ucs2_len($s);
+        const CHARS: [$crate::data_types::Char16; LEN] = $crate::data_types::ucs2_encode::<LEN>($s);
+        // The array is null-terminated and contains no interior nulls
+        unsafe { $crate::data_types::CStr16::from_chars_with_nul_unchecked(&CHARS) }
+    }};
+}
+
+/// The number of characters the [Writer] buffers before passing them on to
+/// the firmware, including the terminating null.
+const WRITER_BUFFER_SIZE: usize = 128;
+
+/// Writes text to the console output.
+///
+/// Characters are buffered and passed to the firmware in batches, as calling
+/// into the firmware once per character is very slow. The buffer is flushed
+/// when full, when [flush](Self::flush) is called, and when the writer is
+/// dropped.
+pub struct Writer {
+    buffer: [Char16; WRITER_BUFFER_SIZE],
+    len: usize,
+}
+
+impl Writer {
+    /// Creates a writer with an empty buffer.
+    pub const fn new() -> Self {
+        Self {
+            buffer: [Char16::NUL; WRITER_BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Adds a character to the buffer, flushing first if it's full.
+    fn push(&mut self, c: Char16) {
+        // Leave room for the terminating null
+        if self.len == WRITER_BUFFER_SIZE - 1 {
+            self.flush();
+        }
+        self.buffer[self.len] = c;
+        self.len += 1;
+    }
+
+    /// Outputs all buffered characters.
+    pub fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        self.buffer[self.len] = Char16::NUL;
+        // Safety: Null characters are never pushed to the buffer, so the only null
+        // is the one we just added
+        let string = unsafe { CStr16::from_chars_with_nul_unchecked(&self.buffer[..self.len + 1]) };
+        system_table().con_out().output_string(string);
+        self.len = 0;
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Write for Writer {
     fn write_str(&mut self, string: &str) -> Result<(), fmt::Error> {
-        let con_out = system_table().con_out();
-
-        // Output the string one character at a time
         for c in string.chars() {
-            con_out.output_string(&[Char16(c as u16), Char16(0)][0]);
-
             // Carriage returns are required for proper newlines in UEFI
             if c == '\n' {
-                con_out.output_string(&[Char16('\r' as u16), Char16(0)][0]);
+                self.push(Char16('\r' as u16));
             }
+
+            // Characters which cannot be represented in UCS-2, and null characters
+            // which would terminate the string early, are replaced
+            let c = match Char16::try_from(c) {
+                Ok(Char16::NUL) | Err(_) => Char16::REPLACEMENT_CHARACTER,
+                Ok(c) => c,
+            };
+            self.push(c);
         }
 
         Ok(())
     }
 }
 
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    Writer.write_fmt(args).unwrap();
+    Writer::new().write_fmt(args).unwrap();
 }
@@ -9,7 +9,18 @@ pub struct EfiGraphicsOutputProtocol {
         info: &mut *mut EfiGraphicsOutputModeInformation,
     ) -> EfiStatus,
     set_mode: extern "efiapi" fn(this: &Self, mode_number: u32) -> EfiStatus,
-    blt: extern "efiapi" fn(), // TODO
+    blt: extern "efiapi" fn(
+        this: &Self,
+        blt_buffer: *mut EfiGraphicsOutputBltPixel,
+        blt_operation: EfiGraphicsOutputBltOperation,
+        source_x: usize,
+        source_y: usize,
+        destination_x: usize,
+        destination_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> EfiStatus,
     mode: *const EfiGraphicsOutputProtocolMode,
 }
 
@@ -39,18 +50,148 @@ impl EfiGraphicsOutputProtocol {
     pub fn mode(&self) -> &EfiGraphicsOutputProtocolMode {
         unsafe { &*self.mode }
     }
+
+    /// Performs a block transfer of pixels, see the UEFI specification for
+    /// the meaning of the arguments for each operation.
+    ///
+    /// # Safety
+    /// `blt_buffer` must be valid for the operation, it's read from or written
+    /// to depending on the operation.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn blt(
+        &self,
+        blt_buffer: *mut EfiGraphicsOutputBltPixel,
+        blt_operation: EfiGraphicsOutputBltOperation,
+        source_x: usize,
+        source_y: usize,
+        destination_x: usize,
+        destination_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> EfiStatus {
+        (self.blt)(
+            self,
+            blt_buffer,
+            blt_operation,
+            source_x,
+            source_y,
+            destination_x,
+            destination_y,
+            width,
+            height,
+            delta,
+        )
+    }
+
+    /// Fills a rectangle on the screen with a single color.
+    pub fn fill(
+        &self,
+        pixel: EfiGraphicsOutputBltPixel,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> EfiStatus {
+        let mut pixel = pixel;
+        // Safety: A fill only reads the single pixel
+        unsafe {
+            self.blt(
+                &mut pixel,
+                EfiGraphicsOutputBltOperation::EfiBltVideoFill,
+                0,
+                0,
+                x,
+                y,
+                width,
+                height,
+                0,
+            )
+        }
+    }
+
+    /// Draws a rectangle of pixels, stored row by row in `pixels`, onto the
+    /// screen.
+    ///
+    /// Fails with `EFI_INVALID_PARAMETER` if `pixels` is too small for the
+    /// rectangle.
+    pub fn draw(
+        &self,
+        pixels: &[EfiGraphicsOutputBltPixel],
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> EfiStatus {
+        if pixels.len() < width * height {
+            return EfiStatus::EFI_INVALID_PARAMETER;
+        }
+        // Safety: The buffer is large enough, and is only read from
+        unsafe {
+            self.blt(
+                pixels.as_ptr() as *mut EfiGraphicsOutputBltPixel,
+                EfiGraphicsOutputBltOperation::EfiBltBufferToVideo,
+                0,
+                0,
+                x,
+                y,
+                width,
+                height,
+                0,
+            )
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+/// A pixel used by [blt](EfiGraphicsOutputProtocol::blt) operations,
+/// independent of the pixel format of the frame buffer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct EfiGraphicsOutputBltPixel {
+    pub blue: u8,
+    pub green: u8,
+    pub red: u8,
+    pub reserved: u8,
+}
+
+impl EfiGraphicsOutputBltPixel {
+    /// Creates a pixel from a color on the form `0xRRGGBB`.
+    pub const fn from_rgb(rgb: u32) -> Self {
+        Self {
+            blue: rgb as u8,
+            green: (rgb >> 8) as u8,
+            red: (rgb >> 16) as u8,
+            reserved: 0,
+        }
+    }
+}
+
+/// The operations which can be performed by
+/// [blt](EfiGraphicsOutputProtocol::blt).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum EfiGraphicsOutputBltOperation {
+    /// Fills a rectangle on the screen with the first pixel of the buffer.
+    EfiBltVideoFill,
+    /// Copies a rectangle from the screen to the buffer.
+    EfiBltVideoToBltBuffer,
+    /// Copies a rectangle from the buffer to the screen.
+    EfiBltBufferToVideo,
+    /// Copies a rectangle from one part of the screen to another.
+    EfiBltVideoToVideo,
+    EfiGraphicsOutputBltOperationMax,
+}
+
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct EfiPixelBitmask {
-    red_mask: u32,
-    green_mask: u32,
-    blue_mask: u32,
-    reserved_mask: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub enum EfiGraphicsPixelFormat {
     PixelRedGreenBlueReserved8BitPerColor,
@@ -66,8 +207,8 @@ pub struct EfiGraphicsOutputModeInformation {
     version: u32,
     pub horizontal_resolution: u32,
     pub vertical_resolution: u32,
-    pixel_format: EfiGraphicsPixelFormat,
-    pixel_information: EfiPixelBitmask,
+    pub pixel_format: EfiGraphicsPixelFormat,
+    pub pixel_information: EfiPixelBitmask,
     pub pixels_per_scan_line: u32,
 }
 
@@ -78,5 +219,12 @@ pub struct EfiGraphicsOutputProtocolMode {
     pub info: *const EfiGraphicsOutputModeInformation,
     size_of_info: usize,
     pub frame_buffer_base: EfiPhysicalAddress,
-    frame_buffer_size: usize,
+    pub frame_buffer_size: usize,
+}
+
+impl EfiGraphicsOutputProtocolMode {
+    /// Returns information about the current mode.
+    pub fn info(&self) -> &EfiGraphicsOutputModeInformation {
+        unsafe { &*self.info }
+    }
 }
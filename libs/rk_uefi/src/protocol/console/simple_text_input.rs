@@ -1,16 +1,74 @@
-use crate::data_types::{EfiEvent, EfiStatus};
+use crate::data_types::{Char16, EfiEvent, EfiStatus};
+
+// Scan codes for keys which don't have a Unicode representation.
+pub const SCAN_NULL: u16 = 0x00;
+pub const SCAN_UP: u16 = 0x01;
+pub const SCAN_DOWN: u16 = 0x02;
+pub const SCAN_RIGHT: u16 = 0x03;
+pub const SCAN_LEFT: u16 = 0x04;
+pub const SCAN_HOME: u16 = 0x05;
+pub const SCAN_END: u16 = 0x06;
+pub const SCAN_INSERT: u16 = 0x07;
+pub const SCAN_DELETE: u16 = 0x08;
+pub const SCAN_PAGE_UP: u16 = 0x09;
+pub const SCAN_PAGE_DOWN: u16 = 0x0a;
+pub const SCAN_F1: u16 = 0x0b;
+pub const SCAN_F2: u16 = 0x0c;
+pub const SCAN_F3: u16 = 0x0d;
+pub const SCAN_F4: u16 = 0x0e;
+pub const SCAN_F5: u16 = 0x0f;
+pub const SCAN_F6: u16 = 0x10;
+pub const SCAN_F7: u16 = 0x11;
+pub const SCAN_F8: u16 = 0x12;
+pub const SCAN_F9: u16 = 0x13;
+pub const SCAN_F10: u16 = 0x14;
+pub const SCAN_ESC: u16 = 0x17;
 
 /// Protocol interfaces for devices that support simple console style text
 /// input.
 #[repr(C)]
 pub struct EfiSimpleTextInputProtocol {
-    reset: extern "efiapi" fn(&Self, bool) -> EfiStatus,
-    read_key_stroke: extern "efiapi" fn(&Self, &mut EfiInputKey) -> EfiStatus,
+    reset: extern "efiapi" fn(this: &Self, extended_verification: bool) -> EfiStatus,
+    read_key_stroke: extern "efiapi" fn(this: &Self, key: &mut EfiInputKey) -> EfiStatus,
     wait_for_key: EfiEvent,
 }
 
+impl EfiSimpleTextInputProtocol {
+    /// Resets the input device.
+    pub fn reset(&self, extended_verification: bool) -> EfiStatus {
+        (self.reset)(self, extended_verification)
+    }
+
+    /// Reads the next keystroke from the input device.
+    ///
+    /// Returns `EFI_NOT_READY` as an error if no keystroke is available.
+    pub fn read_key_stroke(&self) -> Result<EfiInputKey, EfiStatus> {
+        let mut key = EfiInputKey {
+            scan_code: SCAN_NULL,
+            unicode_char: Char16::NUL,
+        };
+        let status = (self.read_key_stroke)(self, &mut key);
+        if status.is_error() {
+            Err(status)
+        } else {
+            Ok(key)
+        }
+    }
+
+    /// Returns the event which is signaled when a keystroke is available, to be
+    /// used with [wait_for_event](crate::table::EfiBootServices::wait_for_event).
+    pub fn wait_for_key(&self) -> EfiEvent {
+        self.wait_for_key
+    }
+}
+
+/// A keystroke.
+///
+/// Keys with a Unicode representation have a scan code of [SCAN_NULL], other
+/// keys have a null Unicode character.
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct EfiInputKey {
-    scan_code: u16,
-    unicode_char: u16,
+    pub scan_code: u16,
+    pub unicode_char: Char16,
 }
@@ -1,4 +1,20 @@
-use crate::data_types::{Char16, EfiStatus};
+use crate::data_types::{CStr16, Char16, EfiStatus};
+
+// Text attributes, a foreground color may be combined with a background color.
+pub const EFI_BLACK: usize = 0x00;
+pub const EFI_BLUE: usize = 0x01;
+pub const EFI_GREEN: usize = 0x02;
+pub const EFI_CYAN: usize = 0x03;
+pub const EFI_RED: usize = 0x04;
+pub const EFI_MAGENTA: usize = 0x05;
+pub const EFI_BROWN: usize = 0x06;
+pub const EFI_LIGHTGRAY: usize = 0x07;
+pub const EFI_DARKGRAY: usize = 0x08;
+pub const EFI_YELLOW: usize = 0x0e;
+pub const EFI_WHITE: usize = 0x0f;
+pub const EFI_BACKGROUND_BLACK: usize = 0x00;
+pub const EFI_BACKGROUND_BLUE: usize = 0x10;
+pub const EFI_BACKGROUND_LIGHTGRAY: usize = 0x70;
 
 /// Protocol interfaces for devices that support console style text displaying.
 #[repr(C)]
@@ -25,8 +41,35 @@ impl EfiSimpleTextOutputProtocol {
         (self.reset)(self, extended_verification)
     }
 
-    pub fn output_string(&mut self, string: &Char16) -> EfiStatus {
-        (self.output_string)(self, string)
+    /// Writes a string to the output device.
+    pub fn output_string(&mut self, string: &CStr16) -> EfiStatus {
+        (self.output_string)(self, string.as_char16())
+    }
+
+    /// Verifies that all characters in a string can be output to the device.
+    pub fn test_string(&self, string: &CStr16) -> EfiStatus {
+        (self.test_string)(self, string.as_char16())
+    }
+
+    /// Sets the foreground and background colors for subsequent output.
+    pub fn set_attribute(&mut self, attribute: usize) -> EfiStatus {
+        (self.set_attribute)(self, attribute)
+    }
+
+    /// Clears the screen with the current background color and moves the
+    /// cursor to the top left corner.
+    pub fn clear_screen(&mut self) -> EfiStatus {
+        (self.clear_screen)(self)
+    }
+
+    /// Moves the cursor to the given position, (0, 0) is the top left corner.
+    pub fn set_cursor_position(&mut self, column: usize, row: usize) -> EfiStatus {
+        (self.set_cursor_position)(self, column, row)
+    }
+
+    /// Makes the cursor visible or invisible.
+    pub fn enable_cursor(&mut self, visible: bool) -> EfiStatus {
+        (self.enable_cursor)(self, visible)
     }
 }
 
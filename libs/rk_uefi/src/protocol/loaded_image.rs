@@ -1,4 +1,4 @@
-use crate::data_types::{EfiHandle, EfiMemoryType, EfiStatus};
+use crate::data_types::{CStr16, Char16, EfiHandle, EfiMemoryType, EfiStatus};
 use crate::protocol::EfiDevicePathProtocol;
 use crate::table::EfiSystemTable;
 use core::ffi::c_void;
@@ -27,4 +27,36 @@ impl EfiLoadedImageProtocol {
     pub fn device_handle(&self) -> EfiHandle {
         self.device_handle
     }
+
+    /// Returns the options the image was loaded with as raw bytes.
+    pub fn load_options(&self) -> &[u8] {
+        if self.load_options.is_null() {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(
+                self.load_options as *const u8,
+                self.load_options_size as usize,
+            )
+        }
+    }
+
+    /// Returns the options the image was loaded with as a string, or `None` if
+    /// they're not a null-terminated UCS-2 string.
+    ///
+    /// The options are a string when the image is started from the UEFI shell,
+    /// or from a boot option with a text argument.
+    pub fn load_options_str(&self) -> Option<&CStr16> {
+        if self.load_options.is_null() || self.load_options as usize & 1 != 0 {
+            return None;
+        }
+        let chars = unsafe {
+            core::slice::from_raw_parts(
+                self.load_options as *const Char16,
+                self.load_options_size as usize / 2,
+            )
+        };
+        let len = chars.iter().position(|&c| c == Char16::NUL)?;
+        CStr16::from_chars_with_nul(&chars[..len + 1]).ok()
+    }
 }
@@ -1,4 +1,4 @@
-use crate::data_types::{Char16, EfiGuid, EfiMemoryType, EfiStatus, EfiTime};
+use crate::data_types::{CStr16, Char16, EfiGuid, EfiMemoryType, EfiStatus, EfiTime};
 use crate::system_table;
 use core::ffi::c_void;
 
@@ -66,11 +66,17 @@ impl EfiFileProtocol {
     pub fn open(
         &self,
         new_handle: &mut *mut Self,
-        file_name: &Char16,
+        file_name: &CStr16,
         open_mode: u64,
         attributes: u64,
     ) -> EfiStatus {
-        (self.open)(self, new_handle, file_name, open_mode, attributes)
+        (self.open)(
+            self,
+            new_handle,
+            file_name.as_char16(),
+            open_mode,
+            attributes,
+        )
     }
 
     /// Closes the file.
@@ -130,6 +136,24 @@ pub struct EfiFileInfo {
     file_name: [Char16; 50], // FIXME: This will lead to errors with longer filenames...
 }
 
+impl EfiFileInfo {
+    /// Returns the size of the file in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the attribute bits of the file.
+    pub fn attribute(&self) -> u64 {
+        self.attribute
+    }
+
+    /// Returns the name of the file.
+    pub fn file_name(&self) -> &CStr16 {
+        // The name is null-terminated and stored at the end of the structure
+        unsafe { CStr16::from_ptr(self.file_name.as_ptr()) }
+    }
+}
+
 #[repr(C)]
 pub struct EfiFileSystemInfo {
     size: u64,
@@ -137,5 +161,13 @@ pub struct EfiFileSystemInfo {
     volume_size: u64,
     free_space: u64,
     block_size: u32,
-    pub volume_label: [Char16; 50], // FIXME: This will lead to errors with longer volume labels...
+    volume_label: [Char16; 50], // FIXME: This will lead to errors with longer volume labels...
+}
+
+impl EfiFileSystemInfo {
+    /// Returns the volume label.
+    pub fn volume_label(&self) -> &CStr16 {
+        // The label is null-terminated and stored at the end of the structure
+        unsafe { CStr16::from_ptr(self.volume_label.as_ptr()) }
+    }
 }
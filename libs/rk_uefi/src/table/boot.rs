@@ -1,10 +1,33 @@
 use crate::data_types::{
-    EfiAllocateType, EfiGuid, EfiHandle, EfiMemoryDescriptor, EfiMemoryType, EfiPhysicalAddress,
-    EfiStatus, EfiTpl,
+    Char16, EfiAllocateType, EfiEvent, EfiGuid, EfiHandle, EfiMemoryDescriptor, EfiMemoryType,
+    EfiPhysicalAddress, EfiStatus, EfiTpl,
 };
 use crate::table::EfiTableHeader;
 use core::ffi::c_void;
 
+// Event types.
+pub const EVT_TIMER: u32 = 0x8000_0000;
+pub const EVT_RUNTIME: u32 = 0x4000_0000;
+pub const EVT_NOTIFY_WAIT: u32 = 0x0000_0100;
+pub const EVT_NOTIFY_SIGNAL: u32 = 0x0000_0200;
+pub const EVT_SIGNAL_EXIT_BOOT_SERVICES: u32 = 0x0000_0201;
+pub const EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE: u32 = 0x6000_0202;
+
+/// Function called when an event is signaled or waited upon.
+pub type EfiEventNotify = extern "efiapi" fn(event: EfiEvent, context: *mut c_void);
+
+/// The type of timer to set with
+/// [set_timer](EfiBootServices::set_timer).
+#[repr(C)]
+pub enum EfiTimerDelay {
+    /// Cancels any pending timer.
+    TimerCancel,
+    /// Signals the event periodically.
+    TimerPeriodic,
+    /// Signals the event once.
+    TimerRelative,
+}
+
 /// Contains a table header and pointers to all of the boot services.
 #[repr(C)]
 pub struct EfiBootServices {
@@ -39,12 +62,23 @@ pub struct EfiBootServices {
     free_pool: extern "efiapi" fn(buffer: *mut c_void) -> EfiStatus,
 
     // Event & Timer Services
-    create_event: extern "efiapi" fn(),   // TODO
-    set_timer: extern "efiapi" fn(),      // TODO
-    wait_for_event: extern "efiapi" fn(), // TODO
-    signal_event: extern "efiapi" fn(),   // TODO
-    close_event: extern "efiapi" fn(),    // TODO
-    check_event: extern "efiapi" fn(),    // TODO
+    create_event: extern "efiapi" fn(
+        type1: u32,
+        notify_tpl: EfiTpl,
+        notify_function: Option<EfiEventNotify>,
+        notify_context: *mut c_void,
+        event: &mut EfiEvent,
+    ) -> EfiStatus,
+    set_timer:
+        extern "efiapi" fn(event: EfiEvent, type1: EfiTimerDelay, trigger_time: u64) -> EfiStatus,
+    wait_for_event: extern "efiapi" fn(
+        number_of_events: usize,
+        event: *const EfiEvent,
+        index: &mut usize,
+    ) -> EfiStatus,
+    signal_event: extern "efiapi" fn(event: EfiEvent) -> EfiStatus,
+    close_event: extern "efiapi" fn(event: EfiEvent) -> EfiStatus,
+    check_event: extern "efiapi" fn(event: EfiEvent) -> EfiStatus,
 
     // Protocol Handler Services
     install_protocol_interface: extern "efiapi" fn(), // TODO
@@ -71,7 +105,12 @@ pub struct EfiBootServices {
     // Miscellaneous Services
     get_next_monotonic_count: extern "efiapi" fn(), // TODO
     stall: extern "efiapi" fn(microseconds: usize) -> EfiStatus, // TODO
-    set_watchdog_timer: extern "efiapi" fn(),       // TODO
+    set_watchdog_timer: extern "efiapi" fn(
+        timeout: usize,
+        watchdog_code: u64,
+        data_size: usize,
+        watchdog_data: *const Char16,
+    ) -> EfiStatus,
 
     // DriverSupport Services
     connect_controller: extern "efiapi" fn(),    // TODO
@@ -118,7 +157,22 @@ impl EfiBootServices {
         memory_type: EfiMemoryType,
         pages: usize,
     ) -> Result<EfiPhysicalAddress, EfiStatus> {
-        let mut buffer_ptr = EfiPhysicalAddress(0);
+        self.allocate_pages_with_address(type1, memory_type, pages, EfiPhysicalAddress(0))
+    }
+
+    /// Allocates memory pages, passing an address to the firmware.
+    ///
+    /// The meaning of the address depends on the allocation type, it's the
+    /// maximum address for `AllocateMaxAddress` and the exact address for
+    /// `AllocateAddress`, and ignored for `AllocateAnyPages`.
+    pub fn allocate_pages_with_address(
+        &self,
+        type1: EfiAllocateType,
+        memory_type: EfiMemoryType,
+        pages: usize,
+        address: EfiPhysicalAddress,
+    ) -> Result<EfiPhysicalAddress, EfiStatus> {
+        let mut buffer_ptr = address;
         let status = (self.allocate_pages)(type1, memory_type, pages, &mut buffer_ptr);
         if status.is_error() {
             Err(status)
@@ -174,6 +228,54 @@ impl EfiBootServices {
         (self.free_pool)(buffer)
     }
 
+    /// Creates an event without a notification function.
+    pub fn create_event(&self, type1: u32, notify_tpl: EfiTpl) -> Result<EfiEvent, EfiStatus> {
+        let mut event = EfiEvent(core::ptr::null());
+        let status =
+            (self.create_event)(type1, notify_tpl, None, core::ptr::null_mut(), &mut event);
+        if status.is_error() {
+            Err(status)
+        } else {
+            Ok(event)
+        }
+    }
+
+    /// Sets the type of timer and the trigger time for a timer event.
+    ///
+    /// The trigger time is in units of 100 ns.
+    pub fn set_timer(&self, event: EfiEvent, type1: EfiTimerDelay, trigger_time: u64) -> EfiStatus {
+        (self.set_timer)(event, type1, trigger_time)
+    }
+
+    /// Stops execution until one of the events is signaled, and returns the
+    /// index of the signaled event.
+    pub fn wait_for_event(&self, events: &[EfiEvent]) -> Result<usize, EfiStatus> {
+        let mut index = 0;
+        let status = (self.wait_for_event)(events.len(), events.as_ptr(), &mut index);
+        if status.is_error() {
+            Err(status)
+        } else {
+            Ok(index)
+        }
+    }
+
+    /// Signals an event.
+    pub fn signal_event(&self, event: EfiEvent) -> EfiStatus {
+        (self.signal_event)(event)
+    }
+
+    /// Closes an event.
+    pub fn close_event(&self, event: EfiEvent) -> EfiStatus {
+        (self.close_event)(event)
+    }
+
+    /// Checks whether an event is in the signaled state.
+    ///
+    /// Returns `EFI_SUCCESS` if it is, and `EFI_NOT_READY` if it's not.
+    pub fn check_event(&self, event: EfiEvent) -> EfiStatus {
+        (self.check_event)(event)
+    }
+
     pub fn handle_protocol(
         &self,
         handle: EfiHandle,
@@ -184,14 +286,30 @@ impl EfiBootServices {
     }
 
     /// Terminates all boot services.
+    ///
+    /// On success, the global allocator (if enabled) stops serving allocations.
     pub fn exit_boot_services(&self, image_handle: EfiHandle, map_key: usize) -> EfiStatus {
-        (self.exit_boot_services)(image_handle, map_key)
+        let status = (self.exit_boot_services)(image_handle, map_key);
+        #[cfg(feature = "allocator")]
+        if !status.is_error() {
+            crate::allocator::disable();
+        }
+        status
     }
 
     pub fn stall(&self, microseconds: usize) -> EfiStatus {
         (self.stall)(microseconds)
     }
 
+    /// Sets the system's watchdog timer, a timeout of zero disables it.
+    ///
+    /// The firmware arms the watchdog with a five minute timeout before
+    /// starting a boot option, so it must be disabled before waiting for user
+    /// input for an unknown amount of time.
+    pub fn set_watchdog_timer(&self, timeout: usize, watchdog_code: u64) -> EfiStatus {
+        (self.set_watchdog_timer)(timeout, watchdog_code, 0, core::ptr::null())
+    }
+
     pub fn open_protocol(
         &self,
         handle: EfiHandle,
@@ -1,4 +1,4 @@
-use crate::data_types::{Char16, EfiGuid, EfiHandle};
+use crate::data_types::{CStr16, Char16, EfiGuid, EfiHandle};
 use crate::protocol::{EfiSimpleTextInputProtocol, EfiSimpleTextOutputProtocol};
 use crate::table::{EfiBootServices, EfiRuntimeServices, EfiTableHeader};
 use core::ffi::c_void;
@@ -27,14 +27,21 @@ impl EfiSystemTable {
         self.hdr.revision
     }
 
-    pub fn firmware_vendor(&self) -> &Char16 {
-        unsafe { &*self.firmware_vendor }
+    /// Returns the name of the firmware vendor.
+    pub fn firmware_vendor(&self) -> &CStr16 {
+        // Safety: The firmware provides a valid null-terminated string
+        unsafe { CStr16::from_ptr(self.firmware_vendor) }
     }
 
     pub fn firmware_revision(&self) -> u32 {
         self.firmware_revision
     }
 
+    /// Returns the simple text input protocol.
+    pub fn con_in(&self) -> &EfiSimpleTextInputProtocol {
+        unsafe { &*self.con_in }
+    }
+
     /// Returns the simple text output protocol.
     pub fn con_out(&self) -> &mut EfiSimpleTextOutputProtocol {
         let con_out_ptr = self.con_out as *mut EfiSimpleTextOutputProtocol;
@@ -44,6 +51,13 @@ impl EfiSystemTable {
     pub fn boot_services(&self) -> &EfiBootServices {
         unsafe { &*self.boot_services }
     }
+
+    /// Returns the configuration tables, pointing to tables like the ACPI RSDP.
+    pub fn configuration_table(&self) -> &[EfiConfigurationTable] {
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+        }
+    }
 }
 
 #[repr(C)]
@@ -51,3 +65,15 @@ pub struct EfiConfigurationTable {
     vendor_guid: EfiGuid,
     vendor_table: *const c_void,
 }
+
+impl EfiConfigurationTable {
+    /// Returns the GUID identifying the table.
+    pub fn vendor_guid(&self) -> EfiGuid {
+        self.vendor_guid
+    }
+
+    /// Returns a pointer to the table.
+    pub fn vendor_table(&self) -> *const c_void {
+        self.vendor_table
+    }
+}
@@ -0,0 +1,201 @@
+//! Host tests of the UCS-2 strings, which need no firmware. The owned strings
+//! are only tested with the `alloc` feature enabled.
+
+use crate::data_types::*;
+
+fn chars(s: &str) -> impl Iterator<Item = Char16> + '_ {
+    s.chars().map(|c| Char16(c as u16))
+}
+
+#[test]
+fn encode_ucs2_writes_a_null_terminated_string() {
+    let mut buf = [Char16(0xffff); 8];
+    assert_eq!(encode_ucs2("Hé€", &mut buf), Ok(3));
+    assert_eq!(
+        buf[..4],
+        [Char16(0x48), Char16(0xe9), Char16(0x20ac), Char16::NUL]
+    );
+    assert_eq!(buf[4], Char16(0xffff));
+
+    assert_eq!(encode_ucs2("", &mut buf), Ok(0));
+    assert_eq!(buf[0], Char16::NUL);
+}
+
+#[test]
+fn encode_ucs2_leaves_small_buffers_untouched() {
+    let mut buf = [Char16(0xffff); 3];
+    assert_eq!(
+        encode_ucs2("abc", &mut buf),
+        Err(EncodeError::BufferTooSmall { required: 4 })
+    );
+    assert_eq!(buf, [Char16(0xffff); 3]);
+    assert_eq!(
+        encode_ucs2("", &mut []),
+        Err(EncodeError::BufferTooSmall { required: 1 })
+    );
+}
+
+#[test]
+fn encode_ucs2_rejects_characters_outside_the_bmp() {
+    let mut buf = [Char16::NUL; 8];
+    // The index is in bytes, and the euro sign takes three
+    assert_eq!(
+        encode_ucs2("€🦀", &mut buf),
+        Err(EncodeError::UnsupportedChar {
+            index: 3,
+            character: '🦀'
+        })
+    );
+}
+
+#[test]
+fn encode_ucs2_rejects_nul() {
+    let mut buf = [Char16::NUL; 8];
+    assert_eq!(
+        encode_ucs2("é\0", &mut buf),
+        Err(EncodeError::InteriorNul { index: 2 })
+    );
+    // Invalid characters are reported before the size of the buffer
+    assert_eq!(
+        encode_ucs2("\0abcdefgh", &mut buf),
+        Err(EncodeError::InteriorNul { index: 0 })
+    );
+}
+
+#[test]
+fn from_utf8_with_buf() {
+    let mut buf = [Char16::NUL; 8];
+    let string = CStr16::from_utf8_with_buf(b"ok", &mut buf).unwrap();
+    assert!(string.to_chars().iter().copied().eq(chars("ok")));
+    assert_eq!(
+        CStr16::from_utf8_with_buf(b"ok\xff", &mut buf),
+        Err(EncodeError::InvalidUtf8 { valid_up_to: 2 })
+    );
+}
+
+#[test]
+fn from_chars_with_nul() {
+    let a = Char16('a' as u16);
+    let units = [a, Char16::NUL];
+    let string = CStr16::from_chars_with_nul(&units).unwrap();
+    assert_eq!(string.len(), 1);
+    assert_eq!(string.to_chars_with_nul(), [a, Char16::NUL]);
+    assert!(matches!(
+        CStr16::from_chars_with_nul(&[a, a]),
+        Err(FromCharsWithNulError::NotNulTerminated)
+    ));
+    assert!(matches!(
+        CStr16::from_chars_with_nul(&[a, Char16::NUL, a, Char16::NUL]),
+        Err(FromCharsWithNulError::InteriorNul { index: 1 })
+    ));
+}
+
+#[test]
+fn surrogates_are_replaced_when_decoding() {
+    let units = [Char16('a' as u16), Char16(0xd800), Char16::NUL];
+    let string = CStr16::from_chars_with_nul(&units).unwrap();
+    assert!(string.chars().eq("a\u{fffd}".chars()));
+}
+
+#[test]
+fn ucs2_len_counts_characters_and_the_null() {
+    assert_eq!(ucs2_len(""), 1);
+    assert_eq!(ucs2_len("abc"), 4);
+    // Two, three, and two bytes of UTF-8
+    assert_eq!(ucs2_len("é€ß"), 4);
+}
+
+#[test]
+fn ucs2_encode_matches_encode_ucs2() {
+    let s = "Größe: 5 €";
+    const N: usize = 11;
+    assert_eq!(ucs2_len(s), N);
+    let mut buf = [Char16(0xffff); N];
+    encode_ucs2(s, &mut buf).unwrap();
+    assert_eq!(ucs2_encode::<N>(s), buf);
+}
+
+#[test]
+#[should_panic(expected = "null character")]
+fn ucs2_len_rejects_nul() {
+    ucs2_len("a\0b");
+}
+
+#[test]
+#[should_panic(expected = "Basic Multilingual Plane")]
+fn ucs2_len_rejects_characters_outside_the_bmp() {
+    ucs2_len("🦀");
+}
+
+#[test]
+fn cstr16_macro() {
+    let string: &'static CStr16 = cstr16!("Boot Menü");
+    assert_eq!(string.len(), 9);
+    assert!(string.to_chars().iter().copied().eq(chars("Boot Menü")));
+    assert_eq!(*string.to_chars_with_nul().last().unwrap(), Char16::NUL);
+
+    let mut buf = [Char16::NUL; 16];
+    assert_eq!(
+        string,
+        CStr16::from_str_with_buf("Boot Menü", &mut buf).unwrap()
+    );
+    assert!(cstr16!("").is_empty());
+}
+
+#[cfg(feature = "alloc")]
+mod owned {
+    use crate::data_types::*;
+    use alloc::format;
+    use alloc::string::ToString;
+
+    #[test]
+    fn push_reports_the_character() {
+        let mut string: CString16 = "abc".parse().unwrap();
+        assert_eq!(
+            string.push('🦀'),
+            Err(EncodeError::UnsupportedChar {
+                index: 0,
+                character: '🦀'
+            })
+        );
+        assert_eq!(
+            string.push('\0'),
+            Err(EncodeError::InteriorNul { index: 0 })
+        );
+        string.push('d').unwrap();
+        assert_eq!(string.to_string(), "abcd");
+    }
+
+    #[test]
+    fn push_str_is_all_or_nothing() {
+        let mut string = CString16::new();
+        string.push_str("ab").unwrap();
+        assert_eq!(
+            string.push_str("cé\0"),
+            Err(EncodeError::InteriorNul { index: 3 })
+        );
+        assert_eq!(string.to_string(), "ab");
+        assert_eq!(string.pop(), Some(Char16('b' as u16)));
+        assert_eq!(string.pop(), Some(Char16('a' as u16)));
+        assert_eq!(string.pop(), None);
+        assert!(string.is_empty());
+    }
+
+    #[test]
+    fn parse_reports_byte_indices() {
+        assert_eq!(
+            "€€🦀".parse::<CString16>(),
+            Err(EncodeError::UnsupportedChar {
+                index: 6,
+                character: '🦀'
+            })
+        );
+        assert_eq!(
+            CString16::from_utf8(b"\xc3"),
+            Err(EncodeError::InvalidUtf8 { valid_up_to: 0 })
+        );
+        let string = CString16::from_utf8("grüß".as_bytes()).unwrap();
+        assert_eq!(&*string, cstr16!("grüß"));
+        assert_eq!(format!("{:?}", string), "\"grüß\"");
+    }
+}
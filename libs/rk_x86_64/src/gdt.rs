@@ -34,3 +34,73 @@ pub const fn kernel_data_segment() -> u64 {
     // indicates a data segment when clear, and is therefore not set.
     0x00af_9300_0000_ffff
 }
+
+/// Returns a user code segment descriptor.
+pub const fn user_code_segment() -> u64 {
+    // The kernel code segment with a Descriptor Privilege Level of 3
+    0x00af_fb00_0000_ffff
+}
+
+/// Returns a user data segment descriptor.
+pub const fn user_data_segment() -> u64 {
+    // The kernel data segment with a Descriptor Privilege Level of 3
+    0x00af_f300_0000_ffff
+}
+
+/// Returns the two descriptors of a task state segment (TSS) at the given
+/// address, which takes up two entries in the GDT.
+pub fn tss_segment(addr: u64) -> [u64; 2] {
+    let limit = (core::mem::size_of::<TaskStateSegment>() - 1) as u64;
+    // Create a segment with the Present bit set, of the type of an available
+    // 64-bit TSS
+    let low = (limit & 0xffff)
+        | (addr & 0xff_ffff) << 16
+        | 0x89 << 40
+        | (limit >> 16 & 0xf) << 48
+        | (addr >> 24 & 0xff) << 56;
+    [low, addr >> 32]
+}
+
+/// Loads the task register with a selector of a TSS descriptor using the `ltr`
+/// instruction.
+///
+/// # Safety
+/// The selector must refer to a valid TSS descriptor in the active GDT, and
+/// the TSS must not be destroyed for as long as it's loaded.
+pub unsafe fn load_tss(selector: u16) {
+    asm!("ltr {0:x}", in(reg) selector, options(nostack));
+}
+
+/// The task state segment (TSS), holding the stacks the CPU switches to when
+/// an interrupt arrives.
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved_1: u32,
+    /// The stack pointers loaded when the privilege level changes to 0, 1, or
+    /// 2, like when an interrupt arrives in user mode.
+    pub privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    /// The stack pointers interrupt descriptors can ask to switch to,
+    /// regardless of the privilege level.
+    pub interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    /// The offset of the I/O permission bitmap from the start of the TSS.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    /// Creates a TSS without any stacks or I/O permission bitmap.
+    pub const fn new() -> Self {
+        Self {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            // A bitmap past the end of the segment is absent
+            iomap_base: core::mem::size_of::<Self>() as u16,
+        }
+    }
+}
@@ -144,6 +144,16 @@ impl<F> Descriptor<F> {
         // Set the present bit
         self.options |= 1 << 15;
     }
+
+    /// Sets the index into the interrupt stack table of the TSS of the stack
+    /// to switch to, or 0 to only switch stacks when the privilege level
+    /// changes.
+    ///
+    /// # Safety
+    /// The index must refer to a valid stack in the loaded TSS.
+    pub unsafe fn set_stack_index(&mut self, index: u16) {
+        self.options = (self.options & !0b111) | index;
+    }
 }
 
 impl Descriptor<InterruptHandler> {
@@ -189,3 +199,17 @@ pub struct InterruptFrame {
     sp: u64,
     ss: u64,
 }
+
+impl InterruptFrame {
+    /// Returns the address of the instruction which was interrupted, or which
+    /// caused the exception.
+    pub fn instruction_pointer(&self) -> u64 {
+        self.ip
+    }
+
+    /// Returns whether the interrupted code ran in user mode, with a
+    /// privilege level of 3.
+    pub fn is_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
@@ -0,0 +1,44 @@
+//! Enabling and disabling of maskable interrupts.
+
+use crate::register::rflags;
+
+/// Enables maskable interrupts.
+#[inline]
+pub fn enable() {
+    unsafe { asm!("sti", options(nomem, nostack)) }
+}
+
+/// Disables maskable interrupts.
+#[inline]
+pub fn disable() {
+    unsafe { asm!("cli", options(nomem, nostack)) }
+}
+
+/// Returns whether maskable interrupts are enabled.
+pub fn are_enabled() -> bool {
+    rflags::read() & rflags::INTERRUPT_FLAG != 0
+}
+
+/// Enables interrupts and halts until the next one.
+///
+/// Since interrupts are only enabled after the instruction following `sti`,
+/// an interrupt arriving in between is not lost, which makes this suitable for
+/// waiting on a condition checked with interrupts disabled.
+#[inline]
+pub fn enable_and_hlt() {
+    unsafe { asm!("sti", "hlt", options(nomem, nostack)) }
+}
+
+/// Runs a closure with interrupts disabled, enabling them again afterwards if
+/// they were enabled before.
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+    let result = f();
+    if enabled {
+        enable();
+    }
+    result
+}
@@ -6,7 +6,9 @@
 
 pub mod gdt;
 pub mod idt;
+pub mod interrupts;
 pub mod paging;
+pub mod port;
 pub mod register;
 
 /// Halts the CPU forever.
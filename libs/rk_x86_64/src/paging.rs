@@ -62,8 +62,9 @@ impl PageTableEntry {
 
     #[inline]
     pub fn addr(&self) -> u64 {
-        // Remove the 12 least significant bits (the options)
-        self.0 >> 12 << 12
+        // Remove the 12 least significant bits and the 12 most significant bits
+        // (the options)
+        self.0 & 0x000f_ffff_ffff_f000
     }
 
     /// Returns the raw bit data of the entry.
@@ -72,3 +73,10 @@ impl PageTableEntry {
         self.0
     }
 }
+
+/// Invalidates the TLB entry of the page containing the address, such that a
+/// changed or removed mapping takes effect.
+#[inline]
+pub fn invalidate_page(addr: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr, options(nostack)) }
+}
@@ -0,0 +1,47 @@
+//! Port-mapped I/O, using the `in` and `out` instructions.
+//!
+//! # Safety
+//! Accessing a port can have arbitrary side effects on the device behind it,
+//! so every function is unsafe.
+
+/// Reads a byte from the port.
+#[inline]
+pub unsafe fn read_u8(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+/// Reads a word from the port.
+#[inline]
+pub unsafe fn read_u16(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+/// Reads a double word from the port.
+#[inline]
+pub unsafe fn read_u32(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+/// Writes a byte to the port.
+#[inline]
+pub unsafe fn write_u8(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
+}
+
+/// Writes a word to the port.
+#[inline]
+pub unsafe fn write_u16(port: u16, value: u16) {
+    asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack));
+}
+
+/// Writes a double word to the port.
+#[inline]
+pub unsafe fn write_u32(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack));
+}
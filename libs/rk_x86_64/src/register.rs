@@ -18,6 +18,19 @@ pub mod cr0 {
     }
 }
 
+/// Control Register 2 (CR2) contains the address which caused the last page
+/// fault.
+pub mod cr2 {
+    /// Returns the current value of the CR2 register.
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe {
+            asm!("mov {}, cr2", out(reg) value);
+        }
+        value
+    }
+}
+
 /// Control Register 3 (CR3) contains the current physical address of the PML4
 /// table.
 pub mod cr3 {
@@ -90,3 +103,68 @@ pub mod cs {
         );
     }
 }
+
+/// The RFLAGS register.
+pub mod rflags {
+    /// The trap flag, which enables single stepping.
+    pub const TRAP_FLAG: u64 = 1 << 8;
+    /// The interrupt enable flag.
+    pub const INTERRUPT_FLAG: u64 = 1 << 9;
+    /// The direction flag, which makes string instructions decrement.
+    pub const DIRECTION_FLAG: u64 = 1 << 10;
+    /// The alignment check flag, which also allows user memory to be accessed
+    /// in kernel mode when SMAP is enabled.
+    pub const ALIGNMENT_CHECK_FLAG: u64 = 1 << 18;
+
+    /// Returns the current value of the RFLAGS register.
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe {
+            asm!("pushfq", "pop {}", out(reg) value);
+        }
+        value
+    }
+}
+
+/// Model specific registers (MSRs).
+pub mod msr {
+    /// The Extended Feature Enable Register.
+    pub const IA32_EFER: u32 = 0xc000_0080;
+    /// The segments loaded by `syscall` and `sysret`.
+    pub const IA32_STAR: u32 = 0xc000_0081;
+    /// The address `syscall` jumps to in 64-bit mode.
+    pub const IA32_LSTAR: u32 = 0xc000_0082;
+    /// The RFLAGS bits cleared by `syscall`.
+    pub const IA32_FMASK: u32 = 0xc000_0084;
+
+    /// EFER: Enables the `syscall` and `sysret` instructions.
+    pub const EFER_SYSCALL_ENABLE: u64 = 1 << 0;
+    /// EFER: Enables the no-execute page flag.
+    pub const EFER_NO_EXECUTE_ENABLE: u64 = 1 << 11;
+
+    /// Returns the value of a model specific register.
+    ///
+    /// # Safety
+    /// The register must exist, or a general protection fault is raised.
+    pub unsafe fn read(msr: u32) -> u64 {
+        let low: u32;
+        let high: u32;
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+        (high as u64) << 32 | low as u64
+    }
+
+    /// Writes a value to a model specific register.
+    ///
+    /// # Safety
+    /// The register must exist and the value must be valid for it, and an
+    /// unexpected value can break safety guarantees.
+    pub unsafe fn write(msr: u32, value: u64) {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack)
+        );
+    }
+}